@@ -226,6 +226,20 @@ Inside the `sim_macro::hcl` macro block, there are a few lines of code that desc
   For a practical instance, `D.valA` is the value at the start of the cycle (you should treat it as
   read-only), while `d.valA` is the value at the end of the cycle (you should assign to it using `@set_stage(d, { valA: signal })`).
 
+== Signal Delay (#HCLrs feature)
+
+The cost of an architecture is computed from the delay of its critical path. By default each hardware device costs 1 and each signal costs 0. A signal with slow combinational logic can be given a delay by an attribute:
+
+```
+#[delay(2)]
+u64 aluA = [
+    E.icode in { RRMOVQ, OPQ } : E.valA;
+    ...
+];
+```
+
+Devices can be annotated in the same way in the hardware module, e.g. `#[delay(3)]` before a unit in `crate::define_units`.
+
 == Stage Divider (#HCLrs feature)
 
 We provide a special divider syntax to separate different (semantic) stages in your HCL logic.
@@ -8,7 +8,7 @@ use dap::prelude::*;
 use serde::Deserialize;
 use y86_sim::{
     architectures::create_sim,
-    framework::{CpuSim, MemData},
+    framework::{CpuSim, Diagram, MemData},
 };
 
 use crate::SimOption;
@@ -34,7 +34,11 @@ pub struct Inner {
     source_name: String,
     scopes: Vec<types::Scope>,
     stage_info: Vec<y86_sim::framework::StageInfo>,
-    sim: Box<dyn CpuSim>,
+    /// Datapath diagram of the architecture, shown as a scope after the stages
+    diagram: Option<Diagram>,
+    sim: Box<dyn CpuSim + Send>,
+    /// Memory of the simulator, to tell when the program writes an exit code
+    mem: MemData,
 }
 
 pub struct DebugServer<R: Read, W: Write> {
@@ -87,7 +91,7 @@ impl<R: Read, W: Write> DebugServer<R, W> {
         let a = y86_sim::assemble(&src, y86_sim::AssembleOption::default())?;
 
         let mem = MemData::init(a.obj.init_mem());
-        let sim = create_sim(self.sim_opt.arch.clone(), mem, false);
+        let sim = create_sim(self.sim_opt.arch.clone(), mem.clone(), false);
         let source_path = program.clone();
         let source_info = a.source;
         let source_name = program.file_name().unwrap().to_string_lossy().to_string();
@@ -126,13 +130,27 @@ impl<R: Read, W: Write> DebugServer<R, W> {
 
         let stage_info = sim.get_stage_info();
 
+        let diagram = sim.diagram().map(Diagram::parse).transpose()?;
+        if diagram.is_some() {
+            scopes.push(types::Scope {
+                name: "Datapath".to_string(),
+                presentation_hint: Some(types::ScopePresentationhint::Locals),
+                variables_reference: (stage_info.len() + 2) as i64,
+                expensive: false,
+                source: Some(main_source.clone()),
+                ..Default::default()
+            });
+        }
+
         self.inner = Some(Inner {
             source_path,
             source_info,
             source_name,
             scopes,
             stage_info,
+            diagram,
             sim,
+            mem,
         });
 
         Ok(())
@@ -310,6 +328,23 @@ impl<R: Read, W: Write> DebugServer<R, W> {
                             }
                         })
                         .collect()
+                } else if let Some(diagram) = inner
+                    .diagram
+                    .as_ref()
+                    .filter(|_| args.variables_reference == (inner.stage_info.len() + 2) as i64)
+                {
+                    // datapath scope, one variable per line of the drawing
+                    diagram
+                        .render_sim(&*inner.sim, false)
+                        .lines()
+                        .enumerate()
+                        .map(|(i, line)| types::Variable {
+                            name: format!("{:>2}", i + 1),
+                            value: line.to_string(),
+                            presentation_hint: Some(VAR_PRESENTATION_HINT),
+                            ..Default::default()
+                        })
+                        .collect()
                 } else {
                     // scopes of each stage
                     let index = args.variables_reference - 2;
@@ -323,9 +358,10 @@ impl<R: Read, W: Write> DebugServer<R, W> {
                     stage
                         .signals
                         .iter()
-                        .map(|(name, val)| types::Variable {
-                            name: name.to_string(),
-                            value: val.clone(),
+                        .map(|sig| types::Variable {
+                            name: sig.name.clone(),
+                            value: sig.value.to_string(),
+                            type_field: Some(sig.typ.to_string()),
                             presentation_hint: Some(VAR_PRESENTATION_HINT),
                             ..Default::default()
                         })
@@ -446,7 +482,8 @@ impl<R: Read, W: Write> DebugServer<R, W> {
 
         // start the simulation loop
         loop {
-            if sim.is_terminate() {
+            let exit_code = inner.mem.exit_code();
+            if sim.is_terminate() || exit_code.is_some() {
                 tracing::info!("program terminated");
                 self.server
                     .send_event(Event::Stopped(events::StoppedEventBody {
@@ -454,11 +491,19 @@ impl<R: Read, W: Write> DebugServer<R, W> {
                         description: Some("Pause on termination".to_string()),
                         thread_id: Some(THREAD_ID),
                         preserve_focus_hint: None,
-                        text: Some(format!(
-                            "pc = {:#x}, cycle count = {}",
-                            sim.program_counter(),
-                            sim.cycle_count()
-                        )),
+                        text: Some(match exit_code {
+                            Some(code) => format!(
+                                "pc = {:#x}, cycle count = {}, exit code = {}",
+                                sim.program_counter(),
+                                sim.cycle_count(),
+                                code
+                            ),
+                            None => format!(
+                                "pc = {:#x}, cycle count = {}",
+                                sim.program_counter(),
+                                sim.cycle_count()
+                            ),
+                        }),
                         all_threads_stopped: None,
                         hit_breakpoint_ids: None,
                     }))?;
@@ -1,3 +1,5 @@
+use std::path::Path;
+
 use anyhow::{bail, Context};
 use rand::prelude::*;
 use y86_sim::{isa::reg_code, utils::get_u64, AssembleOption};
@@ -28,9 +30,12 @@ fn render_nums(data: &[i64]) -> String {
 /// in turn can be used to estimate the architecture performance.
 ///
 /// In this lab, the length of the critical path is simplified as: 1 plus the
-/// maximum number of hardware devices (units) that lines up in a path of the
-/// architecture. For example, `seq_std` has a critical path of length 8, and
-/// `pipe_std` has a critical path of length 4.
+/// total delay of the heaviest path of the architecture. Each hardware device
+/// (unit) costs 1 unless it is annotated with `#[delay(n)]` in the hardware
+/// module, and combinational signals cost nothing unless annotated with
+/// `#[delay(n)]` in HCL. Without annotations this is the maximum number of
+/// devices that lines up in a path plus 1. For example, `seq_std` has a
+/// critical path of length 8, and `pipe_std` has a critical path of length 4.
 ///
 /// One can use `./target/debug/ysim -A [arch_name] -I` to inspect the length
 /// of the critical path and the devices execution order of an architecture.
@@ -49,14 +54,38 @@ pub fn ncopy_scorerate(cpe: f64, cost: u64) -> f64 {
     }
 }
 
+/// Reduce a program on which `arch` fails the ISA check and save the minimal
+/// reproducer as `name` in `dir`, if a directory is given. Reducing runs the
+/// architecture many times, so it is left to the command line.
+fn save_reduced(arch: &str, src: &str, dir: Option<&Path>, name: &str, tty_out: bool) {
+    let Some(dir) = dir else {
+        return;
+    };
+    let path = dir.join(name);
+    let reduced = std::fs::create_dir_all(dir)
+        .map_err(anyhow::Error::from)
+        .and_then(|_| y86_sim::test::reduce::reduce(arch, src));
+    match reduced {
+        Ok(reduced) => {
+            std::fs::write(&path, reduced.to_string()).unwrap();
+            if tty_out {
+                println!("A minimal reproducer is saved as {}", path.display());
+                println!("first divergence: {}", reduced.divergence);
+            }
+        }
+        Err(e) => tracing::warn!("failed to reduce the test program: {e:#}"),
+    }
+}
+
 /// This function assembles the `misc/ncopy.ys` file and run the simulation
 /// using `ncopy` architecture.
 ///
 /// It returns the average cycle per element and the architecture cost.
 ///
 /// It generate random bytes of a list of lengths and test the correctness and
-/// performance of ncopy function.
-pub fn grade_ncopy(tty_out: bool) -> anyhow::Result<(f64, u64)> {
+/// performance of ncopy function. A failing test is reduced into `reduce_dir`
+/// if given.
+pub fn grade_ncopy(reduce_dir: Option<&Path>, tty_out: bool) -> anyhow::Result<(f64, u64)> {
     // fix seed for reproducibility
     let mut rng = SmallRng::from_seed([0; 32]);
 
@@ -72,6 +101,7 @@ pub fn grade_ncopy(tty_out: bool) -> anyhow::Result<(f64, u64)> {
 
     let mut sum_cpe = 0f64;
     let mut sim_cycle_cost = 10; // should be <= 10
+    let mut counters = y86_sim::framework::PerfCounters::default();
 
     for len in data_lens.iter().copied() {
         if tty_out {
@@ -119,9 +149,18 @@ pub fn grade_ncopy(tty_out: bool) -> anyhow::Result<(f64, u64)> {
                 println!("==========================================================");
                 println!("failed to pass ISA check for: ncopy.ys");
                 println!("The test source file is saved as ncopy_test_failure.ys");
-                println!("==========================================================");
             }
             std::fs::write("ncopy_test_failure.ys", &src).unwrap();
+            save_reduced(
+                "ncopy",
+                &src,
+                reduce_dir,
+                "ncopy_test_failure_min.ys",
+                tty_out,
+            );
+            if tty_out {
+                println!("==========================================================");
+            }
         })?;
 
         let sim = res.sim;
@@ -135,6 +174,7 @@ pub fn grade_ncopy(tty_out: bool) -> anyhow::Result<(f64, u64)> {
 
         sum_cpe += cpe;
         sim_cycle_cost = sim.cycle_cost();
+        counters.merge(sim.counters());
 
         let check_data = || {
             let a = y86_sim::assemble(&src, AssembleOption::default())?;
@@ -190,6 +230,11 @@ pub fn grade_ncopy(tty_out: bool) -> anyhow::Result<(f64, u64)> {
 
     let avg_cpe = sum_cpe / data_lens.len() as f64;
 
+    if tty_out {
+        println!("performance counters of all tests:");
+        print!("{counters}");
+    }
+
     Ok((avg_cpe, sim_cycle_cost))
 }
 
@@ -243,7 +288,13 @@ pub fn grade_sum(path: &str) -> anyhow::Result<()> {
 ///
 /// - If `ext_iopq` is true, the architecture should support the `iopq`
 ///   instruction.
-pub fn grade_arch(arch: &str, ext_iopq: bool, tty_out: bool) -> anyhow::Result<()> {
+/// - If `reduce_dir` is given, a failing program is reduced into it.
+pub fn grade_arch(
+    arch: &str,
+    ext_iopq: bool,
+    reduce_dir: Option<&Path>,
+    tty_out: bool,
+) -> anyhow::Result<()> {
     let paths = std::fs::read_dir("misc/y86-code")?
         .filter_map(Result::ok)
         .map(|entry| entry.path())
@@ -272,11 +323,10 @@ pub fn grade_arch(arch: &str, ext_iopq: bool, tty_out: bool) -> anyhow::Result<(
                 println!("- testing {}", path.display());
             }
 
-            sim.test_isa(
-                &std::fs::read_to_string(&path)
-                    .with_context(|| format!("read file {}", path.display()))?,
-            )
-            .inspect_err(|_| {
+            let src = std::fs::read_to_string(&path)
+                .with_context(|| format!("read file {}", path.display()))?;
+
+            sim.test_isa(&src).inspect_err(|_| {
                 if tty_out {
                     println!("==========================================================");
                     println!("failed to pass ISA check for: {}", path.display());
@@ -284,6 +334,9 @@ pub fn grade_arch(arch: &str, ext_iopq: bool, tty_out: bool) -> anyhow::Result<(
                     println!("To reproduce the error, you may run the following command:");
                     println!("./target/debug/yas {}", path.display());
                     println!("./target/debug/ysim {} -A {arch}", path.display());
+                }
+                save_reduced(arch, &src, reduce_dir, "test_failure_min.ys", tty_out);
+                if tty_out {
                     println!("==========================================================");
                 }
             })?;
@@ -329,8 +382,8 @@ fn compare_arch(arch: &str, gt_arch: &str, tty_out: bool) -> anyhow::Result<()>
             let mut gt_sim =
                 y86_sim::architectures::create_sim(gt_arch.to_string(), gt_mem.clone(), false);
 
-            while !gt_sim.is_terminate() {
-                if sim.is_terminate() {
+            while !gt_sim.is_terminate() && gt_mem.exit_code().is_none() {
+                if sim.is_terminate() || mem.exit_code().is_some() {
                     bail!(
                         "testing {}: {} terminates before {}",
                         path.display(),
@@ -338,8 +391,11 @@ fn compare_arch(arch: &str, gt_arch: &str, tty_out: bool) -> anyhow::Result<()>
                         gt_arch
                     );
                 }
-                gt_sim.step();
-                sim.step();
+                gt_sim
+                    .step()
+                    .with_context(|| format!("testing {}: {}", path.display(), gt_arch))?;
+                sim.step()
+                    .with_context(|| format!("testing {}: {}", path.display(), arch))?;
 
                 if sim.registers() != gt_sim.registers() {
                     bail!("testing {}: {} registers mismatch", path.display(), arch);
@@ -351,6 +407,9 @@ fn compare_arch(arch: &str, gt_arch: &str, tty_out: bool) -> anyhow::Result<()>
             if gt_mem.read().as_ref() != mem.read().as_ref() {
                 bail!("testing {}: {} memory mismatch", path.display(), arch);
             }
+            if gt_mem.exit_code() != mem.exit_code() {
+                bail!("testing {}: {} exit code mismatch", path.display(), arch);
+            }
         }
     }
 
@@ -361,13 +420,14 @@ fn compare_arch(arch: &str, gt_arch: &str, tty_out: bool) -> anyhow::Result<()>
 pub fn grade_pipe_placeholder(
     arch: &str,
     gt_arch: Option<&str>,
+    reduce_dir: Option<&Path>,
     tty_out: bool,
 ) -> anyhow::Result<()> {
     if tty_out {
         println!("testing {} ISA:", arch);
     }
     // check ISA correctness
-    grade_arch(arch, false, tty_out)?;
+    grade_arch(arch, false, reduce_dir, tty_out)?;
 
     if let Some(gt_arch) = gt_arch {
         if tty_out {
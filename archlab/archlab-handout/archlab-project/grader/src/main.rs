@@ -46,7 +46,7 @@ fn grade_autolab() {
 
     // Part B
     let seq_full_score = std::panic::catch_unwind(|| {
-        grader::grade_arch("seq_full", true, false)
+        grader::grade_arch("seq_full", true, None, false)
             .map(|_| PART_B_SEQ_FULL_SCORE)
             .unwrap_or(0)
     })
@@ -65,7 +65,7 @@ fn grade_autolab() {
             };
 
             std::panic::catch_unwind(|| {
-                grader::grade_pipe_placeholder(arch, maybe_gt, false)
+                grader::grade_pipe_placeholder(arch, maybe_gt, None, false)
                     .map(|_| score)
                     .unwrap_or(0)
             })
@@ -77,7 +77,7 @@ fn grade_autolab() {
 
     // Part C
 
-    let (cpe, ac, ncopy_score) = grader::grade_ncopy(false)
+    let (cpe, ac, ncopy_score) = grader::grade_ncopy(None, false)
         .map(|(cpe, ac)| {
             (
                 cpe,
@@ -128,6 +128,10 @@ struct Args {
     #[command(subcommand)]
     part: Subcommands,
 
+    /// Reduce a failing test program to a minimal reproducer, saved in DIR
+    #[arg(long, value_name = "DIR", global = true)]
+    reduce: Option<std::path::PathBuf>,
+
     /// Print logs during simulation
     #[command(flatten)]
     verbose: verbose::Verbosity,
@@ -139,6 +143,7 @@ fn inner_main() -> anyhow::Result<()> {
 
     use y86_sim::utils::GRNB;
 
+    let reduce_dir = args.reduce.as_deref();
     match args.part {
         Subcommands::PartA => {
             grade_it("misc/bubble.ys", || grader::grade_bubble("misc/bubble.ys"));
@@ -147,7 +152,9 @@ fn inner_main() -> anyhow::Result<()> {
             grade_it("misc/rsum.ys", || grader::grade_sum("misc/rsum.ys"));
         }
         Subcommands::PartB => {
-            grade_it("seq_full", || grader::grade_arch("seq_full", true, true));
+            grade_it("seq_full", || {
+                grader::grade_arch("seq_full", true, reduce_dir, true)
+            });
 
             let names = y86_sim::architectures::arch_names();
 
@@ -162,12 +169,13 @@ fn inner_main() -> anyhow::Result<()> {
                 };
 
                 grade_it(arch, || {
-                    grader::grade_pipe_placeholder(arch, maybe_gt, true)
+                    grader::grade_pipe_placeholder(arch, maybe_gt, reduce_dir, true)
                 });
             }
         }
         Subcommands::PartC => {
-            let (cpe, cost) = grader::grade_ncopy(true).context("part C: misc/ncopy.ys")?;
+            let (cpe, cost) =
+                grader::grade_ncopy(reduce_dir, true).context("part C: misc/ncopy.ys")?;
             println!(
                 "{GRNB}Part C{GRNB:#}: all tests passed, cpe: {}, arch cost: {}, score: {:.4}",
                 cpe,
@@ -184,7 +192,7 @@ fn inner_main() -> anyhow::Result<()> {
             }
         }
         Subcommands::Arch { arch, iopq } => {
-            grader::grade_arch(&arch, iopq, true).context("test architecture")?;
+            grader::grade_arch(&arch, iopq, reduce_dir, true).context("test architecture")?;
         }
     }
 
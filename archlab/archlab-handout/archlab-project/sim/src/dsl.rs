@@ -12,14 +12,22 @@
 ///    update its output signals, while stage units just store the inputs.
 /// 4. After all signals reaching their destinations, the cycle ends. The inputs
 ///    of stage units will become the starting signals of the next cycle.
+///
+/// A unit may be annotated with `#[delay(n)]` to specify how long its
+/// combinational logic takes in the weighted timing model (see
+/// [`crate::framework::TimingModel`]). Units without the annotation have a
+/// delay of [`crate::framework::DEFAULT_UNIT_DELAY`].
+///
+/// A state of a unit may be annotated with `#[signal]` to read and write it
+/// by name, like the ports (see [`crate::framework::UnitState`]).
 #[macro_export]
 macro_rules! define_units {
     ($(
-        $(#[$att:meta])*
+        $(#[$($att:tt)*])*
         $unit_name:ident $unit_short_name:ident {
             $(.input( $($(#[$input_att:meta])* $iname:ident : $itype:ty),* ))?
             $(.output( $($(#[$output_att:meta])* $oname:ident : $otype:ty),* ))?
-            $($sname:ident : $stype:ty),* $(,)?
+            $($(#[$($satt:tt)*])* $sname:ident : $stype:ty),* $(,)?
         } $($body:block)?
     )*) => {
         /// Input signals of units
@@ -55,6 +63,31 @@ macro_rules! define_units {
         pub struct UnitOutputSignal {
             $(pub $unit_short_name: unit_out::$unit_name),*
         }
+        impl UnitInputSignal {
+            /// Input ports of all units, named `unit.port`.
+            #[allow(unused)]
+            pub fn signals(&self) -> Vec<$crate::framework::Signal> {
+                vec![$( $( $( $crate::framework::Signal::new(
+                    concat!(stringify!($unit_short_name), ".", stringify!($iname)),
+                    $crate::framework::SignalKind::UnitInput,
+                    stringify!($itype),
+                    &self.$unit_short_name.$iname,
+                ), )* )? )*]
+            }
+        }
+        impl UnitOutputSignal {
+            /// Output ports of all units, named `unit.port`.
+            #[allow(unused)]
+            pub fn signals(&self) -> Vec<$crate::framework::Signal> {
+                vec![$( $( $( $crate::framework::Signal::new(
+                    concat!(stringify!($unit_short_name), ".", stringify!($oname)),
+                    $crate::framework::SignalKind::UnitOutput,
+                    stringify!($otype),
+                    &self.$unit_short_name.$oname,
+                ), )* )? )*]
+            }
+        }
+
         /// A unit simulates a circuit in the CPU. It receives signals from
         /// the previous stage and outputs signals to the next stage.
         ///
@@ -63,10 +96,12 @@ macro_rules! define_units {
             fn run(&mut self, signals: (&UnitInputSignal, &mut UnitOutputSignal));
         }
 
-        $( #[allow(unused)]
-        $(#[$att])*
-        struct $unit_name {
-            $(pub $sname: $stype ),*
+        $( $crate::__unit_struct! {
+            [#[allow(unused)]]
+            $(#[$($att)*])*
+            struct $unit_name {
+                $(pub $sname: $stype ),*
+            }
         } )*
 
         $( impl $unit_name {
@@ -90,7 +125,87 @@ macro_rules! define_units {
         pub struct Units {
             $( $unit_short_name: $unit_name, )*
         }
+
+        /// Ports of all units, named `unit.port`. `hcl!` checks the ports it
+        /// references against them (see [`$crate::dsl::has_name`]).
+        #[allow(unused)]
+        pub const PORT_NAMES: &[&str] = &[$(
+            $( $( concat!(stringify!($unit_short_name), ".", stringify!($iname)), )* )?
+            $( $( concat!(stringify!($unit_short_name), ".", stringify!($oname)), )* )?
+        )*];
+        impl UnitInputSignal {
+            /// Set the input port `unit.port`.
+            #[allow(unused)]
+            pub fn set_signal(
+                &mut self,
+                name: &str,
+                value: &$crate::framework::SignalValue,
+            ) -> Result<(), $crate::framework::SignalError> {
+                match name {
+                    $( $( $( concat!(stringify!($unit_short_name), ".", stringify!($iname)) =>
+                        $crate::framework::set_value(
+                            &mut self.$unit_short_name.$iname,
+                            name,
+                            stringify!($itype),
+                            value,
+                        ),
+                    )* )? )*
+                    _ => Err($crate::framework::SignalError::NotFound(name.to_string())),
+                }
+            }
+        }
+        impl UnitOutputSignal {
+            /// Set the output port `unit.port`.
+            #[allow(unused)]
+            pub fn set_signal(
+                &mut self,
+                name: &str,
+                value: &$crate::framework::SignalValue,
+            ) -> Result<(), $crate::framework::SignalError> {
+                match name {
+                    $( $( $( concat!(stringify!($unit_short_name), ".", stringify!($oname)) =>
+                        $crate::framework::set_value(
+                            &mut self.$unit_short_name.$oname,
+                            name,
+                            stringify!($otype),
+                            value,
+                        ),
+                    )* )? )*
+                    _ => Err($crate::framework::SignalError::NotFound(name.to_string())),
+                }
+            }
+        }
+
         impl Units {
+            /// States of units marked `#[signal]`.
+            #[allow(unused)]
+            pub fn signals(&self) -> Vec<$crate::framework::Signal> {
+                let mut signals = Vec::new();
+                $( $( $crate::__unit_signal! {
+                    signals(signals, self.$unit_short_name.$sname,
+                        stringify!($unit_short_name), stringify!($sname), stringify!($stype))
+                    $(#[$($satt)*])*
+                } )* )*
+                signals
+            }
+
+            /// Set a state of a unit marked `#[signal]`.
+            #[allow(unused)]
+            pub fn set_signal(
+                &mut self,
+                name: &str,
+                value: &$crate::framework::SignalValue,
+            ) -> Result<(), $crate::framework::SignalError> {
+                use $crate::framework::SignalError;
+                $( $( $crate::__unit_signal! {
+                    set_signal(self.$unit_short_name.$sname,
+                        stringify!($unit_short_name), stringify!($sname), stringify!($stype),
+                        name, value)
+                    $(#[$($satt)*])*
+                } )* )*
+                Err(SignalError::NotFound(name.to_string()))
+            }
+
             /// Execute this unit by processing the input signals and updating its output signals.
             #[allow(unused)]
             pub fn run(&mut self, name: &'static str, sigs: (&UnitInputSignal, &mut UnitOutputSignal)) {
@@ -112,20 +227,336 @@ macro_rules! define_units {
             $( $( builder.add_unit_output(stringify!($unit_short_name), stringify!($oname)); )* )?
             )*
         }
+
+        impl $crate::hcl::DynUnits for Units {
+            fn hardware_setup(builder: &mut $crate::framework::PropOrderBuilder) {
+                hardware_setup(builder)
+            }
+            fn unit_delays() -> Vec<(&'static str, u64)> {
+                unit_delays()
+            }
+            fn state_signals(&self) -> Vec<$crate::framework::Signal> {
+                self.signals()
+            }
+            fn set_state(
+                &mut self,
+                name: &str,
+                value: &$crate::framework::SignalValue,
+            ) -> Result<(), $crate::framework::SignalError> {
+                self.set_signal(name, value)
+            }
+            fn ports() -> Vec<$crate::hcl::UnitPort> {
+                let port = |unit, name, typ, is_input| $crate::hcl::UnitPort {
+                    unit,
+                    name,
+                    typ,
+                    is_input,
+                };
+                vec![$(
+                    $( $( port(stringify!($unit_short_name), stringify!($iname), stringify!($itype), true), )* )?
+                    $( $( port(stringify!($unit_short_name), stringify!($oname), stringify!($otype), false), )* )?
+                )*]
+            }
+            #[allow(unused)]
+            fn run_dyn(&mut self, name: &str, ports: &mut $crate::hcl::Ports) {
+                use $crate::hcl::HclValue;
+                match name {
+                    $( stringify!($unit_short_name) => {
+                        let port = |p: &str| format!("{}.{}", stringify!($unit_short_name), p);
+                        let mut inputs = unit_in::$unit_name::default();
+                        let mut outputs = unit_out::$unit_name::default();
+                        $( $( if let Some(v) = HclValue::load(ports, &port(stringify!($iname))) {
+                            inputs.$iname = v;
+                        } )* )?
+                        $( $( if let Some(v) = HclValue::load(ports, &port(stringify!($oname))) {
+                            outputs.$oname = v;
+                        } )* )?
+                        $unit_name::trigger(&mut self.$unit_short_name, &inputs, &mut outputs);
+                        $( $( outputs.$oname.store(ports, port(stringify!($oname))); )* )?
+                    } )*
+                    _ => {}
+                }
+            }
+        }
+
+        /// Delays of the units annotated with `#[delay(..)]`, to weigh the
+        /// propagation order built with `hardware_setup`.
+        pub fn unit_delays() -> Vec<(&'static str, u64)> {
+            let mut delays = Vec::new();
+            $(
+            if let Some(delay) = $crate::__unit_delay!($(#[$($att)*])*) {
+                delays.push((stringify!($unit_short_name), delay));
+            }
+            )*
+            delays
+        }
     };
 }
 
-/// In [`sim_macro::hcl`], The `a in {...}` expression is transformed into
-/// `mtc(a, [...])`.
-pub(crate) fn mtc<T: Eq>(sig: T, choice: impl AsRef<[T]>) -> bool {
-    for c in choice.as_ref() {
-        if *c == sig {
-            return true;
+/// Emit the state struct of a unit, dropping the `#[delay(..)]` attribute
+/// which is only meaningful to [`define_units`].
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __unit_struct {
+    ([$($keep:tt)*] #[delay($delay:literal)] $($rest:tt)*) => {
+        $crate::__unit_struct! { [$($keep)*] $($rest)* }
+    };
+    ([$($keep:tt)*] #[$($att:tt)*] $($rest:tt)*) => {
+        $crate::__unit_struct! { [$($keep)* #[$($att)*]] $($rest)* }
+    };
+    ([$($keep:tt)*] struct $($item:tt)*) => {
+        $($keep)* struct $($item)*
+    };
+}
+
+/// List or set a state of a unit if it is marked `#[signal]`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __unit_signal {
+    (signals($out:ident, $field:expr, $unit:expr, $name:expr, $typ:expr) #[signal] $($rest:tt)*) => {
+        $out.extend($crate::framework::UnitState::signals(&$field, $unit, $name, $typ));
+    };
+    (set_signal($field:expr, $unit:expr, $name:expr, $typ:expr, $target:expr, $value:expr)
+        #[signal] $($rest:tt)*) => {
+        match $crate::framework::UnitState::set_signal(
+            &mut $field, $unit, $name, $typ, $target, $value,
+        ) {
+            Err($crate::framework::SignalError::NotFound(_)) => {}
+            result => return result,
         }
+    };
+    ($op:ident $args:tt #[$($att:tt)*] $($rest:tt)*) => {
+        $crate::__unit_signal! { $op $args $($rest)* }
+    };
+    ($op:ident $args:tt) => {};
+}
+
+/// Find the `#[delay(..)]` attribute of a unit.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __unit_delay {
+    (#[delay($delay:literal)] $($rest:tt)*) => {
+        Some::<u64>($delay)
+    };
+    (#[$($att:tt)*] $($rest:tt)*) => {
+        $crate::__unit_delay!($($rest)*)
+    };
+    () => {
+        None::<u64>
+    };
+}
+
+/// In [`sim_macro::hcl`], the signals in an `a in {...}` expression are
+/// tested by `mtc(a, [...])`, the constant members by [`ConstSet`].
+pub(crate) fn mtc<T: PartialEq<U>, U>(sig: T, choice: impl AsRef<[U]>) -> bool {
+    choice.as_ref().iter().any(|c| sig == *c)
+}
+
+/// In [`sim_macro::hcl`], a unit port `name` must be one of `PORT_NAMES`
+/// generated by [`define_units`]. Being `const`, an unknown port fails the
+/// build at the HCL token.
+pub(crate) const fn has_name(names: &[&str], name: &str) -> bool {
+    let name = name.as_bytes();
+    let mut i = 0;
+    while i < names.len() {
+        let other = names[i].as_bytes();
+        if other.len() == name.len() {
+            let mut j = 0;
+            while j < name.len() && other[j] == name[j] {
+                j += 1;
+            }
+            if j == name.len() {
+                return true;
+            }
+        }
+        i += 1;
     }
     false
 }
 
+/// In [`sim_macro::hcl`], `same_type(&a, &m)` makes a member `m` of
+/// `a in {...}` have the type of `a`, as [`mtc`] does for signals.
+pub(crate) fn same_type<T>(_: &T, _: &T) {}
+
+/// A value tested against the constant members of `a in {...}`.
+pub(crate) trait SetMember: Copy {
+    fn word(self) -> u64;
+}
+
+macro_rules! impl_set_member {
+    ($($t:ty)*) => {$(
+        impl SetMember for $t {
+            fn word(self) -> u64 {
+                self as u64
+            }
+        }
+    )*};
+}
+
+impl_set_member!(bool u8 u16 u32 u64 usize crate::isa::Stat);
+
+/// The constant members of an `a in {...}` expression of
+/// [`sim_macro::hcl`], as inclusive ranges of words. It is built at compile
+/// time, and a set of members below 128 is tested with a bitmask.
+pub(crate) struct ConstSet<const N: usize> {
+    ranges: [(u64, u64); N],
+    mask: u128,
+    small: bool,
+}
+
+impl<const N: usize> ConstSet<N> {
+    pub const fn new(ranges: [(u64, u64); N]) -> Self {
+        let mut mask = 0;
+        let mut small = true;
+        let mut i = 0;
+        while i < N {
+            let (lo, hi) = ranges[i];
+            if hi >= 128 {
+                small = false;
+            } else {
+                let mut v = lo;
+                while v <= hi {
+                    mask |= 1 << v;
+                    v += 1;
+                }
+            }
+            i += 1;
+        }
+        Self {
+            ranges,
+            mask,
+            small,
+        }
+    }
+
+    /// Whether the `i`-th member can never be the first to match: it is an
+    /// empty range, or it is covered by the members before it.
+    pub const fn is_unreachable(&self, i: usize) -> bool {
+        let (mut lo, hi) = self.ranges[i];
+        while lo <= hi {
+            // the earlier member containing `lo` that reaches the farthest
+            let mut next = None;
+            let mut j = 0;
+            while j < i {
+                let (a, b) = self.ranges[j];
+                if a <= lo && lo <= b {
+                    next = match next {
+                        Some(n) if n >= b => Some(n),
+                        _ => Some(b),
+                    };
+                }
+                j += 1;
+            }
+            match next {
+                None => return false,
+                Some(b) if b >= hi => return true,
+                Some(b) => lo = b + 1,
+            }
+        }
+        true
+    }
+
+    pub fn contains(&self, sig: impl SetMember) -> bool {
+        let v = sig.word();
+        if self.small {
+            v < 128 && self.mask >> v & 1 != 0
+        } else {
+            self.ranges.iter().any(|&(lo, hi)| lo <= v && v <= hi)
+        }
+    }
+}
+
+/// In [`sim_macro::hcl`], a signal drives a unit input or a stage field
+/// through [`wire`], so that a type mismatch is reported at the destination
+/// in HCL, instead of deep in the generated code.
+#[diagnostic::on_unimplemented(
+    message = "a signal of type `{Self}` can't drive a destination of type `{T}`",
+    label = "the signal is `{Self}`, the destination is `{T}`"
+)]
+pub(crate) trait Wire<T> {
+    fn wire(&self) -> T;
+}
+
+impl<T: Clone> Wire<T> for T {
+    fn wire(&self) -> T {
+        self.clone()
+    }
+}
+
+/// In [`sim_macro::hcl`], `sig -> dest` is transformed into
+/// `wire(&mut dest, &sig)`.
+pub(crate) fn wire<T, S: Wire<T>>(dest: &mut T, signal: &S) {
+    *dest = signal.wire();
+}
+
+/// In [`sim_macro::hcl`], the value of a signal (or of a case of a switch)
+/// is converted to the declared type of the signal by `Assign::value`,
+/// so that a type mismatch is reported at the value in HCL.
+#[diagnostic::on_unimplemented(
+    message = "a value of type `{Self}` can't be assigned to a signal of type `{T}`",
+    label = "expected `{T}`, found `{Self}`"
+)]
+pub(crate) trait Assign<T> {
+    fn value(self) -> T;
+}
+
+impl<T> Assign<T> for T {
+    fn value(self) -> T {
+        self
+    }
+}
+
+/// Integer arithmetic of [`sim_macro::hcl`] expressions. Like the hardware,
+/// it wraps around instead of overflowing, and shifting by the width of the
+/// operand or more gives 0.
+pub(crate) trait HclArith: Copy {
+    fn hcl_add(self, rhs: Self) -> Self;
+    fn hcl_sub(self, rhs: Self) -> Self;
+    fn hcl_shl(self, rhs: Self) -> Self;
+    fn hcl_shr(self, rhs: Self) -> Self;
+}
+
+macro_rules! impl_hcl_arith {
+    ($($t:ty)*) => {$(
+        impl HclArith for $t {
+            fn hcl_add(self, rhs: Self) -> Self {
+                self.wrapping_add(rhs)
+            }
+            fn hcl_sub(self, rhs: Self) -> Self {
+                self.wrapping_sub(rhs)
+            }
+            fn hcl_shl(self, rhs: Self) -> Self {
+                u32::try_from(rhs).ok().and_then(|n| self.checked_shl(n)).unwrap_or(0)
+            }
+            fn hcl_shr(self, rhs: Self) -> Self {
+                u32::try_from(rhs).ok().and_then(|n| self.checked_shr(n)).unwrap_or(0)
+            }
+        }
+    )*};
+}
+
+impl_hcl_arith!(u8 u16 u32 u64 usize i64);
+
+/// In [`sim_macro::hcl`], `a + b` is transformed into `add(a, b)`.
+pub(crate) fn add<T: HclArith>(a: T, b: T) -> T {
+    a.hcl_add(b)
+}
+
+/// In [`sim_macro::hcl`], `a - b` is transformed into `sub(a, b)`.
+pub(crate) fn sub<T: HclArith>(a: T, b: T) -> T {
+    a.hcl_sub(b)
+}
+
+/// In [`sim_macro::hcl`], `a << b` is transformed into `shl(a, b)`.
+pub(crate) fn shl<T: HclArith>(a: T, b: T) -> T {
+    a.hcl_shl(b)
+}
+
+/// In [`sim_macro::hcl`], `a >> b` is transformed into `shr(a, b)`.
+pub(crate) fn shr<T: HclArith>(a: T, b: T) -> T {
+    a.hcl_shr(b)
+}
+
 #[macro_export]
 macro_rules! define_stages {
     ($(
@@ -199,6 +630,72 @@ macro_rules! define_stages {
             pub fn mux(&mut self, new: &PipeRegs) {
                 $( self.$pr_short_name.mux(&new.$pr_short_name); )*
             }
+
+            /// Count the `bubble` and `stall` inputs of all stages.
+            #[allow(unused)]
+            pub fn count_controls(&self, counters: &mut $crate::framework::PerfCounters) {
+                $( counters.count_stage(
+                    stringify!($pr_short_name),
+                    self.$pr_short_name.bubble,
+                    self.$pr_short_name.stall,
+                ); )*
+            }
+
+            /// Fields of all stages, named `stage.field` after the short
+            /// names, each stage followed by its `bubble` and `stall` inputs.
+            #[allow(unused)]
+            pub fn signals(&self) -> Vec<$crate::framework::Signal> {
+                use $crate::framework::{Signal, SignalKind};
+                let mut signals = Vec::new();
+                $(
+                $( signals.push(Signal::new(
+                    concat!(stringify!($pr_short_name), ".", stringify!($pname)),
+                    SignalKind::StageField,
+                    stringify!($ptype),
+                    &self.$pr_short_name.$pname,
+                )); )*
+                for (name, value) in [
+                    (concat!(stringify!($pr_short_name), ".bubble"), self.$pr_short_name.bubble),
+                    (concat!(stringify!($pr_short_name), ".stall"), self.$pr_short_name.stall),
+                ] {
+                    signals.push(Signal::new(name, SignalKind::StageControl, "bool", &value));
+                }
+                )*
+                signals
+            }
+
+            /// Set a field `stage.field` (or the `bubble` or `stall` input)
+            /// of a stage, named after the short name.
+            #[allow(unused)]
+            pub fn set_signal(
+                &mut self,
+                name: &str,
+                value: &$crate::framework::SignalValue,
+            ) -> Result<(), $crate::framework::SignalError> {
+                use $crate::framework::set_value;
+                match name {
+                    $(
+                    $( concat!(stringify!($pr_short_name), ".", stringify!($pname)) =>
+                        set_value(&mut self.$pr_short_name.$pname, name, stringify!($ptype), value),
+                    )*
+                    concat!(stringify!($pr_short_name), ".bubble") =>
+                        set_value(&mut self.$pr_short_name.bubble, name, "bool", value),
+                    concat!(stringify!($pr_short_name), ".stall") =>
+                        set_value(&mut self.$pr_short_name.stall, name, "bool", value),
+                    )*
+                    _ => Err($crate::framework::SignalError::NotFound(name.to_string())),
+                }
+            }
+
+            /// The first stage whose `bubble` and `stall` inputs are both
+            /// set, which is an error of the control logic.
+            #[allow(unused)]
+            pub fn control_conflict(&self) -> Option<&'static str> {
+                $( if self.$pr_short_name.bubble && self.$pr_short_name.stall {
+                    return Some(stringify!($pr_short_name));
+                } )*
+                None
+            }
         }
     };
 }
@@ -0,0 +1,56 @@
+//! Invariants checked at the end of every cycle.
+//!
+//! An architecture states its invariants in HCL with the `@assert` directive,
+//! whose condition may read the same signals as `@count`:
+//!
+//! ```text
+//! @assert(!(e_fwd && m_fwd), "forwarding from E and M at the same time");
+//! @assert(W.stat != Aok || W.icode != HALT, "halt retired with status AOK");
+//! ```
+//!
+//! Besides, no stage may be bubbled and stalled in the same cycle. A failure
+//! makes [`CpuSim::step`](super::CpuSim::step) return an [`AssertionFailure`].
+
+/// A failed `@assert` of HCL, or a stage with both `bubble` and `stall` set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssertionFailure {
+    /// cycle in which the assertion fails, starting from 1
+    pub cycle: u64,
+    pub message: String,
+    /// values of the signals in the condition, as (name, value)
+    pub values: Vec<(String, String)>,
+}
+
+impl AssertionFailure {
+    /// The failure of a stage whose `bubble` and `stall` are both set.
+    pub fn control_conflict(cycle: u64, stage: &str) -> Self {
+        Self {
+            cycle,
+            message: format!("bubble and stall at the same time in stage {stage}"),
+            values: ["bubble", "stall"]
+                .iter()
+                .map(|c| (format!("{stage}.{c}"), "true".to_string()))
+                .collect(),
+        }
+    }
+}
+
+impl std::fmt::Display for AssertionFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "cycle {}: assertion failed: {}",
+            self.cycle, self.message
+        )?;
+        for (i, (name, value)) in self.values.iter().enumerate() {
+            let sep = if i == 0 { " (" } else { ", " };
+            write!(f, "{sep}{name} = {value}")?;
+        }
+        if !self.values.is_empty() {
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for AssertionFailure {}
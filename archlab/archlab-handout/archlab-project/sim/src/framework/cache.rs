@@ -0,0 +1,272 @@
+//! Set-associative cache model for memory units.
+//!
+//! A [`Cache`] keeps only the tags. The bytes stay in [`MemData`](super::MemData), which is
+//! always up to date, so a cache changes the timing of a program but never
+//! its result. A memory unit asks the cache whether an access hits and
+//! exposes that as an output signal:
+//!
+//! ```text
+//! DataMemory dmem {
+//!     .input(read: bool, write: bool, addr: u64, datain: u64)
+//!     .output(dataout: u64, error: bool, hit: bool)
+//!     mem: MemData,
+//!     cache: Cache
+//! } {
+//!     if read || write {
+//!         *hit = cache.access(addr, 8, write);
+//!     }
+//!     // perform the access on `mem`
+//! }
+//! ```
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Replacement {
+    /// Evict the least recently used line.
+    Lru,
+    /// Evict the line that was filled first.
+    Fifo,
+    /// Evict a pseudo-random line. The sequence is the same in every run.
+    Random,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WritePolicy {
+    /// Writes mark the line dirty; it is written back when evicted.
+    WriteBack,
+    /// Writes go to memory immediately.
+    WriteThrough,
+}
+
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    /// Number of sets, a power of two.
+    pub sets: usize,
+    /// Number of lines in each set.
+    pub ways: usize,
+    /// Size of a line in bytes, a power of two.
+    pub block_size: usize,
+    pub replacement: Replacement,
+    pub write: WritePolicy,
+    /// Whether a write miss brings the block into the cache.
+    pub write_allocate: bool,
+    /// Extra cycles taken by a miss.
+    pub miss_penalty: u64,
+}
+
+impl Default for CacheConfig {
+    /// A 4 KiB, 4-way, write-back LRU cache with 32-byte lines.
+    fn default() -> Self {
+        Self {
+            sets: 32,
+            ways: 4,
+            block_size: 32,
+            replacement: Replacement::Lru,
+            write: WritePolicy::WriteBack,
+            write_allocate: true,
+            miss_penalty: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Valid lines replaced by another block.
+    pub evictions: u64,
+    /// Dirty lines written back to memory (write-back caches only).
+    pub writebacks: u64,
+}
+
+impl CacheStats {
+    pub fn accesses(&self) -> u64 {
+        self.hits + self.misses
+    }
+
+    pub fn hit_rate(&self) -> f64 {
+        if self.accesses() == 0 {
+            0.0
+        } else {
+            self.hits as f64 / self.accesses() as f64
+        }
+    }
+}
+
+impl std::fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "hits: {}, misses: {}, evictions: {}, writebacks: {}, hit rate: {:.2}%",
+            self.hits,
+            self.misses,
+            self.evictions,
+            self.writebacks,
+            self.hit_rate() * 100.0
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Line {
+    valid: bool,
+    dirty: bool,
+    tag: u64,
+    /// Time of the last use (LRU) or of the fill (FIFO).
+    stamp: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Cache {
+    pub config: CacheConfig,
+    pub stats: CacheStats,
+    lines: Vec<Line>,
+    time: u64,
+    seed: u64,
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig) -> Self {
+        assert!(config.sets.is_power_of_two(), "sets must be a power of two");
+        assert!(
+            config.block_size.is_power_of_two(),
+            "block size must be a power of two"
+        );
+        assert!(config.ways > 0, "a cache has at least one way");
+        Self {
+            lines: vec![Line::default(); config.sets * config.ways],
+            config,
+            stats: CacheStats::default(),
+            time: 0,
+            seed: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+
+    /// Access `len` bytes from `addr`, updating the tags and the statistics.
+    /// Every block touched by the access counts as one hit or miss, an
+    /// access past the end of the address space stops at its last block.
+    /// Returns whether all of them hit.
+    pub fn access(&mut self, addr: u64, len: usize, write: bool) -> bool {
+        let block = self.config.block_size as u64;
+        let first = addr / block;
+        let last = addr.saturating_add(len.max(1) as u64 - 1) / block;
+        let mut hit = true;
+        for b in first..=last {
+            hit &= self.access_block(b, write);
+        }
+        hit
+    }
+
+    fn access_block(&mut self, block: u64, write: bool) -> bool {
+        self.time += 1;
+        let set = (block as usize) & (self.config.sets - 1);
+        let tag = block / self.config.sets as u64;
+        let ways = self.config.ways;
+        let lines = &mut self.lines[set * ways..(set + 1) * ways];
+
+        if let Some(line) = lines.iter_mut().find(|l| l.valid && l.tag == tag) {
+            self.stats.hits += 1;
+            if self.config.replacement == Replacement::Lru {
+                line.stamp = self.time;
+            }
+            if write && self.config.write == WritePolicy::WriteBack {
+                line.dirty = true;
+            }
+            return true;
+        }
+
+        self.stats.misses += 1;
+        if write && !self.config.write_allocate {
+            return false;
+        }
+        let victim = match lines.iter().position(|l| !l.valid) {
+            Some(i) => i,
+            None => match self.config.replacement {
+                Replacement::Lru | Replacement::Fifo => {
+                    (0..ways).min_by_key(|&i| lines[i].stamp).unwrap()
+                }
+                Replacement::Random => {
+                    // xorshift64
+                    self.seed ^= self.seed << 13;
+                    self.seed ^= self.seed >> 7;
+                    self.seed ^= self.seed << 17;
+                    (self.seed % ways as u64) as usize
+                }
+            },
+        };
+        let line = &mut lines[victim];
+        if line.valid {
+            self.stats.evictions += 1;
+            if line.dirty {
+                self.stats.writebacks += 1;
+            }
+        }
+        *line = Line {
+            valid: true,
+            dirty: write && self.config.write == WritePolicy::WriteBack,
+            tag,
+            stamp: self.time,
+        };
+        false
+    }
+
+    /// Invalidate all lines without touching the statistics.
+    pub fn flush(&mut self) {
+        self.lines.fill(Line::default());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(replacement: Replacement) -> Cache {
+        let config = CacheConfig {
+            sets: 2,
+            ways: 2,
+            block_size: 8,
+            replacement,
+            ..Default::default()
+        };
+        Cache::new(config)
+    }
+
+    #[test]
+    fn test_cache_replacement() {
+        // blocks 0, 2, 4 map to set 0
+        let mut c = cache(Replacement::Lru);
+        assert!(!c.access(0x00, 8, false));
+        assert!(!c.access(0x10, 8, true));
+        assert!(c.access(0x04, 4, false));
+        // evicts the dirty block 2, the least recently used
+        assert!(!c.access(0x20, 8, false));
+        assert!(c.access(0x00, 8, false));
+        assert!(!c.access(0x10, 8, false));
+        assert_eq!(c.stats.hits, 2);
+        assert_eq!(c.stats.misses, 4);
+        assert_eq!(c.stats.evictions, 2);
+        assert_eq!(c.stats.writebacks, 1);
+
+        let mut c = cache(Replacement::Fifo);
+        c.access(0x00, 8, false);
+        c.access(0x10, 8, false);
+        c.access(0x00, 8, false);
+        // evicts block 0, the first filled
+        c.access(0x20, 8, false);
+        assert!(!c.access(0x00, 8, false));
+    }
+
+    #[test]
+    fn test_cache_unaligned() {
+        let mut c = cache(Replacement::Lru);
+        // spans blocks 0 and 1
+        assert!(!c.access(0x04, 8, false));
+        assert_eq!(c.stats.misses, 2);
+        assert!(c.access(0x08, 8, false));
+
+        // the last block of the address space
+        assert!(!c.access(u64::MAX - 3, 8, false));
+        assert_eq!(c.stats.misses, 3);
+        assert!(c.access(u64::MAX, 1, false));
+    }
+}
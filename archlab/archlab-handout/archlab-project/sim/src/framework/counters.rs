@@ -0,0 +1,136 @@
+//! Performance counters of a simulation run.
+//!
+//! The bubbles and stalls of every stage are counted by the simulator. Other
+//! events are counted from HCL with the `@count` directive, whose condition
+//! must be a `bool`:
+//!
+//! ```text
+//! @count(instructions, W.icode != NOP);
+//! @count(load_use, E.icode in { MRMOVQ, POPQ } && E.dstM in { d_srcA, d_srcB });
+//! @count(branches, E.icode == JX && E.ifun != 0);
+//! @count(mispredict, E.icode == JX && !e_cnd);
+//! @count(ret, RET in { D.icode, E.icode, M.icode });
+//! ```
+//!
+//! The counter named `instructions` is the number of retired instructions and
+//! is used to compute the CPI. The counters named `branches` and `mispredict`
+//! are the resolved conditional branches and the mispredicted ones, and give
+//! the accuracy of the branch predictor, whatever unit makes the predictions.
+
+use super::PredictorStats;
+
+/// Bubbles and stalls of a pipeline stage.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct StageCounter {
+    pub name: &'static str,
+    pub bubbles: u64,
+    pub stalls: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PerfCounters {
+    pub cycles: u64,
+    /// In the order the stages are defined.
+    pub stages: Vec<StageCounter>,
+    /// Counters defined in HCL, in the order they are defined.
+    pub events: Vec<(&'static str, u64)>,
+}
+
+impl PerfCounters {
+    /// Record the `bubble` and `stall` inputs of a stage in this cycle.
+    pub fn count_stage(&mut self, name: &'static str, bubble: bool, stall: bool) {
+        let stage = match self.stages.iter().position(|s| s.name == name) {
+            Some(i) => &mut self.stages[i],
+            None => {
+                self.stages.push(StageCounter {
+                    name,
+                    bubbles: 0,
+                    stalls: 0,
+                });
+                self.stages.last_mut().unwrap()
+            }
+        };
+        stage.bubbles += u64::from(bubble);
+        stage.stalls += u64::from(stall);
+    }
+
+    /// Add `n` to the counter `name`, creating it if needed.
+    pub fn count(&mut self, name: &'static str, n: u64) {
+        match self.events.iter_mut().find(|(e, _)| *e == name) {
+            Some((_, value)) => *value += n,
+            None => self.events.push((name, n)),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<u64> {
+        self.events
+            .iter()
+            .find(|(e, _)| *e == name)
+            .map(|(_, value)| *value)
+    }
+
+    /// Retired instructions, if the architecture counts them.
+    pub fn instructions(&self) -> Option<u64> {
+        self.get("instructions")
+    }
+
+    /// Cycles per instruction.
+    pub fn cpi(&self) -> Option<f64> {
+        self.instructions()
+            .filter(|&n| n > 0)
+            .map(|n| self.cycles as f64 / n as f64)
+    }
+
+    /// Resolved conditional branches and mispredicted ones, if the
+    /// architecture counts both.
+    pub fn predictions(&self) -> Option<PredictorStats> {
+        Some(PredictorStats {
+            predictions: self.get("branches")?,
+            mispredictions: self.get("mispredict")?,
+        })
+    }
+
+    /// Add the counters of another run to this one, e.g. to summarize the
+    /// runs of a test suite.
+    pub fn merge(&mut self, other: &PerfCounters) {
+        self.cycles += other.cycles;
+        for stage in &other.stages {
+            self.count_stage(stage.name, false, false);
+            let s = self
+                .stages
+                .iter_mut()
+                .find(|s| s.name == stage.name)
+                .unwrap();
+            s.bubbles += stage.bubbles;
+            s.stalls += stage.stalls;
+        }
+        for &(name, value) in &other.events {
+            self.count(name, value);
+        }
+    }
+}
+
+impl std::fmt::Display for PerfCounters {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{:<16}{:>12}", "cycles", self.cycles)?;
+        if let Some(cpi) = self.cpi() {
+            writeln!(f, "{:<16}{:>12.3}", "CPI", cpi)?;
+        }
+        if let Some(stats) = self.predictions().filter(|s| s.predictions > 0) {
+            let accuracy = format!("{:.2}%", stats.accuracy() * 100.0);
+            writeln!(f, "{:<16}{:>12}", "branch accuracy", accuracy)?;
+        }
+        for (name, value) in &self.events {
+            writeln!(f, "{:<16}{:>12}", name, value)?;
+        }
+        if !self.stages.is_empty() {
+            writeln!(f, "{:<16}{:>12}{:>12}", "stage", "bubbles", "stalls")?;
+            for s in &self.stages {
+                writeln!(f, "{:<16}{:>12}{:>12}", s.name, s.bubbles, s.stalls)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,315 @@
+//! Memory-mapped devices.
+//!
+//! The quad words right after the memory are device registers instead of
+//! bytes. They are only accessed as whole aligned quad words:
+//!
+//! | address                | name      | access                                   |
+//! |------------------------|-----------|------------------------------------------|
+//! | [`CONSOLE`] (`0x10000`) | console   | write: append the low byte to the output |
+//! | [`CYCLES`] (`0x10008`)  | cycles    | read: the number of retired instructions |
+//! | [`EXIT`] (`0x10010`)    | exit code | write: halt with the value as exit code  |
+//! | [`TRAP_BASE`] (`0x10018`) | trap vector | address of the vector table, 0 disables traps |
+//! | [`EPC`] (`0x10020`)     | saved PC  | PC to resume at with `iret`              |
+//! | [`ESTATUS`] (`0x10028`) | saved status | cause and condition codes, see [`Devices::enter_trap`] |
+//! | [`TIMER`] (`0x10030`)   | timer     | write: interrupt after the given number of instructions, 0 cancels; read: instructions left |
+//!
+//! Reading the console or the exit register gives 0, and writing the cycle
+//! counter is ignored. Any other address from [`DEVICE_BASE`] is invalid,
+//! just as an address out of the memory.
+//!
+//! The cycle counter counts the instructions retired before the one reading
+//! it, so that the ISA simulator and an architecture agree on its value
+//! whatever their CPI. [`isa::simulate`](crate::isa::simulate) retires an
+//! instruction once it completes, and stops after the instruction writing
+//! the exit register. A simulator stops at the end of the cycle in which the
+//! exit register is written, see [`MemData::exit_code`].
+//!
+//! The data memory of an architecture accesses the devices through
+//! [`MemData::load`] and [`MemData::store`], and counts the retired
+//! instructions with [`MemData::retire`]. Its `retire` input is set when an
+//! instruction older than the one accessing the memory retires in the cycle,
+//! e.g. `W.stat == Aok` in a five-stage pipeline, or the same signal of the
+//! previous cycle where memory is accessed in the last stage:
+//!
+//! ```text
+//! DataMemory dmem {
+//!     .input(addr: u64, datain: u64, read: bool, write: bool, retire: bool)
+//!     .output(dataout: u64, error: bool)
+//!     binary: MemData
+//! } {
+//!     if retire {
+//!         binary.retire();
+//!     }
+//!     *dataout = 0;
+//!     *error = false;
+//!     if write {
+//!         *error = binary.store(addr, datain).is_none();
+//!     } else if read {
+//!         match binary.load(addr) {
+//!             Some(v) => *dataout = v,
+//!             None => *error = true,
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! The timer counts the instructions retired after the one arming it, and
+//! reading it gives the instructions left to retire, the reading one
+//! included.
+//!
+//! # Traps
+//!
+//! Traps are implemented by the ISA simulator only: none of the builtin
+//! architectures takes them, and a pipeline running a program that enables
+//! them diverges from the ISA simulator at the first trap.
+//!
+//! Once [`TRAP_BASE`] is set, an instruction raising `Adr` or `Ins` and the
+//! timer interrupt don't stop the machine. The PC and the status are saved in
+//! [`EPC`] and [`ESTATUS`], and the machine jumps to the handler whose
+//! address is the quad word at `TRAP_BASE + 8 * cause` ([`TrapCause`]).
+//! Exceptions are precise: a faulting instruction has no effect and `EPC`
+//! is its address, so a handler may skip it by adding its length to `EPC`.
+//! The timer interrupt is taken between two instructions, with the address
+//! of the next one as `EPC`. The `iret` instruction (`0xd0`) returns to
+//! `EPC` and restores the condition codes. The assembler has no `iret`
+//! mnemonic, so a program writes it as `.byte 0xd0`.
+//!
+//! A handler can't be interrupted: the timer waits for `iret`, and an
+//! exception in a handler stops the machine as if traps were disabled.
+//!
+//! A pipeline would handle a trap in the W stage, where the older instructions
+//! have written back and the younger ones have not changed the registers
+//! nor the memory: when `W.stat` is `Adr` or `Ins`, the stages before are
+//! bubbled (the memory write of M cancelled, as for a `pipe_std` exception),
+//! and the fetch restarts at the handler. The timer interrupt is taken in the
+//! same way with the PC of the instruction after the one in W.
+//!
+//! For example, this program prints `hi` and exits with 3:
+//!
+//! ```text
+//!     irmovq $0x10000, %rbx
+//!     irmovq $0x68, %rax
+//!     rmmovq %rax, (%rbx)
+//!     irmovq $0x69, %rax
+//!     rmmovq %rax, (%rbx)
+//!     irmovq $3, %rax
+//!     rmmovq %rax, 0x10(%rbx)
+//! ```
+
+use std::io::Write;
+
+use super::MEM_SIZE;
+use crate::isa::ConditionCode;
+
+/// First address of the device registers, right after the memory.
+pub const DEVICE_BASE: u64 = MEM_SIZE as u64;
+/// Console output port.
+pub const CONSOLE: u64 = DEVICE_BASE;
+/// Cycle counter.
+pub const CYCLES: u64 = DEVICE_BASE + 0x8;
+/// Halt with an exit code.
+pub const EXIT: u64 = DEVICE_BASE + 0x10;
+/// Address of the trap vector table.
+pub const TRAP_BASE: u64 = DEVICE_BASE + 0x18;
+/// Saved PC.
+pub const EPC: u64 = DEVICE_BASE + 0x20;
+/// Saved status.
+pub const ESTATUS: u64 = DEVICE_BASE + 0x28;
+/// Timer interrupt.
+pub const TIMER: u64 = DEVICE_BASE + 0x30;
+
+/// Cause of a trap, the index of its handler in the vector table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrapCause {
+    /// An invalid memory or device address.
+    Adr = 0,
+    /// An invalid instruction.
+    Ins = 1,
+    Timer = 2,
+}
+
+/// State of the memory-mapped devices.
+#[derive(Debug, Clone, Default)]
+pub struct Devices {
+    /// Bytes written to the console.
+    pub console: Vec<u8>,
+    /// Also print the console output to stdout.
+    pub echo: bool,
+    /// Number of retired instructions.
+    pub retired: u64,
+    /// Set when the exit register is written.
+    pub exit_code: Option<u64>,
+    pub trap_base: u64,
+    pub epc: u64,
+    pub estatus: u64,
+    /// Whether a trap handler is running.
+    pub in_trap: bool,
+    /// Number of retired instructions at which the timer interrupt is
+    /// raised.
+    pub timer: Option<u64>,
+}
+
+impl Devices {
+    pub fn new(echo: bool) -> Self {
+        Self {
+            echo,
+            ..Self::default()
+        }
+    }
+
+    /// Whether `addr` is not in the memory but decoded by the devices.
+    pub fn contains(addr: u64) -> bool {
+        addr >= DEVICE_BASE
+    }
+
+    /// Count a retired instruction.
+    pub fn retire(&mut self) {
+        self.retired += 1;
+    }
+
+    /// Read the register at `addr`, `None` if there is no register there.
+    pub fn load(&mut self, addr: u64) -> Option<u64> {
+        match addr {
+            CONSOLE | EXIT => Some(0),
+            CYCLES => Some(self.retired),
+            TRAP_BASE => Some(self.trap_base),
+            EPC => Some(self.epc),
+            ESTATUS => Some(self.estatus),
+            TIMER => Some(self.timer.map_or(0, |t| t.saturating_sub(self.retired))),
+            _ => None,
+        }
+    }
+
+    /// Write the register at `addr`, `None` if there is no register there.
+    pub fn store(&mut self, addr: u64, value: u64) -> Option<()> {
+        match addr {
+            CONSOLE => {
+                let byte = value as u8;
+                self.console.push(byte);
+                if self.echo {
+                    let mut stdout = std::io::stdout();
+                    // the console is best effort, a closed stdout is not an
+                    // error of the program
+                    let _ = stdout.write_all(&[byte]).and_then(|_| stdout.flush());
+                }
+            }
+            CYCLES => {}
+            EXIT => self.exit_code = Some(value),
+            TRAP_BASE => self.trap_base = value,
+            EPC => self.epc = value,
+            ESTATUS => self.estatus = value,
+            // the arming instruction is not retired yet
+            TIMER => {
+                self.timer = (value != 0).then(|| self.retired.saturating_add(value + 1));
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+
+    /// Whether the timer interrupt is to be taken before the next
+    /// instruction.
+    pub fn timer_pending(&self) -> bool {
+        self.trap_base != 0 && !self.in_trap && self.timer.is_some_and(|t| self.retired >= t)
+    }
+
+    /// Save `pc` and the status, and enter the handler. The saved status is
+    /// the cause in the low byte, and `zf`, `sf`, `of` in bits 8, 9, 10.
+    ///
+    /// Return the address of the vector entry holding the handler, `None` if
+    /// traps are disabled or a handler is running.
+    pub fn enter_trap(&mut self, cause: TrapCause, pc: u64, cc: ConditionCode) -> Option<u64> {
+        if self.trap_base == 0 || self.in_trap {
+            return None;
+        }
+        if cause == TrapCause::Timer {
+            self.timer = None;
+        }
+        self.epc = pc;
+        self.estatus =
+            cause as u64 | (cc.zf as u64) << 8 | (cc.sf as u64) << 9 | (cc.of as u64) << 10;
+        self.in_trap = true;
+        Some(self.trap_base.wrapping_add(8 * cause as u64))
+    }
+
+    /// Leave the handler (`iret`), return the saved PC and condition codes,
+    /// `None` if no handler is running.
+    pub fn leave_trap(&mut self) -> Option<(u64, ConditionCode)> {
+        if !self.in_trap {
+            return None;
+        }
+        self.in_trap = false;
+        let cc = ConditionCode {
+            zf: self.estatus >> 8 & 1 != 0,
+            sf: self.estatus >> 9 & 1 != 0,
+            of: self.estatus >> 10 & 1 != 0,
+        };
+        Some((self.epc, cc))
+    }
+
+    /// The console output, invalid UTF-8 replaced.
+    pub fn console_text(&self) -> String {
+        String::from_utf8_lossy(&self.console).into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_devices() {
+        let mut dev = Devices::new(false);
+        dev.retire();
+        dev.retire();
+        assert_eq!(dev.load(CYCLES), Some(2));
+        assert_eq!(dev.store(CYCLES, 7), Some(()));
+        assert_eq!(dev.load(CYCLES), Some(2));
+
+        for b in b"hi" {
+            dev.store(CONSOLE, 0x100 | *b as u64).unwrap();
+        }
+        assert_eq!(dev.console_text(), "hi");
+
+        assert_eq!(dev.exit_code, None);
+        dev.store(EXIT, 3).unwrap();
+        assert_eq!(dev.exit_code, Some(3));
+
+        assert_eq!(dev.load(CONSOLE + 1), None);
+        assert_eq!(dev.store(TIMER + 8, 0), None);
+        assert!(!Devices::contains(DEVICE_BASE - 8));
+    }
+
+    #[test]
+    fn test_traps() {
+        let mut dev = Devices::new(false);
+        let cc = ConditionCode {
+            zf: true,
+            sf: false,
+            of: true,
+        };
+        assert_eq!(dev.enter_trap(TrapCause::Ins, 0x10, cc), None);
+
+        dev.store(TRAP_BASE, 0x800).unwrap();
+        // two instructions after the arming one
+        dev.store(TIMER, 2).unwrap();
+        assert_eq!(dev.load(TIMER), Some(3));
+        dev.retire();
+        dev.retire();
+        assert!(!dev.timer_pending());
+        assert_eq!(dev.load(TIMER), Some(1));
+        dev.retire();
+        assert!(dev.timer_pending());
+
+        assert_eq!(dev.enter_trap(TrapCause::Timer, 0x20, cc), Some(0x810));
+        assert_eq!(dev.load(ESTATUS), Some(0x502));
+        assert!(!dev.timer_pending());
+        // no nested trap
+        assert_eq!(dev.enter_trap(TrapCause::Adr, 0x30, cc), None);
+
+        dev.store(EPC, 0x22).unwrap();
+        assert_eq!(dev.leave_trap(), Some((0x22, cc)));
+        assert_eq!(dev.leave_trap(), None);
+        assert_eq!(dev.load(TIMER), Some(0));
+    }
+}
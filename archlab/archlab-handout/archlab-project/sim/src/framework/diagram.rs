@@ -0,0 +1,310 @@
+//! Datapath diagrams, with the tunnels fired in the current cycle
+//! highlighted and the values of signals overlaid.
+//!
+//! An architecture names its diagram in HCL with `@diagram("path")`. The
+//! template is the drawing, a line `---`, a mask of the drawing in which each
+//! tunnel is drawn with a letter, another line `---`, and the letters of the
+//! tunnels given by `#[tunnel(name)]`:
+//!
+//! ```text
+//! E valA {e_valA}   valB
+//!    │               │
+//!    └──── ALU ──────┘
+//! ---
+//!    a
+//!    a
+//!    aaaaa
+//! ---
+//! a = alu_a
+//! ```
+//!
+//! Light box-drawing characters of a fired tunnel are drawn heavy (and in
+//! color on a terminal). A placeholder `{signal}` is replaced by the value of
+//! the signal, cut to the width of the placeholder so that the drawing keeps
+//! its shape. Spaces before `}` make room for longer values, e.g.
+//! `{f_pc          }`.
+
+use anyhow::{bail, Context};
+
+/// A parsed diagram template.
+#[derive(Debug, Clone)]
+pub struct Diagram {
+    drawing: Vec<Vec<char>>,
+    mask: Vec<Vec<char>>,
+    /// (letter, tunnel)
+    tunnels: Vec<(char, String)>,
+    fields: Vec<Field>,
+}
+
+/// A placeholder `{signal}` in the drawing.
+#[derive(Debug, Clone)]
+struct Field {
+    line: usize,
+    col: usize,
+    width: usize,
+    signal: String,
+}
+
+fn heavy(c: char) -> char {
+    match c {
+        '─' => '━',
+        '│' => '┃',
+        '┌' => '┏',
+        '┐' => '┓',
+        '└' => '┗',
+        '┘' => '┛',
+        '├' => '┣',
+        '┤' => '┫',
+        '┬' => '┳',
+        '┴' => '┻',
+        '┼' => '╋',
+        '┄' => '┅',
+        '┆' => '┇',
+        c => c,
+    }
+}
+
+impl Diagram {
+    pub fn parse(src: &str) -> anyhow::Result<Self> {
+        let mut parts = vec![Vec::new()];
+        for line in src.lines() {
+            if line.trim_end() == "---" {
+                parts.push(Vec::new());
+            } else {
+                parts.last_mut().unwrap().push(line);
+            }
+        }
+        let [drawing, mask, legend] = <[Vec<&str>; 3]>::try_from(parts)
+            .ok()
+            .context("a diagram is a drawing, a mask and the tunnels, separated by `---`")?;
+        let drawing: Vec<Vec<char>> = drawing.iter().map(|l| l.chars().collect()).collect();
+        let mask: Vec<Vec<char>> = mask.iter().map(|l| l.chars().collect()).collect();
+
+        let mut tunnels: Vec<(char, String)> = Vec::new();
+        for line in legend.iter().filter(|l| !l.trim().is_empty()) {
+            let (letter, name) = line
+                .split_once('=')
+                .with_context(|| format!("expected `letter = tunnel`, found `{line}`"))?;
+            let mut letter = letter.trim().chars();
+            let (Some(letter), None) = (letter.next(), letter.next()) else {
+                bail!("expected a single letter in `{line}`");
+            };
+            if tunnels.iter().any(|(l, _)| *l == letter) {
+                bail!("letter `{letter}` is given to two tunnels");
+            }
+            tunnels.push((letter, name.trim().to_string()));
+        }
+
+        for (i, line) in mask.iter().enumerate() {
+            for (j, &c) in line.iter().enumerate() {
+                if c == ' ' {
+                    continue;
+                }
+                let is_drawn = drawing.get(i).is_some_and(|l| j < l.len());
+                if !is_drawn {
+                    bail!("mask line {}: `{c}` is outside of the drawing", i + 1);
+                }
+                if tunnels.iter().all(|(l, _)| *l != c) {
+                    bail!("mask line {}: `{c}` is not the letter of a tunnel", i + 1);
+                }
+            }
+        }
+        for (letter, name) in &tunnels {
+            if !mask.iter().flatten().any(|c| c == letter) {
+                bail!("tunnel `{name}` is not drawn in the mask");
+            }
+        }
+
+        let mut fields = Vec::new();
+        for (i, line) in drawing.iter().enumerate() {
+            let mut j = 0;
+            while j < line.len() {
+                if line[j] == '{' {
+                    let len = line[j + 1..]
+                        .iter()
+                        .position(|c| *c == '}')
+                        .with_context(|| format!("drawing line {}: unclosed `{{`", i + 1))?;
+                    fields.push(Field {
+                        line: i,
+                        col: j,
+                        width: len + 2,
+                        signal: line[j + 1..j + 1 + len]
+                            .iter()
+                            .collect::<String>()
+                            .trim()
+                            .to_string(),
+                    });
+                    j += len + 2;
+                } else {
+                    j += 1;
+                }
+            }
+        }
+
+        Ok(Self {
+            drawing,
+            mask,
+            tunnels,
+            fields,
+        })
+    }
+
+    /// Names of the tunnels in the mask.
+    pub fn tunnels(&self) -> impl Iterator<Item = &str> {
+        self.tunnels.iter().map(|(_, name)| name.as_str())
+    }
+
+    /// Names of the signals in the placeholders.
+    pub fn signals(&self) -> impl Iterator<Item = &str> {
+        self.fields.iter().map(|f| f.signal.as_str())
+    }
+
+    /// Check the names of the template against those of the architecture.
+    pub fn check<'a>(
+        &self,
+        tunnels: impl IntoIterator<Item = &'a str> + Clone,
+        signals: impl IntoIterator<Item = &'a str> + Clone,
+    ) -> anyhow::Result<()> {
+        for name in self.tunnels() {
+            if !tunnels.clone().into_iter().any(|t| t == name) {
+                bail!("`{name}` is not a tunnel of the architecture");
+            }
+        }
+        for name in self.signals() {
+            if !signals.clone().into_iter().any(|s| s == name) {
+                bail!("`{name}` is not a signal of the architecture");
+            }
+        }
+        Ok(())
+    }
+
+    /// Draw the diagram with the tunnels in `fired` highlighted, and the
+    /// placeholders replaced by `value` (`?` if it's unknown).
+    pub fn render(
+        &self,
+        fired: &[&str],
+        value: impl Fn(&str) -> Option<String>,
+        color: bool,
+    ) -> String {
+        use binutils::clap::builder::styling::*;
+        let style = Style::new()
+            .bold()
+            .fg_color(Some(Color::Ansi(AnsiColor::Red)));
+
+        let letters = self
+            .tunnels
+            .iter()
+            .filter(|(_, name)| fired.contains(&name.as_str()))
+            .map(|(letter, _)| *letter)
+            .collect::<Vec<_>>();
+        let mut drawing = self.drawing.clone();
+        let mut lit = drawing
+            .iter()
+            .map(|l| vec![false; l.len()])
+            .collect::<Vec<_>>();
+        for (i, line) in self.mask.iter().enumerate() {
+            for (j, c) in line.iter().enumerate() {
+                if letters.contains(c) {
+                    drawing[i][j] = heavy(drawing[i][j]);
+                    lit[i][j] = true;
+                }
+            }
+        }
+        for field in &self.fields {
+            let value = value(&field.signal).unwrap_or_else(|| "?".to_string());
+            let cells = &mut drawing[field.line][field.col..field.col + field.width];
+            let value = value.chars().chain(std::iter::repeat(' '));
+            for (cell, c) in cells.iter_mut().zip(value) {
+                *cell = c;
+            }
+        }
+
+        let mut out = String::new();
+        for (line, lit) in drawing.iter().zip(&lit) {
+            for (c, lit) in line.iter().zip(lit) {
+                if color && *lit {
+                    out.push_str(&format!("{style}{c}{style:#}"));
+                } else {
+                    out.push(*c);
+                }
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Draw the current cycle of `sim`.
+    pub fn render_sim(&self, sim: &(impl super::CpuSim + ?Sized), color: bool) -> String {
+        let values = sim
+            .get_stage_info()
+            .into_iter()
+            .flat_map(|stage| stage.signals)
+            .collect::<Vec<_>>();
+        let value = |name: &str| {
+            values
+                .iter()
+                .find(|sig| sig.name == name)
+                .map(|sig| sig.value.to_string())
+        };
+        self.render(sim.tunnels(), value, color)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC: &str = "\
+E valA {e_valA}   valB
+   │               │
+   └──── ALU ──────┘
+---
+   a               b
+   a               b
+   aaaaa     bbbbbbb
+---
+a = alu_a
+b = alu_b
+";
+
+    #[test]
+    fn test_render() {
+        let diagram = Diagram::parse(SRC).unwrap();
+        assert_eq!(diagram.tunnels().collect::<Vec<_>>(), ["alu_a", "alu_b"]);
+        assert_eq!(diagram.signals().collect::<Vec<_>>(), ["e_valA"]);
+        let value = |name: &str| (name == "e_valA").then(|| "0x1234567890".to_string());
+        let out = diagram.render(&["alu_a"], value, false);
+        assert_eq!(
+            out,
+            "\
+E valA 0x123456   valB
+   ┃               │
+   ┗━━━━ ALU ──────┘
+"
+        );
+        assert!(diagram.check(["alu_a", "alu_b"], ["e_valA"]).is_ok());
+        assert!(diagram.check(["alu_a"], ["e_valA"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = |from: &str, to: &str| {
+            Diagram::parse(&SRC.replace(from, to))
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            err("b = alu_b", ""),
+            "mask line 1: `b` is not the letter of a tunnel"
+        );
+        assert_eq!(
+            err("b = alu_b", "b = alu_b\nc = alu_c"),
+            "tunnel `alu_c` is not drawn in the mask"
+        );
+        assert_eq!(err("{e_valA}", "{e_valA "), "drawing line 1: unclosed `{`");
+        assert_eq!(
+            err("---\na = ", "a = "),
+            "a diagram is a drawing, a mask and the tunnels, separated by `---`"
+        );
+    }
+}
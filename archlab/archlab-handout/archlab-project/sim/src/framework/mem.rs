@@ -0,0 +1,112 @@
+//! Memory and unit states that are shared between hardware units.
+//!
+//! The instruction memory and the data memory see the same bytes, and the
+//! register file is read and written by different units, so their states are
+//! shared handles. They are built on [`Arc`] and [`RwLock`] so that a
+//! simulator owning them is [`Send`] and can run on a worker thread.
+//!
+//! A register file unit keeps its registers as `state: Shared<RegFile>`, and
+//! `HardwareUnits::init` hands the same handle to every unit reading or
+//! writing them. No unit state may be built on `Rc<RefCell<..>>`.
+
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use super::{Devices, TrapCause, MEM_SIZE};
+use crate::{
+    isa::ConditionCode,
+    utils::{get_u64, put_u64},
+};
+
+/// A state shared by several units (or by the units and the front end).
+/// Cloning the handle does not clone the state.
+#[derive(Debug, Default)]
+pub struct Shared<T>(Arc<RwLock<T>>);
+
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> Shared<T> {
+    pub fn new(value: T) -> Self {
+        Self(Arc::new(RwLock::new(value)))
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        // a unit never panics while holding the lock, so it is never poisoned
+        self.0.read().unwrap()
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        self.0.write().unwrap()
+    }
+}
+
+/// Main memory of the simulator, shared by `imem` and `dmem`, and the
+/// memory-mapped [`Devices`] behind it.
+#[derive(Debug, Clone)]
+pub struct MemData {
+    bytes: Shared<[u8; MEM_SIZE]>,
+    devices: Shared<Devices>,
+}
+
+impl MemData {
+    pub fn init(bin: [u8; MEM_SIZE]) -> Self {
+        Self {
+            bytes: Shared::new(bin),
+            devices: Shared::default(),
+        }
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, [u8; MEM_SIZE]> {
+        self.bytes.read()
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, [u8; MEM_SIZE]> {
+        self.bytes.write()
+    }
+
+    pub fn devices(&self) -> RwLockWriteGuard<'_, Devices> {
+        self.devices.write()
+    }
+
+    /// Read the quad word at `addr` from the memory or a device, `None` if
+    /// the address is invalid.
+    pub fn load(&self, addr: u64) -> Option<u64> {
+        if Devices::contains(addr) {
+            return self.devices().load(addr);
+        }
+        let addr = usize::try_from(addr).ok().filter(|&a| a <= MEM_SIZE - 8)?;
+        Some(get_u64(&self.read()[addr..addr + 8]))
+    }
+
+    /// Write the quad word at `addr` to the memory or a device, `None` if
+    /// the address is invalid.
+    pub fn store(&self, addr: u64, value: u64) -> Option<()> {
+        if Devices::contains(addr) {
+            return self.devices().store(addr, value);
+        }
+        let addr = usize::try_from(addr).ok().filter(|&a| a <= MEM_SIZE - 8)?;
+        put_u64(&mut self.write()[addr..addr + 8], value);
+        Some(())
+    }
+
+    /// Count a retired instruction, see [`Devices::retire`].
+    pub fn retire(&self) {
+        self.devices().retire();
+    }
+
+    /// Enter a trap handler, see [`Devices::enter_trap`]. Return the address
+    /// of the handler, `None` if the machine stops instead.
+    pub fn enter_trap(&self, cause: TrapCause, pc: u64, cc: ConditionCode) -> Option<u64> {
+        let entry = self.devices().enter_trap(cause, pc, cc)?;
+        self.load(entry)
+    }
+
+    /// The exit code written by the program, the simulation should stop once
+    /// it is set.
+    pub fn exit_code(&self) -> Option<u64> {
+        self.devices().exit_code
+    }
+}
@@ -0,0 +1,18 @@
+mod assertion;
+mod cache;
+mod counters;
+mod device;
+mod diagram;
+mod mem;
+mod predictor;
+mod reflect;
+mod timing;
+pub use assertion::*;
+pub use cache::*;
+pub use counters::*;
+pub use device::*;
+pub use diagram::*;
+pub use mem::*;
+pub use predictor::*;
+pub use reflect::*;
+pub use timing::*;
@@ -0,0 +1,247 @@
+//! Branch prediction models for pipeline units.
+//!
+//! A predictor unit looks up its model in the fetch stage with the PC of the
+//! fetched instruction, and updates it once the branch is resolved. Both
+//! sides are ordinary ports, e.g.
+//!
+//! ```text
+//! BranchPredictor bp {
+//!     .input(pc: u64, target: u64, is_jxx: bool,
+//!            upd: bool, upd_pc: u64, upd_taken: bool, upd_mispred: bool)
+//!     .output(taken: bool)
+//!     bht: Bht
+//! } {
+//!     *taken = is_jxx && bht.predict(pc);
+//!     if upd {
+//!         bht.update(upd_pc, upd_taken, upd_mispred);
+//!     }
+//! }
+//! ```
+//!
+//! Every model keeps [`PredictorStats`] of the outcomes reported to it.
+
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PredictorStats {
+    pub predictions: u64,
+    pub mispredictions: u64,
+}
+
+impl PredictorStats {
+    /// Record the outcome of a resolved prediction.
+    pub fn record(&mut self, mispredicted: bool) {
+        self.predictions += 1;
+        if mispredicted {
+            self.mispredictions += 1;
+        }
+    }
+
+    pub fn accuracy(&self) -> f64 {
+        if self.predictions == 0 {
+            0.0
+        } else {
+            1.0 - self.mispredictions as f64 / self.predictions as f64
+        }
+    }
+}
+
+impl std::fmt::Display for PredictorStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "predictions: {}, mispredictions: {}, accuracy: {:.2}%",
+            self.predictions,
+            self.mispredictions,
+            self.accuracy() * 100.0
+        )
+    }
+}
+
+/// Static "backward taken, forward not taken" prediction.
+#[derive(Debug, Clone, Default)]
+pub struct Btfnt {
+    pub stats: PredictorStats,
+}
+
+impl Btfnt {
+    pub fn predict(&self, pc: u64, target: u64) -> bool {
+        target <= pc
+    }
+
+    pub fn update(&mut self, mispredicted: bool) {
+        self.stats.record(mispredicted);
+    }
+}
+
+/// Branch history table of saturating counters indexed by the low bits of
+/// the PC. One-bit counters remember the last outcome; two-bit counters need
+/// two mispredictions in a row to change their mind.
+#[derive(Debug, Clone)]
+pub struct Bht {
+    counters: Vec<u8>,
+    max: u8,
+    pub stats: PredictorStats,
+}
+
+impl Default for Bht {
+    /// 256 two-bit counters.
+    fn default() -> Self {
+        Self::new(256, 2)
+    }
+}
+
+impl Bht {
+    /// `entries` counters of `bits` bits each, initialized to weakly taken.
+    pub fn new(entries: usize, bits: u32) -> Self {
+        assert!(entries.is_power_of_two(), "entries must be a power of two");
+        assert!((1..=8).contains(&bits), "a counter has 1 to 8 bits");
+        let max = ((1u16 << bits) - 1) as u8;
+        Self {
+            counters: vec![max / 2 + 1; entries],
+            max,
+            stats: PredictorStats::default(),
+        }
+    }
+
+    fn index(&self, pc: u64) -> usize {
+        (pc as usize) & (self.counters.len() - 1)
+    }
+
+    pub fn predict(&self, pc: u64) -> bool {
+        self.counters[self.index(pc)] > self.max / 2
+    }
+
+    pub fn update(&mut self, pc: u64, taken: bool, mispredicted: bool) {
+        let i = self.index(pc);
+        let c = &mut self.counters[i];
+        *c = if taken {
+            c.saturating_add(1).min(self.max)
+        } else {
+            c.saturating_sub(1)
+        };
+        self.stats.record(mispredicted);
+    }
+}
+
+/// Direct-mapped branch target buffer.
+#[derive(Debug, Clone)]
+pub struct Btb {
+    entries: Vec<Option<(u64, u64)>>,
+    pub stats: PredictorStats,
+}
+
+impl Default for Btb {
+    fn default() -> Self {
+        Self::new(64)
+    }
+}
+
+impl Btb {
+    pub fn new(entries: usize) -> Self {
+        assert!(entries.is_power_of_two(), "entries must be a power of two");
+        Self {
+            entries: vec![None; entries],
+            stats: PredictorStats::default(),
+        }
+    }
+
+    fn index(&self, pc: u64) -> usize {
+        (pc as usize) & (self.entries.len() - 1)
+    }
+
+    /// The target recorded for the branch at `pc`, if any.
+    pub fn lookup(&self, pc: u64) -> Option<u64> {
+        match self.entries[self.index(pc)] {
+            Some((tag, target)) if tag == pc => Some(target),
+            _ => None,
+        }
+    }
+
+    pub fn update(&mut self, pc: u64, target: u64, mispredicted: bool) {
+        let i = self.index(pc);
+        self.entries[i] = Some((pc, target));
+        self.stats.record(mispredicted);
+    }
+}
+
+/// Return address stack. A `call` pushes its return address, a `ret`
+/// predicts the top of the stack. When full, the oldest entry is dropped.
+#[derive(Debug, Clone)]
+pub struct ReturnStack {
+    stack: Vec<u64>,
+    depth: usize,
+    pub stats: PredictorStats,
+}
+
+impl Default for ReturnStack {
+    fn default() -> Self {
+        Self::new(16)
+    }
+}
+
+impl ReturnStack {
+    pub fn new(depth: usize) -> Self {
+        assert!(depth > 0, "a return stack has at least one entry");
+        Self {
+            stack: Vec::with_capacity(depth),
+            depth,
+            stats: PredictorStats::default(),
+        }
+    }
+
+    pub fn push(&mut self, ret_addr: u64) {
+        if self.stack.len() == self.depth {
+            self.stack.remove(0);
+        }
+        self.stack.push(ret_addr);
+    }
+
+    /// Predicted return address, or `None` if the stack is empty.
+    pub fn pop(&mut self) -> Option<u64> {
+        self.stack.pop()
+    }
+
+    pub fn update(&mut self, mispredicted: bool) {
+        self.stats.record(mispredicted);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bht() {
+        let mut one = Bht::new(4, 1);
+        let mut two = Bht::new(4, 2);
+        // a loop branch: taken, taken, not taken, taken
+        for taken in [true, true, false, true] {
+            let p = one.predict(0x10);
+            one.update(0x10, taken, p != taken);
+            let p = two.predict(0x10);
+            two.update(0x10, taken, p != taken);
+        }
+        assert_eq!(one.stats.mispredictions, 2);
+        assert_eq!(two.stats.mispredictions, 1);
+        assert_eq!(two.stats.accuracy(), 0.75);
+    }
+
+    #[test]
+    fn test_btb_and_return_stack() {
+        let mut btb = Btb::new(4);
+        assert_eq!(btb.lookup(0x13), None);
+        btb.update(0x13, 0x40, true);
+        assert_eq!(btb.lookup(0x13), Some(0x40));
+        // same index, different tag
+        assert_eq!(btb.lookup(0x17), None);
+
+        let mut ras = ReturnStack::new(2);
+        ras.push(1);
+        ras.push(2);
+        ras.push(3);
+        assert_eq!(ras.pop(), Some(3));
+        assert_eq!(ras.pop(), Some(2));
+        assert_eq!(ras.pop(), None);
+        assert!(Btfnt::default().predict(0x20, 0x10));
+    }
+}
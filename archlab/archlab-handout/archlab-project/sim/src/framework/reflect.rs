@@ -0,0 +1,402 @@
+//! Reflection of the signals of an architecture.
+//!
+//! [`crate::define_units`] lists the ports of the units and
+//! [`crate::define_stages`] the fields of the pipeline registers, each with
+//! its name, type and value. [`sim_macro::hcl!`] adds the intermediate
+//! signals, so that [`super::CpuSim::get_stage_info`] shows `E.valA`,
+//! `alu.e` and `e.stall` next to `f_pc`. The states of the units marked
+//! `#[signal]`, such as the condition code or the register file, are listed
+//! too.
+//!
+//! Every signal can also be read and written by name with
+//! [`super::CpuSim::signal`] and [`super::CpuSim::set_signal`].
+
+use super::Shared;
+use crate::isa::{reg_code, ConditionCode, RegFile, Stat};
+
+/// The class of a signal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalKind {
+    /// An input port of a unit, e.g. `alu.a`.
+    UnitInput,
+    /// An output port of a unit, e.g. `alu.e`.
+    UnitOutput,
+    /// A field of a pipeline register at the start of the cycle, e.g.
+    /// `E.valA`.
+    StageField,
+    /// The `bubble` or `stall` input of a pipeline register computed in the
+    /// cycle, e.g. `e.stall`.
+    StageControl,
+    /// An intermediate signal of HCL, e.g. `f_pc`.
+    Intermediate,
+    /// A state of a unit marked `#[signal]`, e.g. `reg_cc.inner_cc` or
+    /// `reg_file.rsp`.
+    UnitState,
+}
+
+/// The value of a signal, in its own type.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SignalValue {
+    Bool(bool),
+    U8(u8),
+    U64(u64),
+    Stat(Stat),
+    ConditionCode(ConditionCode),
+    Bytes(Vec<u8>),
+}
+
+impl SignalValue {
+    /// Number of bits that can be flipped by [`Self::flip_bit`].
+    pub fn width(&self) -> u32 {
+        match self {
+            SignalValue::Bool(_) => 1,
+            SignalValue::U8(_) => 8,
+            SignalValue::U64(_) => 64,
+            // an enum has no bits of its own
+            SignalValue::Stat(_) => 0,
+            SignalValue::ConditionCode(_) => 3,
+            SignalValue::Bytes(v) => v.len() as u32 * 8,
+        }
+    }
+
+    /// The value with bit `bit` inverted. The bits of a condition code are
+    /// `zf`, `of` and `sf`, those of bytes count from the first byte.
+    pub fn flip_bit(&self, bit: u32) -> Option<Self> {
+        if bit >= self.width() {
+            return None;
+        }
+        let mut value = self.clone();
+        match &mut value {
+            SignalValue::Bool(v) => *v = !*v,
+            SignalValue::U8(v) => *v ^= 1 << bit,
+            SignalValue::U64(v) => *v ^= 1 << bit,
+            SignalValue::Stat(_) => unreachable!(),
+            SignalValue::ConditionCode(cc) => {
+                let flag = match bit {
+                    0 => &mut cc.zf,
+                    1 => &mut cc.of,
+                    _ => &mut cc.sf,
+                };
+                *flag = !*flag;
+            }
+            SignalValue::Bytes(v) => v[bit as usize / 8] ^= 1 << (bit % 8),
+        }
+        Some(value)
+    }
+}
+
+impl std::fmt::Display for SignalValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignalValue::Bool(v) => write!(f, "{v}"),
+            SignalValue::U8(v) => write!(f, "{v:#x}"),
+            SignalValue::U64(v) => write!(f, "{v:#x}"),
+            SignalValue::Stat(v) => write!(f, "{v:?}"),
+            SignalValue::ConditionCode(v) => write!(f, "{v:?}"),
+            SignalValue::Bytes(v) => write!(f, "{v:x?}"),
+        }
+    }
+}
+
+/// The Rust types of signals.
+pub trait Reflect: Sized {
+    fn reflect(&self) -> SignalValue;
+    /// The value in this type. Integers are converted if they fit.
+    fn from_value(value: &SignalValue) -> Option<Self>;
+}
+
+impl Reflect for bool {
+    fn reflect(&self) -> SignalValue {
+        SignalValue::Bool(*self)
+    }
+    fn from_value(value: &SignalValue) -> Option<Self> {
+        match value {
+            SignalValue::Bool(v) => Some(*v),
+            _ => None,
+        }
+    }
+}
+
+impl Reflect for u8 {
+    fn reflect(&self) -> SignalValue {
+        SignalValue::U8(*self)
+    }
+    fn from_value(value: &SignalValue) -> Option<Self> {
+        match value {
+            SignalValue::U8(v) => Some(*v),
+            SignalValue::U64(v) => u8::try_from(*v).ok(),
+            _ => None,
+        }
+    }
+}
+
+impl Reflect for u64 {
+    fn reflect(&self) -> SignalValue {
+        SignalValue::U64(*self)
+    }
+    fn from_value(value: &SignalValue) -> Option<Self> {
+        match value {
+            SignalValue::U8(v) => Some(*v as u64),
+            SignalValue::U64(v) => Some(*v),
+            _ => None,
+        }
+    }
+}
+
+impl Reflect for Stat {
+    fn reflect(&self) -> SignalValue {
+        SignalValue::Stat(*self)
+    }
+    fn from_value(value: &SignalValue) -> Option<Self> {
+        match value {
+            SignalValue::Stat(v) => Some(*v),
+            _ => None,
+        }
+    }
+}
+
+impl Reflect for ConditionCode {
+    fn reflect(&self) -> SignalValue {
+        SignalValue::ConditionCode(*self)
+    }
+    fn from_value(value: &SignalValue) -> Option<Self> {
+        match value {
+            SignalValue::ConditionCode(v) => Some(*v),
+            _ => None,
+        }
+    }
+}
+
+impl<const N: usize> Reflect for [u8; N] {
+    fn reflect(&self) -> SignalValue {
+        SignalValue::Bytes(self.to_vec())
+    }
+    fn from_value(value: &SignalValue) -> Option<Self> {
+        match value {
+            SignalValue::Bytes(v) => v.as_slice().try_into().ok(),
+            _ => None,
+        }
+    }
+}
+
+/// Error of [`super::CpuSim::set_signal`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum SignalError {
+    /// There is no signal of this name.
+    NotFound(String),
+    /// The value does not fit the type of the signal.
+    Type {
+        name: String,
+        typ: &'static str,
+        value: SignalValue,
+    },
+}
+
+impl std::fmt::Display for SignalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignalError::NotFound(name) => write!(f, "no signal named `{name}`"),
+            SignalError::Type { name, typ, value } => {
+                write!(f, "`{name}` of type `{typ}` can't be set to {value:?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SignalError {}
+
+/// Set the signal `name` of type `typ` to `value`.
+pub fn set_value<T: Reflect>(
+    dest: &mut T,
+    name: &str,
+    typ: &'static str,
+    value: &SignalValue,
+) -> Result<(), SignalError> {
+    *dest = T::from_value(value).ok_or_else(|| SignalError::Type {
+        name: name.to_string(),
+        typ,
+        value: value.clone(),
+    })?;
+    Ok(())
+}
+
+/// A state of a unit marked `#[signal]` in [`crate::define_units`]. A
+/// value is listed as `unit.field`, and a register file as its registers,
+/// e.g. `reg_file.rsp`.
+pub trait UnitState {
+    fn signals(&self, unit: &str, field: &str, typ: &'static str) -> Vec<Signal>;
+    fn set_signal(
+        &mut self,
+        unit: &str,
+        field: &str,
+        typ: &'static str,
+        name: &str,
+        value: &SignalValue,
+    ) -> Result<(), SignalError>;
+}
+
+impl<T: Reflect> UnitState for T {
+    fn signals(&self, unit: &str, field: &str, typ: &'static str) -> Vec<Signal> {
+        let name = format!("{unit}.{field}");
+        vec![Signal::new(name, SignalKind::UnitState, typ, self)]
+    }
+    fn set_signal(
+        &mut self,
+        unit: &str,
+        field: &str,
+        typ: &'static str,
+        name: &str,
+        value: &SignalValue,
+    ) -> Result<(), SignalError> {
+        if name.split_once('.') != Some((unit, field)) {
+            return Err(SignalError::NotFound(name.to_string()));
+        }
+        set_value(self, name, typ, value)
+    }
+}
+
+impl UnitState for Shared<RegFile> {
+    fn signals(&self, unit: &str, _: &str, _: &'static str) -> Vec<Signal> {
+        let regs = self.read();
+        (0..reg_code::RNONE)
+            .map(|reg| {
+                let name = format!("{unit}.{}", reg_code::name_of(reg).to_lowercase());
+                Signal::new(name, SignalKind::UnitState, "u64", &regs[reg as usize])
+            })
+            .collect()
+    }
+    fn set_signal(
+        &mut self,
+        unit: &str,
+        _: &str,
+        _: &'static str,
+        name: &str,
+        value: &SignalValue,
+    ) -> Result<(), SignalError> {
+        let reg = name
+            .strip_prefix(unit)
+            .and_then(|reg| reg.strip_prefix('.'))
+            .and_then(|reg| {
+                (0..reg_code::RNONE).find(|r| reg_code::name_of(*r).eq_ignore_ascii_case(reg))
+            })
+            .ok_or_else(|| SignalError::NotFound(name.to_string()))?;
+        set_value(&mut self.write()[reg as usize], name, "u64", value)
+    }
+}
+
+/// A signal of an architecture.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signal {
+    /// Name of the signal as written in HCL, e.g. `E.valA` or `alu.e`.
+    pub name: String,
+    pub kind: SignalKind,
+    /// The Rust type of the signal, e.g. `u64` or `[u8; 9]`.
+    pub typ: &'static str,
+    pub value: SignalValue,
+}
+
+impl Signal {
+    pub fn new(
+        name: impl Into<String>,
+        kind: SignalKind,
+        typ: &'static str,
+        value: &impl Reflect,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            kind,
+            typ,
+            value: value.reflect(),
+        }
+    }
+
+    /// Name a stage field after `#![stage_alias(...)]`, given as
+    /// `(short name, alias)` pairs, so that `e.valA` of the current state
+    /// reads `E.valA` like in HCL.
+    pub fn alias_stage(&mut self, alias: &[(&str, &str)]) {
+        if self.kind != SignalKind::StageField {
+            return;
+        }
+        if let Some((stage, field)) = self.name.split_once('.') {
+            if let Some((_, pre)) = alias.iter().find(|(cur, _)| *cur == stage) {
+                self.name = format!("{pre}.{field}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signal() {
+        let mut sig = Signal::new("e.valA", SignalKind::StageField, "u64", &0x10u64);
+        sig.alias_stage(&[("e", "E")]);
+        assert_eq!(sig.name, "E.valA");
+        assert_eq!(sig.value, SignalValue::U64(0x10));
+        assert_eq!(sig.value.to_string(), "0x10");
+
+        let mut sig = Signal::new("e.stall", SignalKind::StageControl, "bool", &true);
+        sig.alias_stage(&[("e", "E")]);
+        assert_eq!(sig.name, "e.stall");
+
+        let sig = Signal::new("imem.align", SignalKind::UnitInput, "[u8; 2]", &[1u8, 0xa]);
+        assert_eq!(sig.value.to_string(), "[1, a]");
+    }
+
+    #[test]
+    fn test_flip_bit() {
+        assert_eq!(SignalValue::U8(1).flip_bit(7), Some(SignalValue::U8(0x81)));
+        assert_eq!(SignalValue::U8(1).flip_bit(8), None);
+        assert_eq!(
+            SignalValue::Bool(true).flip_bit(0),
+            Some(SignalValue::Bool(false))
+        );
+        assert_eq!(SignalValue::Stat(Stat::Aok).flip_bit(0), None);
+        let cc = SignalValue::ConditionCode(ConditionCode::default());
+        let Some(SignalValue::ConditionCode(cc)) = cc.flip_bit(2) else {
+            panic!("a condition code has 3 bits");
+        };
+        assert!(cc.sf && !cc.zf);
+        let bytes = SignalValue::Bytes(vec![0, 0]);
+        assert_eq!(bytes.flip_bit(9), Some(SignalValue::Bytes(vec![0, 2])));
+    }
+
+    #[test]
+    fn test_set_signal() {
+        let mut val_a = 0u8;
+        set_value(&mut val_a, "E.valA", "u8", &SignalValue::U64(0xff)).unwrap();
+        assert_eq!(val_a, 0xff);
+        let err = set_value(&mut val_a, "E.valA", "u8", &SignalValue::U64(0x100)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "`E.valA` of type `u8` can't be set to U64(256)"
+        );
+
+        let mut regs = Shared::new([0; 16]);
+        let names = regs.signals("reg_file", "state", "Shared<RegFile>");
+        assert_eq!(names.len(), 15);
+        assert_eq!(names[4].name, "reg_file.rsp");
+        let value = SignalValue::U64(0x100);
+        regs.set_signal("reg_file", "state", "", "reg_file.rsp", &value)
+            .unwrap();
+        assert_eq!(regs.read()[4], 0x100);
+        assert_eq!(
+            regs.set_signal("reg_file", "state", "", "reg_file.rnone", &value),
+            Err(SignalError::NotFound("reg_file.rnone".to_string()))
+        );
+
+        let mut cc = ConditionCode::default();
+        let value = SignalValue::ConditionCode(ConditionCode { zf: true, ..cc });
+        cc.set_signal(
+            "reg_cc",
+            "inner_cc",
+            "ConditionCode",
+            "reg_cc.inner_cc",
+            &value,
+        )
+        .unwrap();
+        assert!(cc.zf);
+    }
+}
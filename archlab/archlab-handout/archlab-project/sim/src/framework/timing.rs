@@ -0,0 +1,288 @@
+//! Weighted timing model of an architecture.
+//!
+//! [`PropOrder::max_dist`](super::PropOrder) only counts the hardware units
+//! on the longest path. Here every node of the dependency graph carries a
+//! delay instead, so that a register file can be slower than a condition
+//! unit. Units default to [`DEFAULT_UNIT_DELAY`] and may be annotated with
+//! `#[delay(n)]` in [`crate::define_units`]; combinational signals default to
+//! [`DEFAULT_SIGNAL_DELAY`] and may be annotated with `#[delay(n)]` in
+//! [`sim_macro::hcl`].
+//!
+//! The graph is the one of the [`PropOrder`] of the architecture, so units
+//! and ports are registered once, through [`super::PropOrderBuilder`]; only the
+//! delays are given separately.
+//!
+//! A forward pass gives the arrival time of every node and a backward pass
+//! the heaviest path after it, hence its slack. The critical nodes and edges
+//! are the ones without slack: the paths themselves are never listed, as the
+//! forwarding network of a pipeline has exponentially many of them.
+
+use std::collections::BTreeMap;
+
+use super::PropOrder;
+
+/// Delay of a hardware unit without a `#[delay(..)]` annotation.
+pub const DEFAULT_UNIT_DELAY: u64 = 1;
+/// Delay of a combinational signal without a `#[delay(..)]` annotation.
+pub const DEFAULT_SIGNAL_DELAY: u64 = 0;
+
+#[derive(Debug, Clone)]
+pub struct TimingNode {
+    pub name: String,
+    pub is_unit: bool,
+    pub delay: u64,
+    /// Time at which the output of this node becomes stable, counted from
+    /// the start of the cycle.
+    pub arrival: u64,
+    /// How much this node can be slowed down without lengthening the
+    /// critical path. Nodes on a critical path have zero slack.
+    pub slack: u64,
+    /// Indices of the predecessors that arrive last, i.e. the ones that
+    /// determine the arrival time of this node.
+    pub preds: Vec<usize>,
+}
+
+/// Result of the weighted critical path analysis.
+#[derive(Debug, Clone)]
+pub struct TimingModel {
+    /// Nodes sorted by name.
+    pub nodes: Vec<TimingNode>,
+    /// Total delay of the heaviest path.
+    pub critical_delay: u64,
+}
+
+impl TimingModel {
+    /// Weigh the dependency graph of `order`, the propagation order built by
+    /// [`super::PropOrderBuilder`], with the delays of `delays` and compute the
+    /// arrival time of every node. Nodes not in `delays` take the default
+    /// delay of a unit or a signal.
+    ///
+    /// Panics if the graph contains a cycle, which means the combinational
+    /// logic of the architecture never settles.
+    pub fn new(order: &PropOrder, delays: &[(&str, u64)]) -> TimingModel {
+        // units and intermediate signals, and the ports only found in edges
+        let mut is_unit: BTreeMap<&str, bool> = order
+            .order
+            .iter()
+            .map(|item| (item.name, item.is_unit))
+            .collect();
+        for (from, to) in &order.edges {
+            is_unit.entry(from).or_insert(false);
+            is_unit.entry(to).or_insert(false);
+        }
+        let names: Vec<&str> = is_unit.keys().copied().collect();
+        let index: BTreeMap<&str, usize> = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (*name, i))
+            .collect();
+
+        let mut succs = vec![Vec::new(); names.len()];
+        let mut preds = vec![Vec::new(); names.len()];
+        for (from, to) in &order.edges {
+            let (u, v) = (index[from.as_str()], index[to.as_str()]);
+            if !succs[u].contains(&v) {
+                succs[u].push(v);
+                preds[v].push(u);
+            }
+        }
+
+        let nodes: Vec<TimingNode> = names
+            .iter()
+            .map(|&name| {
+                let is_unit = is_unit[name];
+                let default = if is_unit {
+                    DEFAULT_UNIT_DELAY
+                } else {
+                    DEFAULT_SIGNAL_DELAY
+                };
+                let delay = delays.iter().find(|(n, _)| *n == name);
+                TimingNode {
+                    name: name.to_string(),
+                    is_unit,
+                    delay: delay.map_or(default, |&(_, delay)| delay),
+                    arrival: 0,
+                    slack: 0,
+                    preds: Vec::new(),
+                }
+            })
+            .collect();
+
+        // Kahn's algorithm
+        let mut indeg: Vec<usize> = preds.iter().map(Vec::len).collect();
+        let mut topo: Vec<usize> = (0..nodes.len()).filter(|&i| indeg[i] == 0).collect();
+        let mut head = 0;
+        while head < topo.len() {
+            let u = topo[head];
+            head += 1;
+            for &v in &succs[u] {
+                indeg[v] -= 1;
+                if indeg[v] == 0 {
+                    topo.push(v);
+                }
+            }
+        }
+        if topo.len() != nodes.len() {
+            let cyclic = (0..nodes.len())
+                .filter(|&i| indeg[i] > 0)
+                .map(|i| nodes[i].name.as_str())
+                .collect::<Vec<_>>();
+            panic!("combinational loop among: {}", cyclic.join(", "));
+        }
+
+        let mut model = TimingModel {
+            nodes,
+            critical_delay: 0,
+        };
+        // forward pass
+        for &v in &topo {
+            let start = preds[v]
+                .iter()
+                .map(|&u| model.nodes[u].arrival)
+                .max()
+                .unwrap_or(0);
+            // keep the predecessors that determine the arrival time
+            model.nodes[v].preds = preds[v]
+                .iter()
+                .copied()
+                .filter(|&u| model.nodes[u].arrival == start)
+                .collect();
+            model.nodes[v].arrival = start + model.nodes[v].delay;
+            model.critical_delay = model.critical_delay.max(model.nodes[v].arrival);
+        }
+
+        // backward pass, tail[v]: delay of the heaviest path after v
+        let mut tail = vec![0; model.nodes.len()];
+        for &u in topo.iter().rev() {
+            tail[u] = succs[u]
+                .iter()
+                .map(|&v| model.nodes[v].delay + tail[v])
+                .max()
+                .unwrap_or(0);
+            model.nodes[u].slack = model.critical_delay - model.nodes[u].arrival - tail[u];
+        }
+
+        model
+    }
+
+    /// Cost of one cycle: 1 (for the clocked registers) plus the delay of the
+    /// critical path. Without any `#[delay(..)]` annotation this is 1 plus
+    /// the maximum number of units lined up in a path.
+    pub fn cycle_cost(&self) -> u64 {
+        1 + self.critical_delay
+    }
+
+    fn node(&self, name: &str) -> Option<&TimingNode> {
+        let i = self
+            .nodes
+            .binary_search_by(|node| node.name.as_str().cmp(name))
+            .ok()?;
+        Some(&self.nodes[i])
+    }
+
+    /// Whether the node lies on a critical path, i.e. has no slack.
+    pub fn is_critical(&self, name: &str) -> bool {
+        self.critical_delay > 0 && self.node(name).is_some_and(|node| node.slack == 0)
+    }
+
+    /// Whether `from -> to` is an edge of a critical path: both ends have no
+    /// slack, and `to` starts as soon as `from` arrives.
+    pub fn is_critical_edge(&self, from: &str, to: &str) -> bool {
+        let Ok(u) = self
+            .nodes
+            .binary_search_by(|node| node.name.as_str().cmp(from))
+        else {
+            return false;
+        };
+        self.is_critical(from)
+            && self.is_critical(to)
+            && self.node(to).is_some_and(|node| node.preds.contains(&u))
+    }
+}
+
+impl std::fmt::Display for TimingModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "critical path (delay {}):", self.critical_delay)?;
+        let mut critical = self
+            .nodes
+            .iter()
+            .filter(|n| self.is_critical(&n.name))
+            .collect::<Vec<_>>();
+        critical.sort_by_key(|n| n.arrival);
+        for node in critical {
+            writeln!(f, "{:>4}  {} ({})", node.arrival, node.name, node.delay)?;
+        }
+        writeln!(f, "slack:")?;
+        let mut nodes = self
+            .nodes
+            .iter()
+            .filter(|n| n.slack > 0)
+            .collect::<Vec<_>>();
+        nodes.sort_by_key(|n| n.slack);
+        for node in nodes {
+            writeln!(f, "{:>4}  {}", node.slack, node.name)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framework::PropOrderBuilder;
+
+    /// imem -> icode -> { cond, reg_read }
+    fn fetch_decode() -> PropOrder {
+        let mut g = PropOrderBuilder::new();
+        g.add_unit_node("imem");
+        g.add_unit_output("imem", "icode");
+        g.add_unit_node("cond");
+        g.add_unit_input("cond", "icode");
+        g.add_unit_node("reg_read");
+        g.add_unit_input("reg_read", "srcA");
+        g.add_edge("imem.icode".to_string(), "icode".to_string());
+        g.add_intermediate("icode");
+        g.add_edge("icode".to_string(), "cond.icode".to_string());
+        g.add_edge("icode".to_string(), "reg_read.srcA".to_string());
+        g.build()
+    }
+
+    #[test]
+    fn test_unweighted_critical_path() {
+        let model = TimingModel::new(&fetch_decode(), &[]);
+        assert_eq!(model.critical_delay, 2);
+        assert_eq!(model.cycle_cost(), 3);
+    }
+
+    #[test]
+    fn test_weighted_critical_path() {
+        let delays = [("imem", 3), ("reg_read", 2), ("icode", 1)];
+        let model = TimingModel::new(&fetch_decode(), &delays);
+        assert_eq!(model.critical_delay, 6);
+        assert_eq!(model.cycle_cost(), 7);
+    }
+
+    #[test]
+    fn test_critical_path_report() {
+        let model = TimingModel::new(&fetch_decode(), &[("reg_read", 2)]);
+
+        let critical = model
+            .nodes
+            .iter()
+            .filter(|n| model.is_critical(&n.name))
+            .map(|n| n.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            critical,
+            ["icode", "imem", "imem.icode", "reg_read", "reg_read.srcA"]
+        );
+        assert!(model.is_critical_edge("icode", "reg_read.srcA"));
+        assert!(!model.is_critical_edge("icode", "cond.icode"));
+        assert!(!model.is_critical("cond"));
+
+        let slack = |name| model.nodes.iter().find(|n| n.name == name).unwrap().slack;
+        assert_eq!(slack("cond"), 1);
+        assert_eq!(slack("cond.icode"), 1);
+        assert_eq!(slack("imem"), 0);
+    }
+}
@@ -0,0 +1,877 @@
+//! Importer of the original CS:APP HCL dialect (`seq-std.hcl`,
+//! `pipe-std.hcl`, ...) into the syntax of [`sim_macro::hcl!`].
+//!
+//! CS:APP declares every signal computed by the C simulator with
+//! `boolsig`/`wordsig` and binds it to a C expression. Here such a signal is
+//! a constant, a pipeline register field or an output of a hardware unit:
+//!
+//! - constants are recognized by their C name, e.g. `wordsig IRRMOVQ
+//!   'I_RRMOVQ'` becomes `CMOVX`;
+//! - `D_icode` becomes the pipeline register field `D.icode`;
+//! - other signals are looked up in [`ImportConfig::outputs`], e.g. `e_valE`
+//!   becomes `alu.e`.
+//!
+//! HCL signals listed in [`ImportConfig::inputs`] are connected to their unit
+//! with `@set_input`. Pipeline registers are fed the way the C simulator does
+//! it: `E.valA` from the signal `d_valA`, or from `D.valA` if there is no such
+//! signal, and `stall`/`bubble` from `E_stall`/`E_bubble`.
+//!
+//! Whatever can't be translated is reported as a warning and left in the
+//! output as is.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use pest::{iterators::Pair, Parser};
+use pest_derive::Parser;
+
+use super::constant;
+
+#[derive(Parser)]
+#[grammar = "hcl/csapp.pest"]
+struct CsappParser;
+
+/// Pipeline registers of CS:APP, from the first to the last.
+const STAGES: [&str; 5] = ["F", "D", "E", "M", "W"];
+
+/// Names of the stage types declared for [`STAGES`].
+const STAGE_TYPES: [&str; 5] = [
+    "FetchStage",
+    "DecodeStage",
+    "ExecuteStage",
+    "MemoryStage",
+    "WritebackStage",
+];
+
+/// How the names of a CS:APP file map to the hardware of the simulator.
+#[derive(Debug, Clone)]
+pub struct ImportConfig {
+    /// Hardware module of the translated architecture. Defaults to
+    /// `hardware_pipe` if the file uses pipeline registers and
+    /// `hardware_seq` otherwise.
+    pub hardware: Option<String>,
+    /// Signals computed by the C simulator, as `name => unit.port`.
+    pub outputs: BTreeMap<String, String>,
+    /// HCL signals driving the inputs of units, as `(name, unit.port)`.
+    pub inputs: Vec<(String, String)>,
+}
+
+impl Default for ImportConfig {
+    /// The names of `seq-std.hcl` and `pipe-std.hcl`, mapped to the ports of
+    /// the built-in hardware modules.
+    fn default() -> Self {
+        let outputs = [
+            // pipe-std.hcl
+            ("imem_icode", "imem.icode"),
+            ("imem_ifun", "imem.ifun"),
+            ("imem_error", "imem.error"),
+            ("f_rA", "imem.rA"),
+            ("f_rB", "imem.rB"),
+            ("f_valC", "imem.valC"),
+            ("f_valP", "pc_inc.new_pc"),
+            ("d_rvalA", "reg_file.valA"),
+            ("d_rvalB", "reg_file.valB"),
+            ("e_valE", "alu.e"),
+            ("e_Cnd", "cond.cnd"),
+            ("m_valM", "dmem.dataout"),
+            ("dmem_error", "dmem.error"),
+            // seq-std.hcl
+            ("icode", "imem.icode"),
+            ("ifun", "imem.ifun"),
+            ("rA", "imem.rA"),
+            ("rB", "imem.rB"),
+            ("valC", "imem.valC"),
+            ("valP", "pc_inc.new_pc"),
+            ("valA", "reg_file.valA"),
+            ("valB", "reg_file.valB"),
+            ("valE", "alu.e"),
+            ("Cnd", "cond.cnd"),
+            ("valM", "dmem.dataout"),
+        ];
+        let inputs = [
+            ("f_pc", "imem.pc"),
+            ("f_pc", "pc_inc.pc"),
+            ("pc", "imem.pc"),
+            ("pc", "pc_inc.pc"),
+            ("need_regids", "pc_inc.need_regids"),
+            ("need_valC", "pc_inc.need_valC"),
+            ("d_srcA", "reg_file.srcA"),
+            ("d_srcB", "reg_file.srcB"),
+            ("srcA", "reg_file.srcA"),
+            ("srcB", "reg_file.srcB"),
+            ("w_dstE", "reg_file.dstE"),
+            ("w_valE", "reg_file.valE"),
+            ("w_dstM", "reg_file.dstM"),
+            ("w_valM", "reg_file.valM"),
+            ("dstE", "reg_file.dstE"),
+            ("dstM", "reg_file.dstM"),
+            ("aluA", "alu.a"),
+            ("aluB", "alu.b"),
+            ("alufun", "alu.fun"),
+            ("set_cc", "reg_cc.set_cc"),
+            ("mem_addr", "dmem.addr"),
+            ("mem_data", "dmem.datain"),
+            ("mem_read", "dmem.read"),
+            ("mem_write", "dmem.write"),
+        ];
+        Self {
+            hardware: None,
+            outputs: outputs
+                .into_iter()
+                .map(|(name, port)| (name.to_string(), port.to_string()))
+                .collect(),
+            inputs: inputs
+                .into_iter()
+                .map(|(name, port)| (name.to_string(), port.to_string()))
+                .collect(),
+        }
+    }
+}
+
+/// A construct that could not be translated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportWarning {
+    /// `None` for the file as a whole.
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl std::fmt::Display for ImportWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {}: {}", line, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Imported {
+    /// The `define_stages!` declarations of the pipeline registers, with the
+    /// fields the file uses. Empty for a sequential architecture.
+    pub stages: String,
+    /// The body of a `sim_macro::hcl!` block.
+    pub hcl: String,
+    pub warnings: Vec<ImportWarning>,
+}
+
+impl Imported {
+    /// The declarations and the body as one file for
+    /// [`HclSim`](super::HclSim).
+    pub fn interpreted(&self) -> String {
+        format!("{}{}", self.stages, self.hcl)
+    }
+}
+
+/// Our name of a constant declared with the C name `cname`.
+fn constant_of(cname: &str) -> Option<String> {
+    let name = match cname {
+        "I_RRMOVQ" => "CMOVX",
+        "I_ALU" => "OPQ",
+        "I_JMP" => "JX",
+        "I_IADDQ" => "IOPQ",
+        "F_NONE" => return Some("0".to_string()),
+        "REG_NONE" => "RNONE",
+        "STAT_AOK" => "Aok",
+        "STAT_BUB" => "Bub",
+        "STAT_HLT" => "Hlt",
+        "STAT_ADR" => "Adr",
+        "STAT_INS" => "Ins",
+        _ => ["I_", "REG_", "A_", "C_"]
+            .iter()
+            .find_map(|prefix| cname.strip_prefix(prefix))?,
+    };
+    constant(name).map(|_| name.to_string())
+}
+
+/// Type of a signal in our dialect, guessed from its CS:APP name.
+fn hcl_type(name: &str, typ: &str) -> &'static str {
+    if typ == "bool" {
+        return "bool";
+    }
+    let base = name.rsplit('_').next().unwrap_or(name);
+    match base {
+        "icode" | "ifun" | "rA" | "rB" | "srcA" | "srcB" | "dstE" | "dstM" | "alufun" => "u8",
+        "stat" | "Stat" => "Stat",
+        _ => "u64",
+    }
+}
+
+/// `X_field` of a pipeline register, as `(X, field)`.
+fn stage_field(name: &str) -> Option<(&str, &str)> {
+    let (stage, field) = name.split_once('_')?;
+    (STAGES.contains(&stage) && !field.is_empty()).then_some((stage, field))
+}
+
+fn is_path(s: &str) -> bool {
+    s.split('.').all(|id| {
+        id.chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    })
+}
+
+/// `#` comments of the source, as (offset, text, follows code on its line).
+fn scan_comments(src: &str) -> VecDeque<(usize, String, bool)> {
+    let mut comments = VecDeque::new();
+    let mut offset = 0;
+    for line in src.split_inclusive('\n') {
+        let mut quoted = false;
+        for (j, c) in line.char_indices() {
+            match c {
+                '\'' => quoted = !quoted,
+                '#' if !quoted => {
+                    let text = line[j..].trim_end().to_string();
+                    let trailing = !line[..j].trim().is_empty();
+                    comments.push_back((offset + j, text, trailing));
+                    break;
+                }
+                _ => {}
+            }
+        }
+        offset += line.len();
+    }
+    comments
+}
+
+struct Importer<'a> {
+    src: &'a str,
+    config: &'a ImportConfig,
+    /// Declared names and their C expressions.
+    decls: BTreeMap<String, String>,
+    /// Names declared with `boolsig`.
+    bools: BTreeSet<String>,
+    /// Signals defined in HCL, and their names in the output.
+    defined: BTreeMap<String, String>,
+    is_pipe: bool,
+    comments: VecDeque<(usize, String, bool)>,
+    /// Fields of the pipeline registers, as (stage, field).
+    fields: BTreeSet<(String, String)>,
+    /// Names already warned about.
+    unknown: BTreeSet<String>,
+    out: String,
+    warnings: Vec<ImportWarning>,
+}
+
+impl Importer<'_> {
+    fn warn(&mut self, line: Option<usize>, message: String) {
+        self.warnings.push(ImportWarning { line, message });
+    }
+
+    /// Emit the comments before `offset`. Banners such as
+    /// `#### Fetch Stage ####` become stage dividers, and are the only
+    /// comments kept before the first signal.
+    fn flush_comments(&mut self, offset: usize, indent: &str, emit: bool) {
+        while let Some((_, text, _)) = self.comments.front().filter(|c| c.0 < offset) {
+            let text = text.clone();
+            self.comments.pop_front();
+            if !emit || text.contains("$begin") || text.contains("$end") {
+                continue;
+            }
+            let title = text.trim_matches('#').trim();
+            if text.starts_with("####") && text.ends_with('#') {
+                if !title.is_empty() {
+                    self.out += &format!("\n:====: {title} :====:\n\n");
+                }
+            } else if self.out.is_empty() {
+                continue;
+            } else if title.is_empty() {
+                self.out += &format!("{indent}//\n");
+            } else {
+                self.out += &format!("{indent}// {}\n", text.trim_start_matches('#').trim());
+            }
+        }
+    }
+
+    /// Append the comment at the end of the line of `offset` to the last
+    /// emitted line.
+    fn trailing_comment(&mut self, offset: usize) {
+        let line_end = self.src[offset..]
+            .find('\n')
+            .map_or(self.src.len(), |i| offset + i);
+        let Some((_, text, _)) = self
+            .comments
+            .front()
+            .filter(|c| c.2 && c.0 >= offset && c.0 < line_end)
+        else {
+            return;
+        };
+        let text = text.trim_start_matches('#').trim().to_string();
+        self.comments.pop_front();
+        if self.out.ends_with('\n') {
+            self.out.pop();
+        }
+        self.out += &format!(" // {text}\n");
+    }
+
+    /// Translate a name used in an expression.
+    fn resolve(&mut self, name: &str, line: Option<usize>) -> String {
+        if let Some(renamed) = self.defined.get(name) {
+            return renamed.clone();
+        }
+        let cname = self.decls.get(name).cloned().unwrap_or_default();
+        if let Some(c) = constant_of(&cname) {
+            return c;
+        }
+        if let Some((stage, field)) = stage_field(name) {
+            self.fields.insert((stage.to_string(), field.to_string()));
+            return format!("{stage}.{field}");
+        }
+        if let Some(port) = self.config.outputs.get(name) {
+            return port.clone();
+        }
+        if self.unknown.insert(name.to_string()) {
+            let message = if cname.is_empty() {
+                format!("`{name}` is never declared or defined")
+            } else {
+                format!("no translation for `{name}` ('{cname}')")
+            };
+            self.warn(line, message);
+        }
+        name.to_string()
+    }
+
+    /// Translate an expression. Integers in a `boolean` context become
+    /// `true`/`false`.
+    fn expr(&mut self, pair: Pair<Rule>, boolean: bool) -> String {
+        let line = Some(pair.line_col().0);
+        match pair.as_rule() {
+            Rule::expr | Rule::land => {
+                let sep = if pair.as_rule() == Rule::expr {
+                    " || "
+                } else {
+                    " && "
+                };
+                let inner = pair.into_inner().collect::<Vec<_>>();
+                let boolean = boolean || inner.len() > 1;
+                let parts = inner
+                    .into_iter()
+                    .map(|p| self.expr(p, boolean))
+                    .collect::<Vec<_>>();
+                parts.join(sep)
+            }
+            Rule::rel => {
+                let mut inner = pair.into_inner();
+                let first = inner.next().unwrap();
+                let Some(op) = inner.next() else {
+                    return self.expr(first, boolean);
+                };
+                let lhs = self.expr(first, false);
+                if op.as_rule() == Rule::cmp_op {
+                    let op = op.as_str();
+                    let rhs = self.expr(inner.next().unwrap(), false);
+                    return format!("{lhs} {op} {rhs}");
+                }
+                let members = inner.map(|p| self.expr(p, false)).collect::<Vec<_>>();
+                if members.iter().all(|m| is_path(m)) {
+                    format!("{lhs} in {{ {} }}", members.join(", "))
+                } else {
+                    let alts = members
+                        .iter()
+                        .map(|m| format!("{lhs} == {m}"))
+                        .collect::<Vec<_>>();
+                    format!("({})", alts.join(" || "))
+                }
+            }
+            Rule::neg => {
+                let mut inner = pair.into_inner().collect::<Vec<_>>();
+                let rel = inner.pop().unwrap();
+                if inner.is_empty() {
+                    return self.expr(rel, boolean);
+                }
+                let nots = "!".repeat(inner.len());
+                let is_compound = rel.clone().into_inner().len() > 1;
+                let e = self.expr(rel, true);
+                if is_compound {
+                    format!("{nots}({e})")
+                } else {
+                    format!("{nots}{e}")
+                }
+            }
+            Rule::primary => {
+                let inner = pair.into_inner().next().unwrap();
+                match inner.as_rule() {
+                    Rule::expr => format!("({})", self.expr(inner, boolean)),
+                    _ => self.expr(inner, boolean),
+                }
+            }
+            Rule::int if boolean => match pair.as_str() {
+                "0" => "false".to_string(),
+                "1" => "true".to_string(),
+                s => {
+                    self.warn(line, format!("integer `{s}` used as a condition"));
+                    s.to_string()
+                }
+            },
+            Rule::int => match pair.as_str().strip_prefix('-') {
+                // our words are unsigned
+                Some(n) => match n.parse::<u64>() {
+                    Ok(n) => format!("{:#x}", n.wrapping_neg()),
+                    Err(_) => {
+                        self.warn(line, format!("integer `-{n}` is not supported"));
+                        pair.as_str().to_string()
+                    }
+                },
+                None => pair.as_str().to_string(),
+            },
+            Rule::ident => self.resolve(pair.as_str(), line),
+            _ => {
+                self.warn(
+                    line,
+                    "nested case expressions are not supported".to_string(),
+                );
+                pair.as_str().to_string()
+            }
+        }
+    }
+
+    fn definition(&mut self, pair: Pair<Rule>) {
+        let mut inner = pair.into_inner();
+        let typ = inner.next().unwrap().as_str();
+        let name = inner.next().unwrap().as_str();
+        let source = inner.next().unwrap();
+
+        let boolean = typ == "bool";
+        let typ = hcl_type(name, typ);
+        let name = self.defined[name].clone();
+        let end = source.as_span().end();
+        if source.as_rule() == Rule::expr {
+            // comments inside the expression go before the signal
+            self.flush_comments(end, "", true);
+            let e = self.expr(source, boolean);
+            self.out += &format!("{typ} {name} = {e};\n");
+            self.trailing_comment(end);
+            return;
+        }
+        self.out += &format!("{typ} {name} = [\n");
+        for case in source.into_inner() {
+            let span = case.as_span();
+            self.flush_comments(span.start(), "    ", true);
+            let mut inner = case.into_inner();
+            let cond = self.expr(inner.next().unwrap(), true);
+            let value = self.expr(inner.next().unwrap(), boolean);
+            self.out += &format!("    {cond} : {value};\n");
+            self.trailing_comment(span.end());
+        }
+        self.flush_comments(end, "    ", true);
+        self.out += "];\n";
+    }
+
+    /// `define_stages!` with the fields of every pipeline register. The
+    /// defaults are those of a bubble: `Bub`, `NOP`, `RNONE` or zero.
+    fn define_stages(&self) -> String {
+        let mut out = "define_stages! {\n".to_string();
+        for (stage, typ_name) in STAGES.iter().zip(STAGE_TYPES) {
+            let fields = self
+                .fields
+                .iter()
+                .filter(|(s, _)| s == stage)
+                .map(|(_, field)| {
+                    let name = format!("{stage}_{field}");
+                    let is_bool = self.bools.contains(&name);
+                    let typ = hcl_type(&name, if is_bool { "bool" } else { "word" });
+                    let default = match (typ, field.as_str()) {
+                        ("bool", _) => "false",
+                        ("Stat", _) => "Bub",
+                        (_, "icode") => "NOP",
+                        (_, "rA" | "rB" | "srcA" | "srcB" | "dstE" | "dstM") => "RNONE",
+                        _ => "0",
+                    };
+                    format!("        {field}: {typ} = {default},\n")
+                })
+                .collect::<String>();
+            let short = stage.to_lowercase();
+            out += &format!("    {typ_name} {short} {{\n{fields}    }}\n");
+        }
+        out + "}\n\n"
+    }
+
+    /// Inputs of each pipeline register, as (stage, [(field, signal)]).
+    /// Missing signals are defined in `wires`.
+    fn stage_inputs(&mut self, wires: &mut Vec<String>) -> Vec<(String, Vec<(String, String)>)> {
+        let mut stages = Vec::new();
+        let mut stage_wires = Vec::new();
+        // backwards, as a field may be passed through from the previous stage
+        for (i, stage) in STAGES.iter().enumerate().rev() {
+            let fields = self
+                .fields
+                .iter()
+                .filter(|(s, _)| s == stage)
+                .map(|(_, f)| f.clone())
+                .collect::<Vec<_>>();
+            let prev = if i == 0 { "F" } else { STAGES[i - 1] };
+            let mut inputs = Vec::new();
+            let mut wires = Vec::new();
+            for field in fields {
+                let signal = format!("{}_{field}", prev.to_lowercase());
+                let is_bool = self.bools.contains(&signal)
+                    || self.bools.contains(&format!("{stage}_{field}"));
+                let typ = hcl_type(&signal, if is_bool { "bool" } else { "word" });
+                let src = if let Some(renamed) = self.defined.get(&signal) {
+                    renamed.clone()
+                } else if let Some(port) = self.config.outputs.get(&signal) {
+                    wires.push(format!("{typ} {signal} = {port};"));
+                    signal
+                } else if i >= 2 {
+                    // passed through from the previous register
+                    self.fields.insert((prev.to_string(), field.clone()));
+                    wires.push(format!("{typ} {signal} = {prev}.{field};"));
+                    signal
+                } else {
+                    self.warn(
+                        None,
+                        format!("no source for the pipeline register field {stage}.{field}"),
+                    );
+                    continue;
+                };
+                inputs.push((field, src));
+            }
+            for control in ["stall", "bubble"] {
+                if let Some(renamed) = self.defined.get(&format!("{stage}_{control}")) {
+                    inputs.push((control.to_string(), renamed.clone()));
+                }
+            }
+            if !inputs.is_empty() {
+                stages.push((stage.to_lowercase(), inputs));
+            }
+            stage_wires.push(wires);
+        }
+        stages.reverse();
+        wires.extend(stage_wires.into_iter().rev().flatten());
+        stages
+    }
+}
+
+/// Translate a CS:APP HCL file. Syntax errors are returned as errors, and
+/// constructs that can't be translated as warnings.
+pub fn import_csapp(src: &str, config: &ImportConfig) -> anyhow::Result<Imported> {
+    let file = CsappParser::parse(Rule::file, src)?.next().unwrap();
+    let items = file
+        .into_inner()
+        .filter(|p| p.as_rule() != Rule::EOI)
+        .collect::<Vec<_>>();
+
+    let mut decls = BTreeMap::new();
+    let mut bools = BTreeSet::new();
+    let mut defined = BTreeMap::new();
+    for item in &items {
+        let mut inner = item.clone().into_inner();
+        match item.as_rule() {
+            Rule::decl => {
+                let kind = inner.next().unwrap().as_str();
+                let name = inner.next().unwrap().as_str();
+                if kind == "boolsig" {
+                    bools.insert(name.to_string());
+                }
+                let cname = inner.next().unwrap().as_str().trim_matches('\'');
+                decls.insert(name.to_string(), cname.to_string());
+            }
+            Rule::definition => {
+                let name = inner.nth(1).unwrap().as_str().to_string();
+                defined.insert(name.clone(), name);
+            }
+            _ => {}
+        }
+    }
+    let is_pipe = decls
+        .keys()
+        .chain(defined.keys())
+        .any(|n| stage_field(n).is_some());
+
+    let mut importer = Importer {
+        src,
+        config,
+        decls,
+        bools,
+        defined,
+        is_pipe,
+        comments: scan_comments(src),
+        fields: BTreeSet::new(),
+        unknown: BTreeSet::new(),
+        out: String::new(),
+        warnings: Vec::new(),
+    };
+
+    // names that clash with our types and constants
+    let names = importer.defined.keys().cloned().collect::<Vec<_>>();
+    for name in names {
+        if name == "Stat" || constant(&name).is_some() {
+            let mut renamed = name.to_lowercase();
+            while importer.defined.contains_key(&renamed) {
+                renamed += "_";
+            }
+            importer.defined.insert(name, renamed);
+        }
+    }
+
+    for (name, cname) in importer.decls.clone() {
+        let is_const = name.chars().all(|c| !c.is_ascii_lowercase());
+        if is_const && !importer.defined.contains_key(&name) && constant_of(&cname).is_none() {
+            importer.warn(None, format!("unknown constant `{name}` ('{cname}')"));
+        }
+    }
+
+    for item in items {
+        let line = Some(item.line_col().0);
+        match item.as_rule() {
+            Rule::quote | Rule::decl => {
+                // including the comment at the end of the line
+                let end = item.as_span().end();
+                let line_end = src[end..].find('\n').map_or(src.len(), |i| end + i);
+                importer.flush_comments(line_end, "", false);
+                if item.as_rule() == Rule::quote {
+                    importer.warn(line, "C code in `quote` is not translated".to_string());
+                }
+            }
+            _ => {
+                importer.flush_comments(item.as_span().start(), "", true);
+                importer.definition(item);
+            }
+        }
+    }
+    importer.flush_comments(src.len(), "", true);
+
+    let mut body = std::mem::take(&mut importer.out);
+
+    // termination
+    let stat = match importer.defined.get("Stat") {
+        Some(stat) => Some(stat.clone()),
+        None if importer.is_pipe => Some(importer.resolve("W_stat", None)),
+        None => None,
+    };
+    let mut term = "prog_term".to_string();
+    while importer.defined.contains_key(&term) {
+        term += "_";
+    }
+    match stat {
+        Some(stat) => body += &format!("\nbool {term} = {stat} in {{ Hlt, Adr, Ins }};\n"),
+        None => {
+            importer.warn(None, "no `Stat` signal for the termination".to_string());
+            body += &format!("\nbool {term} = false;\n");
+        }
+    }
+
+    // inputs of units and pipeline registers
+    let mut wires = Vec::new();
+    let stage_inputs = importer.stage_inputs(&mut wires);
+    if !wires.is_empty() {
+        body += "\n:====: Pipeline Register Inputs :====:\n\n";
+        for wire in &wires {
+            body += &format!("{wire}\n");
+        }
+    }
+    let mut unit_inputs: Vec<(String, Vec<(String, String)>)> = Vec::new();
+    for (name, port) in &config.inputs {
+        let (Some(signal), Some((unit, port))) = (importer.defined.get(name), port.split_once('.'))
+        else {
+            continue;
+        };
+        let field = (port.to_string(), signal.clone());
+        match unit_inputs.iter_mut().find(|(u, _)| u == unit) {
+            Some((_, fields)) => fields.push(field),
+            None => unit_inputs.push((unit.to_string(), vec![field])),
+        }
+    }
+    let mut directives = String::new();
+    for (unit, fields) in &unit_inputs {
+        let fields = fields
+            .iter()
+            .map(|(port, signal)| format!("    {port}: {signal},\n"))
+            .collect::<String>();
+        directives += &format!("@set_input({unit}, {{\n{fields}}});\n");
+    }
+    for (stage, fields) in &stage_inputs {
+        let fields = fields
+            .iter()
+            .map(|(field, signal)| format!("    {field}: {signal},\n"))
+            .collect::<String>();
+        directives += &format!("@set_stage({stage}, {{\n{fields}}});\n");
+    }
+    if !directives.is_empty() {
+        body += &format!("\n{directives}");
+    }
+
+    // global attributes
+    let hardware = config.hardware.clone().unwrap_or_else(|| {
+        let module = if importer.is_pipe {
+            "hardware_pipe"
+        } else {
+            "hardware_seq"
+        };
+        format!("crate::architectures::{module}")
+    });
+    let pc = ["f_pc", "pc", "new_pc"]
+        .iter()
+        .find_map(|pc| importer.defined.get(*pc))
+        .cloned();
+    let pc = pc.unwrap_or_else(|| {
+        importer.warn(None, "no program counter signal".to_string());
+        "pc".to_string()
+    });
+    let mut hcl = format!(
+        "#![hardware = {hardware}]\n#![program_counter = {pc}]\n#![termination = {term}]\n"
+    );
+    if importer.is_pipe {
+        let alias = STAGES
+            .iter()
+            .map(|s| format!("{s} => {}", s.to_lowercase()))
+            .collect::<Vec<_>>();
+        hcl += &format!("#![stage_alias({})]\n", alias.join(", "));
+    }
+    hcl += "\n";
+    hcl += body.trim_start_matches('\n');
+
+    let stages = match importer.is_pipe {
+        true => importer.define_stages(),
+        false => String::new(),
+    };
+    Ok(Imported {
+        stages,
+        hcl,
+        warnings: importer.warnings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framework::{CpuSim, HardwareUnits};
+    use crate::hcl::test_arch::count_down;
+    use crate::hcl::test_hardware::Units;
+    use crate::hcl::{HclFile, HclSim};
+
+    #[test]
+    fn test_import_csapp() {
+        let src = r#"
+#/* $begin pipe-all-hcl */
+quote '#include <stdio.h>'
+wordsig INOP 'I_NOP'
+wordsig IRRMOVQ 'I_RRMOVQ'
+wordsig FNONE 'F_NONE'
+wordsig SBUB 'STAT_BUB'
+wordsig SAOK 'STAT_AOK'
+wordsig F_predPC 'pc_curr->pc'
+wordsig imem_icode 'imem_icode'
+boolsig imem_error 'imem_error'
+wordsig D_icode 'if_id_curr->icode'
+wordsig D_ifun 'if_id_curr->ifun'
+wordsig W_stat 'mem_wb_curr->status'
+wordsig e_valE 'ex_mem_next->vale'
+boolsig weird 'some_c_code()'
+
+################ Fetch Stage     ###################################
+
+word f_pc = F_predPC;
+# Determine icode of fetched instruction
+word f_icode = [
+	imem_error : INOP;
+	1: imem_icode;
+];
+word f_ifun = [ 1 : FNONE ];
+word f_predPC = [ f_icode in { IRRMOVQ, INOP } : f_pc; 1 : e_valE ];
+bool d_odd = D_ifun in { FNONE, D_icode } && !weird;
+bool d_lt = D_icode < 3;
+bool d_nop = !D_icode in { INOP };
+
+################ Pipeline Register Control #########################
+
+bool F_stall = 0;
+bool D_bubble = D_icode == IRRMOVQ;
+word Stat = [ W_stat == SBUB : SAOK; 1 : W_stat ];
+"#;
+        let imported = import_csapp(src, &ImportConfig::default()).unwrap();
+        let hcl = &imported.hcl;
+        assert!(hcl.starts_with("#![hardware = crate::architectures::hardware_pipe]\n"));
+        assert!(hcl.contains("#![stage_alias(F => f, D => d, E => e, M => m, W => w)]"));
+        assert!(hcl.contains(":====: Fetch Stage :====:"));
+        assert!(hcl.contains("// Determine icode of fetched instruction\nu8 f_icode = [\n"));
+        assert!(hcl.contains("    imem.error : NOP;\n    true : imem.icode;\n"));
+        assert!(hcl.contains(
+            "u64 f_predPC = [\n    f_icode in { CMOVX, NOP } : f_pc;\n    true : alu.e;\n"
+        ));
+        assert!(hcl.contains("bool d_odd = (D.ifun == 0 || D.ifun == D.icode) && !weird;"));
+        assert!(hcl.contains("bool d_nop = !(D.icode in { NOP });"));
+        assert!(hcl.contains("Stat stat = [\n    W.stat == Bub : Aok;"));
+        assert!(hcl.contains("bool prog_term = stat in { Hlt, Adr, Ins };"));
+        assert!(hcl.contains("@set_input(imem, {\n    pc: f_pc,\n});"));
+        assert!(hcl.contains("@set_stage(f, {\n    predPC: f_predPC,\n    stall: F_stall,\n});"));
+        // D.icode and D.ifun come from the fetch stage
+        assert!(hcl.contains("    icode: f_icode,\n    ifun: f_ifun,\n    bubble: D_bubble,\n"));
+        assert!(!hcl.contains("$begin"));
+
+        let messages = imported
+            .warnings
+            .iter()
+            .map(|w| w.message.as_str())
+            .collect::<Vec<_>>();
+        assert!(messages.contains(&"C code in `quote` is not translated"));
+        assert!(messages.contains(&"no translation for `weird` ('some_c_code()')"));
+        assert!(hcl.contains("D.icode < 3"));
+        // W.stat is passed through from D.stat, which has no source
+        assert!(hcl.contains("Stat d_stat = D.stat;\nStat e_stat = E.stat;\nStat m_stat = M.stat;"));
+        assert!(messages.contains(&"no source for the pipeline register field D.stat"));
+
+        assert!(imported
+            .stages
+            .contains("    DecodeStage d {\n        icode: u8 = NOP,\n"));
+        let file = HclFile::parse(&imported.interpreted()).unwrap();
+        assert_eq!(
+            file.stage_decls,
+            [
+                "Fetch Stage",
+                "Pipeline Register Control",
+                "Pipeline Register Inputs"
+            ]
+        );
+        assert_eq!(file.stages.len(), 5);
+    }
+
+    #[test]
+    fn test_import_runs() {
+        let src = r#"
+wordsig INOP 'I_NOP'
+wordsig IHALT 'I_HALT'
+wordsig SAOK 'STAT_AOK'
+wordsig SHLT 'STAT_HLT'
+wordsig SBUB 'STAT_BUB'
+wordsig F_predPC 'pc_curr->pc'
+wordsig imem_icode 'imem_icode'
+boolsig imem_error 'imem_error'
+wordsig f_valP 'if_id_next->valp'
+wordsig W_stat 'mem_wb_curr->status'
+
+word f_pc = F_predPC;
+word f_icode = [
+	imem_error : INOP;
+	1: imem_icode;
+];
+word f_stat = [
+	f_icode == IHALT : SHLT;
+	1 : SAOK;
+];
+word f_predPC = f_valP;
+word Stat = [ W_stat == SBUB : SAOK; 1 : W_stat ];
+"#;
+        let mut config = ImportConfig {
+            hardware: Some("crate::hcl::test_hardware".to_string()),
+            inputs: vec![("f_pc".to_string(), "imem.pc".to_string())],
+            ..Default::default()
+        };
+        config
+            .outputs
+            .insert("f_valP".to_string(), "imem.valP".to_string());
+        let imported = import_csapp(src, &config).unwrap();
+        assert_eq!(imported.warnings, []);
+
+        // fetch the instructions in a row until the `halt` reaches W
+        let units = Units::init(count_down());
+        let mut sim = HclSim::new(&imported.interpreted(), units, false).unwrap();
+        while !sim.is_terminate() {
+            assert!(sim.cycle_count() < 20, "the program doesn't halt");
+            sim.step().unwrap();
+        }
+        // nothing stops fetching after the `halt` at 0x1f
+        assert_eq!(sim.program_counter(), 0x1f + 4);
+        assert_eq!(sim.cycle_count(), 9);
+    }
+}
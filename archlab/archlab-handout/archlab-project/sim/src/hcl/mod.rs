@@ -0,0 +1,1233 @@
+//! Run-time interpreter of HCL.
+//!
+//! [`sim_macro::hcl!`] compiles an architecture into the simulator, so a new
+//! architecture needs a rebuild. This module parses the same syntax (plus the
+//! [`crate::define_stages`] declarations of the architecture) from a text
+//! file and runs it behind [`CpuSim`](crate::framework::CpuSim), on top of
+//! the compiled hardware units of a hardware module.
+//!
+//! Every signal is kept as a `u64` word while interpreting, except byte
+//! arrays (e.g. `[u8; 9]`), which are kept as bytes and can only be copied.
+//! Unit ports are converted from and to their Rust types with [`HclValue`].
+//! The declared types are checked by [`check_types`] instead.
+//!
+//! Files in the HCL dialect of CS:APP can be translated into this syntax with
+//! [`import_csapp`].
+
+mod csapp;
+mod module;
+mod sim;
+#[cfg(test)]
+pub(crate) mod test_arch;
+#[cfg(test)]
+pub(crate) mod test_hardware;
+mod types;
+mod verilog;
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use pest::{iterators::Pair, Parser};
+use pest_derive::Parser;
+
+use crate::isa::{inst_code, op_code, reg_code, ConditionCode, Stat};
+
+pub use csapp::{import_csapp, ImportConfig, ImportWarning, Imported};
+pub use sim::HclSim;
+pub use types::{check_types, TypeEnv};
+pub use verilog::{export_testbench, export_verilog, memh};
+
+/// Values of the unit ports while interpreting, keyed by `unit.port`.
+#[derive(Debug, Clone, Default)]
+pub struct Ports {
+    pub words: BTreeMap<String, u64>,
+    /// ports of byte array types
+    pub bytes: BTreeMap<String, Vec<u8>>,
+}
+
+/// Conversion between the Rust type of a unit port and the word used by the
+/// interpreter.
+pub trait HclValue: Sized {
+    fn to_word(&self) -> u64;
+    fn from_word(word: u64) -> Self;
+
+    /// Value of the port `key`, `None` if it has not been set.
+    fn load(ports: &Ports, key: &str) -> Option<Self> {
+        ports.words.get(key).map(|word| Self::from_word(*word))
+    }
+    fn store(&self, ports: &mut Ports, key: String) {
+        ports.words.insert(key, self.to_word());
+    }
+}
+
+impl HclValue for bool {
+    fn to_word(&self) -> u64 {
+        *self as u64
+    }
+    fn from_word(word: u64) -> Self {
+        word != 0
+    }
+}
+
+impl HclValue for u8 {
+    fn to_word(&self) -> u64 {
+        *self as u64
+    }
+    fn from_word(word: u64) -> Self {
+        word as u8
+    }
+}
+
+impl HclValue for u64 {
+    fn to_word(&self) -> u64 {
+        *self
+    }
+    fn from_word(word: u64) -> Self {
+        word
+    }
+}
+
+impl HclValue for Stat {
+    fn to_word(&self) -> u64 {
+        *self as u64
+    }
+    fn from_word(word: u64) -> Self {
+        match word {
+            0 => Stat::Aok,
+            1 => Stat::Bub,
+            2 => Stat::Hlt,
+            3 => Stat::Adr,
+            _ => Stat::Ins,
+        }
+    }
+}
+
+impl HclValue for ConditionCode {
+    fn to_word(&self) -> u64 {
+        (self.sf as u64) << 2 | (self.of as u64) << 1 | self.zf as u64
+    }
+    fn from_word(word: u64) -> Self {
+        ConditionCode {
+            sf: word & 4 != 0,
+            of: word & 2 != 0,
+            zf: word & 1 != 0,
+        }
+    }
+}
+
+/// Byte arrays are stored in [`Ports::bytes`]. As a word, an array is its
+/// first 8 bytes in little endian.
+impl<const N: usize> HclValue for [u8; N] {
+    fn to_word(&self) -> u64 {
+        let mut word = [0; 8];
+        let n = N.min(8);
+        word[..n].copy_from_slice(&self[..n]);
+        u64::from_le_bytes(word)
+    }
+    fn from_word(word: u64) -> Self {
+        let mut bytes = [0; N];
+        let n = N.min(8);
+        bytes[..n].copy_from_slice(&word.to_le_bytes()[..n]);
+        bytes
+    }
+
+    fn load(ports: &Ports, key: &str) -> Option<Self> {
+        let value = ports.bytes.get(key)?;
+        let mut bytes = [0; N];
+        let n = N.min(value.len());
+        bytes[..n].copy_from_slice(&value[..n]);
+        Some(bytes)
+    }
+    fn store(&self, ports: &mut Ports, key: String) {
+        ports.bytes.insert(key, self.to_vec());
+    }
+}
+
+/// A port of a hardware unit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UnitPort {
+    pub unit: &'static str,
+    pub name: &'static str,
+    /// The Rust type of the port, e.g. `u64` or `[u8; 10]`.
+    pub typ: &'static str,
+    pub is_input: bool,
+}
+
+/// Hardware units driven by name, implemented by [`crate::define_units`].
+pub trait DynUnits {
+    fn hardware_setup(builder: &mut crate::framework::PropOrderBuilder);
+    /// Delays of the units annotated with `#[delay(..)]`.
+    fn unit_delays() -> Vec<(&'static str, u64)>;
+    /// Ports of all units, in the order they are defined.
+    fn ports() -> Vec<UnitPort>;
+    /// States of the units marked `#[signal]`.
+    fn state_signals(&self) -> Vec<crate::framework::Signal>;
+    fn set_state(
+        &mut self,
+        name: &str,
+        value: &crate::framework::SignalValue,
+    ) -> Result<(), crate::framework::SignalError>;
+    /// Run the unit `name`, nothing if there is no such unit. Its ports are
+    /// read from and written to `ports`; missing ports take their default
+    /// values.
+    fn run_dyn(&mut self, name: &str, ports: &mut Ports);
+}
+
+#[derive(Parser)]
+#[grammar = "hcl/grammar.pest"]
+struct HclParser;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Int(u64),
+    Bool(bool),
+    LVal(Vec<String>),
+    Not(Box<Expr>),
+    Eq(Box<Expr>, Box<Expr>),
+    NotEq(Box<Expr>, Box<Expr>),
+    In(Box<Expr>, Vec<Member>),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    /// `cond ? a : b`
+    Cond(Box<Expr>, Box<Expr>, Box<Expr>),
+}
+
+/// A member of `a in { .. }`: a signal, a constant or an integer, or an
+/// inclusive range `lo..=hi` of constants and integers.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Member {
+    One(Expr),
+    Range(Expr, Expr),
+}
+
+impl Member {
+    pub fn exprs(&self) -> Vec<&Expr> {
+        match self {
+            Member::One(e) => vec![e],
+            Member::Range(lo, hi) => vec![lo, hi],
+        }
+    }
+
+    /// `(lo, hi)` of a member known while parsing.
+    fn const_range(&self) -> Option<(u64, u64)> {
+        match self {
+            Member::One(e) => eval_const(e).map(|v| (v, v)),
+            Member::Range(lo, hi) => Some((eval_const(lo)?, eval_const(hi)?)),
+        }
+    }
+
+    fn map(self, f: &impl Fn(Vec<String>) -> Vec<String>) -> Self {
+        match self {
+            Member::One(e) => Member::One(e.map(f)),
+            Member::Range(lo, hi) => Member::Range(lo.map(f), hi.map(f)),
+        }
+    }
+}
+
+impl std::fmt::Display for Member {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let show = |e: &Expr| match e {
+            Expr::Int(v) => v.to_string(),
+            Expr::LVal(lv) => lv.join("."),
+            e => format!("{e:?}"),
+        };
+        match self {
+            Member::One(e) => write!(f, "{}", show(e)),
+            Member::Range(lo, hi) => write!(f, "{}..={}", show(lo), show(hi)),
+        }
+    }
+}
+
+/// Whether `lo..=hi` is empty or covered by the ranges in `before`, the same
+/// check as `dsl::ConstSet::is_unreachable` of the generated code.
+fn is_unreachable(mut lo: u64, hi: u64, before: &[(u64, u64)]) -> bool {
+    while lo <= hi {
+        let next = before
+            .iter()
+            .filter(|(a, b)| *a <= lo && lo <= *b)
+            .map(|(_, b)| *b)
+            .max();
+        match next {
+            None => return false,
+            Some(b) if b >= hi => return true,
+            Some(b) => lo = b + 1,
+        }
+    }
+    true
+}
+
+/// Members of `a in { .. }`. Like [`sim_macro::hcl!`], a member given twice,
+/// an empty range or a member covered by the ones before it is an error.
+fn parse_members<'a>(
+    line: usize,
+    pairs: impl Iterator<Item = Pair<'a, Rule>>,
+) -> anyhow::Result<Vec<Member>> {
+    let mut members: Vec<Member> = Vec::new();
+    let mut ranges = Vec::new();
+    for pair in pairs {
+        let mut inner = pair.into_inner();
+        let lo = parse_expr(inner.next().unwrap())?;
+        let member = match (inner.next(), inner.next()) {
+            (Some(op), _) if op.as_str() == ".." => {
+                bail!("line {line}: use an inclusive range `lo..=hi`")
+            }
+            (Some(_), Some(hi)) => Member::Range(lo, parse_expr(hi)?),
+            _ => Member::One(lo),
+        };
+        if members.contains(&member) {
+            bail!("line {line}: `{member}` appears twice in `in {{ .. }}`");
+        }
+        if let Member::Range(lo, hi) = &member {
+            if eval_const(lo).is_none() || eval_const(hi).is_none() {
+                bail!("line {line}: the bounds of `{member}` must be literals or constants");
+            }
+        }
+        if let Some((lo, hi)) = member.const_range() {
+            if lo > hi {
+                bail!("line {line}: empty range `{member}` in `in {{ .. }}`");
+            }
+            if is_unreachable(lo, hi, &ranges) {
+                bail!("line {line}: `{member}` is covered by the members before it");
+            }
+            ranges.push((lo, hi));
+        }
+        members.push(member);
+    }
+    Ok(members)
+}
+
+/// Arithmetic, bitwise and ordering operators of [`Expr::Binary`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl BinOp {
+    fn parse(s: &str) -> Self {
+        match s {
+            "+" => BinOp::Add,
+            "-" => BinOp::Sub,
+            "<<" => BinOp::Shl,
+            ">>" => BinOp::Shr,
+            "&" => BinOp::BitAnd,
+            "|" => BinOp::BitOr,
+            "^" => BinOp::BitXor,
+            "<" => BinOp::Lt,
+            "<=" => BinOp::Le,
+            ">" => BinOp::Gt,
+            ">=" => BinOp::Ge,
+            _ => unreachable!("unknown operator {s}"),
+        }
+    }
+
+    /// The operator in HCL (and Verilog) syntax.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Shl => "<<",
+            BinOp::Shr => ">>",
+            BinOp::BitAnd => "&",
+            BinOp::BitOr => "|",
+            BinOp::BitXor => "^",
+            BinOp::Lt => "<",
+            BinOp::Le => "<=",
+            BinOp::Gt => ">",
+            BinOp::Ge => ">=",
+        }
+    }
+
+    /// Apply the operator to words of the width of the operands in bits (8
+    /// for `u8`), as the generated code does: arithmetic wraps around at that
+    /// width, shifting by the width or more gives 0, and comparisons are
+    /// unsigned.
+    pub fn apply(self, a: u64, b: u64, bits: u32) -> u64 {
+        let mask = u64::MAX >> (64 - bits.clamp(1, 64));
+        let (a, b) = (a & mask, b & mask);
+        let shift = |f: fn(u64, u32) -> Option<u64>| {
+            u32::try_from(b)
+                .ok()
+                .filter(|n| *n < bits)
+                .and_then(|n| f(a, n))
+                .unwrap_or(0)
+        };
+        let word = match self {
+            BinOp::Add => a.wrapping_add(b),
+            BinOp::Sub => a.wrapping_sub(b),
+            BinOp::Shl => shift(u64::checked_shl),
+            BinOp::Shr => shift(u64::checked_shr),
+            BinOp::BitAnd => a & b,
+            BinOp::BitOr => a | b,
+            BinOp::BitXor => a ^ b,
+            BinOp::Lt => (a < b) as u64,
+            BinOp::Le => (a <= b) as u64,
+            BinOp::Gt => (a > b) as u64,
+            BinOp::Ge => (a >= b) as u64,
+        };
+        word & mask
+    }
+}
+
+impl Expr {
+    /// All lvalues in the expression.
+    pub fn lvalues(&self) -> Vec<&[String]> {
+        match self {
+            Expr::Int(_) | Expr::Bool(_) => Vec::new(),
+            Expr::LVal(lv) => vec![lv],
+            Expr::Not(e) => e.lvalues(),
+            Expr::Eq(a, b) | Expr::NotEq(a, b) | Expr::Binary(_, a, b) => {
+                let mut lvs = a.lvalues();
+                lvs.extend(b.lvalues());
+                lvs
+            }
+            Expr::In(a, members) => {
+                let mut lvs = a.lvalues();
+                lvs.extend(
+                    members
+                        .iter()
+                        .flat_map(Member::exprs)
+                        .flat_map(Expr::lvalues),
+                );
+                lvs
+            }
+            Expr::And(es) | Expr::Or(es) => es.iter().flat_map(Expr::lvalues).collect(),
+            Expr::Cond(c, a, b) => {
+                let mut lvs = c.lvalues();
+                lvs.extend(a.lvalues());
+                lvs.extend(b.lvalues());
+                lvs
+            }
+        }
+    }
+
+    /// Map all lvalues in the expression.
+    pub fn map(self, f: &impl Fn(Vec<String>) -> Vec<String>) -> Self {
+        let map = |e: Box<Expr>| Box::new(e.map(f));
+        match self {
+            Expr::Int(_) | Expr::Bool(_) => self,
+            Expr::LVal(lv) => Expr::LVal(f(lv)),
+            Expr::Not(e) => Expr::Not(map(e)),
+            Expr::Eq(a, b) => Expr::Eq(map(a), map(b)),
+            Expr::NotEq(a, b) => Expr::NotEq(map(a), map(b)),
+            Expr::In(a, members) => {
+                Expr::In(map(a), members.into_iter().map(|m| m.map(f)).collect())
+            }
+            Expr::And(es) => Expr::And(es.into_iter().map(|e| e.map(f)).collect()),
+            Expr::Or(es) => Expr::Or(es.into_iter().map(|e| e.map(f)).collect()),
+            Expr::Binary(op, a, b) => Expr::Binary(op, map(a), map(b)),
+            Expr::Cond(c, a, b) => Expr::Cond(map(c), map(a), map(b)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Case {
+    pub tunnel: Option<String>,
+    pub condition: Expr,
+    pub value: Expr,
+}
+
+#[derive(Debug, Clone)]
+pub enum Source {
+    Switch(Vec<Case>),
+    Expr { tunnel: Option<String>, expr: Expr },
+}
+
+impl Source {
+    pub fn lvalues(&self) -> Vec<&[String]> {
+        match self {
+            Source::Switch(cases) => cases
+                .iter()
+                .flat_map(|c| {
+                    let mut lvs = c.condition.lvalues();
+                    lvs.extend(c.value.lvalues());
+                    lvs
+                })
+                .collect(),
+            Source::Expr { expr, .. } => expr.lvalues(),
+        }
+    }
+
+    /// Map all lvalues in the conditions and values.
+    pub fn map(self, f: &impl Fn(Vec<String>) -> Vec<String>) -> Self {
+        match self {
+            Source::Switch(cases) => Source::Switch(
+                cases
+                    .into_iter()
+                    .map(|c| Case {
+                        tunnel: c.tunnel,
+                        condition: c.condition.map(f),
+                        value: c.value.map(f),
+                    })
+                    .collect(),
+            ),
+            Source::Expr { tunnel, expr } => Source::Expr {
+                tunnel,
+                expr: expr.map(f),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Dest {
+    pub tunnel: Option<String>,
+    pub dest: Vec<String>,
+    /// this destination is stage field or device input
+    pub is_stage_field: bool,
+    /// line of the destination in the source, which is in a `@set_input` or
+    /// `@set_stage` directive for some destinations
+    pub line: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct SignalDef {
+    pub name: String,
+    pub typ: String,
+    /// line of the definition in the source
+    pub line: usize,
+    pub source: Source,
+    pub destinations: Vec<Dest>,
+    pub stage_index: Option<usize>,
+    pub delay: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StageField {
+    pub name: String,
+    pub typ: String,
+    pub default: Expr,
+}
+
+#[derive(Debug, Clone)]
+pub struct StageDef {
+    pub name: String,
+    pub short_name: String,
+    pub fields: Vec<StageField>,
+}
+
+/// A parsed HCL file.
+#[derive(Debug, Clone, Default)]
+pub struct HclFile {
+    /// Path of the hardware module, e.g. `crate::architectures::hardware_pipe`.
+    pub hardware: String,
+    pub program_counter: Vec<String>,
+    pub termination: Vec<String>,
+    /// (cur, pre)
+    pub stage_alias: Vec<(String, String)>,
+    /// Titles of the stage dividers.
+    pub stage_decls: Vec<String>,
+    pub stages: Vec<StageDef>,
+    pub intermediate_signals: Vec<SignalDef>,
+    /// `@count(name, expr)` directives, as (name, expr, line).
+    pub counters: Vec<(String, Expr, usize)>,
+    /// `@assert(expr, "message")` directives, as (expr, message, line).
+    pub assertions: Vec<(Expr, String, usize)>,
+    /// The template of `@diagram("path")`, see [`crate::framework::Diagram`].
+    pub diagram: Option<String>,
+}
+
+/// Value of a constant that the HCL of the built-in architectures can use.
+pub fn constant(name: &str) -> Option<u64> {
+    let code = |name_of: fn(u8) -> &'static str, n: u8| (name_of(n) == name).then_some(n as u64);
+    (0..=0xf)
+        .find_map(|n| code(inst_code::name_of, n))
+        .or_else(|| (0..=0xf).find_map(|n| code(reg_code::name_of, n)))
+        .or_else(|| (0..=0xf).find_map(|n| code(op_code::name_of, n)))
+        .or(match name {
+            "Aok" => Some(Stat::Aok as u64),
+            "Bub" => Some(Stat::Bub as u64),
+            "Hlt" => Some(Stat::Hlt as u64),
+            "Adr" => Some(Stat::Adr as u64),
+            "Ins" => Some(Stat::Ins as u64),
+            _ => None,
+        })
+}
+
+/// Value of a constant expression, such as the default of a stage field.
+fn eval_const(expr: &Expr) -> Option<u64> {
+    match expr {
+        Expr::Int(v) => Some(*v),
+        Expr::Bool(b) => Some(*b as u64),
+        Expr::LVal(lv) if lv.len() == 1 => constant(&lv[0]),
+        _ => None,
+    }
+}
+
+fn parse_int(s: &str) -> anyhow::Result<u64> {
+    let s = ["u8", "u64", "i64", "usize"]
+        .iter()
+        .find_map(|suffix| s.strip_suffix(suffix))
+        .unwrap_or(s)
+        .replace('_', "");
+    let r = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    r.with_context(|| format!("invalid integer: {s}"))
+}
+
+fn parse_lvalue(pair: Pair<Rule>) -> Vec<String> {
+    pair.into_inner().map(|p| p.as_str().to_string()).collect()
+}
+
+/// `#[name(arg)]` attributes, as (name, arg).
+fn parse_attrs<'a>(
+    pairs: &mut std::iter::Peekable<impl Iterator<Item = Pair<'a, Rule>>>,
+) -> Vec<(String, String)> {
+    let mut attrs = Vec::new();
+    while let Some(p) = pairs.next_if(|p| p.as_rule() == Rule::attr) {
+        let mut inner = p.into_inner();
+        let name = inner.next().unwrap().as_str().to_string();
+        let arg = inner
+            .next()
+            .map(|a| a.as_str().trim().to_string())
+            .unwrap_or_default();
+        attrs.push((name, arg));
+    }
+    attrs
+}
+
+fn find_attr(attrs: &[(String, String)], name: &str) -> Option<String> {
+    attrs
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, arg)| arg.clone())
+}
+
+fn parse_expr(pair: Pair<Rule>) -> anyhow::Result<Expr> {
+    Ok(match pair.as_rule() {
+        Rule::expr => {
+            let mut inner = pair.into_inner();
+            let cond = parse_expr(inner.next().unwrap())?;
+            match (inner.next(), inner.next()) {
+                (Some(a), Some(b)) => Expr::Cond(
+                    Box::new(cond),
+                    Box::new(parse_expr(a)?),
+                    Box::new(parse_expr(b)?),
+                ),
+                _ => cond,
+            }
+        }
+        Rule::lor | Rule::land => {
+            let is_or = pair.as_rule() == Rule::lor;
+            let mut es = pair
+                .into_inner()
+                .map(parse_expr)
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            if es.len() == 1 {
+                es.pop().unwrap()
+            } else if is_or {
+                Expr::Or(es)
+            } else {
+                Expr::And(es)
+            }
+        }
+        Rule::rel => {
+            let line = pair.line_col().0;
+            let mut inner = pair.into_inner();
+            let lhs = parse_expr(inner.next().unwrap())?;
+            match inner.next() {
+                None => lhs,
+                Some(op) if op.as_rule() == Rule::rel_op => {
+                    let rhs = Box::new(parse_expr(inner.next().unwrap())?);
+                    match op.as_str() {
+                        "==" => Expr::Eq(Box::new(lhs), rhs),
+                        "!=" => Expr::NotEq(Box::new(lhs), rhs),
+                        op => Expr::Binary(BinOp::parse(op), Box::new(lhs), rhs),
+                    }
+                }
+                Some(_) => Expr::In(Box::new(lhs), parse_members(line, inner)?),
+            }
+        }
+        Rule::bitor | Rule::bitxor | Rule::bitand | Rule::shift | Rule::sum => {
+            // left associative
+            let mut inner = pair.into_inner();
+            let mut e = parse_expr(inner.next().unwrap())?;
+            while let Some(op) = inner.next() {
+                let rhs = parse_expr(inner.next().unwrap())?;
+                e = Expr::Binary(BinOp::parse(op.as_str()), Box::new(e), Box::new(rhs));
+            }
+            e
+        }
+        Rule::unary => {
+            let mut inner = pair.into_inner().collect::<Vec<_>>();
+            let mut e = parse_expr(inner.pop().unwrap())?;
+            for _ in inner {
+                e = Expr::Not(Box::new(e));
+            }
+            e
+        }
+        Rule::primary => parse_expr(pair.into_inner().next().unwrap())?,
+        Rule::bool_lit => Expr::Bool(pair.as_str() == "true"),
+        Rule::int => Expr::Int(parse_int(pair.as_str())?),
+        Rule::lvalue => Expr::LVal(parse_lvalue(pair)),
+        rule => bail!("unexpected {:?}", rule),
+    })
+}
+
+fn parse_signal_def(pair: Pair<Rule>) -> anyhow::Result<SignalDef> {
+    let line = pair.line_col().0;
+    let mut inner = pair.into_inner().peekable();
+    let attrs = parse_attrs(&mut inner);
+    let delay = find_attr(&attrs, "delay")
+        .map(|d| parse_int(&d))
+        .transpose()?;
+    let typ = inner.next().unwrap().as_str().to_string();
+    let name = inner.next().unwrap().as_str().to_string();
+
+    let source = inner.next().unwrap().into_inner().next().unwrap();
+    let source = match source.as_rule() {
+        Rule::switch => Source::Switch(
+            source
+                .into_inner()
+                .map(|case| {
+                    let mut inner = case.into_inner().peekable();
+                    let attrs = parse_attrs(&mut inner);
+                    Ok(Case {
+                        tunnel: find_attr(&attrs, "tunnel"),
+                        condition: parse_expr(inner.next().unwrap())?,
+                        value: parse_expr(inner.next().unwrap())?,
+                    })
+                })
+                .collect::<anyhow::Result<_>>()?,
+        ),
+        _ => {
+            let mut inner = source.into_inner().peekable();
+            let attrs = parse_attrs(&mut inner);
+            Source::Expr {
+                tunnel: find_attr(&attrs, "tunnel"),
+                expr: parse_expr(inner.next().unwrap())?,
+            }
+        }
+    };
+
+    let destinations = inner
+        .next()
+        .map(|dests| {
+            dests
+                .into_inner()
+                .map(|dest| {
+                    let line = dest.line_col().0;
+                    let mut inner = dest.into_inner().peekable();
+                    let attrs = parse_attrs(&mut inner);
+                    Dest {
+                        tunnel: find_attr(&attrs, "tunnel"),
+                        dest: parse_lvalue(inner.next().unwrap()),
+                        is_stage_field: false,
+                        line,
+                    }
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok(SignalDef {
+        name,
+        typ,
+        line,
+        source,
+        destinations,
+        stage_index: None,
+        delay,
+    })
+}
+
+fn parse_stage_def(pair: Pair<Rule>) -> anyhow::Result<StageDef> {
+    let mut inner = pair.into_inner().peekable();
+    let _ = parse_attrs(&mut inner);
+    let name = inner.next().unwrap().as_str().to_string();
+    let short_name = inner.next().unwrap().as_str().to_string();
+    let fields = inner
+        .map(|field| {
+            let mut inner = field.into_inner().peekable();
+            let _ = parse_attrs(&mut inner);
+            Ok(StageField {
+                name: inner.next().unwrap().as_str().to_string(),
+                typ: inner.next().unwrap().as_str().to_string(),
+                default: parse_expr(inner.next().unwrap())?,
+            })
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok(StageDef {
+        name,
+        short_name,
+        fields,
+    })
+}
+
+/// `(unit, [(field, signal, line)])` of a `@set_input` or `@set_stage`
+/// directive.
+fn parse_component_inputs(pair: Pair<Rule>) -> (String, Vec<(String, String, usize)>) {
+    let mut inner = pair.into_inner();
+    let name = inner.next().unwrap().as_str().to_string();
+    let fields = inner
+        .map(|f| {
+            let line = f.line_col().0;
+            let mut f = f.into_inner();
+            let field = f.next().unwrap().as_str().to_string();
+            let signal = f.next().unwrap().as_str().to_string();
+            (field, signal, line)
+        })
+        .collect();
+    (name, fields)
+}
+
+impl HclFile {
+    /// Parse a file. The paths of `@import` and `@diagram` are relative to
+    /// the directory of the file, as in [`sim_macro::hcl!`].
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let src = std::fs::read_to_string(path)
+            .with_context(|| format!("can't read {}", path.display()))?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        Self::parse_in(&src, dir).with_context(|| format!("in {}", path.display()))
+    }
+
+    /// Parse source that is not read from a file, whose paths of `@import`
+    /// and `@diagram` are relative to the current directory.
+    pub fn parse(src: &str) -> anyhow::Result<Self> {
+        Self::parse_in(src, Path::new(""))
+    }
+
+    fn parse_in(src: &str, dir: &Path) -> anyhow::Result<Self> {
+        let file = HclParser::parse(Rule::file, src)?.next().unwrap();
+
+        let mut data = HclFile::default();
+        let mut set_inputs = Vec::new();
+        let mut set_stages = Vec::new();
+        let mut modules = Vec::new();
+        // (index of the next signal, number of stage dividers so far, instance)
+        let mut instances = Vec::new();
+        for pair in file.into_inner() {
+            match pair.as_rule() {
+                Rule::hardware => data.hardware = pair.into_inner().as_str().to_string(),
+                Rule::program_counter => {
+                    data.program_counter = parse_lvalue(pair.into_inner().next().unwrap())
+                }
+                Rule::termination => {
+                    data.termination = parse_lvalue(pair.into_inner().next().unwrap())
+                }
+                Rule::stage_alias => {
+                    for alias in pair.into_inner() {
+                        let mut inner = alias.into_inner();
+                        let pre = inner.next().unwrap().as_str().to_string();
+                        let cur = inner.next().unwrap().as_str().to_string();
+                        data.stage_alias.push((cur, pre));
+                    }
+                }
+                Rule::stage_decl => {
+                    let title = pair.into_inner().next().unwrap().into_inner();
+                    let title = title
+                        .map(|w| w.as_str().trim_matches('"').to_string())
+                        .collect::<Vec<_>>();
+                    data.stage_decls.push(title.join(" "));
+                }
+                Rule::set_input => set_inputs.push(parse_component_inputs(pair)),
+                Rule::set_stage => set_stages.push(parse_component_inputs(pair)),
+                Rule::count => {
+                    let line = pair.line_col().0;
+                    let mut inner = pair.into_inner();
+                    let name = inner.next().unwrap().as_str().to_string();
+                    data.counters
+                        .push((name, parse_expr(inner.next().unwrap())?, line));
+                }
+                Rule::assert => {
+                    let line = pair.line_col().0;
+                    let mut inner = pair.into_inner();
+                    let expr = parse_expr(inner.next().unwrap())?;
+                    let message = inner.next().unwrap().as_str().trim_matches('"');
+                    data.assertions.push((expr, message.to_string(), line));
+                }
+                Rule::instance => instances.push((
+                    data.intermediate_signals.len(),
+                    data.stage_decls.len(),
+                    module::parse_instance(pair),
+                )),
+                Rule::import => {
+                    let path = pair.into_inner().next().unwrap().as_str().trim_matches('"');
+                    let src = std::fs::read_to_string(dir.join(path))
+                        .with_context(|| format!("can't read {path}"))?;
+                    modules.extend(
+                        module::parse_module_file(&src).with_context(|| format!("in {path}"))?,
+                    );
+                }
+                Rule::diagram => {
+                    let line = pair.line_col().0;
+                    if data.diagram.is_some() {
+                        bail!("line {line}: an architecture has a single `@diagram`");
+                    }
+                    let path = pair.into_inner().next().unwrap().as_str().trim_matches('"');
+                    let src = std::fs::read_to_string(dir.join(path))
+                        .with_context(|| format!("can't read {path}"))?;
+                    data.diagram = Some(src);
+                }
+                Rule::module_def => modules.push(module::parse_module(pair)?),
+                Rule::stage_def => data.stages.push(parse_stage_def(pair)?),
+                Rule::signal_def => {
+                    let mut item = parse_signal_def(pair)?;
+                    if !data.stage_decls.is_empty() {
+                        item.stage_index = Some(data.stage_decls.len() - 1);
+                    }
+                    for dest in &mut item.destinations {
+                        if data.stage_alias.iter().any(|(cur, _)| cur == &dest.dest[0]) {
+                            dest.is_stage_field = true;
+                        }
+                    }
+                    data.intermediate_signals.push(item);
+                }
+                Rule::EOI => {}
+                rule => bail!("unexpected {:?}", rule),
+            }
+        }
+
+        if data.hardware.is_empty() {
+            bail!("missing #![hardware = ...]");
+        }
+        if data.program_counter.is_empty() {
+            bail!("missing #![program_counter = ...]");
+        }
+        if data.termination.is_empty() {
+            bail!("missing #![termination = ...]");
+        }
+
+        // expand the instances where they are, from the last one so that the
+        // positions of the others stay valid
+        let mut instance_names: Vec<(&str, &module::Module)> = Vec::new();
+        for (pos, n_decls, inst) in instances.iter().rev() {
+            let line = inst.line;
+            let Some(module) = modules.iter().find(|m| m.name == inst.module) else {
+                bail!("line {line}: undefined module `{}`", inst.module);
+            };
+            if instance_names.iter().any(|(name, _)| *name == inst.name) {
+                bail!("line {line}: instance `{}` is defined twice", inst.name);
+            }
+            if data
+                .stage_alias
+                .iter()
+                .any(|(cur, pre)| cur == &inst.name || pre == &inst.name)
+            {
+                bail!(
+                    "line {line}: instance `{}` has the name of a stage",
+                    inst.name
+                );
+            }
+            instance_names.push((&inst.name, module));
+
+            let (mut signals, inputs) = module.instantiate(inst)?;
+            for item in &mut signals {
+                item.stage_index = n_decls.checked_sub(1);
+                for dest in &mut item.destinations {
+                    if data.stage_alias.iter().any(|(cur, _)| cur == &dest.dest[0]) {
+                        dest.is_stage_field = true;
+                    }
+                }
+            }
+            data.intermediate_signals.splice(*pos..*pos, signals);
+            for (unit, fields, is_stage_field) in inputs {
+                if is_stage_field {
+                    set_stages.push((unit, fields));
+                } else {
+                    set_inputs.push((unit, fields));
+                }
+            }
+        }
+
+        // `fwd_a.val` reads the signal `val` of instance `fwd_a`
+        let mut lvalues = vec![data.program_counter.as_slice(), &data.termination];
+        for sig in &data.intermediate_signals {
+            lvalues.extend(sig.source.lvalues());
+        }
+        for expr in data.counters.iter().map(|(_, e, _)| e) {
+            lvalues.extend(expr.lvalues());
+        }
+        for expr in data.assertions.iter().map(|(e, _, _)| e) {
+            lvalues.extend(expr.lvalues());
+        }
+        for lv in lvalues {
+            let Some((inst, module)) = instance_names.iter().find(|(name, _)| *name == lv[0])
+            else {
+                continue;
+            };
+            if lv.len() != 2 || !module.outputs.contains(&lv[1]) {
+                bail!(
+                    "`{}` is not an output of instance `{inst}` of module `{}`",
+                    lv.join("."),
+                    module.name
+                );
+            }
+        }
+        let output = |lv: Vec<String>| {
+            if lv.len() == 2 && instance_names.iter().any(|(name, _)| *name == lv[0]) {
+                vec![module::instance_signal(&lv[0], &lv[1])]
+            } else {
+                lv
+            }
+        };
+        for sig in &mut data.intermediate_signals {
+            let source = std::mem::replace(&mut sig.source, Source::Switch(Vec::new()));
+            sig.source = source.map(&output);
+        }
+        for (_, expr, _) in &mut data.counters {
+            *expr = std::mem::replace(expr, Expr::Bool(false)).map(&output);
+        }
+        for (expr, _, _) in &mut data.assertions {
+            *expr = std::mem::replace(expr, Expr::Bool(false)).map(&output);
+        }
+        data.program_counter = output(std::mem::take(&mut data.program_counter));
+        data.termination = output(std::mem::take(&mut data.termination));
+
+        for (i, sig) in data.intermediate_signals.iter().enumerate() {
+            if data.intermediate_signals[..i]
+                .iter()
+                .any(|s| s.name == sig.name)
+            {
+                bail!("line {}: signal `{}` is defined twice", sig.line, sig.name);
+            }
+        }
+
+        for (set, is_stage_field) in [(set_inputs, false), (set_stages, true)] {
+            for (uname, fields) in set {
+                for (field, signal, line) in fields {
+                    let Some(sig) = data
+                        .intermediate_signals
+                        .iter_mut()
+                        .find(|s| s.name == signal)
+                    else {
+                        bail!("signal {signal} not found");
+                    };
+                    sig.destinations.push(Dest {
+                        tunnel: None,
+                        dest: vec![uname.clone(), field],
+                        is_stage_field,
+                        line,
+                    });
+                }
+            }
+        }
+
+        if let Some(src) = &data.diagram {
+            let signals = data.intermediate_signals.iter().map(|s| s.name.as_str());
+            crate::framework::Diagram::parse(src)
+                .and_then(|d| d.check(data.tunnels(), signals))
+                .context("in the template of `@diagram`")?;
+        }
+
+        Ok(data)
+    }
+
+    /// Names of the tunnels given by `#[tunnel(name)]`.
+    pub fn tunnels(&self) -> Vec<&str> {
+        let mut tunnels = Vec::new();
+        for sig in &self.intermediate_signals {
+            match &sig.source {
+                Source::Switch(cases) => {
+                    tunnels.extend(cases.iter().filter_map(|c| c.tunnel.as_deref()))
+                }
+                Source::Expr { tunnel, .. } => tunnels.extend(tunnel.as_deref()),
+            }
+            tunnels.extend(sig.destinations.iter().filter_map(|d| d.tunnel.as_deref()));
+        }
+        tunnels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hcl() {
+        let file = HclFile::parse(
+            r#"
+            #![hardware = crate::architectures::hardware_pipe]
+            #![program_counter = f_pc]
+            #![termination = prog_term]
+            #![stage_alias(F => f, D => d)]
+
+            define_stages! {
+                /// fetch stage
+                FetchStage f { pred_pc: u64 = 0 }
+                DecodeStage d { stat: Stat = Bub, icode: u8 = NOP }
+            }
+
+            :====: Fetch Stage :====:
+            u64 f_pc = F.pred_pc;
+            @set_input(imem, { pc: f_pc });
+            u8 f_icode = [
+                imem.error : NOP;
+                #[tunnel(icode)]
+                1 : imem.icode;
+            ] -> d.icode;
+            #[delay(2)]
+            bool need_valC = f_icode in { IRMOVQ, RMMOVQ } && !imem.error;
+
+            :====: Decode :====:
+            bool prog_term = D.stat in { Hlt, Adr, Ins };
+            @count(instructions, D.icode != NOP);
+            @assert(!(d.bubble && D.stat == Aok), "bubble with status AOK");
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(file.hardware, "crate::architectures::hardware_pipe");
+        assert_eq!(
+            file.stage_alias,
+            [("f".into(), "F".into()), ("d".into(), "D".into())]
+        );
+        assert_eq!(file.stage_decls, ["Fetch Stage", "Decode"]);
+        assert_eq!(
+            file.stages[1].fields[1].default,
+            Expr::LVal(vec!["NOP".into()])
+        );
+        let sigs = &file.intermediate_signals;
+        assert_eq!(sigs[0].destinations[0].dest, ["imem", "pc"]);
+        assert!(sigs[1].destinations[0].is_stage_field);
+        assert_eq!(sigs[2].delay, Some(2));
+        assert_eq!(sigs[3].stage_index, Some(1));
+        assert_eq!(file.assertions[0].1, "bubble with status AOK");
+        assert_eq!(file.assertions[0].0.lvalues().len(), 3);
+        assert_eq!(constant("IRMOVQ"), Some(3));
+        assert_eq!(constant("Bub"), Some(1));
+    }
+
+    #[test]
+    fn test_operators() {
+        let file = HclFile::parse(
+            r#"
+            #![hardware = crate::architectures::hardware_seq]
+            #![program_counter = pc]
+            #![termination = prog_term]
+            u64 pc = 0;
+            u8 op = 2 << 4 | 1;
+            u64 sum = pc + 8 - 1 -> imem.pc;
+            bool prog_term = pc < 0x10 && (pc & 0xff ^ 3) >= 1;
+            u64 next = prog_term ? pc : sum + 1;
+            "#,
+        )
+        .unwrap();
+        let sigs = &file.intermediate_signals;
+        let int = |v| Box::new(Expr::Int(v));
+        let pc = || Box::new(Expr::LVal(vec!["pc".into()]));
+        let Source::Expr { expr, .. } = &sigs[1].source else {
+            panic!("not an expression");
+        };
+        assert_eq!(
+            *expr,
+            Expr::Binary(
+                BinOp::BitOr,
+                Box::new(Expr::Binary(BinOp::Shl, int(2), int(4))),
+                int(1)
+            )
+        );
+        assert_eq!(eval_binary(expr), 0x21);
+        // `u8` arithmetic wraps around at 8 bits, as `HclArith`
+        assert_eq!(BinOp::Add.apply(100, 200, 8), 44);
+        assert_eq!(BinOp::Lt.apply(BinOp::Add.apply(100, 200, 8), 100, 8), 1);
+        assert_eq!(BinOp::Sub.apply(0, 1, 8), 0xff);
+        assert_eq!(BinOp::Shl.apply(1, 8, 8), 0);
+        assert_eq!(BinOp::Shl.apply(1, 8, 64), 0x100);
+        let Source::Expr { expr, .. } = &sigs[2].source else {
+            panic!("not an expression");
+        };
+        // `->` is not a subtraction
+        assert_eq!(sigs[2].destinations[0].dest, ["imem", "pc"]);
+        assert_eq!(
+            *expr,
+            Expr::Binary(
+                BinOp::Sub,
+                Box::new(Expr::Binary(BinOp::Add, pc(), int(8))),
+                int(1)
+            )
+        );
+        let Source::Expr { expr, .. } = &sigs[3].source else {
+            panic!("not an expression");
+        };
+        let Expr::And(es) = expr else {
+            panic!("not a conjunction");
+        };
+        assert_eq!(es[0], Expr::Binary(BinOp::Lt, pc(), int(0x10)));
+        let Source::Expr { expr, .. } = &sigs[4].source else {
+            panic!("not an expression");
+        };
+        assert!(matches!(expr, Expr::Cond(..)));
+        assert_eq!(expr.lvalues().len(), 3);
+    }
+
+    #[test]
+    fn test_in_sets() {
+        let src = r#"
+            #![hardware = crate::architectures::hardware_seq]
+            #![program_counter = pc]
+            #![termination = prog_term]
+            u64 pc = 0;
+            u8 icode = imem.icode;
+            bool prog_term = icode in { HALT, 0xc, NOP..=CMOVX, 0x7..=0x9, imem.ifun };
+            "#;
+        let file = HclFile::parse(src).unwrap();
+        let Source::Expr { expr, .. } = &file.intermediate_signals[2].source else {
+            panic!("not an expression");
+        };
+        let Expr::In(_, members) = expr else {
+            panic!("not an `in`");
+        };
+        let ranges = members.iter().map(Member::const_range).collect::<Vec<_>>();
+        assert_eq!(
+            ranges,
+            [
+                Some((0, 0)),
+                Some((0xc, 0xc)),
+                Some((1, 2)),
+                Some((7, 9)),
+                None
+            ]
+        );
+        assert_eq!(expr.lvalues().len(), 5);
+
+        let err = |from: &str, to: &str| {
+            HclFile::parse(&src.replace(from, to))
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            err("0x7..=0x9", "0x7..=0x9, 0x8"),
+            "line 7: `8` is covered by the members before it"
+        );
+        assert_eq!(
+            err("0x7..=0x9", "0x7..=0x9, 0x3..=0xb, 0x3..=0xb"),
+            "line 7: `3..=11` appears twice in `in { .. }`"
+        );
+        assert_eq!(
+            err("0x7..=0x9", "0x9..=0x7"),
+            "line 7: empty range `9..=7` in `in { .. }`"
+        );
+        assert_eq!(
+            err("0x7..=0x9", "0x7..0x9"),
+            "line 7: use an inclusive range `lo..=hi`"
+        );
+        assert_eq!(
+            err("0x7..=0x9", "0x7..=pc"),
+            "line 7: the bounds of `7..=pc` must be literals or constants"
+        );
+    }
+
+    fn eval_binary(expr: &Expr) -> u64 {
+        match expr {
+            Expr::Int(v) => *v,
+            Expr::Binary(op, a, b) => op.apply(eval_binary(a), eval_binary(b), 64),
+            _ => unreachable!(),
+        }
+    }
+}
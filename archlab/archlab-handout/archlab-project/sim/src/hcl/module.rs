@@ -0,0 +1,352 @@
+//! Modules of HCL, named blocks of signals instantiated by `@instance`.
+//!
+//! They are expanded into ordinary signals while parsing, the same way as
+//! [`sim_macro::hcl!`] does: signal `val` of instance `fwd_a` is named
+//! `fwd_a.val`, as it is read outside the module, and the parameters in the
+//! body are replaced by the lvalues bound to them.
+
+use anyhow::bail;
+use pest::{iterators::Pair, Parser};
+
+use super::{parse_component_inputs, parse_lvalue, parse_signal_def, HclParser, Rule, SignalDef};
+
+/// `(unit, [(field, signal, line)], is_stage)` of a `@set_input` or
+/// `@set_stage` directive.
+pub(super) type UnitInputs = (String, Vec<(String, String, usize)>, bool);
+
+pub(super) struct Module {
+    pub name: String,
+    pub params: Vec<String>,
+    pub outputs: Vec<String>,
+    pub signals: Vec<SignalDef>,
+    pub inputs: Vec<UnitInputs>,
+}
+
+/// `@instance(name = module, { param: lvalue, ... })`
+pub(super) struct Instance {
+    pub name: String,
+    pub module: String,
+    pub args: Vec<(String, Vec<String>)>,
+    pub line: usize,
+}
+
+fn idents(pair: Pair<Rule>) -> Vec<String> {
+    pair.into_inner().map(|p| p.as_str().to_string()).collect()
+}
+
+pub(super) fn parse_module(pair: Pair<Rule>) -> anyhow::Result<Module> {
+    let mut inner = pair.into_inner();
+    let _ = inner.next(); // `module`
+    let name = inner.next().unwrap().as_str().to_string();
+    let params = idents(inner.next().unwrap());
+    let outputs = idents(inner.next().unwrap());
+    let mut signals = Vec::new();
+    let mut inputs = Vec::new();
+    for item in inner {
+        match item.as_rule() {
+            Rule::signal_def => signals.push(parse_signal_def(item)?),
+            rule => {
+                let (unit, fields) = parse_component_inputs(item);
+                inputs.push((unit, fields, rule == Rule::set_stage));
+            }
+        }
+    }
+    let module = Module {
+        name,
+        params,
+        outputs,
+        signals,
+        inputs,
+    };
+    module.validate()?;
+    Ok(module)
+}
+
+/// Modules of a file given to `@import`.
+pub(super) fn parse_module_file(src: &str) -> anyhow::Result<Vec<Module>> {
+    let file = HclParser::parse(Rule::module_file, src)?.next().unwrap();
+    file.into_inner()
+        .filter(|p| p.as_rule() == Rule::module_def)
+        .map(parse_module)
+        .collect()
+}
+
+pub(super) fn parse_instance(pair: Pair<Rule>) -> Instance {
+    let line = pair.line_col().0;
+    let mut inner = pair.into_inner();
+    let name = inner.next().unwrap().as_str().to_string();
+    let module = inner.next().unwrap().as_str().to_string();
+    let args = inner
+        .map(|arg| {
+            let mut arg = arg.into_inner();
+            let param = arg.next().unwrap().as_str().to_string();
+            (param, parse_lvalue(arg.next().unwrap()))
+        })
+        .collect();
+    Instance {
+        name,
+        module,
+        args,
+        line,
+    }
+}
+
+impl Module {
+    fn is_signal(&self, name: &str) -> bool {
+        self.signals.iter().any(|s| s.name == name)
+    }
+
+    /// The body may only read its parameters and its own signals, besides
+    /// constants, unit ports and stage fields.
+    fn validate(&self) -> anyhow::Result<()> {
+        let check = |name: &str, line: usize| {
+            let is_constant = name.starts_with(|c: char| c.is_uppercase());
+            if !is_constant && !self.params.iter().any(|p| p == name) && !self.is_signal(name) {
+                bail!(
+                    "line {line}: `{name}` is neither a parameter nor a signal of module `{}`",
+                    self.name
+                );
+            }
+            Ok(())
+        };
+        for sig in &self.signals {
+            for lv in sig.source.lvalues() {
+                if lv.len() == 1 {
+                    check(&lv[0], sig.line)?;
+                }
+            }
+        }
+        for (_, fields, _) in &self.inputs {
+            for (_, signal, line) in fields {
+                check(signal, *line)?;
+            }
+        }
+        for output in &self.outputs {
+            if !self.is_signal(output) {
+                bail!(
+                    "output `{output}` is not a signal of module `{}`",
+                    self.name
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Signals and `@set_input`/`@set_stage` directives of `inst`, with the
+    /// signals renamed and the parameters replaced by their arguments.
+    pub fn instantiate(
+        &self,
+        inst: &Instance,
+    ) -> anyhow::Result<(Vec<SignalDef>, Vec<UnitInputs>)> {
+        let line = inst.line;
+        for (i, (param, _)) in inst.args.iter().enumerate() {
+            if !self.params.contains(param) {
+                bail!(
+                    "line {line}: module `{}` has no parameter `{param}`",
+                    self.name
+                );
+            }
+            if inst.args[..i].iter().any(|(p, _)| p == param) {
+                bail!("line {line}: parameter `{param}` is bound twice");
+            }
+        }
+        let missing = self
+            .params
+            .iter()
+            .filter(|p| inst.args.iter().all(|(a, _)| a != *p))
+            .map(|p| format!("`{p}`"))
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            bail!(
+                "line {line}: missing arguments of module `{}`: {}",
+                self.name,
+                missing.join(", ")
+            );
+        }
+
+        let rename = |lv: Vec<String>| -> Vec<String> {
+            let mut renamed = if let Some((_, arg)) = inst.args.iter().find(|(p, _)| p == &lv[0]) {
+                arg.clone()
+            } else if self.is_signal(&lv[0]) {
+                vec![instance_signal(&inst.name, &lv[0])]
+            } else {
+                return lv;
+            };
+            renamed.extend(lv.into_iter().skip(1));
+            renamed
+        };
+
+        let signals = self
+            .signals
+            .iter()
+            .map(|sig| {
+                let mut sig = sig.clone();
+                sig.name = instance_signal(&inst.name, &sig.name);
+                sig.source = sig.source.map(&rename);
+                for dest in &mut sig.destinations {
+                    dest.dest = rename(std::mem::take(&mut dest.dest));
+                }
+                sig
+            })
+            .collect();
+
+        let mut inputs = Vec::new();
+        for (unit, fields, is_stage) in &self.inputs {
+            let mut renamed = Vec::new();
+            for (field, signal, line) in fields {
+                let lv = rename(vec![signal.clone()]);
+                if lv.len() != 1 {
+                    bail!("line {line}: `{signal}` must be bound to a signal, since it's used in a directive");
+                }
+                renamed.push((field.clone(), lv[0].clone(), *line));
+            }
+            inputs.push((unit.clone(), renamed, *is_stage));
+        }
+        Ok((signals, inputs))
+    }
+}
+
+/// Name of signal `name` of instance `inst`, which no signal outside the
+/// modules can have.
+pub(super) fn instance_signal(inst: &str, name: &str) -> String {
+    format!("{inst}.{name}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Expr, HclFile, Source};
+
+    const SRC: &str = r#"
+        #![hardware = crate::architectures::hardware_pipe]
+        #![program_counter = pc]
+        #![termination = prog_term]
+        #![stage_alias(F => f, D => d)]
+
+        module forward(src, rval, e_dst, e_val) -> (val) {
+            bool hit = src == e_dst;
+            u64 val = [
+                hit : e_val;
+                src == D.rB : 0;
+                true : rval;
+            ];
+        }
+
+        module fetch(pc_in, next) -> (pc) {
+            u64 pc = pc_in;
+            @set_input(imem, { pc: pc });
+            u64 pred = alu.e -> next;
+        }
+
+        @instance(fe = fetch, { pc_in: F.pc, next: f.pc });
+        u64 pc = fe.pc;
+        :====: Decode :====:
+        u8 d_srcA = D.rA;
+        u8 e_dstE = RNONE;
+        u64 e_valE = 0;
+        @instance(fwd_a = forward, { src: d_srcA, rval: reg_file.valA, e_dst: e_dstE, e_val: e_valE });
+        bool prog_term = fwd_a.val == 0;
+        "#;
+
+    #[test]
+    fn test_modules() {
+        let file = HclFile::parse(SRC).unwrap();
+        let names = file
+            .intermediate_signals
+            .iter()
+            .map(|s| s.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "fe.pc",
+                "fe.pred",
+                "pc",
+                "d_srcA",
+                "e_dstE",
+                "e_valE",
+                "fwd_a.hit",
+                "fwd_a.val",
+                "prog_term"
+            ]
+        );
+        let sigs = &file.intermediate_signals;
+        assert_eq!(sigs[0].destinations[0].dest, ["imem", "pc"]);
+        assert!(!sigs[0].destinations[0].is_stage_field);
+        assert_eq!(sigs[1].destinations[0].dest, ["f", "pc"]);
+        assert!(sigs[1].destinations[0].is_stage_field);
+        assert_eq!(sigs[7].stage_index, Some(0));
+        let Source::Switch(cases) = &sigs[7].source else {
+            panic!("not a switch");
+        };
+        assert_eq!(cases[0].condition, Expr::LVal(vec!["fwd_a.hit".into()]));
+        assert_eq!(
+            cases[1].condition,
+            Expr::Eq(
+                Box::new(Expr::LVal(vec!["d_srcA".into()])),
+                Box::new(Expr::LVal(vec!["D".into(), "rB".into()]))
+            )
+        );
+        assert_eq!(
+            cases[2].value,
+            Expr::LVal(vec!["reg_file".into(), "valA".into()])
+        );
+        let Source::Expr { expr, .. } = &sigs[8].source else {
+            panic!("not an expression");
+        };
+        assert_eq!(expr.lvalues(), [["fwd_a.val".to_string()]]);
+
+        // modules can be imported from another file
+        let (modules, rest) = SRC.split_at(SRC.find("@instance(fe").unwrap());
+        let (header, modules) = modules.split_at(SRC.find("module forward").unwrap());
+        let path = std::env::temp_dir().join("y86_hcl_test_modules.hcl");
+        std::fs::write(&path, modules).unwrap();
+        let src = format!("{header}@import(\"{}\");\n{rest}", path.display());
+        let imported = HclFile::parse(&src).unwrap();
+        assert_eq!(imported.intermediate_signals.len(), names.len());
+
+        // relative to the directory of a loaded file
+        let main = path.with_file_name("y86_hcl_test_main.hcl");
+        std::fs::write(
+            &main,
+            format!("{header}@import(\"y86_hcl_test_modules.hcl\");\n{rest}"),
+        )
+        .unwrap();
+        let loaded = HclFile::load(&main).unwrap();
+        assert_eq!(loaded.intermediate_signals.len(), names.len());
+    }
+
+    #[test]
+    fn test_module_errors() {
+        let err = |from: &str, to: &str| {
+            HclFile::parse(&SRC.replace(from, to))
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            err("fwd_a.val == 0", "fwd_a.hit"),
+            "`fwd_a.hit` is not an output of instance `fwd_a` of module `forward`"
+        );
+        assert_eq!(
+            err("src == D.rB", "src == d_srcA"),
+            "line 9: `d_srcA` is neither a parameter nor a signal of module `forward`"
+        );
+        assert_eq!(
+            err("{ pc_in: F.pc, next: f.pc }", "{ pc_in: F.pc }"),
+            "line 22: missing arguments of module `fetch`: `next`"
+        );
+        assert_eq!(
+            err("fe = fetch", "fe = fetcher"),
+            "line 22: undefined module `fetcher`"
+        );
+        assert_eq!(
+            err("u64 pc = fe.pc;", "u64 pc = fe.pc;\nu64 pc = 0;"),
+            "line 24: signal `pc` is defined twice"
+        );
+
+        // `fe.pc` and `fe_pc` are different signals
+        let src = SRC.replace("u64 pc = fe.pc;", "u64 fe_pc = fe.pc;\nu64 pc = fe_pc;");
+        let file = HclFile::parse(&src).unwrap();
+        let names = file.intermediate_signals.iter().map(|s| s.name.as_str());
+        assert_eq!(names.filter(|n| n.starts_with("fe")).count(), 3);
+    }
+}
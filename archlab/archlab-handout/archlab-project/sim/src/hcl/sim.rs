@@ -0,0 +1,861 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::bail;
+
+use super::types::{constant_type, is_array};
+use super::{
+    check_types, constant, eval_const, BinOp, DynUnits, Expr, HclFile, HclValue, Member, Ports,
+    Source, TypeEnv,
+};
+use crate::framework::{
+    AssertionFailure, CpuSim, HardwareUnits, PerfCounters, PropOrder, PropOrderBuilder, Reflect,
+    Signal, SignalError, SignalKind, SignalValue, StageInfo, TimingModel,
+};
+use crate::isa::{ConditionCode, Stat};
+
+/// Mask a word to the width of its declared type.
+fn mask(typ: &str, word: u64) -> u64 {
+    match typ {
+        "bool" => (word != 0) as u64,
+        "u8" => word & 0xff,
+        _ => word,
+    }
+}
+
+/// Width in bits of the words of a type in arithmetic, see
+/// [`BinOp::apply`](super::BinOp::apply).
+fn bits(typ: &str) -> u32 {
+    match typ {
+        "bool" => 1,
+        "u8" => 8,
+        _ => 64,
+    }
+}
+
+/// Length of a byte array type, e.g. 9 for `[u8; 9]`.
+fn array_len(typ: &str) -> usize {
+    let typ: String = typ.split_whitespace().collect();
+    typ.strip_prefix("[u8;")
+        .and_then(|len| len.strip_suffix(']'))
+        .and_then(|len| len.parse().ok())
+        .unwrap_or(0)
+}
+
+/// The value of a word in its declared type, as the generated code reflects
+/// it.
+fn reflect_word(typ: &str, word: u64) -> SignalValue {
+    // stage fields may be declared as `crate::isa::Stat`
+    match typ.rsplit("::").next().unwrap_or(typ) {
+        "bool" => SignalValue::Bool(word != 0),
+        "u8" => SignalValue::U8(word as u8),
+        "Stat" => SignalValue::Stat(Stat::from_word(word)),
+        "ConditionCode" => SignalValue::ConditionCode(ConditionCode::from_word(word)),
+        _ => SignalValue::U64(word),
+    }
+}
+
+/// The word of `value` for a signal of type `typ`, converted like the
+/// generated code does with [`Reflect::from_value`].
+fn word_of(typ: &str, value: &SignalValue) -> Option<u64> {
+    match reflect_word(typ, 0) {
+        SignalValue::Bool(_) => bool::from_value(value).map(|v| v.to_word()),
+        SignalValue::U8(_) => u8::from_value(value).map(|v| v.to_word()),
+        SignalValue::Stat(_) => Stat::from_value(value).map(|v| v.to_word()),
+        SignalValue::ConditionCode(_) => ConditionCode::from_value(value).map(|v| v.to_word()),
+        _ => u64::from_value(value),
+    }
+}
+
+fn signal(name: impl Into<String>, kind: SignalKind, typ: &'static str, word: u64) -> Signal {
+    Signal {
+        name: name.into(),
+        kind,
+        typ,
+        value: reflect_word(typ, word),
+    }
+}
+
+/// A signal of a byte array type, zeros until it is set.
+fn array_signal(
+    name: impl Into<String>,
+    kind: SignalKind,
+    typ: &'static str,
+    bytes: Option<&Vec<u8>>,
+) -> Signal {
+    let mut bytes = bytes.cloned().unwrap_or_default();
+    bytes.resize(array_len(typ), 0);
+    Signal {
+        name: name.into(),
+        kind,
+        typ,
+        value: SignalValue::Bytes(bytes),
+    }
+}
+
+/// Stage registers of the interpreted architecture, with the `bubble` and
+/// `stall` inputs stored as ordinary fields.
+type StageRegs = Vec<BTreeMap<String, u64>>;
+
+/// Simulator of an architecture interpreted from HCL source. It behaves the
+/// same as [`crate::framework::PipeSim`] running the code generated by
+/// [`sim_macro::hcl!`] for the same source.
+pub struct HclSim<U> {
+    file: HclFile,
+    order: PropOrder,
+    steps: Vec<Step>,
+    timing: Arc<TimingModel>,
+    /// the names and types of the file, interned once, see [`intern`]
+    names: BTreeMap<String, &'static str>,
+    units: U,
+    types: TypeEnv,
+    ports: Ports,
+    inter: BTreeMap<String, u64>,
+    /// intermediate signals of byte array types
+    bytes: BTreeMap<String, Vec<u8>>,
+    cur_state: StageRegs,
+    nex_state: StageRegs,
+    defaults: StageRegs,
+    counters: PerfCounters,
+    /// tunnels fired in this cycle
+    tunnels: Vec<&'static str>,
+    cycle_count: u64,
+    terminate: bool,
+    tty_out: bool,
+}
+
+impl<U: DynUnits + HardwareUnits + std::fmt::Display> HclSim<U> {
+    /// Build a simulator from HCL source. `units` must come from the hardware
+    /// module named by `#![hardware = ...]`.
+    pub fn new(src: &str, units: U, tty_out: bool) -> anyhow::Result<Self> {
+        Self::with_file(HclFile::parse(src)?, units, tty_out)
+    }
+
+    /// Build a simulator from an HCL file, see [`HclFile::load`].
+    pub fn load(path: impl AsRef<Path>, units: U, tty_out: bool) -> anyhow::Result<Self> {
+        Self::with_file(HclFile::load(path)?, units, tty_out)
+    }
+
+    fn with_file(file: HclFile, units: U, tty_out: bool) -> anyhow::Result<Self> {
+        check_types(&file, &U::ports())?;
+
+        let mut defaults = StageRegs::new();
+        for stage in &file.stages {
+            let mut regs = BTreeMap::new();
+            for field in &stage.fields {
+                if is_array(&field.typ) {
+                    bail!(
+                        "field {}.{} of type `{}`: arrays can't be kept in stage registers",
+                        stage.short_name,
+                        field.name,
+                        field.typ
+                    );
+                }
+                let Some(v) = eval_const(&field.default) else {
+                    bail!(
+                        "default of {}.{} is not a constant",
+                        stage.short_name,
+                        field.name
+                    );
+                };
+                regs.insert(field.name.clone(), mask(&field.typ, v));
+            }
+            regs.insert("bubble".to_string(), 0);
+            regs.insert("stall".to_string(), 0);
+            defaults.push(regs);
+        }
+        for (cur, _) in &file.stage_alias {
+            if !file.stages.iter().any(|s| &s.short_name == cur) {
+                bail!("stage {cur} is not declared in define_stages!");
+            }
+        }
+
+        let order = {
+            let mut g = PropOrderBuilder::new();
+            U::hardware_setup(&mut g);
+            for step in graph_steps(&file) {
+                match step {
+                    GraphStep::Edge(from, to) => g.add_edge(from, to),
+                    GraphStep::Intermediate(name) => g.add_intermediate(name),
+                }
+            }
+            g.build()
+        };
+        let timing = {
+            let mut delays: Vec<(&str, u64)> = U::unit_delays();
+            for sig in &file.intermediate_signals {
+                if let Some(delay) = sig.delay {
+                    delays.push((&sig.name, delay));
+                }
+            }
+            Arc::new(TimingModel::new(&order, &delays))
+        };
+        let names = interned_names(&file);
+        let steps = order
+            .order
+            .iter()
+            .map(|item| {
+                if item.is_unit {
+                    Step::Unit(item.name)
+                } else {
+                    let i = file
+                        .intermediate_signals
+                        .iter()
+                        .position(|s| s.name == item.name)
+                        .unwrap();
+                    Step::Signal(i)
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            order,
+            steps,
+            timing,
+            names,
+            units,
+            types: TypeEnv::new(&file, &U::ports()),
+            ports: Ports::default(),
+            inter: file
+                .intermediate_signals
+                .iter()
+                .map(|s| (s.name.clone(), 0))
+                .collect(),
+            bytes: file
+                .intermediate_signals
+                .iter()
+                .filter(|s| is_array(&s.typ))
+                .map(|s| (s.name.clone(), vec![0; array_len(&s.typ)]))
+                .collect(),
+            cur_state: defaults.clone(),
+            nex_state: defaults.clone(),
+            defaults,
+            counters: PerfCounters::default(),
+            tunnels: Vec::new(),
+            cycle_count: 0,
+            terminate: false,
+            tty_out,
+            file,
+        })
+    }
+
+    /// Value of an lvalue in an expression, mapped the same way as in
+    /// [`sim_macro::hcl!`]: intermediate signals, fields of the previous
+    /// stage registers, unit outputs and constants. With `next_stage`, the
+    /// stage inputs computed in this cycle (e.g. `d.stall`) can be read too.
+    ///
+    /// The lvalues are resolved by [`check_types`] before running; anything
+    /// else reads as 0.
+    fn read(&self, lv: &[String], next_stage: bool) -> u64 {
+        if lv.len() == 1 {
+            return self
+                .inter
+                .get(&lv[0])
+                .copied()
+                .or_else(|| constant(&lv[0]))
+                .unwrap_or(0);
+        }
+        let key = lv[1..].join(".");
+        let field = |regs: &StageRegs, cur: &str| {
+            stage_index(&self.file, cur)
+                .and_then(|i| regs[i].get(&key))
+                .copied()
+                .unwrap_or(0)
+        };
+        if let Some((cur, _)) = self.file.stage_alias.iter().find(|(_, pre)| pre == &lv[0]) {
+            return field(&self.cur_state, cur);
+        }
+        if next_stage {
+            if let Some((cur, _)) = self.file.stage_alias.iter().find(|(cur, _)| cur == &lv[0]) {
+                return field(&self.nex_state, cur);
+            }
+        }
+        self.ports.words.get(&lv.join(".")).copied().unwrap_or(0)
+    }
+
+    /// Bytes of a signal or a port of a byte array type, the only
+    /// expression of such a type.
+    fn read_bytes(&self, expr: &Expr) -> Option<Vec<u8>> {
+        match expr {
+            Expr::LVal(lv) if lv.len() == 1 => self.bytes.get(&lv[0]).cloned(),
+            Expr::LVal(lv) => self.ports.bytes.get(&lv.join(".")).cloned(),
+            _ => None,
+        }
+    }
+
+    fn eval(&self, expr: &Expr, next_stage: bool) -> u64 {
+        match expr {
+            Expr::Int(v) => *v,
+            Expr::Bool(b) => *b as u64,
+            Expr::LVal(lv) => self.read(lv, next_stage),
+            Expr::Not(e) => (self.eval(e, next_stage) == 0) as u64,
+            Expr::Eq(a, b) => (self.eval(a, next_stage) == self.eval(b, next_stage)) as u64,
+            Expr::NotEq(a, b) => (self.eval(a, next_stage) != self.eval(b, next_stage)) as u64,
+            Expr::In(a, members) => {
+                let a = self.eval(a, next_stage);
+                members.iter().any(|m| match m {
+                    Member::One(v) => self.eval(v, next_stage) == a,
+                    Member::Range(lo, hi) => {
+                        (self.eval(lo, next_stage)..=self.eval(hi, next_stage)).contains(&a)
+                    }
+                }) as u64
+            }
+            Expr::And(es) => es.iter().all(|e| self.eval(e, next_stage) != 0) as u64,
+            Expr::Or(es) => es.iter().any(|e| self.eval(e, next_stage) != 0) as u64,
+            Expr::Binary(op, a, b) => {
+                let bits = self.bits_of(a).or_else(|| self.bits_of(b));
+                let (a, b) = (self.eval(a, next_stage), self.eval(b, next_stage));
+                op.apply(a, b, bits.unwrap_or(64))
+            }
+            Expr::Cond(c, a, b) => {
+                if self.eval(c, next_stage) != 0 {
+                    self.eval(a, next_stage)
+                } else {
+                    self.eval(b, next_stage)
+                }
+            }
+        }
+    }
+
+    /// Width in bits of the type of an expression, `None` for an integer
+    /// literal, which takes the type of the other operand as in Rust.
+    fn bits_of(&self, expr: &Expr) -> Option<u32> {
+        match expr {
+            Expr::Int(_) => None,
+            Expr::LVal(lv) => self.types.get(lv).or_else(|| constant_type(lv)).map(bits),
+            Expr::Binary(BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge, ..) => Some(1),
+            Expr::Binary(_, a, b) | Expr::Cond(_, a, b) => {
+                self.bits_of(a).or_else(|| self.bits_of(b))
+            }
+            Expr::Bool(_)
+            | Expr::Not(_)
+            | Expr::Eq(..)
+            | Expr::NotEq(..)
+            | Expr::In(..)
+            | Expr::And(_)
+            | Expr::Or(_) => Some(1),
+        }
+    }
+
+    fn update_signal(&mut self, index: usize) {
+        let sig = &self.file.intermediate_signals[index];
+        let (value, tunnel) = match &sig.source {
+            Source::Switch(cases) => {
+                match cases
+                    .iter()
+                    .find(|case| self.eval(&case.condition, false) != 0)
+                {
+                    Some(case) => (Some(&case.value), case.tunnel.as_ref()),
+                    None => (None, None),
+                }
+            }
+            Source::Expr { tunnel, expr } => (Some(expr), tunnel.as_ref()),
+        };
+        // as in the generated code, the tunnel of a destination fires when
+        // the value comes through a tunnel
+        if let Some(tunnel) = tunnel {
+            let dests = sig.destinations.iter().filter_map(|d| d.tunnel.as_ref());
+            for name in std::iter::once(tunnel).chain(dests) {
+                let name = self.names[name];
+                if !self.tunnels.contains(&name) {
+                    self.tunnels.push(name);
+                }
+            }
+        }
+        // like the generated code, a switch without a matching case keeps
+        // the value of the previous cycle
+        if is_array(&sig.typ) {
+            if let Some(bytes) = value.and_then(|expr| self.read_bytes(expr)) {
+                self.bytes.insert(sig.name.clone(), bytes);
+            }
+            let bytes = self.bytes.get(&sig.name).cloned().unwrap_or_default();
+            // arrays only drive unit inputs, see `new`
+            for dest in &sig.destinations {
+                self.ports.bytes.insert(dest.dest.join("."), bytes.clone());
+            }
+            return;
+        }
+        if let Some(expr) = value {
+            let value = self.eval(expr, false);
+            self.inter.insert(sig.name.clone(), mask(&sig.typ, value));
+        }
+        let value = self.inter[&sig.name];
+        for dest in &sig.destinations {
+            if dest.is_stage_field {
+                if let Some(i) = stage_index(&self.file, &dest.dest[0]) {
+                    self.nex_state[i].insert(dest.dest[1..].join("."), value);
+                }
+            } else {
+                self.ports.words.insert(dest.dest.join("."), value);
+            }
+        }
+    }
+
+    fn update(&mut self) {
+        self.tunnels.clear();
+        for i in 0..self.steps.len() {
+            match self.steps[i] {
+                Step::Unit(name) => self.units.run_dyn(name, &mut self.ports),
+                Step::Signal(index) => self.update_signal(index),
+            }
+        }
+    }
+
+    fn count_events(&mut self) {
+        self.counters.cycles += 1;
+        for (stage, regs) in self.file.stages.iter().zip(&self.nex_state) {
+            self.counters.count_stage(
+                self.names[&stage.short_name],
+                regs["bubble"] != 0,
+                regs["stall"] != 0,
+            );
+        }
+        for i in 0..self.file.counters.len() {
+            let (name, expr, _) = &self.file.counters[i];
+            let fired = self.eval(expr, true) != 0;
+            self.counters.count(self.names[name], fired as u64);
+        }
+    }
+
+    /// Check the `@assert` directives, and that no stage is bubbled and
+    /// stalled at the same time, at the end of a cycle.
+    fn check_assertions(&self) -> Result<(), AssertionFailure> {
+        for (stage, regs) in self.file.stages.iter().zip(&self.nex_state) {
+            if regs["bubble"] != 0 && regs["stall"] != 0 {
+                return Err(AssertionFailure::control_conflict(
+                    self.cycle_count,
+                    &stage.short_name,
+                ));
+            }
+        }
+        for (expr, message, _) in &self.file.assertions {
+            if self.eval(expr, true) != 0 {
+                continue;
+            }
+            // values of the signals in the condition (not the constants),
+            // each reported once
+            let mut values: Vec<(String, String)> = Vec::new();
+            for lv in expr.lvalues() {
+                let name = lv.join(".");
+                let is_constant = lv.len() == 1 && !self.inter.contains_key(&lv[0]);
+                if !is_constant && values.iter().all(|(n, _)| n != &name) {
+                    let typ = self.types.get(lv).unwrap_or("u64");
+                    values.push((name, reflect_word(typ, self.read(lv, true)).to_string()));
+                }
+            }
+            return Err(AssertionFailure {
+                cycle: self.cycle_count,
+                message: message.clone(),
+                values,
+            });
+        }
+        Ok(())
+    }
+}
+
+fn stage_index(file: &HclFile, short_name: &str) -> Option<usize> {
+    file.stages.iter().position(|s| s.short_name == short_name)
+}
+
+/// Names in the propagation order, the counters and the reflected signals
+/// are `&'static str`, so the names of an interpreted architecture are
+/// leaked, each distinct name only once. Only [`HclSim::new`] interns names;
+/// a running simulator looks them up in its own map.
+fn intern(s: &str) -> &'static str {
+    use std::sync::Mutex;
+    static NAMES: Mutex<BTreeSet<&'static str>> = Mutex::new(BTreeSet::new());
+    let mut names = NAMES.lock().unwrap();
+    match names.get(s) {
+        Some(n) => n,
+        None => {
+            let n = Box::leak(s.to_string().into_boxed_str());
+            names.insert(n);
+            n
+        }
+    }
+}
+
+/// The stage names, stage titles, counters, tunnels and types of a file.
+fn interned_names(file: &HclFile) -> BTreeMap<String, &'static str> {
+    let mut names: Vec<&str> = Vec::new();
+    for stage in &file.stages {
+        names.push(&stage.short_name);
+        names.extend(stage.fields.iter().map(|f| f.typ.as_str()));
+    }
+    names.extend(file.stage_decls.iter().map(String::as_str));
+    names.extend(file.counters.iter().map(|(name, _, _)| name.as_str()));
+    names.extend(file.tunnels());
+    names.extend(file.intermediate_signals.iter().map(|s| s.typ.as_str()));
+    names
+        .into_iter()
+        .map(|name| (name.to_string(), intern(name)))
+        .collect()
+}
+
+/// An item of the propagation order.
+#[derive(Debug, Clone, Copy)]
+enum Step {
+    Unit(&'static str),
+    /// index of the intermediate signal
+    Signal(usize),
+}
+
+enum GraphStep {
+    Edge(String, String),
+    Intermediate(&'static str),
+}
+
+/// Calls to the graph builder for the intermediate signals, in the same
+/// order as the code generated by [`sim_macro::hcl!`].
+fn graph_steps(file: &HclFile) -> Vec<GraphStep> {
+    let mut steps = Vec::new();
+    for sig in &file.intermediate_signals {
+        for lv in sig.source.lvalues() {
+            let is_inter =
+                lv.len() == 1 && file.intermediate_signals.iter().any(|s| s.name == lv[0]);
+            let is_port = lv.len() == 2 && file.stage_alias.iter().all(|(_, pre)| pre != &lv[0]);
+            if is_inter || is_port {
+                steps.push(GraphStep::Edge(lv.join("."), sig.name.clone()));
+            }
+        }
+        steps.push(GraphStep::Intermediate(intern(&sig.name)));
+        for dest in &sig.destinations {
+            if !dest.is_stage_field {
+                steps.push(GraphStep::Edge(sig.name.clone(), dest.dest.join(".")));
+            }
+        }
+    }
+    steps
+}
+
+impl<U: DynUnits + HardwareUnits + std::fmt::Display> CpuSim for HclSim<U> {
+    fn initiate_next_cycle(&mut self) {
+        for i in 0..self.cur_state.len() {
+            let new = &self.nex_state[i];
+            if new["bubble"] != 0 {
+                if new["stall"] != 0 {
+                    tracing::error!("bubble and stall at the same time");
+                }
+                for field in &self.file.stages[i].fields {
+                    self.cur_state[i].insert(field.name.clone(), self.defaults[i][&field.name]);
+                }
+            } else if new["stall"] == 0 {
+                for field in &self.file.stages[i].fields {
+                    self.cur_state[i].insert(field.name.clone(), new[&field.name]);
+                }
+            }
+        }
+    }
+
+    fn propagate_signals(&mut self) {
+        self.update();
+        self.cycle_count += 1;
+        self.count_events();
+
+        if self.read(&self.file.termination, false) != 0 {
+            self.terminate = true;
+        }
+    }
+
+    fn program_counter(&self) -> u64 {
+        self.read(&self.file.program_counter, false)
+    }
+
+    fn is_terminate(&self) -> bool {
+        self.terminate
+    }
+
+    fn cycle_count(&self) -> u64 {
+        self.cycle_count
+    }
+
+    fn cycle_cost(&self) -> u64 {
+        self.timing.cycle_cost()
+    }
+
+    fn timing(&self) -> &TimingModel {
+        &self.timing
+    }
+
+    fn counters(&self) -> &PerfCounters {
+        &self.counters
+    }
+
+    fn tunnels(&self) -> &[&'static str] {
+        &self.tunnels
+    }
+
+    fn diagram(&self) -> Option<&str> {
+        self.file.diagram.as_deref()
+    }
+
+    fn registers(&self) -> crate::isa::RegFile {
+        self.units.register_file()
+    }
+
+    fn get_stage_info(&self) -> Vec<StageInfo> {
+        let mut info = if self.file.stage_decls.is_empty() {
+            vec![StageInfo {
+                name: "(default)",
+                signals: vec![],
+            }]
+        } else {
+            self.file
+                .stage_decls
+                .iter()
+                .map(|name| StageInfo {
+                    name: self.names[name],
+                    signals: vec![],
+                })
+                .collect()
+        };
+        for sig in &self.file.intermediate_signals {
+            let kind = SignalKind::Intermediate;
+            let typ = self.names[&sig.typ];
+            let sig_info = match is_array(typ) {
+                true => array_signal(&sig.name, kind, typ, self.bytes.get(&sig.name)),
+                false => signal(&sig.name, kind, typ, self.inter[&sig.name]),
+            };
+            info[sig.stage_index.unwrap_or(0)].signals.push(sig_info);
+        }
+
+        // fields at the start of the cycle, and the stage controls computed
+        // in it, in the order of `PipeRegs::signals`
+        let mut regs = Vec::new();
+        for (i, stage) in self.file.stages.iter().enumerate() {
+            let name = &stage.short_name;
+            let alias = match self.file.stage_alias.iter().find(|(cur, _)| cur == name) {
+                Some((_, pre)) => pre,
+                None => name,
+            };
+            for field in &stage.fields {
+                let word = self.cur_state[i][&field.name];
+                let name = format!("{alias}.{}", field.name);
+                let typ = self.names[&field.typ];
+                regs.push(signal(name, SignalKind::StageField, typ, word));
+            }
+            for control in ["bubble", "stall"] {
+                let word = self.nex_state[i][control];
+                let name = format!("{name}.{control}");
+                regs.push(signal(name, SignalKind::StageControl, "bool", word));
+            }
+        }
+        info.push(StageInfo {
+            name: "Pipeline registers",
+            signals: regs,
+        });
+        let ports = U::ports();
+        let ports = ports
+            .iter()
+            .filter(|p| p.is_input)
+            .chain(ports.iter().filter(|p| !p.is_input));
+        let ports = ports
+            .map(|p| {
+                let name = format!("{}.{}", p.unit, p.name);
+                let kind = match p.is_input {
+                    true => SignalKind::UnitInput,
+                    false => SignalKind::UnitOutput,
+                };
+                if is_array(p.typ) {
+                    let bytes = self.ports.bytes.get(&name);
+                    return array_signal(name, kind, p.typ, bytes);
+                }
+                let word = self.ports.words.get(&name).copied().unwrap_or(0);
+                signal(name, kind, p.typ, word)
+            })
+            .chain(self.units.state_signals())
+            .collect();
+        info.push(StageInfo {
+            name: "Units",
+            signals: ports,
+        });
+        info
+    }
+
+    fn signal(&self, name: &str) -> Option<SignalValue> {
+        self.get_stage_info()
+            .into_iter()
+            .flat_map(|stage| stage.signals)
+            .find(|sig| sig.name == name)
+            .map(|sig| sig.value)
+    }
+
+    fn signal_names(&self) -> Vec<String> {
+        self.get_stage_info()
+            .into_iter()
+            .flat_map(|stage| stage.signals)
+            .map(|sig| sig.name)
+            .collect()
+    }
+
+    fn set_signal(&mut self, name: &str, value: SignalValue) -> Result<(), SignalError> {
+        let set = |typ: &'static str, dest: &mut u64| match word_of(typ, &value) {
+            Some(word) => {
+                *dest = word;
+                Ok(())
+            }
+            None => Err(SignalError::Type {
+                name: name.to_string(),
+                typ,
+                value: value.clone(),
+            }),
+        };
+        let bytes_of = |typ: &'static str| match &value {
+            SignalValue::Bytes(bytes) if bytes.len() == array_len(typ) => Ok(bytes.clone()),
+            _ => Err(SignalError::Type {
+                name: name.to_string(),
+                typ,
+                value: value.clone(),
+            }),
+        };
+        if let Some(sig) = self
+            .file
+            .intermediate_signals
+            .iter()
+            .find(|s| s.name == name)
+        {
+            let typ = self.names[&sig.typ];
+            if is_array(typ) {
+                self.bytes.insert(name.to_string(), bytes_of(typ)?);
+                return Ok(());
+            }
+            return set(typ, self.inter.entry(name.to_string()).or_default());
+        }
+        // `E.valA` is a field at the start of the cycle, `e.stall` an input
+        // computed in it, as in the generated code
+        if let Some((stage, field)) = name.split_once('.') {
+            let is_control = matches!(field, "bubble" | "stall");
+            let alias = self.file.stage_alias.iter().find(|(_, pre)| pre == stage);
+            let regs = match alias {
+                Some((cur, _)) if !is_control => Some((cur.as_str(), &mut self.cur_state)),
+                Some(_) => None,
+                None if is_control => Some((stage, &mut self.nex_state)),
+                None => Some((stage, &mut self.cur_state)),
+            };
+            if let Some((short, regs)) = regs {
+                if let Some(i) = self.file.stages.iter().position(|s| s.short_name == short) {
+                    let typ = match is_control {
+                        true => Some("bool"),
+                        false => self.file.stages[i]
+                            .fields
+                            .iter()
+                            .find(|f| f.name == field)
+                            .map(|f| self.names[&f.typ]),
+                    };
+                    if let Some(typ) = typ {
+                        return set(typ, regs[i].entry(field.to_string()).or_default());
+                    }
+                }
+            }
+        }
+        if let Some(port) = U::ports()
+            .iter()
+            .find(|p| format!("{}.{}", p.unit, p.name) == name)
+        {
+            if is_array(port.typ) {
+                self.ports
+                    .bytes
+                    .insert(name.to_string(), bytes_of(port.typ)?);
+                return Ok(());
+            }
+            return set(
+                port.typ,
+                self.ports.words.entry(name.to_string()).or_default(),
+            );
+        }
+        self.units.set_state(name, &value)
+    }
+
+    fn step(&mut self) -> Result<(), AssertionFailure> {
+        use binutils::clap::builder::styling::*;
+        let title_style = Style::new().bold();
+
+        if self.tty_out {
+            println!(
+                "{title_style}{summary:=^80}{title_style:#}",
+                summary = format!(
+                    " [Cycle {} (*{})] ",
+                    self.cycle_count() + 1,
+                    self.cycle_cost()
+                ),
+            );
+        }
+
+        self.propagate_signals();
+        let checked = self.check_assertions();
+
+        if self.tty_out {
+            println!(
+                "{title_style}PC = {:#x}{title_style:#}",
+                self.program_counter()
+            );
+            println!("{}", self.units);
+            for stage in self.get_stage_info() {
+                tracing::info!("{:-^70}", format!(" {} ", stage.name));
+                for sig in stage.signals {
+                    tracing::info!("{:<10} = {}", sig.name, sig.value);
+                }
+            }
+        }
+
+        // the state of the failing cycle is printed above
+        checked?;
+        if self.is_terminate() {
+            if self.tty_out {
+                println!("terminate!");
+            }
+        } else {
+            self.initiate_next_cycle();
+        }
+        Ok(())
+    }
+
+    fn proporder(&self) -> &PropOrder {
+        &self.order
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framework::PipeSim;
+    use crate::hcl::test_arch::{count_down, interpreted, Arch};
+    use crate::hcl::test_hardware::Units;
+
+    #[test]
+    fn test_matches_compiled() {
+        let mut compiled = PipeSim::<Arch>::new(count_down(), false);
+        let mut interp = HclSim::new(&interpreted(), Units::init(count_down()), false).unwrap();
+        assert_eq!(interp.cycle_cost(), compiled.cycle_cost());
+        // the module of the test arch is expanded the same way
+        assert!(compiled.signal_names().contains(&"op_a.val".to_string()));
+        assert_eq!(interp.signal_names(), compiled.signal_names());
+
+        while !compiled.is_terminate() {
+            assert!(compiled.cycle_count() < 100, "the program doesn't halt");
+            compiled.step().unwrap();
+            interp.step().unwrap();
+            let cycle = compiled.cycle_count();
+            assert_eq!(interp.cycle_count(), cycle);
+            assert_eq!(
+                interp.program_counter(),
+                compiled.program_counter(),
+                "cycle {cycle}"
+            );
+            assert_eq!(
+                format!("{:?}", interp.get_stage_info()),
+                format!("{:?}", compiled.get_stage_info()),
+                "cycle {cycle}"
+            );
+            assert_eq!(interp.tunnels(), compiled.tunnels(), "cycle {cycle}");
+            assert_eq!(
+                interp.counters().to_string(),
+                compiled.counters().to_string(),
+                "cycle {cycle}"
+            );
+            assert_eq!(interp.registers(), compiled.registers(), "cycle {cycle}");
+        }
+        assert!(interp.is_terminate());
+    }
+}
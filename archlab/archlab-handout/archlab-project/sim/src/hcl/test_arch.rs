@@ -0,0 +1,308 @@
+// A two-stage pipeline on the hardware of `test_hardware.rs`, compiled here
+// and interpreted from the same source by the tests. E resolves the branch
+// that F predicted with `bp` in the previous cycle; a misprediction bubbles
+// the instruction fetched on the wrong path. E also accesses the memory, and
+// `F.retired` tells `dmem` that the instruction in E retired last cycle.
+crate::define_stages! {
+    FetchStage f {
+        pc: u64 = 0, retired: bool = false
+    }
+    ExecuteStage e {
+        stat: Stat = Bub, icode: u8 = NOP, ifun: u8 = 0,
+        rA: u8 = RNONE, rB: u8 = RNONE,
+        valC: u64 = 0, valP: u64 = 0,
+        pc: u64 = 0, pred: bool = false
+    }
+}
+
+sim_macro::hcl! {
+
+#![hardware = crate::hcl::test_hardware]
+#![program_counter = f_pc]
+#![termination = prog_term]
+#![stage_alias(F => f, E => e)]
+
+use Stat::*;
+
+:====: Fetch :====:
+
+u64 f_pc = F.pc -> e.pc;
+
+@set_input(imem, {
+    pc: f_pc
+});
+
+u8 f_icode = [
+    imem.error : NOP;
+    1 : imem.icode;
+] -> e.icode;
+
+u8 f_ifun = [
+    imem.error : 0;
+    1 : imem.ifun;
+] -> e.ifun;
+
+Stat f_stat = [
+    imem.error : Adr;
+    !(f_icode in { HALT, NOP, IRMOVQ, RMMOVQ, MRMOVQ, OPQ, JX }) : Ins;
+    f_icode == HALT : Hlt;
+    1 : Aok;
+] -> e.stat;
+
+u8 f_rA = imem.rA -> e.rA;
+u8 f_rB = imem.rB -> e.rB;
+u64 f_valC = imem.valC -> e.valC;
+u64 f_valP = imem.valP -> e.valP;
+
+bool f_is_jxx = f_icode == JX && f_ifun != 0;
+
+@set_input(bp, {
+    pc: f_pc,
+    is_jxx: f_is_jxx,
+    upd: e_is_jxx,
+    upd_pc: e_pc,
+    upd_taken: e_cnd,
+    upd_mispred: e_mispred,
+});
+
+// `jmp` is always taken
+bool f_pred = [
+    f_icode == JX && f_ifun == 0 : true;
+    1 : bp.taken;
+] -> e.pred;
+
+u64 f_pred_pc = [
+    f_pred : f_valC;
+    1 : f_valP;
+];
+
+:====: Execute :====:
+
+u64 e_pc = E.pc;
+
+@set_input(reg_read, {
+    srcA: e_srcA,
+    srcB: e_srcB,
+});
+
+u8 e_srcA = E.rA;
+u8 e_srcB = E.rB;
+
+// the constant of `irmovq` and of the memory accesses, or a register
+module operand(imm, reg) -> (val) {
+    u64 val = [
+        E.icode in { IRMOVQ, RMMOVQ, MRMOVQ } : imm;
+        1 : reg;
+    ];
+}
+
+@instance(op_a = operand, { imm: E.valC, reg: reg_read.valA });
+u64 aluA = op_a.val;
+
+u64 aluB = [
+    E.icode == IRMOVQ : 0;
+    1 : reg_read.valB;
+];
+
+u8 alufun = [
+    E.icode == OPQ : E.ifun;
+    1 : ADD;
+];
+
+@set_input(alu, {
+    a: aluA,
+    b: aluB,
+    fun: alufun,
+});
+
+u64 e_valE = alu.e;
+
+bool set_cc = E.icode == OPQ;
+
+@set_input(reg_cc, {
+    ifun: e_ifun,
+    set_cc: set_cc,
+    a: aluA,
+    b: aluB,
+    e: e_valE,
+    opfun: alufun,
+});
+
+u8 e_ifun = E.ifun;
+
+bool e_cnd = E.icode == JX && reg_cc.cnd;
+bool e_is_jxx = E.icode == JX && E.ifun != 0;
+bool e_mispred = e_is_jxx && e_cnd != E.pred;
+
+u64 e_valA = reg_read.valA;
+bool e_mem_read = E.icode == MRMOVQ;
+bool e_mem_write = E.icode == RMMOVQ;
+bool prev_retired = F.retired;
+
+@set_input(dmem, {
+    addr: e_valE,
+    datain: e_valA,
+    read: e_mem_read,
+    write: e_mem_write,
+    retire: prev_retired,
+});
+
+Stat e_stat = [
+    dmem.error : Adr;
+    1 : E.stat;
+];
+
+bool e_retired = e_stat == Aok -> f.retired;
+
+u8 e_dstE = [
+    E.icode in { IRMOVQ, OPQ } : E.rB;
+    1 : RNONE;
+];
+
+u8 e_dstM = [
+    e_mem_read && !dmem.error : E.rA;
+    1 : RNONE;
+];
+
+u64 e_valM = dmem.dataout;
+
+@set_input(reg_write, {
+    dstE: e_dstE,
+    valE: e_valE,
+    dstM: e_dstM,
+    valM: e_valM,
+});
+
+// resume at the other path of a mispredicted branch
+u64 f_next_pc = [
+    e_mispred && E.pred : E.valP;
+    e_mispred : E.valC;
+    1 : f_pred_pc;
+] -> f.pc;
+
+bool e_bubble = e_mispred -> e.bubble;
+
+bool prog_term = e_stat in { Hlt, Adr, Ins };
+
+@count(instructions, E.stat == Aok);
+@count(branches, e_is_jxx);
+@count(mispredict, e_mispred);
+}
+
+use crate::framework::{MemData, MEM_SIZE};
+
+/// The `define_stages!` declarations and the body of `hcl!` above, as an
+/// interpreted file.
+pub(crate) fn interpreted() -> String {
+    let src = include_str!("test_arch.rs");
+    let (stages, rest) = src.split_once("sim_macro::hcl! {").unwrap();
+    // the end of `hcl!`, not of a module
+    let end = rest.find("\n}\n\nuse ").unwrap();
+    stages.replace("crate::define_stages!", "define_stages!") + &rest[..end]
+}
+
+/// ```text
+///     irmovq $3, %rsi
+///     irmovq $1, %r9
+/// loop:
+///     subq %r9, %rsi
+///     jne loop
+///     halt
+/// ```
+pub(crate) fn count_down() -> MemData {
+    let mut bin = [0; MEM_SIZE];
+    let mut put = |at: usize, bytes: &[u8]| bin[at..at + bytes.len()].copy_from_slice(bytes);
+    put(0x00, &[0x30, 0xf6, 3]);
+    put(0x0a, &[0x30, 0xf9, 1]);
+    put(0x14, &[0x61, 0x96]);
+    put(0x16, &[0x74, 0x14]);
+    put(0x1f, &[0x00]);
+    MemData::init(bin)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framework::{CpuSim, HardwareUnits, PipeSim, SignalValue};
+    use crate::hcl::{test_hardware::Units, HclSim};
+    use crate::isa::reg_code::{R9, RAX, RDX, RSI};
+
+    fn run(sim: &mut dyn CpuSim) {
+        while !sim.is_terminate() {
+            sim.step().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_branch_prediction() {
+        let mut compiled = PipeSim::<Arch>::new(count_down(), false);
+        let units = Units::init(count_down());
+        let mut interpreted = HclSim::new(&interpreted(), units, false).unwrap();
+        for sim in [&mut compiled as &mut dyn CpuSim, &mut interpreted] {
+            run(sim);
+            assert_eq!(sim.registers()[RSI as usize], 0);
+            assert_eq!(sim.registers()[R9 as usize], 1);
+
+            // the 2-bit counter starts weakly taken and misses the loop exit
+            let counters = sim.counters();
+            let stats = counters.predictions().unwrap();
+            assert_eq!((stats.predictions, stats.mispredictions), (3, 1));
+            assert_eq!(counters.instructions(), Some(8));
+            // the halt, a bubble at the start and one for the misprediction
+            assert_eq!(counters.cycles, 11);
+            assert!(counters
+                .to_string()
+                .contains(&format!("{:<16}{:>12}", "branch accuracy", "66.67%")));
+        }
+    }
+
+    /// ```text
+    ///     irmovq $0x10008, %rbx
+    ///     irmovq $1, %rcx
+    ///     mrmovq (%rbx), %rax     # the cycle counter
+    ///     rmmovq %rax, 0x100
+    ///     mrmovq 0x100, %rdx
+    ///     halt
+    /// ```
+    #[test]
+    fn test_memory() {
+        let mut bin = [0; MEM_SIZE];
+        let mut put = |at: usize, bytes: &[u8]| bin[at..at + bytes.len()].copy_from_slice(bytes);
+        put(0x00, &[0x30, 0xf3, 0x08, 0x00, 0x01]);
+        put(0x0a, &[0x30, 0xf1, 1]);
+        put(0x14, &[0x50, 0x03]);
+        put(0x1e, &[0x40, 0x0f, 0x00, 0x01]);
+        put(0x28, &[0x50, 0x2f, 0x00, 0x01]);
+        put(0x32, &[0x00]);
+
+        let mut compiled = PipeSim::<Arch>::new(MemData::init(bin), false);
+        let units = Units::init(MemData::init(bin));
+        let mut interpreted = HclSim::new(&interpreted(), units, false).unwrap();
+        for sim in [&mut compiled as &mut dyn CpuSim, &mut interpreted] {
+            run(sim);
+            // two instructions retired before the counter is read, as in the
+            // ISA simulator
+            assert_eq!(sim.registers()[RAX as usize], 2);
+            assert_eq!(sim.registers()[RDX as usize], 2);
+        }
+    }
+
+    #[test]
+    fn test_unit_states() {
+        let mut compiled = PipeSim::<Arch>::new(count_down(), false);
+        let units = Units::init(count_down());
+        let mut interpreted = HclSim::new(&interpreted(), units, false).unwrap();
+        for sim in [&mut compiled as &mut dyn CpuSim, &mut interpreted] {
+            run(sim);
+            assert_eq!(sim.signal("reg_read.r9"), Some(SignalValue::U64(1)));
+            // `subq` counted down to 0
+            let Some(SignalValue::ConditionCode(cc)) = sim.signal("reg_cc.inner_cc") else {
+                panic!("the condition codes are not a signal");
+            };
+            assert!(cc.zf);
+
+            sim.set_signal("reg_read.rsi", SignalValue::U64(7)).unwrap();
+            assert_eq!(sim.registers()[RSI as usize], 7);
+        }
+    }
+}
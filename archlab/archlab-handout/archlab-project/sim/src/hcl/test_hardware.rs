@@ -0,0 +1,158 @@
+//! Hardware of the test architecture in `test_arch.rs`: a fetch unit, a
+//! branch predictor, a register file split into a read and a write port, an
+//! ALU, the condition codes and the data memory. It runs the Y86 subset
+//! `halt`, `nop`, `irmovq`, `rmmovq`, `mrmovq`, `OPq` and `jXX`, which is
+//! enough for a counting loop and the memory-mapped devices.
+//!
+//! The register file and the condition codes are marked `#[signal]`, so they
+//! can be read and written by name, e.g. `reg_read.rsi` or `reg_cc.inner_cc`.
+
+use crate::{
+    define_units,
+    framework::{Bht, HardwareUnits, MemData, Shared, MEM_SIZE},
+    isa::{
+        inst_code::*,
+        reg_code::{self, *},
+        RegFile,
+    },
+    utils::get_u64,
+};
+
+pub use crate::isa::{arithmetic_compute, ConditionCode, Stat, CC_INIT};
+
+define_units! {
+    /// Fetch and split an instruction of the supported subset.
+    InstructionMemory imem {
+        .input(pc: u64)
+        .output(error: bool, icode: u8, ifun: u8, rA: u8, rB: u8, valC: u64, valP: u64)
+        binary: MemData
+    } {
+        let binary: &[u8; MEM_SIZE] = &binary.read();
+        if pc > MEM_SIZE as u64 - 10 {
+            *error = true;
+        } else {
+            let pc = pc as usize;
+            *icode = binary[pc] >> 4;
+            *ifun = binary[pc] & 0xf;
+            let need_regids = matches!(*icode, IRMOVQ | RMMOVQ | MRMOVQ | OPQ);
+            let need_valc = matches!(*icode, IRMOVQ | RMMOVQ | MRMOVQ | JX);
+            (*rA, *rB) = match need_regids {
+                true => (binary[pc + 1] >> 4, binary[pc + 1] & 0xf),
+                false => (RNONE, RNONE),
+            };
+            let c = pc + 1 + need_regids as usize;
+            *valC = if need_valc { get_u64(&binary[c..c + 8]) } else { 0 };
+            *valP = (c + if need_valc { 8 } else { 0 }) as u64;
+        }
+    }
+
+    /// Looks up the branch fetched at `pc` and learns the outcome of the
+    /// branch resolved at `upd_pc`.
+    BranchPredictor bp {
+        .input(pc: u64, is_jxx: bool, upd: bool, upd_pc: u64, upd_taken: bool, upd_mispred: bool)
+        .output(taken: bool)
+        bht: Bht
+    } {
+        *taken = is_jxx && bht.predict(pc);
+        if upd {
+            bht.update(upd_pc, upd_taken, upd_mispred);
+        }
+    }
+
+    RegisterFileRead reg_read {
+        .input(srcA: u8, srcB: u8)
+        .output(valA: u64, valB: u64)
+        #[signal]
+        state: Shared<RegFile>
+    } {
+        let state = state.read();
+        *valA = if srcA != RNONE { state[srcA as usize] } else { 0 };
+        *valB = if srcB != RNONE { state[srcB as usize] } else { 0 };
+    }
+
+    RegisterFileWrite reg_write {
+        .input(dstE: u8, valE: u64, dstM: u8, valM: u64)
+        state: Shared<RegFile>
+    } {
+        if dstE != RNONE {
+            tracing::info!("write back: dstE = {}, valE = {:#x}", reg_code::name_of(dstE), valE);
+            state.write()[dstE as usize] = valE;
+        }
+        if dstM != RNONE {
+            tracing::info!("write back: dstM = {}, valM = {:#x}", reg_code::name_of(dstM), valM);
+            state.write()[dstM as usize] = valM;
+        }
+    }
+
+    /// Reads or writes a quad word of the memory or of the devices, see
+    /// [`crate::framework::Devices`].
+    DataMemory dmem {
+        .input(addr: u64, datain: u64, read: bool, write: bool, retire: bool)
+        .output(dataout: u64, error: bool)
+        binary: MemData
+    } {
+        if retire {
+            binary.retire();
+        }
+        *dataout = 0;
+        *error = false;
+        if write {
+            *error = binary.store(addr, datain).is_none();
+        } else if read {
+            match binary.load(addr) {
+                Some(v) => *dataout = v,
+                None => *error = true,
+            }
+        }
+    }
+
+    ArithmeticLogicUnit alu {
+        .input(a: u64, b: u64, fun: u8)
+        .output(e: u64)
+    } {
+        *e = arithmetic_compute(a, b, fun).unwrap_or(0);
+    }
+
+    /// Tests the condition of `ifun` before an `OPq` sets the codes.
+    RegisterCC reg_cc {
+        .input(ifun: u8, set_cc: bool, a: u64, b: u64, e: u64, opfun: u8)
+        .output(cnd: bool)
+        #[signal]
+        inner_cc: ConditionCode
+    } {
+        *cnd = inner_cc.test(ifun);
+        if set_cc {
+            inner_cc.set(a, b, e, opfun);
+        }
+    }
+}
+
+impl std::fmt::Display for Units {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let regs = crate::utils::format_reg_file(*self.reg_read.state.read());
+        f.write_fmt(format_args!("{regs}\n{cc}", cc = self.reg_cc.inner_cc))
+    }
+}
+
+impl HardwareUnits for Units {
+    fn init(memory: MemData) -> Self {
+        let reg = Shared::new([0; 16]);
+        Self {
+            imem: InstructionMemory {
+                binary: memory.clone(),
+            },
+            bp: BranchPredictor {
+                bht: Bht::default(),
+            },
+            reg_read: RegisterFileRead { state: reg.clone() },
+            reg_write: RegisterFileWrite { state: reg },
+            alu: ArithmeticLogicUnit {},
+            reg_cc: RegisterCC { inner_cc: CC_INIT },
+            dmem: DataMemory { binary: memory },
+        }
+    }
+
+    fn register_file(&self) -> RegFile {
+        *self.reg_read.state.read()
+    }
+}
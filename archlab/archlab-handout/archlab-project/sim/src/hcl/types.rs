@@ -0,0 +1,313 @@
+//! Type checking of interpreted HCL.
+//!
+//! [`sim_macro::hcl!`] leaves type checking to rustc. The interpreter keeps
+//! every signal as a word, so it checks the declared types itself before
+//! running: every signal must have the type of the units inputs and stage
+//! fields it drives, and the value of a signal (or of a case of a switch)
+//! must have the type of the signal. Every name must be a signal, a port, a
+//! stage field or a constant. Byte arrays can only be copied from a signal
+//! or a port.
+
+use std::collections::BTreeMap;
+
+use super::{constant, BinOp, Expr, HclFile, Source, UnitPort};
+
+/// `crate::isa::Stat` and `Stat` are the same type, and so are `[u8; 10]`
+/// and `[u8;10]`.
+fn normalize(typ: &str) -> String {
+    let typ: String = typ.split_whitespace().collect();
+    match typ.rsplit_once("::") {
+        Some((_, name)) if !typ.starts_with('[') => name.to_string(),
+        _ => typ,
+    }
+}
+
+/// Whether `typ` is an array, which the interpreter keeps as bytes.
+pub(super) fn is_array(typ: &str) -> bool {
+    typ.trim_start().starts_with('[')
+}
+
+/// Type of a constant, see [`constant`].
+pub(super) fn constant_type(lv: &[String]) -> Option<&'static str> {
+    let [name] = lv else {
+        return None;
+    };
+    constant(name)?;
+    Some(match name.as_str() {
+        "Aok" | "Bub" | "Hlt" | "Adr" | "Ins" => "Stat",
+        _ => "u8",
+    })
+}
+
+/// Declared types of signals, unit ports (`unit.port`) and stage fields
+/// (`F.field` and `f.field`).
+#[derive(Debug, Clone, Default)]
+pub struct TypeEnv {
+    types: BTreeMap<String, String>,
+}
+
+impl TypeEnv {
+    pub fn new(file: &HclFile, ports: &[UnitPort]) -> Self {
+        let mut types = BTreeMap::new();
+        for port in ports {
+            types.insert(format!("{}.{}", port.unit, port.name), port.typ.to_string());
+        }
+        for (cur, pre) in &file.stage_alias {
+            let Some(stage) = file.stages.iter().find(|s| &s.short_name == cur) else {
+                continue;
+            };
+            for field in &stage.fields {
+                types.insert(format!("{cur}.{}", field.name), field.typ.clone());
+                types.insert(format!("{pre}.{}", field.name), field.typ.clone());
+            }
+            for control in ["bubble", "stall"] {
+                types.insert(format!("{cur}.{control}"), "bool".to_string());
+            }
+        }
+        for sig in &file.intermediate_signals {
+            types.insert(sig.name.clone(), sig.typ.clone());
+        }
+        Self { types }
+    }
+
+    /// Declared type of a signal, port or stage field.
+    pub fn get(&self, lv: &[String]) -> Option<&str> {
+        self.types.get(&lv.join(".")).map(String::as_str)
+    }
+
+    /// Type of an operand of an operator, which can't be an array.
+    fn operand(&self, expr: &Expr) -> Result<Option<String>, String> {
+        match self.type_of(expr)? {
+            Some(typ) if is_array(&typ) => {
+                Err(format!("a value of type `{typ}` can only be copied"))
+            }
+            typ => Ok(typ),
+        }
+    }
+
+    /// Check that the condition of a directive is a `bool`. Unlike the
+    /// cases of a switch, a word is not compared with 0.
+    fn condition(&self, expr: &Expr) -> Result<(), String> {
+        match self.operand(expr)? {
+            Some(typ) if normalize(&typ) == "bool" => Ok(()),
+            Some(typ) => Err(format!("the condition has type `{typ}`, not `bool`")),
+            None => Err("the condition is an integer, not a `bool`".to_string()),
+        }
+    }
+
+    /// Type of an expression. Integer literals have no type, as they fit any
+    /// type.
+    pub fn type_of(&self, expr: &Expr) -> Result<Option<String>, String> {
+        let same = |a: Option<String>, b: Option<String>, what: &str| match (a, b) {
+            (Some(a), Some(b)) if normalize(&a) != normalize(&b) => {
+                Err(format!("{what} have types `{a}` and `{b}`"))
+            }
+            (a, b) => Ok(a.or(b)),
+        };
+        Ok(match expr {
+            Expr::Int(_) => None,
+            Expr::Bool(_) => Some("bool".to_string()),
+            Expr::LVal(lv) => match self.get(lv) {
+                Some(typ) => Some(typ.to_string()),
+                None => match constant_type(lv) {
+                    Some(typ) => Some(typ.to_string()),
+                    None => return Err(format!("unknown signal `{}`", lv.join("."))),
+                },
+            },
+            Expr::Not(e) => self.operand(e)?,
+            Expr::Eq(a, b) | Expr::NotEq(a, b) => {
+                same(
+                    self.operand(a)?,
+                    self.operand(b)?,
+                    "operands of a comparison",
+                )?;
+                Some("bool".to_string())
+            }
+            Expr::In(a, members) => {
+                let mut typ = self.operand(a)?;
+                for m in members {
+                    for v in m.exprs() {
+                        typ = same(typ, self.operand(v)?, "members of `in`")?;
+                    }
+                }
+                Some("bool".to_string())
+            }
+            Expr::And(es) | Expr::Or(es) => {
+                for e in es {
+                    self.operand(e)?;
+                }
+                Some("bool".to_string())
+            }
+            Expr::Binary(op, a, b) => {
+                let what = format!("operands of `{}`", op.as_str());
+                let typ = same(self.operand(a)?, self.operand(b)?, &what)?;
+                match op {
+                    BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => Some("bool".to_string()),
+                    _ => typ,
+                }
+            }
+            Expr::Cond(c, a, b) => {
+                self.operand(c)?;
+                same(self.operand(a)?, self.operand(b)?, "branches of `?:`")?
+            }
+        })
+    }
+}
+
+/// Check the declared types of `file` running on units with `ports`. All
+/// mismatches are reported, one per line.
+pub fn check_types(file: &HclFile, ports: &[UnitPort]) -> anyhow::Result<()> {
+    let env = TypeEnv::new(file, ports);
+    let mut errors = Vec::new();
+    for sig in &file.intermediate_signals {
+        let values = match &sig.source {
+            Source::Switch(cases) => {
+                for case in cases {
+                    if let Err(e) = env.operand(&case.condition) {
+                        errors.push(format!("line {}: in signal `{}`, {e}", sig.line, sig.name));
+                    }
+                }
+                cases.iter().map(|c| &c.value).collect()
+            }
+            Source::Expr { expr, .. } => vec![expr],
+        };
+        for value in values {
+            if is_array(&sig.typ) && !matches!(value, Expr::LVal(_)) {
+                errors.push(format!(
+                    "line {}: signal `{}` of type `{}` can only be copied from a signal or a port",
+                    sig.line, sig.name, sig.typ
+                ));
+                continue;
+            }
+            match env.type_of(value) {
+                Ok(Some(typ)) if normalize(&typ) != normalize(&sig.typ) => errors.push(format!(
+                    "line {}: a value of type `{typ}` can't be assigned to signal `{}` of type `{}`",
+                    sig.line, sig.name, sig.typ
+                )),
+                Ok(_) => {}
+                Err(e) => errors.push(format!("line {}: in signal `{}`, {e}", sig.line, sig.name)),
+            }
+        }
+        for dest in &sig.destinations {
+            let Some(typ) = env.get(&dest.dest) else {
+                errors.push(format!(
+                    "line {}: signal `{}` drives unknown `{}`",
+                    dest.line,
+                    sig.name,
+                    dest.dest.join(".")
+                ));
+                continue;
+            };
+            if normalize(typ) != normalize(&sig.typ) {
+                errors.push(format!(
+                    "line {}: signal `{}` of type `{}` can't drive `{}` of type `{typ}`",
+                    dest.line,
+                    sig.name,
+                    sig.typ,
+                    dest.dest.join(".")
+                ));
+            }
+        }
+    }
+    for (name, expr, line) in &file.counters {
+        if let Err(e) = env.condition(expr) {
+            errors.push(format!("line {line}: in counter `{name}`, {e}"));
+        }
+    }
+    for (expr, _, line) in &file.assertions {
+        if let Err(e) = env.condition(expr) {
+            errors.push(format!("line {line}: in assertion, {e}"));
+        }
+    }
+    for (directive, lv) in [
+        ("program_counter", &file.program_counter),
+        ("termination", &file.termination),
+    ] {
+        if let Err(e) = env.operand(&Expr::LVal(lv.clone())) {
+            errors.push(format!("in #![{directive} = ...], {e}"));
+        }
+    }
+    if !errors.is_empty() {
+        anyhow::bail!("{}", errors.join("\n"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_types() {
+        let ports = [
+            UnitPort {
+                unit: "imem",
+                name: "pc",
+                typ: "u64",
+                is_input: true,
+            },
+            UnitPort {
+                unit: "imem",
+                name: "icode",
+                typ: "u8",
+                is_input: false,
+            },
+        ];
+        let src = r#"
+            #![hardware = crate::architectures::hardware_seq]
+            #![program_counter = pc]
+            #![termination = prog_term]
+            #![stage_alias(F => f)]
+            define_stages! {
+                FetchStage f { pc: u64 = 0, stat: crate::isa::Stat = Aok }
+            }
+            u64 pc = F.pc;
+            @set_input(imem, { pc: pc });
+            u8 icode = [
+                F.stat == Aok : imem.icode;
+                true : NOP;
+            ];
+            u64 next = pc + 10 -> f.pc;
+            bool prog_term = icode == HALT && F.stat != Bub;
+            @count(halts, prog_term);
+            @assert(!prog_term || icode == HALT, "halting on another instruction");
+            "#;
+        let file = HclFile::parse(src).unwrap();
+        check_types(&file, &ports).unwrap();
+        let Source::Expr { expr, .. } = &file.intermediate_signals[3].source else {
+            panic!("not an expression");
+        };
+        let env = TypeEnv::new(&file, &ports);
+        assert_eq!(env.type_of(expr), Ok(Some("bool".to_string())));
+        assert_eq!(
+            env.get(&["F".into(), "stat".into()]),
+            Some("crate::isa::Stat")
+        );
+
+        let src = src
+            .replace(
+                "@set_input(imem, { pc: pc });",
+                "@set_input(imem, { pc: icode });",
+            )
+            .replace("true : NOP;", "true : F.pc;")
+            .replace("u64 next = pc + 10", "u64 next = icode + pc")
+            .replace("-> f.pc;", "-> f.pcc;")
+            .replace("F.stat != Bub", "F.stt != Bub")
+            .replace("@count(halts, prog_term)", "@count(halts, icode)")
+            .replace("!prog_term || icode == HALT", "next");
+        let file = HclFile::parse(&src).unwrap();
+        let err = check_types(&file, &ports).unwrap_err().to_string();
+        assert_eq!(
+            err.lines().collect::<Vec<_>>(),
+            [
+                "line 11: a value of type `u64` can't be assigned to signal `icode` of type `u8`",
+                "line 10: signal `icode` of type `u8` can't drive `imem.pc` of type `u64`",
+                "line 15: in signal `next`, operands of `+` have types `u8` and `u64`",
+                "line 15: signal `next` drives unknown `f.pcc`",
+                "line 16: in signal `prog_term`, unknown signal `F.stt`",
+                "line 17: in counter `halts`, the condition has type `u8`, not `bool`",
+                "line 18: in assertion, the condition has type `u64`, not `bool`",
+            ]
+        );
+    }
+}
@@ -0,0 +1,639 @@
+//! Verilog export of an HCL architecture.
+//!
+//! The design has a module for each pipeline register, a stub for each
+//! hardware unit and a top module `cpu` with the combinational logic:
+//!
+//! - a pipeline register `stage_<name>` loads its inputs at the rising edge
+//!   of the clock, unless `stall` is set; `bubble` (or `rst`) loads the
+//!   defaults declared in `define_stages!`, like `mux` in the simulator;
+//! - a unit `unit_<name>` has the ports of [`DynUnits::ports`](super::DynUnits::ports)
+//!   plus `clk` and `rst`. The instruction memory `imem`, the data memory
+//!   `dmem` and the `alu` are implemented as in the built-in hardware when
+//!   their outputs are the usual ones; other units drive their outputs with
+//!   zero until they are implemented by hand;
+//! - every intermediate signal is a wire, a switch becomes a chain of `?:`.
+//!   As in the simulator, a switch without a matching case keeps the value
+//!   of the previous cycle, held in the register `<name>_prev`.
+//!
+//! The memory image is the array `cpu.mem`, loaded by the testbench of
+//! [`export_testbench`] and shared by the memory units through hierarchical
+//! references. The testbench prints the program counter of every cycle, to
+//! be compared with the simulator.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{bail, Context};
+
+use super::{check_types, constant, eval_const, Expr, HclFile, Member, Source, UnitPort};
+use crate::framework::MEM_SIZE;
+
+/// Width in bits of a Rust type of the HCL.
+fn width(typ: &str) -> Option<usize> {
+    let typ = typ.replace(' ', "");
+    Some(match typ.as_str() {
+        "bool" => 1,
+        "u8" => 8,
+        "u64" | "i64" | "usize" => 64,
+        // see `HclValue`
+        "Stat" | "ConditionCode" => 3,
+        _ => {
+            let (elem, len) = typ.strip_prefix('[')?.strip_suffix(']')?.split_once(';')?;
+            width(elem)? * len.parse::<usize>().ok()?
+        }
+    })
+}
+
+fn type_width(typ: &str, what: &str) -> anyhow::Result<usize> {
+    width(typ).with_context(|| format!("type `{typ}` of {what} has no Verilog width"))
+}
+
+fn range(width: usize) -> String {
+    if width == 1 {
+        String::new()
+    } else {
+        format!("[{}:0] ", width - 1)
+    }
+}
+
+const KEYWORDS: &[&str] = &[
+    "always",
+    "and",
+    "assign",
+    "begin",
+    "bit",
+    "buf",
+    "byte",
+    "case",
+    "default",
+    "else",
+    "end",
+    "for",
+    "function",
+    "generate",
+    "if",
+    "initial",
+    "inout",
+    "input",
+    "int",
+    "integer",
+    "logic",
+    "module",
+    "negedge",
+    "not",
+    "or",
+    "output",
+    "parameter",
+    "localparam",
+    "posedge",
+    "reg",
+    "task",
+    "wire",
+    "xor",
+];
+
+/// Escape identifiers that are Verilog keywords.
+fn ident(name: &str) -> String {
+    // keywords and the signals of instances such as `fwd_a.val` are escaped
+    if KEYWORDS.contains(&name) || name.contains('.') {
+        format!("\\{name} ")
+    } else {
+        name.to_string()
+    }
+}
+
+fn literal(width: usize, value: u64) -> String {
+    if width == 1 {
+        format!("1'b{}", value & 1)
+    } else {
+        format!("{width}'h{value:x}")
+    }
+}
+
+/// `count` bytes of `cpu.mem` from `addr`, little endian.
+fn mem_word(addr: &str, count: usize) -> String {
+    let bytes = (0..count)
+        .rev()
+        .map(|i| format!("cpu.mem[{addr} + {i}]"))
+        .collect::<Vec<_>>();
+    format!("{{{}}}", bytes.join(", "))
+}
+
+/// Logic of the units that behave as in the built-in hardware, driving the
+/// given outputs. `None` for any other unit, or if one of the outputs is not
+/// one the implementation knows.
+fn unit_logic(unit: &str, outputs: &[&str]) -> Option<String> {
+    let (wires, drivers): (String, &[(&str, String)]) = match unit {
+        "imem" => (
+            // the split and align of the fetch stage
+            [
+                "    wire [3:0] code = cpu.mem[pc][7:4];\n",
+                "    wire need_regids = code == 4'h2 || code == 4'h3 || code == 4'h4 || code == 4'h5\n",
+                "        || code == 4'h6 || code == 4'ha || code == 4'hb || code == 4'hc;\n",
+                "    wire need_valc = code == 4'h3 || code == 4'h4 || code == 4'h5 || code == 4'h7\n",
+                "        || code == 4'h8 || code == 4'hc;\n",
+                "    wire [63:0] c = pc + 1 + need_regids;\n",
+            ]
+            .concat(),
+            &[
+                ("error", format!("pc > 64'd{}", MEM_SIZE - 10)),
+                ("icode", "{4'h0, code}".to_string()),
+                ("ifun", "{4'h0, cpu.mem[pc][3:0]}".to_string()),
+                ("rA", "need_regids ? {4'h0, cpu.mem[pc + 1][7:4]} : 8'hf".to_string()),
+                ("rB", "need_regids ? {4'h0, cpu.mem[pc + 1][3:0]} : 8'hf".to_string()),
+                ("valC", format!("need_valc ? {} : 64'h0", mem_word("c", 8))),
+                ("valP", "c + (need_valc ? 8 : 0)".to_string()),
+            ],
+        ),
+        "dmem" => (
+            format!(
+                "    always @(posedge clk) begin\n        if (!rst && write && !error)\n            {} <= datain;\n    end\n",
+                mem_word("addr", 8)
+            ),
+            &[
+                ("error", format!("addr >= 64'd{}", MEM_SIZE - 8)),
+                (
+                    "dataout",
+                    format!("read && !error && !write ? {} : 64'h0", mem_word("addr", 8)),
+                ),
+            ],
+        ),
+        // see `isa::arithmetic_compute`
+        "alu" => (
+            String::new(),
+            &[(
+                "e",
+                "fun == 0 ? b + a : fun == 1 ? b - a : fun == 2 ? b & a : fun == 3 ? b ^ a : 64'h0"
+                    .to_string(),
+            )],
+        ),
+        _ => return None,
+    };
+    let mut out = wires;
+    for output in outputs {
+        let (_, driver) = drivers.iter().find(|(name, _)| name == output)?;
+        out += &format!("    assign {} = {driver};\n", ident(output));
+    }
+    Some(out)
+}
+
+struct Exporter<'a> {
+    file: &'a HclFile,
+    /// Constants used by the logic.
+    constants: BTreeSet<String>,
+}
+
+impl Exporter<'_> {
+    /// Wire of a stage register output, named after the alias of the
+    /// previous cycle, e.g. `D_icode`.
+    fn stage_output(&self, short_name: &str, field: &str) -> String {
+        let pre = self
+            .file
+            .stage_alias
+            .iter()
+            .find(|(cur, _)| cur == short_name)
+            .map_or_else(|| format!("{short_name}_q"), |(_, pre)| pre.clone());
+        ident(&format!("{pre}_{field}"))
+    }
+
+    fn lvalue(&mut self, lv: &[String]) -> anyhow::Result<String> {
+        if lv.len() == 1 {
+            let name = &lv[0];
+            if self
+                .file
+                .intermediate_signals
+                .iter()
+                .any(|s| &s.name == name)
+            {
+                return Ok(ident(name));
+            }
+            if constant(name).is_some() {
+                self.constants.insert(name.clone());
+                return Ok(name.clone());
+            }
+            bail!("unknown name: {name}");
+        }
+        let field = lv[1..].join("_");
+        if let Some((cur, _)) = self.file.stage_alias.iter().find(|(_, pre)| pre == &lv[0]) {
+            return Ok(self.stage_output(cur, &field));
+        }
+        if self.file.stage_alias.iter().any(|(cur, _)| cur == &lv[0]) {
+            bail!("stage inputs such as {} can't be read", lv.join("."));
+        }
+        Ok(ident(&format!("{}_{field}", lv[0])))
+    }
+
+    fn expr(&mut self, expr: &Expr) -> anyhow::Result<String> {
+        Ok(match expr {
+            Expr::Int(v) => {
+                if *v <= u32::MAX as u64 {
+                    v.to_string()
+                } else {
+                    literal(64, *v)
+                }
+            }
+            Expr::Bool(b) => literal(1, *b as u64),
+            Expr::LVal(lv) => self.lvalue(lv)?,
+            Expr::Not(e) => format!("!{}", self.expr(e)?),
+            Expr::Eq(a, b) => format!("({} == {})", self.expr(a)?, self.expr(b)?),
+            Expr::NotEq(a, b) => format!("({} != {})", self.expr(a)?, self.expr(b)?),
+            Expr::Binary(op, a, b) => {
+                format!("({} {} {})", self.expr(a)?, op.as_str(), self.expr(b)?)
+            }
+            Expr::Cond(c, a, b) => {
+                format!(
+                    "({} ? {} : {})",
+                    self.expr(c)?,
+                    self.expr(a)?,
+                    self.expr(b)?
+                )
+            }
+            Expr::In(a, members) => {
+                let a = self.expr(a)?;
+                let alts = members
+                    .iter()
+                    .map(|m| {
+                        Ok(match m {
+                            Member::One(v) => format!("{a} == {}", self.expr(v)?),
+                            Member::Range(lo, hi) => {
+                                format!("({a} >= {} && {a} <= {})", self.expr(lo)?, self.expr(hi)?)
+                            }
+                        })
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                format!("({})", alts.join(" || "))
+            }
+            Expr::And(es) | Expr::Or(es) => {
+                let op = if matches!(expr, Expr::And(_)) {
+                    " && "
+                } else {
+                    " || "
+                };
+                let es = es
+                    .iter()
+                    .map(|e| self.expr(e))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                format!("({})", es.join(op))
+            }
+        })
+    }
+}
+
+/// Export the architecture as a Verilog design. `ports` are the ports of the
+/// units of its hardware module, see [`DynUnits::ports`](super::DynUnits::ports).
+pub fn export_verilog(file: &HclFile, ports: &[UnitPort]) -> anyhow::Result<String> {
+    check_types(file, ports)?;
+    let mut ex = Exporter {
+        file,
+        constants: BTreeSet::new(),
+    };
+    let mut out = String::from("// Generated from HCL by y86-sim.\n\n");
+
+    // inputs of the pipeline registers and units, as source signal
+    let mut inputs = BTreeMap::new();
+    for sig in &file.intermediate_signals {
+        for dest in &sig.destinations {
+            inputs.insert(dest.dest.join("."), ident(&sig.name));
+        }
+    }
+
+    // pipeline registers
+    for stage in &file.stages {
+        let mut ports = vec![
+            "input clk".to_string(),
+            "input rst".to_string(),
+            "input bubble".to_string(),
+            "input stall".to_string(),
+        ];
+        let mut body = String::new();
+        for field in &stage.fields {
+            let what = format!("{}.{}", stage.short_name, field.name);
+            let w = type_width(&field.typ, &what)?;
+            let default = eval_const(&field.default)
+                .with_context(|| format!("default of {what} is not a constant"))?;
+            ports.push(format!("input {}{}_in", range(w), field.name));
+            ports.push(format!("output reg {}{}", range(w), ident(&field.name)));
+            body += &format!(
+                "            {} <= {};\n",
+                ident(&field.name),
+                literal(w, default)
+            );
+        }
+        let load = stage
+            .fields
+            .iter()
+            .map(|f| format!("            {} <= {}_in;\n", ident(&f.name), f.name))
+            .collect::<String>();
+        out += &format!(
+            "// {} pipeline register\nmodule stage_{} (\n    {}\n);\n",
+            stage.name,
+            stage.short_name,
+            ports.join(",\n    ")
+        );
+        out += &format!(
+            "    always @(posedge clk) begin\n        if (rst || bubble) begin\n{body}        end else if (!stall) begin\n{load}        end\n    end\nendmodule\n\n"
+        );
+    }
+
+    // unit stubs
+    let mut units: Vec<(&str, Vec<&UnitPort>)> = Vec::new();
+    for port in ports {
+        match units.iter_mut().find(|(u, _)| *u == port.unit) {
+            Some((_, ports)) => ports.push(port),
+            None => units.push((port.unit, vec![port])),
+        }
+    }
+    for (unit, ports) in &units {
+        let mut decls = vec!["input clk".to_string(), "input rst".to_string()];
+        let mut assigns = String::new();
+        for port in ports {
+            let w = type_width(port.typ, &format!("{unit}.{}", port.name))?;
+            let dir = if port.is_input { "input" } else { "output" };
+            decls.push(format!("{dir} {}{}", range(w), ident(port.name)));
+            if !port.is_input {
+                assigns += &format!("    assign {} = {};\n", ident(port.name), literal(w, 0));
+            }
+        }
+        let outputs = ports
+            .iter()
+            .filter(|p| !p.is_input)
+            .map(|p| p.name)
+            .collect::<Vec<_>>();
+        let (title, body) = match unit_logic(unit, &outputs) {
+            Some(logic) => (format!("// the unit `{unit}`"), logic),
+            None => (format!("// TODO: implement the unit `{unit}`"), assigns),
+        };
+        out += &format!(
+            "{title}\nmodule unit_{unit} (\n    {}\n);\n{body}endmodule\n\n",
+            decls.join(",\n    ")
+        );
+    }
+
+    // top module
+    let mut wires = String::new();
+    let mut names = BTreeSet::new();
+    let mut declare = |name: String, w: usize| -> anyhow::Result<()> {
+        if !names.insert(name.clone()) {
+            bail!("wire {name} is defined twice");
+        }
+        wires += &format!("    wire {}{name};\n", range(w));
+        Ok(())
+    };
+    for stage in &file.stages {
+        for field in &stage.fields {
+            let w = type_width(&field.typ, &field.name)?;
+            declare(ex.stage_output(&stage.short_name, &field.name), w)?;
+        }
+    }
+    for port in ports.iter().filter(|p| !p.is_input) {
+        let w = type_width(port.typ, port.name)?;
+        declare(ident(&format!("{}_{}", port.unit, port.name)), w)?;
+    }
+    let mut regs = String::new();
+    let mut logic = String::new();
+    for sig in &file.intermediate_signals {
+        let w = type_width(&sig.typ, &sig.name)?;
+        declare(ident(&sig.name), w)?;
+        let value = match &sig.source {
+            Source::Expr { expr, .. } => format!(" {}", ex.expr(expr)?),
+            Source::Switch(cases) => {
+                let mut chain = String::new();
+                for case in cases {
+                    let cond = ex.expr(&case.condition)?;
+                    let value = ex.expr(&case.value)?;
+                    chain += &format!("\n        {cond} ? {value} :");
+                }
+                let always = cases
+                    .iter()
+                    .any(|case| eval_const(&case.condition).is_some_and(|c| c != 0));
+                if always {
+                    format!("{chain}\n        {}", literal(w, 0))
+                } else {
+                    // the value of the previous cycle
+                    let prev = ident(&format!("{}_prev", sig.name));
+                    regs += &format!("    reg {}{prev};\n", range(w));
+                    logic += &format!(
+                        "    always @(posedge clk) {prev} <= rst ? {} : {};\n",
+                        literal(w, 0),
+                        ident(&sig.name)
+                    );
+                    format!("{chain}\n        {prev}")
+                }
+            }
+        };
+        logic += &format!("    assign {} ={value};\n", ident(&sig.name));
+    }
+
+    let mut instances = String::new();
+    for stage in &file.stages {
+        let input = |field: &str| {
+            let key = format!("{}.{field}", stage.short_name);
+            inputs.get(&key).cloned()
+        };
+        let mut conns = vec![
+            ".clk(clk)".to_string(),
+            ".rst(rst)".to_string(),
+            format!(".bubble({})", input("bubble").unwrap_or("1'b0".into())),
+            format!(".stall({})", input("stall").unwrap_or("1'b0".into())),
+        ];
+        for field in &stage.fields {
+            // inputs never set keep their defaults
+            let w = type_width(&field.typ, &field.name)?;
+            let default = literal(w, eval_const(&field.default).unwrap_or(0));
+            let src = input(&field.name).unwrap_or(default);
+            conns.push(format!(".{}_in({src})", field.name));
+            conns.push(format!(
+                ".{}({})",
+                ident(&field.name),
+                ex.stage_output(&stage.short_name, &field.name)
+            ));
+        }
+        instances += &format!(
+            "    stage_{0} {0}_reg (\n        {1}\n    );\n",
+            stage.short_name,
+            conns.join(",\n        ")
+        );
+    }
+    for (unit, ports) in &units {
+        let mut conns = vec![".clk(clk)".to_string(), ".rst(rst)".to_string()];
+        for port in ports {
+            let key = format!("{unit}.{}", port.name);
+            let src = if port.is_input {
+                inputs.get(&key).cloned().unwrap_or("0".into())
+            } else {
+                ident(&format!("{unit}_{}", port.name))
+            };
+            conns.push(format!(".{}({src})", ident(port.name)));
+        }
+        instances += &format!(
+            "    unit_{unit} {unit} (\n        {}\n    );\n",
+            conns.join(",\n        ")
+        );
+    }
+
+    let pc = ex.lvalue(&file.program_counter)?;
+    let term = ex.lvalue(&file.termination)?;
+    let params = ex
+        .constants
+        .iter()
+        .map(|c| format!("    localparam {c} = {};\n", constant(c).unwrap()))
+        .collect::<String>();
+
+    out += "module cpu (\n    input clk,\n    input rst,\n    output [63:0] pc,\n    output term\n);\n";
+    out += &params;
+    out += &format!(
+        "\n    // memory image, loaded by the testbench\n    reg [7:0] mem [0:{}];\n\n",
+        MEM_SIZE - 1
+    );
+    out += &wires;
+    out += &regs;
+    out += "\n";
+    out += &instances;
+    out += "\n";
+    out += &logic;
+    out += &format!("\n    assign pc = {pc};\n    assign term = {term};\nendmodule\n");
+    Ok(out)
+}
+
+/// A testbench for the design of [`export_verilog`]. It loads the memory
+/// image from `hex_file` (see [`memh`]) and prints the program counter of
+/// every cycle until the program terminates or `max_cycles` is reached.
+pub fn export_testbench(hex_file: &str, max_cycles: u64) -> String {
+    format!(
+        r#"`timescale 1ns / 1ps
+
+module tb;
+    reg clk = 0;
+    reg rst = 1;
+    wire [63:0] pc;
+    wire term;
+    integer cycle = 0;
+
+    cpu dut (
+        .clk(clk),
+        .rst(rst),
+        .pc(pc),
+        .term(term)
+    );
+
+    always #5 clk = ~clk;
+
+    initial begin
+        $readmemh("{hex_file}", dut.mem);
+        // the first rising edge loads the defaults of the pipeline registers
+        @(posedge clk);
+        rst = 0;
+    end
+
+    // signals are stable before the falling edge
+    always @(negedge clk) begin
+        if (!rst) begin
+            cycle = cycle + 1;
+            $display("cycle %0d: pc = %h", cycle, pc);
+            if (term || cycle >= {max_cycles}) $finish;
+        end
+    end
+endmodule
+"#
+    )
+}
+
+/// Render a memory image for `$readmemh`, e.g. from
+/// [`Object::init_mem`](crate::Object::init_mem). Trailing zeros are
+/// omitted.
+pub fn memh(mem: &[u8]) -> String {
+    let len = mem.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+    let mut out = String::from("@0\n");
+    for line in mem[..len].chunks(16) {
+        let bytes = line.iter().map(|b| format!("{b:02x}")).collect::<Vec<_>>();
+        out += &bytes.join(" ");
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hcl::test_arch::interpreted;
+    use crate::hcl::{test_hardware::Units, DynUnits};
+
+    #[test]
+    fn test_export_verilog() {
+        let file = HclFile::parse(
+            r#"
+            #![hardware = crate::architectures::hardware_pipe]
+            #![program_counter = f_pc]
+            #![termination = prog_term]
+            #![stage_alias(F => f, D => d)]
+
+            define_stages! {
+                FetchStage f { pred_pc: u64 = 0 }
+                DecodeStage d { stat: Stat = Bub, icode: u8 = NOP }
+            }
+
+            u64 f_pc = F.pred_pc;
+            @set_input(imem, { pc: f_pc });
+            u8 f_icode = [
+                imem.error : NOP;
+                true : imem.icode;
+            ] -> d.icode;
+            bool d_stall = D.icode in { HALT, NOP } && !imem.error;
+            @set_stage(d, { stall: d_stall });
+            bool prog_term = D.stat == Hlt;
+            u64 f_hold = [
+                imem.error : 0;
+            ];
+            "#,
+        )
+        .unwrap();
+        let port = |name, typ, is_input| UnitPort {
+            unit: "imem",
+            name,
+            typ,
+            is_input,
+        };
+        let ports = [
+            port("pc", "u64", true),
+            port("icode", "u8", false),
+            port("error", "bool", false),
+            port("inst", "[u8 ; 10]", false),
+        ];
+        let v = export_verilog(&file, &ports).unwrap();
+
+        assert!(v.contains("module stage_d (\n    input clk,\n    input rst,\n    input bubble,\n    input stall,\n    input [2:0] stat_in,\n    output reg [2:0] stat,"));
+        assert!(v.contains("        if (rst || bubble) begin\n            stat <= 3'h1;\n            icode <= 8'h1;\n        end else if (!stall) begin\n            stat <= stat_in;\n"));
+        assert!(v.contains("module unit_imem (\n    input clk,\n    input rst,\n    input [63:0] pc,\n    output [7:0] icode,\n    output error,\n    output [79:0] inst\n);"));
+        assert!(v.contains(
+            "    localparam HALT = 0;\n    localparam Hlt = 2;\n    localparam NOP = 1;\n"
+        ));
+        assert!(v.contains("    wire [2:0] D_stat;\n"));
+        assert!(v.contains("        .bubble(1'b0),\n        .stall(d_stall),\n        .stat_in(3'h1),\n        .stat(D_stat),\n        .icode_in(f_icode),"));
+        assert!(v.contains("    unit_imem imem (\n        .clk(clk),\n        .rst(rst),\n        .pc(f_pc),\n        .icode(imem_icode),"));
+        assert!(v.contains("    assign f_icode =\n        imem_error ? NOP :\n        1'b1 ? imem_icode :\n        8'h0;\n"));
+        assert!(v.contains(
+            "    assign d_stall = ((D_icode == HALT || D_icode == NOP) && !imem_error);\n"
+        ));
+        assert!(v.contains("    assign pc = f_pc;\n    assign term = prog_term;\n"));
+        // a switch without a matching case keeps its value
+        assert!(v.contains("    reg [63:0] f_hold_prev;\n"));
+        assert!(v.contains("    always @(posedge clk) f_hold_prev <= rst ? 64'h0 : f_hold;\n"));
+        assert!(v.contains("        imem_error ? 0 :\n        f_hold_prev;\n"));
+
+        assert!(export_testbench("prog.hex", 100).contains("$readmemh(\"prog.hex\", dut.mem);"));
+        assert_eq!(memh(&[0x30, 0xf4, 0, 0]), "@0\n30 f4\n");
+    }
+
+    #[test]
+    fn test_export_units() {
+        let file = HclFile::parse(&interpreted()).unwrap();
+        let v = export_verilog(&file, &Units::ports()).unwrap();
+        // the fetch stage runs on the memory image
+        assert!(v.contains("// the unit `imem`\nmodule unit_imem ("));
+        assert!(v.contains("    wire [3:0] code = cpu.mem[pc][7:4];\n"));
+        assert!(v.contains("    assign valC = need_valc ? {cpu.mem[c + 7], "));
+        assert!(v.contains("    assign valP = c + (need_valc ? 8 : 0);\n"));
+        assert!(v.contains("// the unit `alu`\nmodule unit_alu ("));
+        assert!(v.contains("// TODO: implement the unit `bp`\nmodule unit_bp ("));
+        assert!(v.contains("    assign taken = 1'b0;\n"));
+    }
+}
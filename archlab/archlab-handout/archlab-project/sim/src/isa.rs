@@ -1,6 +1,7 @@
 //! Instruction Set definition for Y86-64 Architecture
 
 use crate::{
+    framework::{Devices, TrapCause},
     object::BIN_SIZE,
     utils::{format_reg_file, get_u64, mem_diff, put_u64},
 };
@@ -41,6 +42,8 @@ define_code! {
     POPQ = 0xb;
     // extended instruction
     IOPQ = 0xc;
+    // return from a trap handler, see `crate::framework::Devices`
+    IRET = 0xd;
 }
 
 define_code! {
@@ -100,7 +103,7 @@ define_code! {
 }
 
 /// A data structure that simulates the condition codes.
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 #[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct ConditionCode {
     pub sf: bool,
@@ -211,13 +214,63 @@ pub struct StandardResult {
     pub regs: RegFile,
     pub pc: usize,
     pub n_insts: u64,
+    /// the memory-mapped devices, holding the console output and the exit
+    /// code
+    pub devices: Devices,
+}
+
+/// Architectural effect of an executed instruction, see [`simulate_trace`].
+#[derive(Debug, Clone)]
+pub struct InstEffect {
+    /// address of the instruction
+    pub pc: usize,
+    /// register file after the instruction
+    pub regs: RegFile,
+    /// `(address, value)` of the quad word written to memory, if any
+    pub mem_write: Option<(usize, u64)>,
 }
 
 /// Execute Y86 machine code w.r.t. the ISA specification. This function
 /// is used to verify the correctness of the pipeline architectures.
 ///
-/// It supports the extended `iopq` instruction.
-pub fn simulate(mut bin: [u8; BIN_SIZE], tty_out: bool) -> anyhow::Result<StandardResult> {
+/// It supports the extended `iopq` instruction and the memory-mapped devices
+/// of [`crate::framework::Devices`], counting the completed instructions as
+/// retired. Once a trap vector is set, `Adr`, `Ins` and the timer interrupt
+/// jump to their handlers, which return with `iret` (`.byte 0xd0`, as the
+/// assembler has no mnemonic for it).
+pub fn simulate(bin: [u8; BIN_SIZE], tty_out: bool) -> anyhow::Result<StandardResult> {
+    execute(bin, tty_out, u64::MAX, |_| {})
+}
+
+/// Same as [`simulate`], but calls `trace` after each instruction (except
+/// `halt`) and fails if more than `max_insts` instructions are executed.
+pub fn simulate_trace(
+    bin: [u8; BIN_SIZE],
+    max_insts: u64,
+    trace: impl FnMut(&InstEffect),
+) -> anyhow::Result<StandardResult> {
+    execute(bin, false, max_insts, trace)
+}
+
+/// An invalid memory or device address, which raises [`TrapCause::Adr`]
+/// instead of [`TrapCause::Ins`].
+#[derive(Debug)]
+struct AddressError(String);
+
+impl std::fmt::Display for AddressError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for AddressError {}
+
+fn execute(
+    mut bin: [u8; BIN_SIZE],
+    tty_out: bool,
+    max_insts: u64,
+    mut trace: impl FnMut(&InstEffect),
+) -> anyhow::Result<StandardResult> {
     let original = bin;
     let mut pc = 0;
 
@@ -228,14 +281,70 @@ pub fn simulate(mut bin: [u8; BIN_SIZE], tty_out: bool) -> anyhow::Result<Standa
         Ok(reg as usize)
     }
 
-    // Condition code register
-    let mut reg_cc = ConditionCode::default();
-    let mut reg_file = [0u64; 16];
+    fn ensure_addr(addr: u64) -> anyhow::Result<usize> {
+        if addr > (BIN_SIZE - 8) as u64 {
+            return Err(AddressError(format!("invalid memory address: {:#x}", addr)).into());
+        }
+        Ok(addr as usize)
+    }
 
-    let mut n_insts = 0;
+    fn load(bin: &[u8; BIN_SIZE], devices: &mut Devices, addr: u64) -> anyhow::Result<u64> {
+        if Devices::contains(addr) {
+            let v = devices
+                .load(addr)
+                .ok_or_else(|| AddressError(format!("invalid device address: {:#x}", addr)))?;
+            return Ok(v);
+        }
+        let addr = ensure_addr(addr)?;
+        Ok(get_u64(&bin[addr..(addr + 8)]))
+    }
 
-    loop {
-        n_insts += 1;
+    /// Return the memory write, `None` if a device is written.
+    fn store(
+        bin: &mut [u8; BIN_SIZE],
+        devices: &mut Devices,
+        addr: u64,
+        v: u64,
+    ) -> anyhow::Result<Option<(usize, u64)>> {
+        if Devices::contains(addr) {
+            devices
+                .store(addr, v)
+                .ok_or_else(|| AddressError(format!("invalid device address: {:#x}", addr)))?;
+            return Ok(None);
+        }
+        let addr = ensure_addr(addr)?;
+        put_u64(&mut bin[addr..(addr + 8)], v);
+        Ok(Some((addr, v)))
+    }
+
+    /// Return the address of the handler, `None` if the machine stops.
+    fn enter_trap(
+        bin: &[u8; BIN_SIZE],
+        devices: &mut Devices,
+        cause: TrapCause,
+        pc: usize,
+        cc: ConditionCode,
+    ) -> Option<usize> {
+        let entry = devices.enter_trap(cause, pc as u64, cc)?;
+        load(bin, devices, entry)
+            .ok()
+            .map(|handler| handler as usize)
+    }
+
+    /// Execute the instruction at `pc` and return the next pc, `None` on
+    /// `halt`. `mem_write` is set to the memory write of the instruction.
+    fn exec_inst(
+        bin: &mut [u8; BIN_SIZE],
+        pc: usize,
+        tty_out: bool,
+        reg_file: &mut [u64; 16],
+        reg_cc: &mut ConditionCode,
+        devices: &mut Devices,
+        mem_write: &mut Option<(usize, u64)>,
+    ) -> anyhow::Result<Option<usize>> {
+        if pc > BIN_SIZE - 10 {
+            return Err(AddressError(format!("invalid pc: {:#x}", pc)).into());
+        }
         let icode = bin[pc] >> 4;
         let ifun = bin[pc] & 0xf;
 
@@ -282,14 +391,14 @@ pub fn simulate(mut bin: [u8; BIN_SIZE], tty_out: bool) -> anyhow::Result<Standa
             };
         }
 
-        let nex_pc = match icode {
+        Ok(Some(match icode {
             inst_code::HALT => {
                 if ifun != 0 {
                     anyhow::bail!("invalid ifun for HALT: {:#x}", ifun);
                 }
                 print_inst!();
                 print_end!();
-                break;
+                return Ok(None);
             }
             inst_code::NOP => {
                 let nex_pc = pc.checked_add(1).ok_or(anyhow::anyhow!("pc overflow"))?;
@@ -350,11 +459,8 @@ pub fn simulate(mut bin: [u8; BIN_SIZE], tty_out: bool) -> anyhow::Result<Standa
                 let rb = ensure_reg(bin[pc + 1] & 0xf)?;
                 let v = get_u64(&bin[(pc + 2)..(pc + 10)]);
 
-                let addr = (reg_file[rb] as i64 + v as i64) as usize;
-                if addr >= BIN_SIZE {
-                    anyhow::bail!("invalid memory address: {:#x}", addr);
-                }
-                put_u64(&mut bin[addr..(addr + 8)], reg_file[ra]);
+                let addr = reg_file[rb].wrapping_add(v);
+                *mem_write = store(bin, devices, addr, reg_file[ra])?;
 
                 nex_pc
             }
@@ -371,11 +477,8 @@ pub fn simulate(mut bin: [u8; BIN_SIZE], tty_out: bool) -> anyhow::Result<Standa
                 let rb = ensure_reg(bin[pc + 1] & 0xf)?;
                 let v = get_u64(&bin[(pc + 2)..(pc + 10)]);
 
-                let addr = (reg_file[rb] as i64 + v as i64) as usize;
-                if addr >= BIN_SIZE {
-                    anyhow::bail!("invalid memory address: {:#x}", addr);
-                }
-                reg_file[ra] = get_u64(&bin[addr..(addr + 8)]);
+                let addr = reg_file[rb].wrapping_add(v);
+                reg_file[ra] = load(bin, devices, addr)?;
 
                 nex_pc
             }
@@ -424,11 +527,11 @@ pub fn simulate(mut bin: [u8; BIN_SIZE], tty_out: bool) -> anyhow::Result<Standa
                 print_end!();
 
                 let rsp = reg_file.get_mut(reg_code::RSP as usize).unwrap();
-                *rsp = rsp.checked_sub(8).ok_or(anyhow::anyhow!("rsp overflow"))?;
-                put_u64(
-                    &mut bin[(*rsp as usize)..(*rsp as usize + 8)],
-                    pc as u64 + 9,
-                );
+                let new_rsp = rsp
+                    .checked_sub(8)
+                    .ok_or_else(|| AddressError("rsp overflow".to_string()))?;
+                *mem_write = store(bin, devices, new_rsp, pc as u64 + 9)?;
+                *rsp = new_rsp;
 
                 v as usize
             }
@@ -440,9 +543,11 @@ pub fn simulate(mut bin: [u8; BIN_SIZE], tty_out: bool) -> anyhow::Result<Standa
                 print_end!();
 
                 let rsp = reg_file.get_mut(reg_code::RSP as usize).unwrap();
-                let v = get_u64(&bin[(*rsp as usize)..(*rsp as usize + 8)]);
+                let v = load(bin, devices, *rsp)?;
 
-                *rsp = rsp.checked_add(8).ok_or(anyhow::anyhow!("rsp overflow"))?;
+                *rsp = rsp
+                    .checked_add(8)
+                    .ok_or_else(|| AddressError("rsp overflow".to_string()))?;
 
                 v as usize
             }
@@ -459,9 +564,11 @@ pub fn simulate(mut bin: [u8; BIN_SIZE], tty_out: bool) -> anyhow::Result<Standa
                 let va = reg_file[ra];
 
                 let rsp = reg_file.get_mut(reg_code::RSP as usize).unwrap();
-                *rsp = rsp.checked_sub(8).ok_or(anyhow::anyhow!("rsp overflow"))?;
-                let new_rsp = *rsp as usize;
-                put_u64(&mut bin[new_rsp..(new_rsp + 8)], va);
+                let new_rsp = rsp
+                    .checked_sub(8)
+                    .ok_or_else(|| AddressError("rsp overflow".to_string()))?;
+                *mem_write = store(bin, devices, new_rsp, va)?;
+                *rsp = new_rsp;
 
                 nex_pc
             }
@@ -477,9 +584,11 @@ pub fn simulate(mut bin: [u8; BIN_SIZE], tty_out: bool) -> anyhow::Result<Standa
                 let ra = ensure_reg(bin[pc + 1] >> 4)?;
 
                 let rsp = reg_file.get_mut(reg_code::RSP as usize).unwrap();
-                let old_rsp = *rsp as usize;
-                *rsp = rsp.checked_add(8).ok_or(anyhow::anyhow!("rsp overflow"))?;
-                reg_file[ra] = get_u64(&bin[old_rsp..(old_rsp + 8)]);
+                let v = load(bin, devices, *rsp)?;
+                *rsp = rsp
+                    .checked_add(8)
+                    .ok_or_else(|| AddressError("rsp overflow".to_string()))?;
+                reg_file[ra] = v;
 
                 nex_pc
             }
@@ -508,14 +617,91 @@ pub fn simulate(mut bin: [u8; BIN_SIZE], tty_out: bool) -> anyhow::Result<Standa
 
                 nex_pc
             }
+            inst_code::IRET => {
+                if ifun != 0 {
+                    anyhow::bail!("invalid ifun for IRET: {:#x}", ifun);
+                }
+                print_inst!();
+                print_end!();
+
+                let Some((epc, cc)) = devices.leave_trap() else {
+                    anyhow::bail!("iret outside of a trap handler");
+                };
+                *reg_cc = cc;
+
+                epc as usize
+            }
             _ => anyhow::bail!("unknown icode: {:#x}", icode),
-        };
-        pc = nex_pc;
+        }))
+    }
+
+    // Condition code register
+    let mut reg_cc = ConditionCode::default();
+    let mut reg_file = [0u64; 16];
+    let mut devices = Devices::new(tty_out);
+
+    let mut n_insts = 0;
+
+    loop {
+        n_insts += 1;
+        if n_insts > max_insts {
+            anyhow::bail!("instruction limit exceeded: {}", max_insts);
+        }
+        let mut mem_write = None;
+        // the next pc, `None` on halt
+        let result = exec_inst(
+            &mut bin,
+            pc,
+            tty_out,
+            &mut reg_file,
+            &mut reg_cc,
+            &mut devices,
+            &mut mem_write,
+        );
+        match result {
+            Ok(Some(nex_pc)) => {
+                devices.retire();
+                trace(&InstEffect {
+                    pc,
+                    regs: reg_file,
+                    mem_write,
+                });
+                pc = nex_pc;
+            }
+            Ok(None) => break,
+            Err(err) => {
+                let cause = if err.is::<AddressError>() {
+                    TrapCause::Adr
+                } else {
+                    TrapCause::Ins
+                };
+                let Some(handler) = enter_trap(&bin, &mut devices, cause, pc, reg_cc) else {
+                    return Err(err);
+                };
+                if tty_out {
+                    println!("trap: {err}");
+                }
+                pc = handler;
+            }
+        }
+        if devices.exit_code.is_some() {
+            break;
+        }
+        if devices.timer_pending() {
+            pc = enter_trap(&bin, &mut devices, TrapCause::Timer, pc, reg_cc)
+                .ok_or_else(|| anyhow::anyhow!("invalid trap vector: {:#x}", devices.trap_base))?;
+            if tty_out {
+                println!("trap: timer interrupt");
+            }
+        }
     }
 
     if tty_out {
         println!();
         println!("total instructions: {}", n_insts);
+        if let Some(code) = devices.exit_code {
+            println!("exit code: {}", code);
+        }
         println!("{}", format_reg_file(reg_file));
         mem_diff(&original, &bin);
     }
@@ -526,5 +712,6 @@ pub fn simulate(mut bin: [u8; BIN_SIZE], tty_out: bool) -> anyhow::Result<Standa
         regs: reg_file,
         pc,
         n_insts,
+        devices,
     })
 }
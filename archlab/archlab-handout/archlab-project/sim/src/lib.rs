@@ -2,9 +2,11 @@ pub mod architectures;
 mod asm;
 mod dsl;
 pub mod framework;
+pub mod hcl;
 pub mod isa;
 mod lab;
 mod object;
+pub mod occupancy;
 pub mod test;
 pub mod utils;
 
@@ -22,6 +24,69 @@ mod tests {
         eprintln!("{}", r);
     }
 
+    #[test]
+    fn test_devices() {
+        let src = "\
+    irmovq $0x10000, %rbx
+    irmovq $0x68, %rax
+    rmmovq %rax, (%rbx)
+    irmovq $0x69, %rax
+    rmmovq %rax, (%rbx)
+    mrmovq 8(%rbx), %rcx
+    irmovq $3, %rax
+    rmmovq %rax, 0x10(%rbx)
+    irmovq $1, %rax
+    halt
+";
+        let r = assemble(src, AssembleOption::default()).unwrap();
+        let res = crate::isa::simulate(r.obj.init_mem(), false).unwrap();
+        assert_eq!(res.devices.console_text(), "hi");
+        // the counter is read by the 6th instruction, after 5 retired
+        assert_eq!(res.regs[1], 5);
+        // the instructions after the exit are not executed
+        assert_eq!(res.devices.exit_code, Some(3));
+        assert_eq!(res.regs[0], 3);
+    }
+
+    #[test]
+    fn test_traps() {
+        // `iret` is written as `.byte 0xd0`
+        let src = "\
+    irmovq $0x10000, %rbx
+    irmovq vectors, %rax
+    rmmovq %rax, 0x18(%rbx)     # enable the traps
+    mrmovq 0x1000(%rbx), %rcx   # Adr, skipped by the handler
+    irmovq $3, %rax
+    rmmovq %rax, 0x30(%rbx)     # timer interrupt after 3 instructions
+loop:
+    andq %rdi, %rdi
+    je loop
+    rmmovq %rsi, 0x10(%rbx)     # exit with %rsi
+    halt
+adr:
+    irmovq $1, %rsi
+    mrmovq 0x20(%rbx), %rax
+    irmovq $10, %r8
+    addq %r8, %rax
+    rmmovq %rax, 0x20(%rbx)     # skip the faulting mrmovq
+    .byte 0xd0
+timer:
+    irmovq $1, %rdi
+    .byte 0xd0
+    .align 8
+vectors:
+    .quad adr
+    .quad 0
+    .quad timer
+";
+        let r = assemble(src, AssembleOption::default()).unwrap();
+        let res = crate::isa::simulate(r.obj.init_mem(), false).unwrap();
+        assert_eq!(res.devices.exit_code, Some(1));
+        assert_eq!(res.regs[1], 0);
+        assert_eq!(res.regs[7], 1);
+        assert!(!res.devices.in_trap);
+    }
+
     /// in visualization of the architecture of pipeline, each tunnel
     /// starts from one ore more start points, may split to multiple heads,
     /// reaching various destination. What we concern is
@@ -54,46 +119,47 @@ mod tests {
     ///
     /// Moreover, a tunnel is simply (source, intermediate, ...dist)
     /// For better readability, we maintain the condition separately.
+    ///
+    /// The tunnels of a diagram are drawn in its mask, see
+    /// [`crate::framework::Diagram`]. Those of `pipe_std` are the forwarding
+    /// paths into `d_valA`/`d_valB` and the predicted PC.
     #[test]
     fn test_draw() {
-        println!(
-            r#"
-                     ┏━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━┓
-                     ┃      ┏━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━┓┃
-W stat icode       valE   valM      dstE dstM              ┃┃
-   │     │           ┃      ┣━━━━━━━━│━━━━│━━━━━━━━━━━━━━━┓┃┃
-   │     ├───#Mem.##┄┃┄┄┄┄┄Data##    │    │               ┃┃┃ 
-   │     ├───Control┄┃┄┄┄┄┄memory    │    │               ┃┃┃
-   │     │           ┃  Addr┛  ┃     │    │               ┃┃┃
-   │     │           ┃  ┃ ┗━━━━┃━━━━━│━━━━│━━━━━━━━━━━━━━┓┃┃┃
-   │     │           ┗━━╋━━━━━━┃━━━━━│━━━━│━━━━━━━━━━━━━┓┃┃┃┃
-M stat icode    Cnd   valE   valA   dstE dstM           ┃┃┃┃┃
-   │     │       │      ┣━━━━━━┃━━━━━│━━━━│━━━━━━━━━━━━┓┃┃┃┃┃
-   │     │       CC─────ALU ┏━━┛     │    │            ┃┃┃┃┃┃
-   │     │          AluA┛ ┗━┃━━AluB  │    │            ┃┃┃┃┃┃
-   │     │           ┃┗━━━━━┫    ┃   │    │            ┃┃┃┃┃┃
-E stat icode   ifun valC  valA valB dstE dstM srcA srcB┃┃┃┃┃┃
-   │     │       │   ┃      ┃    ┃                     ┃┃┃┃┃┃
-   │     │       │   ┃  #######━###━━━━━━━━━━━━━━━━━━━━┛┃┃┃┃┃
-   │     │       │   ┃  #######━###━━━━━━━━━━━━━━━━━━━━━┛┃┃┃┃
-   │     │       │   ┃  Sel+Fwd━Fwd━━━━━━━━━━━━━━━━━━━━━━┃┛┃┃
-   │     │       │   ┃  ###A###━#B#━━━━━━━━━━━━━━━━━━━━━━┃━┫┃
-   │     │       │   ┃  #######━###━━━━━━━━━━━━━━━━━━━━━━┃━┃┫
-   │     │       │   ┃      ┃ ┃  ┃                       ┃ ┃┃
-   │     │       │   ┃      ┃ ┗Register━━━━━━━━━━━━━━━━━━┃━┫┃
-   │     │       │   ┃      ┗┓ ##file##━━━━━━━━━━━━━━━━━━┃━┃┛
-   │     │       │   ┗━━━━━┓ ┗━━━┓                       ┃ ┃
-D stat icode   ifun rA rB valC  valP                     ┃ ┃
-   │     │       │   │ │   ┣━━━━━┃━━━━━━━━━━Predict      ┃ ┃
-  Stat───┴───┐   │   │ │   ┃     ┣━━━━━━━━━━##PC###      ┃ ┃
-             Instruction━━━┛  ###PC####        ┃         ┃ ┃
-             ##memory###      increment        ┃         ┃ ┃
-                  ┣━━━━━━━━━━━━━━┛             ┃         ┃ ┃
-                Select━━━━━━━━━━━━━━━━━━━━━━━━━┃━━━━━━━━━┛ ┃
-                ##PC##━━━━━━━━━━━━━━━━━━━━━━━━━┃━━━━━━━━━━━┛
-F        predPC━┛                              ┃
-            ┗━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━┛
-"#
-        )
+        let src = include_str!("architectures/builtin/pipe_std.diagram");
+        let diagram = crate::framework::Diagram::parse(src).unwrap();
+        let value = |name: &str| (name == "f_pc").then(|| "0x17".to_string());
+        let fired = ["fwd_m_valM", "pred_valP"];
+        let out = diagram.render(&fired, value, false);
+        let lines = out.lines().collect::<Vec<_>>();
+        let drawing = src.split("\n---\n").next().unwrap().lines();
+        assert_eq!(lines.len(), drawing.count());
+
+        // `valM` forwarded from M, and `valP` into the PC prediction
+        assert_eq!(
+            lines[3],
+            "   │     │           │      ├━━━━━━━━│━━━━│━━━━━━━━━━━━━━━┓││"
+        );
+        assert_eq!(
+            lines[18],
+            "   │     │       │   │  Sel+Fwd─Fwd━━━━━━━━━━━━━━━━━━━━━━│┛││"
+        );
+        assert_eq!(
+            lines[27],
+            "  Stat───┴───┐   │   │ │   │     ├━━━━━━━━━━##PC###      │ │"
+        );
+        // forwarding from E is not fired
+        assert_eq!(
+            lines[10],
+            "   │     │       │      ├──────│─────│────│────────────┐││┃││"
+        );
+        // the values fit the placeholders, unknown ones are `?`
+        assert_eq!(
+            lines.last().unwrap().trim_end(),
+            "PC 0x17                valA ?                   valB ?"
+        );
+
+        let colored = diagram.render(&fired, value, true);
+        assert!(colored.contains("\x1b["));
+        assert!(!diagram.render(&[], value, true).contains("\x1b["));
     }
 }
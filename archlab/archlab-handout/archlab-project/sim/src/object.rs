@@ -178,6 +178,14 @@ impl SourceInfo {
             .position(|x| x.addr == Some(addr))
             .map(|x| x as i64 + 1)
     }
+
+    /// Get the line of the instruction at `addr`, skipping label-only lines
+    /// of the same address.
+    pub fn get_inst_by_addr(&self, addr: u64) -> Option<&LineInfo> {
+        self.0
+            .iter()
+            .find(|x| x.addr == Some(addr) && x.inst.is_some())
+    }
 }
 
 /// object file with source info.
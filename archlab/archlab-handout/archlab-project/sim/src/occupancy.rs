@@ -0,0 +1,360 @@
+//! Pipeline occupancy chart, as drawn in CS:APP: instructions down the side,
+//! cycles across the top, and the stage of each instruction in each cycle.
+//!
+//! The chart is reconstructed from what a [`CpuSim`] already reports after
+//! each cycle: the fetched PC and the [`PerfCounters`]. The per-stage bubble
+//! and stall counters tell where bubbles are inserted and which stages hold
+//! their instruction, so every instruction can be followed through the
+//! pipeline registers. The same controls annotate the cycles with the
+//! hazards handled in them (e.g. `d.stall, e.bubble` for a load/use hazard):
+//! every architecture drives them, unlike the counters it defines with
+//! `@count`.
+//!
+//! The stages are not read back through [`CpuSim::signal`]: the pipeline
+//! registers of a CS:APP pipeline carry no instruction address after fetch
+//! (`D.valP` is the next one), and their fields are named differently in
+//! every architecture. Following the bubble and stall inputs is what the
+//! pipeline registers do themselves, so the chart is exact for a pipeline
+//! whose registers are all declared as stages and are controlled only by
+//! `bubble` and `stall`. It goes wrong in two cases, which the builtin
+//! architectures avoid:
+//!
+//! - an instruction cancelled by rewriting its fields (e.g. turning it into
+//!   a `nop`) instead of a bubble is shown as completing,
+//! - a PC that doesn't come from the fetch stage (see
+//!   `#![program_counter = ...]`) labels the rows with other addresses.
+
+use crate::framework::{CpuSim, MemData, PerfCounters};
+use crate::SourceInfo;
+
+/// A cell of the chart: the stage an instruction occupies in a cycle.
+#[derive(Debug, Clone)]
+pub struct Cell {
+    pub cycle: u64,
+    pub stage: &'static str,
+    /// The stage is stalled, so the instruction stays in it for the next
+    /// cycle.
+    pub stalled: bool,
+}
+
+/// A row of the chart.
+#[derive(Debug, Clone)]
+pub struct Row {
+    /// Address of the instruction, or `None` for a bubble.
+    pub pc: Option<u64>,
+    pub cells: Vec<Cell>,
+    /// The instruction is cancelled before leaving the pipeline, e.g. after
+    /// a mispredicted branch.
+    pub squashed: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct OccupancyChart {
+    /// Stage names, from the first to the last stage.
+    stages: Vec<&'static str>,
+    /// Row in each stage, `slots[0]` is the instruction being fetched.
+    slots: Vec<Option<usize>>,
+    pub rows: Vec<Row>,
+    /// Stage controls set in each cycle, e.g. `d.stall`.
+    pub events: Vec<(u64, Vec<String>)>,
+    last: PerfCounters,
+    cycles: u64,
+}
+
+impl OccupancyChart {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run `sim`, whose data memory is `mem`, until it terminates, the
+    /// program writes an exit code or `max_cycles` cycles are simulated,
+    /// recording every cycle.
+    pub fn trace(sim: &mut dyn CpuSim, mem: &MemData, max_cycles: u64) -> Self {
+        let mut chart = Self::new();
+        for _ in 0..max_cycles {
+            sim.propagate_signals();
+            chart.record(sim.program_counter(), sim.counters());
+            if sim.is_terminate() || mem.exit_code().is_some() {
+                break;
+            }
+            sim.initiate_next_cycle();
+        }
+        chart
+    }
+
+    fn new_row(&mut self, pc: Option<u64>) -> usize {
+        self.rows.push(Row {
+            pc,
+            cells: Vec::new(),
+            squashed: false,
+        });
+        self.rows.len() - 1
+    }
+
+    /// Record a cycle, given the PC fetched in it and the counters at its
+    /// end.
+    pub fn record(&mut self, pc: u64, counters: &PerfCounters) {
+        self.cycles += 1;
+        let cycle = self.cycles;
+        if self.stages.is_empty() {
+            self.stages = counters.stages.iter().map(|s| s.name).collect();
+            self.slots = vec![None; self.stages.len().max(1)];
+        }
+
+        // bubble and stall inputs computed in this cycle
+        let control = |i: usize| {
+            let cur = &counters.stages[i];
+            let (b, s) = self
+                .last
+                .stages
+                .get(i)
+                .map_or((0, 0), |l| (l.bubbles, l.stalls));
+            (cur.bubbles > b, cur.stalls > s)
+        };
+        let controls: Vec<(bool, bool)> = (0..self.stages.len()).map(control).collect();
+
+        // a stalled fetch stage fetches the same instruction again
+        if self.slots[0].is_none() {
+            self.slots[0] = Some(self.new_row(Some(pc)));
+        }
+        for (i, slot) in self.slots.iter().enumerate() {
+            if let Some(r) = *slot {
+                self.rows[r].cells.push(Cell {
+                    cycle,
+                    stage: self.stages.get(i).copied().unwrap_or("F"),
+                    stalled: controls.get(i).is_some_and(|c| c.1),
+                });
+            }
+        }
+
+        // move the instructions to the next stage at the end of the cycle
+        let mut next = vec![None; self.slots.len()];
+        for i in (0..self.slots.len()).rev() {
+            let (bubble, stall) = controls.get(i).copied().unwrap_or_default();
+            next[i] = if bubble {
+                if i == 0 {
+                    None
+                } else {
+                    Some(self.new_row(None))
+                }
+            } else if stall {
+                self.slots[i]
+            } else if i > 0 {
+                self.slots[i - 1]
+            } else {
+                None
+            };
+        }
+        for i in 0..self.slots.len() - 1 {
+            if let Some(r) = self.slots[i] {
+                if next[i] != Some(r) && next[i + 1] != Some(r) {
+                    self.rows[r].squashed = true;
+                }
+            }
+        }
+        self.slots = next;
+
+        let mut fired = Vec::new();
+        for (name, &(bubble, stall)) in self.stages.iter().zip(&controls) {
+            if stall {
+                fired.push(format!("{name}.stall"));
+            }
+            if bubble {
+                fired.push(format!("{name}.bubble"));
+            }
+        }
+        if !fired.is_empty() {
+            self.events.push((cycle, fired));
+        }
+        self.last = counters.clone();
+    }
+
+    /// Number of recorded cycles.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    fn label(row: &Row, source: Option<&SourceInfo>) -> String {
+        let Some(pc) = row.pc else {
+            return "bubble".to_string();
+        };
+        let inst = source
+            .and_then(|s| s.get_inst_by_addr(pc))
+            .map(|line| line.src.trim().to_string())
+            .unwrap_or_default();
+        format!("{:#05x}: {}", pc, inst).trim_end().to_string()
+    }
+
+    fn cell_text(cell: &Cell) -> String {
+        let stage = cell.stage.to_uppercase();
+        if cell.stalled {
+            format!("{stage}*")
+        } else {
+            stage
+        }
+    }
+
+    fn row_cells(row: &Row, cycles: u64) -> Vec<String> {
+        let mut cells = vec![String::new(); cycles as usize];
+        for cell in &row.cells {
+            cells[cell.cycle as usize - 1] = Self::cell_text(cell);
+        }
+        if row.squashed {
+            if let Some(last) = row.cells.last() {
+                if (last.cycle as usize) < cells.len() {
+                    cells[last.cycle as usize] = "x".to_string();
+                }
+            }
+        }
+        cells
+    }
+
+    /// Render the chart as plain text. `*` marks a stalled stage and `x` a
+    /// squashed instruction.
+    pub fn render_text(&self, source: Option<&SourceInfo>) -> String {
+        let labels = self
+            .rows
+            .iter()
+            .map(|row| Self::label(row, source))
+            .collect::<Vec<_>>();
+        let width = labels.iter().map(|l| l.len()).max().unwrap_or(0).min(40);
+
+        let mut out = format!("{:width$} |", "");
+        for cycle in 1..=self.cycles {
+            out += &format!("{cycle:>4}");
+        }
+        out.push('\n');
+        for (row, label) in self.rows.iter().zip(labels) {
+            let label: String = label.chars().take(width).collect();
+            out += &format!("{label:width$} |");
+            for cell in Self::row_cells(row, self.cycles) {
+                out += &format!("{cell:>4}");
+            }
+            out.push('\n');
+        }
+        for (cycle, names) in &self.events {
+            out += &format!("cycle {cycle}: {}\n", names.join(", "));
+        }
+        out
+    }
+
+    /// Render the chart as a standalone HTML table.
+    pub fn render_html(&self, source: Option<&SourceInfo>) -> String {
+        let escape = |s: &str| {
+            s.replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+        };
+        let mut out = String::from(
+            "<table style=\"border-collapse: collapse; font-family: monospace\">\n<tr><th></th>",
+        );
+        for cycle in 1..=self.cycles {
+            let title = self
+                .events
+                .iter()
+                .find(|(c, _)| *c == cycle)
+                .map(|(_, names)| format!(" title=\"{}\" style=\"color: red\"", names.join(", ")))
+                .unwrap_or_default();
+            out += &format!("<th{title}>{cycle}</th>");
+        }
+        out += "</tr>\n";
+        for row in &self.rows {
+            let color = if row.pc.is_none() { "gray" } else { "black" };
+            out += &format!(
+                "<tr style=\"color: {color}\"><td style=\"text-align: left; padding-right: 1em\">{}</td>",
+                escape(&Self::label(row, source))
+            );
+            for cell in Self::row_cells(row, self.cycles) {
+                let style = match cell.as_str() {
+                    "" => "",
+                    "x" => " style=\"background: #fcc\"",
+                    c if c.ends_with('*') => " style=\"background: #ffc\"",
+                    _ => " style=\"background: #cdf\"",
+                };
+                out += &format!("<td{style}>{cell}</td>");
+            }
+            out += "</tr>\n";
+        }
+        out += "</table>\n";
+        out
+    }
+
+    /// Render the chart as CSV, one row per instruction and one column per
+    /// cycle.
+    pub fn render_csv(&self, source: Option<&SourceInfo>) -> String {
+        let quote = |s: &str| format!("\"{}\"", s.replace('"', "\"\""));
+        let mut out = String::from("instruction");
+        for cycle in 1..=self.cycles {
+            out += &format!(",{cycle}");
+        }
+        out.push('\n');
+        for row in &self.rows {
+            out += &quote(&Self::label(row, source));
+            for cell in Self::row_cells(row, self.cycles) {
+                out += &format!(",{cell}");
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counters(controls: &[(bool, bool)], prev: &PerfCounters) -> PerfCounters {
+        let mut c = prev.clone();
+        for (name, &(bubble, stall)) in ["f", "d", "e"].into_iter().zip(controls) {
+            c.count_stage(name, bubble, stall);
+        }
+        c
+    }
+
+    #[test]
+    fn test_occupancy_stall_and_squash() {
+        let mut chart = OccupancyChart::new();
+        let mut c = PerfCounters::default();
+        // cycle 1: fetch 0x0, cycle 2: fetch 0x2, stall F and D, bubble E
+        for (pc, controls) in [
+            (0x0, [(false, false); 3]),
+            (0x2, [(false, true), (false, true), (true, false)]),
+            (0x2, [(false, false), (true, false), (false, false)]),
+            (0x4, [(false, false); 3]),
+        ] {
+            c = counters(&controls, &c);
+            chart.record(pc, &c);
+        }
+        let cells = |r: usize| {
+            chart.rows[r]
+                .cells
+                .iter()
+                .map(OccupancyChart::cell_text)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(chart.rows[0].pc, Some(0x0));
+        assert_eq!(cells(0), ["F", "D*", "D", "E"]);
+        assert_eq!(chart.rows[1].pc, Some(0x2));
+        assert_eq!(cells(1), ["F*", "F"]);
+        // 0x2 is squashed by the bubble in D
+        assert!(chart.rows[1].squashed);
+        assert_eq!(chart.rows[2].pc, None);
+        assert_eq!(cells(2), ["E"]);
+        assert!(chart.render_csv(None).starts_with("instruction,1,2,3,4\n"));
+
+        let text = chart.render_text(None);
+        assert_eq!(
+            text.lines().map(str::trim_end).collect::<Vec<_>>(),
+            [
+                "       |   1   2   3   4",
+                "0x000: |   F  D*   D   E",
+                "0x002: |      F*   F   x",
+                "bubble |           E",
+                "bubble |               D",
+                "0x004: |               F",
+                "cycle 2: f.stall, d.stall, e.bubble",
+                "cycle 3: d.bubble",
+            ]
+        );
+    }
+}
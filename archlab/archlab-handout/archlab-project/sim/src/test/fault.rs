@@ -0,0 +1,559 @@
+//! Fault injection campaigns.
+//!
+//! A [`Fault`] flips a bit of a signal at the start of a cycle, usually a
+//! state element such as a pipeline register field (`E.valA`) or a register
+//! of the register file (`reg_file.rax`), or a bit of a memory byte. Each
+//! fault runs in a fresh simulator, and the outcome is compared with the
+//! fault-free (golden) run:
+//!
+//! - masked: the registers, the memory and the exit code are the same as the
+//!   golden run;
+//! - silent data corruption: the program halts with different results;
+//! - trap: the program stops with `Adr` or `Ins`;
+//! - hang: the program doesn't stop within the cycle limit;
+//! - detected: an `@assert` of the architecture fails.
+//!
+//! A fault list has a fault per line, `cycle target bit`, where the target
+//! is a signal name or `mem[addr]`:
+//!
+//! ```text
+//! # flip bit 3 of E.valA at the start of cycle 12
+//! 12 E.valA 3
+//! 40 mem[0x100] 7
+//! ```
+//!
+//! A fault must be in a cycle of the golden run, after which it couldn't be
+//! injected. [`Campaign::random`] draws the faults from the state elements
+//! instead.
+
+use anyhow::{bail, Context};
+
+use super::random::Rng;
+use crate::{
+    architectures::create_sim,
+    framework::{CpuSim, MemData, SignalKind, SignalValue, MEM_SIZE},
+    isa::{RegFile, Stat},
+    utils::parse_literal,
+    AssembleOption,
+};
+
+/// What a fault flips.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    /// A signal, see [`CpuSim::signal_names`].
+    Signal(String),
+    /// A byte of the memory.
+    Memory(u64),
+}
+
+impl Target {
+    /// The stage (or unit) of the target, e.g. `E` for `E.valA`, under which
+    /// the outcomes are summarized.
+    pub fn group(&self) -> &str {
+        match self {
+            Target::Signal(name) => name.split('.').next().unwrap_or(name),
+            Target::Memory(_) => "mem",
+        }
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Signal(name) => write!(f, "{name}"),
+            Target::Memory(addr) => write!(f, "mem[{addr:#x}]"),
+        }
+    }
+}
+
+/// A bit flip injected at the start of a cycle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fault {
+    /// counted from 1
+    pub cycle: u64,
+    pub target: Target,
+    pub bit: u32,
+}
+
+impl std::fmt::Display for Fault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} {}", self.cycle, self.target, self.bit)
+    }
+}
+
+impl std::str::FromStr for Fault {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let [cycle, target, bit] = s.split_whitespace().collect::<Vec<_>>()[..] else {
+            bail!("expected `cycle target bit`, found `{s}`");
+        };
+        let cycle = parse_literal(cycle)
+            .filter(|&c| c > 0)
+            .with_context(|| format!("invalid cycle `{cycle}`"))?;
+        let bit = bit
+            .parse()
+            .with_context(|| format!("invalid bit `{bit}`"))?;
+        let target = match target
+            .strip_prefix("mem[")
+            .and_then(|t| t.strip_suffix(']'))
+        {
+            Some(addr) => Target::Memory(
+                parse_literal(addr).with_context(|| format!("invalid address `{addr}`"))?,
+            ),
+            None => Target::Signal(target.to_string()),
+        };
+        Ok(Self { cycle, target, bit })
+    }
+}
+
+/// Parse a fault list, see the [module documentation](self).
+pub fn parse_faults(src: &str) -> anyhow::Result<Vec<Fault>> {
+    src.lines()
+        .enumerate()
+        .map(|(i, line)| (i, line.split('#').next().unwrap_or_default().trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(i, line)| line.parse().with_context(|| format!("line {}", i + 1)))
+        .collect()
+}
+
+/// Outcome of a faulty run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Masked,
+    SilentDataCorruption,
+    /// `Adr` or `Ins`
+    Trap(Stat),
+    Hang,
+    /// An `@assert` of the architecture fails.
+    Detected,
+}
+
+/// Configuration of a random campaign.
+#[derive(Debug, Clone)]
+pub struct RandomSpec {
+    pub faults: usize,
+    pub seed: u64,
+    /// Memory bytes that may be flipped besides the state elements.
+    pub memory: Option<std::ops::Range<u64>>,
+}
+
+/// Outcomes of a campaign.
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub golden_cycles: u64,
+    pub runs: Vec<(Fault, Outcome)>,
+}
+
+/// Number of runs of each outcome.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Tally {
+    pub masked: u64,
+    pub sdc: u64,
+    pub trap: u64,
+    pub hang: u64,
+    pub detected: u64,
+}
+
+impl Tally {
+    fn add(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Masked => self.masked += 1,
+            Outcome::SilentDataCorruption => self.sdc += 1,
+            Outcome::Trap(_) => self.trap += 1,
+            Outcome::Hang => self.hang += 1,
+            Outcome::Detected => self.detected += 1,
+        }
+    }
+
+    pub fn runs(&self) -> u64 {
+        self.masked + self.sdc + self.trap + self.hang + self.detected
+    }
+}
+
+impl Report {
+    fn tally(&self, key: impl Fn(&Fault) -> String) -> Vec<(String, Tally)> {
+        let mut rows: Vec<(String, Tally)> = Vec::new();
+        for (fault, outcome) in &self.runs {
+            let key = key(fault);
+            match rows.iter_mut().find(|(k, _)| *k == key) {
+                Some((_, tally)) => tally.add(*outcome),
+                None => {
+                    let mut tally = Tally::default();
+                    tally.add(*outcome);
+                    rows.push((key, tally));
+                }
+            }
+        }
+        rows
+    }
+
+    /// Outcomes per target, memory bytes counted as `mem`, in the order of
+    /// the faults.
+    pub fn by_signal(&self) -> Vec<(String, Tally)> {
+        self.tally(|fault| match fault.target {
+            Target::Memory(_) => "mem".to_string(),
+            _ => fault.target.to_string(),
+        })
+    }
+
+    /// Outcomes per stage (or unit), see [`Target::group`].
+    pub fn by_stage(&self) -> Vec<(String, Tally)> {
+        self.tally(|fault| fault.target.group().to_string())
+    }
+}
+
+fn write_table(
+    f: &mut std::fmt::Formatter<'_>,
+    title: &str,
+    rows: Vec<(String, Tally)>,
+) -> std::fmt::Result {
+    writeln!(
+        f,
+        "{:<16}{:>8}{:>8}{:>8}{:>8}{:>8}{:>10}",
+        title, "runs", "masked", "sdc", "trap", "hang", "detected"
+    )?;
+    for (name, t) in rows {
+        writeln!(
+            f,
+            "{:<16}{:>8}{:>8}{:>8}{:>8}{:>8}{:>10}",
+            name,
+            t.runs(),
+            t.masked,
+            t.sdc,
+            t.trap,
+            t.hang,
+            t.detected
+        )?;
+    }
+    Ok(())
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{:<16}{:>8}", "golden cycles", self.golden_cycles)?;
+        write_table(f, "signal", self.by_signal())?;
+        write_table(f, "stage", self.by_stage())
+    }
+}
+
+/// The fault-free run.
+struct Golden {
+    bin: [u8; MEM_SIZE],
+    cycles: u64,
+    regs: RegFile,
+    mem: [u8; MEM_SIZE],
+    exit_code: Option<u64>,
+    stat: Option<Stat>,
+    /// state elements that can be flipped, with their widths
+    targets: Vec<(String, u32)>,
+}
+
+/// A fault injection campaign on a program.
+#[derive(Debug, Clone)]
+pub struct Campaign {
+    pub arch: String,
+    /// Signals holding the status of the machine, the first that exists is
+    /// read when the simulation stops (`prog_stat` of `pipe_std`, `stat` of
+    /// `seq_std`).
+    pub stat_signals: Vec<String>,
+    /// Cycle limit of the golden run.
+    pub max_cycles: u64,
+    /// A faulty run hangs after `hang_factor` times the golden cycles.
+    pub hang_factor: u64,
+}
+
+impl Campaign {
+    pub fn new(arch: &str) -> Self {
+        Self {
+            arch: arch.to_string(),
+            stat_signals: vec!["prog_stat".to_string(), "stat".to_string()],
+            max_cycles: 1 << 20,
+            hang_factor: 4,
+        }
+    }
+
+    fn stat(&self, sim: &dyn CpuSim) -> Option<Stat> {
+        self.stat_signals
+            .iter()
+            .find_map(|name| match sim.signal(name) {
+                Some(SignalValue::Stat(stat)) => Some(stat),
+                _ => None,
+            })
+    }
+
+    fn golden(&self, src: &str) -> anyhow::Result<Golden> {
+        let bin = crate::assemble(src, AssembleOption::default())?
+            .obj
+            .init_mem();
+        let mem = MemData::init(bin);
+        let mut sim = create_sim(self.arch.clone(), mem.clone(), false);
+        let targets = sim
+            .get_stage_info()
+            .into_iter()
+            .flat_map(|stage| stage.signals)
+            .filter(|sig| matches!(sig.kind, SignalKind::StageField | SignalKind::UnitState))
+            .map(|sig| (sig.name, sig.value.width()))
+            .filter(|(_, width)| *width > 0)
+            .collect();
+        while !sim.is_terminate() && mem.exit_code().is_none() {
+            if sim.cycle_count() >= self.max_cycles {
+                bail!("the program doesn't stop in {} cycles", self.max_cycles);
+            }
+            sim.step()?;
+        }
+        let exit_code = mem.exit_code();
+        let mem = *mem.read();
+        Ok(Golden {
+            bin,
+            cycles: sim.cycle_count(),
+            regs: sim.registers(),
+            mem,
+            exit_code,
+            stat: self.stat(sim.as_ref()),
+            targets,
+        })
+    }
+
+    fn inject(&self, golden: &Golden, fault: &Fault) -> anyhow::Result<Outcome> {
+        if fault.cycle > golden.cycles {
+            bail!(
+                "cycle {} is after the end of the golden run ({} cycles)",
+                fault.cycle,
+                golden.cycles
+            );
+        }
+        let mem = MemData::init(golden.bin);
+        let mut sim = create_sim(self.arch.clone(), mem.clone(), false);
+        let max_cycles = golden.cycles * self.hang_factor;
+        while !sim.is_terminate() && mem.exit_code().is_none() {
+            if sim.cycle_count() >= max_cycles {
+                return Ok(Outcome::Hang);
+            }
+            if sim.cycle_count() + 1 == fault.cycle {
+                match &fault.target {
+                    Target::Signal(name) => {
+                        let value = sim
+                            .signal(name)
+                            .with_context(|| format!("no signal named `{name}`"))?;
+                        let flipped = value.flip_bit(fault.bit).with_context(|| {
+                            format!("`{name}` has no bit {} to flip", fault.bit)
+                        })?;
+                        sim.set_signal(name, flipped)?;
+                    }
+                    Target::Memory(addr) => {
+                        if *addr >= MEM_SIZE as u64 || fault.bit >= 8 {
+                            bail!("can't flip bit {} of mem[{addr:#x}]", fault.bit);
+                        }
+                        mem.write()[*addr as usize] ^= 1 << fault.bit;
+                    }
+                }
+            }
+            if sim.step().is_err() {
+                return Ok(Outcome::Detected);
+            }
+        }
+
+        let stat = self.stat(sim.as_ref());
+        if stat != golden.stat {
+            if let Some(stat @ (Stat::Adr | Stat::Ins)) = stat {
+                return Ok(Outcome::Trap(stat));
+            }
+        }
+        if sim.registers() == golden.regs
+            && *mem.read() == golden.mem
+            && mem.exit_code() == golden.exit_code
+        {
+            Ok(Outcome::Masked)
+        } else {
+            Ok(Outcome::SilentDataCorruption)
+        }
+    }
+
+    fn run_golden(&self, golden: &Golden, faults: &[Fault]) -> anyhow::Result<Report> {
+        let runs = faults
+            .iter()
+            .map(|fault| {
+                let outcome = self
+                    .inject(golden, fault)
+                    .with_context(|| format!("fault `{fault}`"))?;
+                Ok((fault.clone(), outcome))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Report {
+            golden_cycles: golden.cycles,
+            runs,
+        })
+    }
+
+    /// Inject each of `faults` in a run of `src`.
+    pub fn run(&self, src: &str, faults: &[Fault]) -> anyhow::Result<Report> {
+        let golden = self.golden(src)?;
+        self.run_golden(&golden, faults)
+    }
+
+    /// Inject faults drawn at random: a bit of a pipeline register field,
+    /// of a unit state (or of a memory byte in `spec.memory`) in a cycle of
+    /// the golden run. The same `spec` gives the same faults.
+    pub fn random(&self, src: &str, spec: &RandomSpec) -> anyhow::Result<Report> {
+        let golden = self.golden(src)?;
+        let mut targets: Vec<(Target, u32)> = golden
+            .targets
+            .iter()
+            .map(|(name, width)| (Target::Signal(name.clone()), *width))
+            .collect();
+        if let Some(range) = &spec.memory {
+            targets.extend(range.clone().map(|addr| (Target::Memory(addr), 8)));
+        }
+        if targets.is_empty() {
+            bail!("{} has no state element to flip", self.arch);
+        }
+        if golden.cycles == 0 {
+            bail!("the golden run has no cycle to inject a fault in");
+        }
+
+        let mut rng = Rng::new(spec.seed);
+        let faults: Vec<Fault> = (0..spec.faults)
+            .map(|_| {
+                let (target, width) = &targets[rng.below(targets.len() as u64) as usize];
+                Fault {
+                    cycle: rng.below(golden.cycles) + 1,
+                    target: target.clone(),
+                    bit: rng.below(*width as u64) as u32,
+                }
+            })
+            .collect();
+        self.run_golden(&golden, &faults)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_faults() {
+        let faults = parse_faults(
+            "\
+# comment
+12 E.valA 3
+0x28 mem[0x100] 7   # hex
+",
+        )
+        .unwrap();
+        assert_eq!(
+            faults,
+            [
+                Fault {
+                    cycle: 12,
+                    target: Target::Signal("E.valA".to_string()),
+                    bit: 3,
+                },
+                Fault {
+                    cycle: 40,
+                    target: Target::Memory(0x100),
+                    bit: 7,
+                },
+            ]
+        );
+        assert_eq!(faults[1].to_string(), "40 mem[0x100] 7");
+        assert_eq!(faults[0].target.group(), "E");
+
+        let err = parse_faults("1 E.valA\n").unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "line 1: expected `cycle target bit`, found `1 E.valA`"
+        );
+        assert!(parse_faults("0 E.valA 1").is_err());
+    }
+
+    #[test]
+    fn test_report() {
+        let fault = |s: &str| s.parse::<Fault>().unwrap();
+        let report = Report {
+            golden_cycles: 8,
+            runs: vec![
+                (fault("1 E.valC 3"), Outcome::Masked),
+                (fault("1 mem[0x14] 7"), Outcome::Trap(Stat::Ins)),
+                (fault("1 mem[0x800] 0"), Outcome::SilentDataCorruption),
+                (fault("2 E.valA 0"), Outcome::Hang),
+                (fault("3 reg_file.rax 1"), Outcome::Detected),
+            ],
+        };
+        assert_eq!(
+            report.to_string(),
+            "\
+golden cycles          8
+signal              runs  masked     sdc    trap    hang  detected
+E.valC                 1       1       0       0       0         0
+mem                    2       0       1       1       0         0
+E.valA                 1       0       0       0       1         0
+reg_file.rax           1       0       0       0       0         1
+stage               runs  masked     sdc    trap    hang  detected
+E                      2       1       0       0       1         0
+mem                    2       0       1       1       0         0
+reg_file               1       0       0       0       0         1
+"
+        );
+    }
+
+    #[test]
+    fn test_campaign() {
+        let src = "\
+    irmovq $5, %rax
+    irmovq $8, %rbx
+    addq %rax, %rbx
+    halt
+";
+        let campaign = Campaign::new("pipe_std");
+        let faults = parse_faults(
+            "\
+# the memory is not the same at the end
+1 mem[0x800] 0
+# E holds a bubble in the first cycle
+1 E.valC 3
+# `addq` (0x60) at 0x14 becomes 0xe0
+1 mem[0x14] 7
+",
+        )
+        .unwrap();
+        let report = campaign.run(src, &faults).unwrap();
+        let outcomes = report.runs.iter().map(|(_, o)| *o).collect::<Vec<_>>();
+        assert_eq!(
+            outcomes,
+            [
+                Outcome::SilentDataCorruption,
+                Outcome::Masked,
+                Outcome::Trap(Stat::Ins),
+            ]
+        );
+        let table = report.to_string();
+        let rows = table.lines().collect::<Vec<_>>();
+        assert_eq!(
+            rows[1..4],
+            [
+                "signal              runs  masked     sdc    trap    hang  detected",
+                "mem                    2       0       1       1       0         0",
+                "E.valC                 1       1       0       0       0         0",
+            ]
+        );
+        // after the program halts
+        let faults = parse_faults("100 E.valA 0").unwrap();
+        let err = campaign.run(src, &faults).unwrap_err();
+        assert!(format!("{err:#}")
+            .starts_with("fault `100 E.valA 0`: cycle 100 is after the end of the golden run"));
+
+        let spec = RandomSpec {
+            faults: 20,
+            seed: 1,
+            memory: None,
+        };
+        let report = campaign.random(src, &spec).unwrap();
+        assert_eq!(report.runs.len(), 20);
+        let total: u64 = report.by_stage().iter().map(|(_, t)| t.runs()).sum();
+        assert_eq!(total, 20);
+        // the same seed draws the same faults
+        let again = campaign.random(src, &spec).unwrap();
+        assert_eq!(again.to_string(), report.to_string());
+    }
+}
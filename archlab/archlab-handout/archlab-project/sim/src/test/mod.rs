@@ -0,0 +1,3 @@
+pub mod fault;
+pub mod random;
+pub mod reduce;
@@ -0,0 +1,435 @@
+//! Random Y86 program generator for differential fuzzing.
+//!
+//! Every generated program is valid and terminates: branches only jump
+//! forward, loops are counted down from a bounded trip count, functions only
+//! call functions with a larger index and every `pushq` is matched by a
+//! `popq` before the enclosing block ends.
+//!
+//! Register usage:
+//!
+//! - `%rsp` is the stack pointer, `%rbp` points to the data area used by
+//!   loads and stores.
+//! - `%r14` is the loop counter. Functions other than `main` save it, so
+//!   calls may appear inside loops.
+//! - All the other registers are freely read and written.
+
+use std::fmt::Write;
+
+/// Relative weights of the instruction kinds. A weight of `0` disables the
+/// kind.
+#[derive(Debug, Clone)]
+pub struct InstMix {
+    /// `opq` (and `iopq` if enabled in [`GenConfig`])
+    pub alu: u32,
+    pub irmovq: u32,
+    /// `rrmovq` and `cmovXX`
+    pub cmov: u32,
+    /// `mrmovq`
+    pub load: u32,
+    /// `rmmovq`
+    pub store: u32,
+    /// `pushq` and `popq`
+    pub stack: u32,
+    /// forward conditional and unconditional jumps
+    pub branch: u32,
+    pub call: u32,
+    /// counted loops
+    pub repeat: u32,
+    pub nop: u32,
+}
+
+impl Default for InstMix {
+    fn default() -> Self {
+        Self {
+            alu: 8,
+            irmovq: 3,
+            cmov: 3,
+            load: 4,
+            store: 4,
+            stack: 2,
+            branch: 2,
+            call: 1,
+            repeat: 1,
+            nop: 1,
+        }
+    }
+}
+
+/// Configuration of the program generator.
+#[derive(Debug, Clone)]
+pub struct GenConfig {
+    /// number of instructions generated for each function body
+    pub length: usize,
+    /// number of functions besides `main`, which bounds the call depth
+    pub functions: usize,
+    pub mix: InstMix,
+    /// source registers are picked from the last `dep_distance` written
+    /// registers. Small values produce more data hazards.
+    pub dep_distance: usize,
+    /// maximum number of loops in each function
+    pub loops: usize,
+    /// maximum trip count of a loop
+    pub max_trip: u64,
+    /// number of quad words in the data area
+    pub data_words: usize,
+    /// whether to generate `iaddq` and friends
+    pub iopq: bool,
+}
+
+impl Default for GenConfig {
+    fn default() -> Self {
+        Self {
+            length: 40,
+            functions: 3,
+            mix: InstMix::default(),
+            dep_distance: 3,
+            loops: 2,
+            max_trip: 5,
+            data_words: 16,
+            iopq: false,
+        }
+    }
+}
+
+/// Registers that can be freely read and written by generated code.
+const REGS: [&str; 12] = [
+    "rax", "rcx", "rdx", "rbx", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13",
+];
+const OPS: [&str; 4] = ["add", "sub", "and", "xor"];
+const CONDS: [&str; 7] = ["", "le", "l", "e", "ne", "ge", "g"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Alu,
+    Irmovq,
+    Cmov,
+    Load,
+    Store,
+    Stack,
+    Branch,
+    Call,
+    Repeat,
+    Nop,
+}
+
+/// xorshift64, good enough for test generation and stable across platforms.
+pub(super) struct Rng(u64);
+
+impl Rng {
+    pub(super) fn new(seed: u64) -> Self {
+        // xorshift gets stuck at zero, and close seeds should diverge quickly
+        Self(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+    /// uniform in `0..n`, `n` must be positive
+    pub(super) fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+    fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+        items[self.below(items.len() as u64) as usize]
+    }
+    fn chance(&mut self, percent: u64) -> bool {
+        self.below(100) < percent
+    }
+}
+
+struct Generator<'a> {
+    config: &'a GenConfig,
+    rng: Rng,
+    out: String,
+    labels: usize,
+    /// indices into [`REGS`] of recently written registers, latest last
+    recent: Vec<usize>,
+}
+
+impl Generator<'_> {
+    fn emit(&mut self, line: impl AsRef<str>) {
+        writeln!(self.out, "    {}", line.as_ref()).unwrap();
+    }
+    fn label(&mut self) -> String {
+        self.labels += 1;
+        format!("L{}", self.labels)
+    }
+    fn imm(&mut self) -> i64 {
+        match self.rng.below(4) {
+            0 => self.rng.below(4) as i64 - 1,
+            1 => self.rng.below(256) as i64 - 128,
+            2 => self.rng.below(1 << 16) as i64,
+            _ => self.rng.next() as i64,
+        }
+    }
+    fn src(&mut self) -> &'static str {
+        let window = self.config.dep_distance.min(self.recent.len());
+        if window == 0 || self.rng.chance(10) {
+            return self.rng.pick(&REGS);
+        }
+        let i = self.recent.len() - 1 - self.rng.below(window as u64) as usize;
+        REGS[self.recent[i]]
+    }
+    fn dst(&mut self) -> &'static str {
+        let i = self.rng.below(REGS.len() as u64) as usize;
+        self.recent.push(i);
+        if self.recent.len() > 64 {
+            self.recent.drain(..32);
+        }
+        REGS[i]
+    }
+    fn mem(&mut self) -> String {
+        format!(
+            "{}(%rbp)",
+            8 * self.rng.below(self.config.data_words as u64)
+        )
+    }
+
+    fn kind(&mut self, allowed: &[Kind]) -> Kind {
+        let mix = &self.config.mix;
+        let weight = |k: &Kind| match k {
+            Kind::Alu => mix.alu,
+            Kind::Irmovq => mix.irmovq,
+            Kind::Cmov => mix.cmov,
+            Kind::Load => mix.load,
+            Kind::Store => mix.store,
+            Kind::Stack => mix.stack,
+            Kind::Branch => mix.branch,
+            Kind::Call => mix.call,
+            Kind::Repeat => mix.repeat,
+            Kind::Nop => mix.nop,
+        } as u64;
+        let total: u64 = allowed.iter().map(weight).sum();
+        if total == 0 {
+            return Kind::Nop;
+        }
+        let mut x = self.rng.below(total);
+        for k in allowed {
+            if x < weight(k) {
+                return *k;
+            }
+            x -= weight(k);
+        }
+        unreachable!()
+    }
+
+    /// Instructions that neither change control flow nor touch the stack.
+    fn simple(&mut self, kind: Kind) {
+        match kind {
+            Kind::Alu if self.config.iopq && self.rng.chance(30) => {
+                let op = self.rng.pick(&OPS);
+                let imm = self.imm();
+                let dst = self.dst();
+                self.emit(format!("i{op}q ${imm}, %{dst}"));
+            }
+            Kind::Alu => {
+                let op = self.rng.pick(&OPS);
+                let src = self.src();
+                let dst = self.dst();
+                self.emit(format!("{op}q %{src}, %{dst}"));
+            }
+            Kind::Irmovq => {
+                let imm = self.imm();
+                let dst = self.dst();
+                self.emit(format!("irmovq ${imm}, %{dst}"));
+            }
+            Kind::Cmov => {
+                let name = match self.rng.pick(&CONDS) {
+                    "" => "rrmovq".to_string(),
+                    c => format!("cmov{c}"),
+                };
+                let src = self.src();
+                let dst = self.dst();
+                self.emit(format!("{name} %{src}, %{dst}"));
+            }
+            Kind::Load => {
+                let mem = self.mem();
+                let dst = self.dst();
+                self.emit(format!("mrmovq {mem}, %{dst}"));
+            }
+            Kind::Store => {
+                let src = self.src();
+                let mem = self.mem();
+                self.emit(format!("rmmovq %{src}, {mem}"));
+            }
+            _ => self.emit("nop"),
+        }
+    }
+
+    /// Generate `len` instructions of function `func`. The stack is balanced
+    /// at the end of the block.
+    fn block(&mut self, len: usize, func: usize, loops: &mut usize) {
+        use Kind::*;
+        let mut pushes = 0;
+        let mut n = 0;
+        while n < len {
+            n += 1;
+            match self.kind(&[
+                Alu, Irmovq, Cmov, Load, Store, Stack, Branch, Call, Repeat, Nop,
+            ]) {
+                Stack if pushes > 0 && self.rng.chance(50) => {
+                    let dst = self.dst();
+                    self.emit(format!("popq %{dst}"));
+                    pushes -= 1;
+                }
+                Stack => {
+                    let src = self.src();
+                    self.emit(format!("pushq %{src}"));
+                    pushes += 1;
+                }
+                Branch => {
+                    // the skipped instructions must not touch the stack
+                    let target = self.label();
+                    let jump = match self.rng.pick(&CONDS) {
+                        "" => "mp",
+                        c => c,
+                    };
+                    self.emit(format!("j{jump} {target}"));
+                    let skip = 1 + self.rng.below(3) as usize;
+                    for _ in 0..skip {
+                        let kind = self.kind(&[Alu, Irmovq, Cmov, Load, Store, Nop]);
+                        self.simple(kind);
+                    }
+                    n += skip;
+                    self.out.push_str(&format!("{target}:\n"));
+                }
+                Call if func < self.config.functions => {
+                    let callee =
+                        func + 1 + self.rng.below((self.config.functions - func) as u64) as usize;
+                    self.emit(format!("call f{callee}"));
+                }
+                Repeat if *loops > 0 && len - n >= 2 => {
+                    *loops -= 1;
+                    let head = self.label();
+                    let trip = 1 + self.rng.below(self.config.max_trip.max(1));
+                    let body = 2 + self.rng.below((len - n).min(6) as u64 - 1) as usize;
+                    self.emit(format!("irmovq ${trip}, %r14"));
+                    self.out.push_str(&format!("{head}:\n"));
+                    // nested loops would clobber the counter
+                    let mut inner = 0;
+                    self.block(body, func, &mut inner);
+                    let one = self.dst();
+                    self.emit(format!("irmovq $1, %{one}"));
+                    self.emit(format!("subq %{one}, %r14"));
+                    self.emit(format!("jne {head}"));
+                    n += body;
+                }
+                kind => self.simple(kind),
+            }
+        }
+        for _ in 0..pushes {
+            let dst = self.dst();
+            self.emit(format!("popq %{dst}"));
+        }
+    }
+
+    fn function(&mut self, func: usize) {
+        let name = if func == 0 {
+            "main".to_string()
+        } else {
+            format!("f{func}")
+        };
+        self.out.push_str(&format!("\n{name}:\n"));
+        if func > 0 {
+            self.emit("pushq %r14");
+        }
+        let mut loops = self.config.loops;
+        self.block(self.config.length, func, &mut loops);
+        if func > 0 {
+            self.emit("popq %r14");
+        }
+        self.emit("ret");
+    }
+}
+
+/// Generate the source of a random Y86 program. The same `config` and `seed`
+/// always produce the same program.
+pub fn generate(config: &GenConfig, seed: u64) -> String {
+    let mut g = Generator {
+        config,
+        rng: Rng::new(seed),
+        out: String::new(),
+        labels: 0,
+        recent: Vec::new(),
+    };
+    writeln!(g.out, "# generated with seed {seed}").unwrap();
+    g.out.push_str(".pos 0\n");
+    g.emit("irmovq stack, %rsp");
+    g.emit("irmovq data, %rbp");
+    for reg in REGS {
+        let imm = g.imm();
+        g.emit(format!("irmovq ${imm}, %{reg}"));
+    }
+    g.emit("call main");
+    g.emit("halt");
+
+    for func in 0..=config.functions {
+        g.function(func);
+    }
+
+    g.out.push_str("\n.align 8\ndata:\n");
+    for _ in 0..config.data_words.max(1) {
+        let imm = g.imm();
+        g.emit(format!(".quad {imm}"));
+    }
+    // each frame holds at most one word per instruction, the return address
+    // and the saved loop counter
+    let stack_words = (config.functions + 1) * (config.length + 2) + 8;
+    g.out.push_str("\n.align 8\n");
+    for _ in 0..stack_words {
+        g.emit(".quad 0");
+    }
+    g.out.push_str("stack:\n");
+    g.out
+}
+
+impl super::SimTester {
+    /// Run `n` random programs generated from seeds `seed..seed + n` and
+    /// compare the architecture against the ISA simulator.
+    ///
+    /// The source of each failing program is saved as `fuzz-<seed>.ys`. The
+    /// failing seeds are returned.
+    pub fn fuzz(&self, n: u64, seed: u64) -> Vec<u64> {
+        self.fuzz_with(&GenConfig::default(), n, seed)
+    }
+
+    /// Same as [`Self::fuzz`], with a custom generator configuration.
+    pub fn fuzz_with(&self, config: &GenConfig, n: u64, seed: u64) -> Vec<u64> {
+        let mut failed = Vec::new();
+        for seed in seed..seed + n {
+            let src = generate(config, seed);
+            if let Err(e) = self.test_isa(&src) {
+                tracing::warn!("fuzz seed {seed} failed: {e:#}");
+                let path = format!("fuzz-{seed}.ys");
+                if let Err(e) = std::fs::write(&path, &src) {
+                    tracing::error!("failed to save {path}: {e}");
+                }
+                failed.push(seed);
+            }
+        }
+        failed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate() {
+        let config = GenConfig::default();
+        let a = generate(&config, 42);
+        assert_eq!(a, generate(&config, 42));
+        assert_ne!(a, generate(&config, 43));
+        assert!(a.contains("call main"));
+
+        let r = crate::assemble(&a, crate::AssembleOption::default()).unwrap();
+        let res = crate::isa::simulate(r.obj.init_mem(), false).unwrap();
+        // the stack is balanced when the program halts
+        assert_eq!(
+            res.regs[crate::isa::reg_code::RSP as usize],
+            r.obj.symbols["stack"]
+        );
+    }
+}
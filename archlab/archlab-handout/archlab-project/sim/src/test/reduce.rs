@@ -0,0 +1,437 @@
+//! Test-case minimisation for failing architecture checks.
+//!
+//! [`reduce`] shrinks a program on which an architecture disagrees with the
+//! ISA simulator by delta debugging: it repeatedly removes lines
+//! (instructions and data) and decreases immediates, as long as the
+//! disagreement persists.
+//!
+//! The disagreement is located by [`diverge`], which runs the program on the
+//! architecture cycle by cycle. Every change of the register file or the
+//! memory must be the next state change of the ISA simulator, otherwise the
+//! changed register or memory word is reported.
+
+use crate::{
+    architectures::create_sim,
+    framework::{CpuSim, MemData, MEM_SIZE},
+    isa::{self, reg_code, RegFile},
+    utils::{get_u64, parse_literal, put_u64},
+    AssembleOption,
+};
+
+/// Maximum number of instructions executed by the ISA simulator.
+const MAX_INSTS: u64 = 1 << 20;
+/// Maximum cycles per instruction of the architecture.
+const MAX_CPI: u64 = 64;
+/// Number of ISA state changes that may be observed in a single cycle.
+const LOOKAHEAD: usize = 4;
+
+/// The first point where an architecture disagrees with the ISA simulator.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// cycle in which the divergence is observed, starting from 1
+    pub cycle: u64,
+    /// program counter of the architecture in that cycle
+    pub pc: u64,
+    /// the diverging state: a register (`%rax`), a memory word
+    /// (`mem[0x1f8]`) or `terminate`
+    pub signal: String,
+    pub actual: String,
+    pub expected: String,
+}
+
+impl std::fmt::Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "cycle {} (pc = {:#x}): {} = {}, expected {}",
+            self.cycle, self.pc, self.signal, self.actual, self.expected
+        )
+    }
+}
+
+fn reg_divergence(actual: &RegFile, expected: &RegFile) -> Option<(String, String, String)> {
+    let r = (0..actual.len()).find(|&r| actual[r] != expected[r])?;
+    Some((
+        format!("%{}", reg_code::name_of(r as u8).to_lowercase()),
+        format!("{:#x}", actual[r]),
+        format!("{:#x}", expected[r]),
+    ))
+}
+
+fn mem_divergence(
+    actual: &[u8; MEM_SIZE],
+    expected: &[u8; MEM_SIZE],
+) -> Option<(String, String, String)> {
+    let addr = (0..MEM_SIZE).find(|&i| actual[i] != expected[i])? & !7;
+    let end = (addr + 8).min(MEM_SIZE);
+    Some((
+        format!("mem[{addr:#x}]"),
+        format!("{:#x}", get_u64(&actual[addr..end])),
+        format!("{:#x}", get_u64(&expected[addr..end])),
+    ))
+}
+
+/// Run `src` on the architecture `arch` and on the ISA simulator, and return
+/// the first divergence, if any.
+///
+/// It fails if the program can't be assembled or the ISA simulator fails to
+/// execute it. The cycle counter and the timer count retired instructions on
+/// both (see [`Devices`](crate::framework::Devices)), so a program using them
+/// is compared as any other.
+pub fn diverge(arch: &str, src: &str) -> anyhow::Result<Option<Divergence>> {
+    diverge_within(&simulator(arch), src, MAX_INSTS)
+}
+
+/// A simulator of `arch` on a memory image.
+type NewSim<'a> = dyn Fn(MemData) -> Box<dyn CpuSim> + 'a;
+
+fn simulator(arch: &str) -> impl Fn(MemData) -> Box<dyn CpuSim> + '_ {
+    move |mem| create_sim(arch.to_string(), mem, false)
+}
+
+fn diverge_within(
+    new_sim: &NewSim,
+    src: &str,
+    max_insts: u64,
+) -> anyhow::Result<Option<Divergence>> {
+    let obj = crate::assemble(src, AssembleOption::default())?.obj;
+    let bin = obj.init_mem();
+
+    // distinct register files and memory writes that change the memory, in
+    // program order
+    let mut regs = vec![RegFile::default()];
+    let mut writes = Vec::new();
+    let mut isa_mem = bin;
+    let std = isa::simulate_trace(bin, max_insts, |effect| {
+        if regs.last() != Some(&effect.regs) {
+            regs.push(effect.regs);
+        }
+        if let Some((addr, val)) = effect.mem_write {
+            if get_u64(&isa_mem[addr..(addr + 8)]) != val {
+                put_u64(&mut isa_mem[addr..(addr + 8)], val);
+                writes.push((addr, val));
+            }
+        }
+    })?;
+
+    let mem = MemData::init(bin);
+    let mut sim = new_sim(mem.clone());
+
+    let mut reg_pos = 0;
+    let mut shadow = bin;
+    let mut write_pos = 0;
+    let max_cycles = (std.n_insts + 16) * MAX_CPI;
+
+    let diverged = |sim: &dyn CpuSim, (signal, actual, expected)| Divergence {
+        cycle: sim.cycle_count(),
+        pc: sim.program_counter(),
+        signal,
+        actual,
+        expected,
+    };
+
+    while !sim.is_terminate() && mem.exit_code().is_none() {
+        if sim.cycle_count() >= max_cycles {
+            return Ok(Some(diverged(
+                sim.as_ref(),
+                ("terminate".into(), "false".into(), "true".into()),
+            )));
+        }
+        if let Err(failure) = sim.step() {
+            // an architecture breaking its own invariants diverges as well
+            let signal = format!("@assert({})", failure.message);
+            return Ok(Some(diverged(
+                sim.as_ref(),
+                (signal, "false".into(), "true".into()),
+            )));
+        }
+
+        let cur = sim.registers();
+        if cur != regs[reg_pos] {
+            match (reg_pos + 1..regs.len().min(reg_pos + 1 + LOOKAHEAD)).find(|&i| regs[i] == cur) {
+                Some(i) => reg_pos = i,
+                None => {
+                    let expected = regs.get(reg_pos + 1).unwrap_or(&regs[reg_pos]);
+                    let d = reg_divergence(&cur, expected).unwrap();
+                    return Ok(Some(diverged(sim.as_ref(), d)));
+                }
+            }
+        }
+
+        let cur = mem.read();
+        if *cur != shadow {
+            let mut next = shadow;
+            let mut matched = None;
+            for (i, &(addr, val)) in writes.iter().enumerate().skip(write_pos).take(LOOKAHEAD) {
+                put_u64(&mut next[addr..(addr + 8)], val);
+                if next == *cur {
+                    matched = Some(i + 1);
+                    break;
+                }
+            }
+            match matched {
+                Some(i) => {
+                    shadow = next;
+                    write_pos = i;
+                }
+                None => {
+                    // compare with the state after the next write
+                    let mut expected = shadow;
+                    if let Some(&(addr, val)) = writes.get(write_pos) {
+                        put_u64(&mut expected[addr..(addr + 8)], val);
+                    }
+                    let d = mem_divergence(&cur, &expected).unwrap();
+                    return Ok(Some(diverged(sim.as_ref(), d)));
+                }
+            }
+        }
+    }
+
+    // the architecture terminates, check that nothing is left behind
+    if let Some(d) = reg_divergence(&sim.registers(), &std.regs) {
+        return Ok(Some(diverged(sim.as_ref(), d)));
+    }
+    if let Some(d) = mem_divergence(&mem.read(), &std.bin) {
+        return Ok(Some(diverged(sim.as_ref(), d)));
+    }
+    let exit_code = mem.exit_code();
+    if exit_code != std.devices.exit_code {
+        let format = |code: Option<u64>| code.map_or("none".to_string(), |c| c.to_string());
+        let d = (
+            "exit code".to_string(),
+            format(exit_code),
+            format(std.devices.exit_code),
+        );
+        return Ok(Some(diverged(sim.as_ref(), d)));
+    }
+    Ok(None)
+}
+
+/// A minimal program that reproduces a divergence.
+#[derive(Debug, Clone)]
+pub struct Reduced {
+    pub arch: String,
+    pub source: String,
+    pub divergence: Divergence,
+}
+
+impl std::fmt::Display for Reduced {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "# minimal reproducer for architecture {}", self.arch)?;
+        writeln!(f, "# first divergence from the ISA simulator:")?;
+        writeln!(f, "#   {}", self.divergence)?;
+        write!(f, "{}", self.source)
+    }
+}
+
+/// Reduce a program on which `arch` disagrees with the ISA simulator.
+pub fn reduce(arch: &str, src: &str) -> anyhow::Result<Reduced> {
+    reduce_with(arch, &simulator(arch), src)
+}
+
+fn reduce_with(arch: &str, new_sim: &NewSim, src: &str) -> anyhow::Result<Reduced> {
+    if diverge_within(new_sim, src, MAX_INSTS)?.is_none() {
+        anyhow::bail!("{arch} agrees with the ISA simulator on this program");
+    }
+    // removing instructions may create infinite loops, which must fail fast
+    let obj = crate::assemble(src, AssembleOption::default())?.obj;
+    let n_insts = isa::simulate_trace(obj.init_mem(), MAX_INSTS, |_| {})?.n_insts;
+    let max_insts = n_insts * 2 + 1024;
+
+    let source = minimize(src, |src| {
+        matches!(diverge_within(new_sim, src, max_insts), Ok(Some(_)))
+    });
+    let divergence =
+        diverge_within(new_sim, &source, MAX_INSTS)?.expect("reduced program must diverge");
+    Ok(Reduced {
+        arch: arch.to_string(),
+        source,
+        divergence,
+    })
+}
+
+fn render(lines: &[&str], keep: &[bool]) -> String {
+    lines
+        .iter()
+        .zip(keep)
+        .filter(|(_, k)| **k)
+        .map(|(l, _)| format!("{l}\n"))
+        .collect()
+}
+
+/// Shrink `src` as long as it is `interesting`, which must hold for `src`.
+fn minimize(src: &str, mut interesting: impl FnMut(&str) -> bool) -> String {
+    // comments and blank lines never matter
+    let mut src: String = src
+        .lines()
+        .filter(|l| !l.split('#').next().unwrap_or_default().trim().is_empty())
+        .map(|l| format!("{l}\n"))
+        .collect();
+
+    loop {
+        let before = src.clone();
+        src = remove_lines(&src, &mut interesting);
+        src = shrink_immediates(&src, &mut interesting);
+        if src == before {
+            return src;
+        }
+    }
+}
+
+/// Delta debugging on lines. Removing a label that is still referenced
+/// makes the program fail to assemble, so it is not interesting.
+fn remove_lines(src: &str, interesting: &mut impl FnMut(&str) -> bool) -> String {
+    let lines: Vec<&str> = src.lines().collect();
+    let mut keep = vec![true; lines.len()];
+    let mut granularity = 2;
+    loop {
+        let candidates: Vec<usize> = (0..lines.len()).filter(|&i| keep[i]).collect();
+        if candidates.is_empty() {
+            break;
+        }
+        granularity = granularity.min(candidates.len());
+        let chunk = candidates.len().div_ceil(granularity);
+
+        let mut progress = false;
+        for part in candidates.chunks(chunk) {
+            let mut attempt = keep.clone();
+            for &i in part {
+                attempt[i] = false;
+            }
+            if interesting(&render(&lines, &attempt)) {
+                keep = attempt;
+                progress = true;
+            }
+        }
+
+        if progress {
+            granularity = (granularity - 1).max(2);
+        } else if chunk == 1 {
+            break;
+        } else {
+            granularity *= 2;
+        }
+    }
+    render(&lines, &keep)
+}
+
+/// Replace immediates (`$n`) and data (`.quad n`) by smaller values.
+fn shrink_immediates(src: &str, interesting: &mut impl FnMut(&str) -> bool) -> String {
+    let mut lines: Vec<String> = src.lines().map(str::to_string).collect();
+    for i in 0..lines.len() {
+        let code = lines[i].split('#').next().unwrap_or_default().to_string();
+        let literal = if let Some(pos) = code.find('$') {
+            pos + 1
+        } else if let Some(pos) = code.find(".quad") {
+            pos + ".quad".len()
+        } else {
+            continue;
+        };
+        let start = literal + code[literal..].len() - code[literal..].trim_start().len();
+        let len = code[start..]
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-'))
+            .unwrap_or(code.len() - start);
+        let Some(mut value) = parse_literal(&code[start..start + len]) else {
+            continue;
+        };
+
+        let mut len = len;
+        while value != 0 {
+            let smaller = [0, value / 2, value - 1];
+            let Some((v, line)) = smaller.into_iter().filter(|&v| v < value).find_map(|v| {
+                let text = v.to_string();
+                let line = format!("{}{text}{}", &lines[i][..start], &lines[i][start + len..]);
+                let mut attempt = lines.clone();
+                attempt[i] = line.clone();
+                interesting(&(attempt.join("\n") + "\n")).then_some((v, line))
+            }) else {
+                break;
+            };
+            len = v.to_string().len();
+            lines[i] = line;
+            value = v;
+        }
+    }
+    lines.join("\n") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_minimize() {
+        let src = "\
+.pos 0
+    irmovq $100, %rax   # comment
+    irmovq $3, %rbx
+    addq %rax, %rbx
+loop:
+    nop
+
+    halt
+.align 8
+data:
+    .quad 0x1234
+    .quad 7
+";
+        // `interesting` if it contains an addq, and the sum of immediates
+        // is at least 10
+        let result = minimize(src, |s| {
+            let sum: u64 = s
+                .lines()
+                .filter_map(|l| l.split('$').nth(1))
+                .filter_map(|l| l.split(',').next()?.trim().parse::<u64>().ok())
+                .sum();
+            s.contains("addq") && sum >= 10
+        });
+        assert_eq!(
+            result,
+            "    irmovq $10, %rax   # comment\n    addq %rax, %rbx\n"
+        );
+    }
+
+    /// The test architecture of the interpreter, which runs `halt`, `nop`,
+    /// `irmovq`, `OPq` and `jXX`.
+    fn test_arch(mem: MemData) -> Box<dyn CpuSim> {
+        use crate::framework::PipeSim;
+        use crate::hcl::test_arch::Arch;
+        Box::new(PipeSim::<Arch>::new(mem, false))
+    }
+
+    const COUNT_DOWN: &str = "\
+    irmovq $3, %rsi
+    irmovq $1, %r9
+loop:
+    subq %r9, %rsi
+    jne loop
+    halt
+";
+
+    #[test]
+    fn test_no_divergence() {
+        let divergence = diverge_within(&test_arch, COUNT_DOWN, MAX_INSTS).unwrap();
+        assert_eq!(divergence, None);
+    }
+
+    #[test]
+    fn test_reduce() {
+        // `rrmovq` is not in the subset, the test architecture stops there
+        let src = format!("{COUNT_DOWN}    irmovq $5, %rax\n    rrmovq %rax, %rbx\n");
+        let src = src.replacen("    halt\n", "", 1) + "    halt\n";
+        let reduced = reduce_with("test", &test_arch, &src).unwrap();
+        assert_eq!(
+            reduced.source,
+            "    irmovq $1, %rax\n    rrmovq %rax, %rbx\n"
+        );
+        assert_eq!(reduced.divergence.signal, "%rbx");
+    }
+
+    #[test]
+    fn test_cycle_counter() {
+        // both read 2 retired instructions into %rax
+        let src = "irmovq $65544, %rbx\nirmovq $1, %rcx\nmrmovq (%rbx), %rax\nhalt\n";
+        let divergence = diverge_within(&test_arch, src, MAX_INSTS).unwrap();
+        assert_eq!(divergence, None);
+    }
+}
@@ -129,9 +129,12 @@ pub fn format_reg_file(reg_file: RegFile) -> String {
     )
 }
 
+/// Render the dependency graph as a Sankey chart. Nodes and links on the
+/// critical paths of `timing` are highlighted.
 pub fn render_arch_dependency_graph(
     arch_name: &str,
     order: &crate::framework::PropOrder,
+    timing: &crate::framework::TimingModel,
 ) -> anyhow::Result<()> {
     use charming::{
         element::{Color, Emphasis, EmphasisFocus},
@@ -162,23 +165,40 @@ pub fn render_arch_dependency_graph(
         .filter_map(|o| o.is_unit.then_some(o.name))
         .collect::<Vec<_>>();
 
+    let critical_nodes = timing
+        .nodes
+        .iter()
+        .filter(|n| timing.is_critical(&n.name))
+        .map(|n| map_name(&n.name))
+        .collect::<Vec<_>>();
+
     let nodes = nodes
         .into_iter()
         .map(|name| {
             let is_device = devices_nodes.contains(&name.as_str());
-            let color = if is_device {
-                Color::Value("rgba(0,0,255,0.5)".to_string())
-            } else {
-                Color::Value("rgba(255,0,0,0.5)".to_string())
+            let is_critical = critical_nodes.contains(&name);
+            let color = match (is_device, is_critical) {
+                (true, false) => Color::Value("rgba(0,0,255,0.5)".to_string()),
+                (false, false) => Color::Value("rgba(255,0,0,0.5)".to_string()),
+                (true, true) => Color::Value("rgba(0,0,255,1)".to_string()),
+                (false, true) => Color::Value("rgba(255,128,0,1)".to_string()),
             };
             SankeyNode::new(name).item_style(color)
         })
         .collect();
 
+    // links on the critical paths are drawn thicker
     let links = order
         .edges
         .iter()
-        .map(|(from, to)| (map_name(from), map_name(to), 1))
+        .map(|(from, to)| {
+            let width = if timing.is_critical_edge(from, to) {
+                3
+            } else {
+                1
+            };
+            (map_name(from), map_name(to), width)
+        })
         .collect();
 
     let c = Chart::new().series(
@@ -1,16 +1,37 @@
 //! Expression in CS:APP HCL language
-
-use quote::ToTokens;
-use syn::{parse::Parse, punctuated::Punctuated, Token};
+//!
+//! Operators follow the precedence of Rust, from the lowest to the highest:
+//!
+//! - `c ? a : b` (right associative)
+//! - `||`
+//! - `&&`
+//! - `== != < <= > >=` and `in {..}`, which can't be chained
+//! - `|`
+//! - `^`
+//! - `&`
+//! - `<< >>`
+//! - `+ -`
+//! - `!`
+//!
+//! Integer arithmetic wraps around like the hardware does, see
+//! `crate::dsl::add` and friends in the simulator.
+
+use proc_macro2::{Span, TokenStream};
+use quote::{quote, quote_spanned, ToTokens};
+use syn::{parse::Parse, parse::ParseStream, punctuated::Punctuated, Token};
 
 #[derive(Debug, Clone)]
 pub struct LValue(pub Punctuated<syn::Ident, Token![.]>);
 
 impl Parse for LValue {
     fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
-        let idents = input.call(
-            syn::punctuated::Punctuated::<syn::Ident, syn::Token![.]>::parse_separated_nonempty,
-        )?;
+        // not `parse_separated_nonempty`, which takes the `..` of a range
+        let mut idents = Punctuated::new();
+        idents.push_value(input.parse()?);
+        while input.peek(Token![.]) && !input.peek(Token![..]) {
+            idents.push_punct(input.parse()?);
+            idents.push_value(input.parse()?);
+        }
         Ok(Self(idents))
     }
 }
@@ -21,338 +42,591 @@ impl ToTokens for LValue {
     }
 }
 
+/// A member of `expr in { .. }`: a signal, a constant such as `IOPQ` or
+/// `Aok`, an integer literal, or an inclusive range `lo..=hi` of constants
+/// and literals.
 #[derive(Debug, Clone)]
-pub enum PrimaryExpr {
+pub enum Member {
     LVal(LValue),
     LitInt(syn::LitInt),
-    LitBool(syn::LitBool),
-    Paren(Box<Expr>),
+    Range(Box<Member>, Token![..=], Box<Member>),
 }
 
-impl Parse for PrimaryExpr {
-    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
-        let lookahead = input.lookahead1();
-        if lookahead.peek(syn::LitInt) {
+impl Member {
+    fn parse_bound(input: ParseStream) -> syn::Result<Self> {
+        if input.peek(syn::LitInt) {
             Ok(Self::LitInt(input.parse()?))
-        } else if lookahead.peek(syn::LitBool) {
-            Ok(Self::LitBool(input.parse()?))
-        } else if lookahead.peek(syn::token::Paren) {
-            let content;
-            let _ = syn::parenthesized!(content in input);
-            let expr = content.parse()?;
-            Ok(Self::Paren(Box::new(expr)))
         } else {
             Ok(Self::LVal(input.parse()?))
         }
     }
-}
-
-#[derive(Debug, Clone)]
-pub enum UnaryExpr {
-    Primary(PrimaryExpr),
-    Not(Box<UnaryExpr>),
-}
 
-impl Parse for UnaryExpr {
-    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
-        let lookahead = input.lookahead1();
-        if lookahead.peek(Token![!]) {
-            let _ = input.parse::<Token![!]>()?;
-            let expr = input.parse()?;
-            Ok(Self::Not(Box::new(expr)))
-        } else {
-            Ok(Self::Primary(input.parse()?))
+    /// Constants and literals are known at compile time. After the mapping
+    /// of [`Expr::map`], a signal is never a single identifier.
+    fn is_constant(&self) -> bool {
+        match self {
+            Self::LVal(lv) => lv.0.len() == 1,
+            _ => true,
         }
     }
-}
-
-#[derive(Debug, Clone)]
-pub enum RelExpr {
-    Unary(UnaryExpr),
-    Eq(UnaryExpr, UnaryExpr),
-    NotEq(UnaryExpr, UnaryExpr),
-    In(UnaryExpr, Punctuated<LValue, Token![,]>),
-}
 
-impl Parse for RelExpr {
-    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
-        let lhs = input.parse::<UnaryExpr>()?;
-        let lookahead = input.lookahead1();
-        if lookahead.peek(Token![==]) {
-            let _ = input.parse::<Token![==]>()?;
-            let rhs = input.parse()?;
-            Ok(Self::Eq(lhs, rhs))
-        } else if lookahead.peek(Token![!=]) {
-            let _ = input.parse::<Token![!=]>()?;
-            let rhs = input.parse()?;
-            Ok(Self::NotEq(lhs, rhs))
-        } else if lookahead.peek(Token![in]) {
-            let _ = input.parse::<Token![in]>()?;
-            let items;
-            let _ = syn::braced!(items in input);
-            let values = items.parse_terminated(LValue::parse, Token![,])?;
-            Ok(Self::In(lhs, values))
-        } else {
-            Ok(Self::Unary(lhs))
+    fn lvalues(&self) -> Vec<LValue> {
+        match self {
+            Self::LVal(lv) => vec![lv.clone()],
+            Self::LitInt(_) => Vec::new(),
+            Self::Range(lo, _, hi) => {
+                let mut lvalues = lo.lvalues();
+                lvalues.extend(hi.lvalues());
+                lvalues
+            }
         }
     }
-}
-
-#[derive(Debug, Clone)]
-pub enum LAndExpr {
-    Rel(RelExpr),
 
-    /// `expr && expr`
-    RelLAnd(RelExpr, Box<LAndExpr>),
+    fn map(self, f: impl Fn(LValue) -> LValue + Clone) -> Self {
+        match self {
+            Self::LVal(lv) => Self::LVal(f(lv)),
+            Self::LitInt(_) => self,
+            Self::Range(lo, op, hi) => {
+                Self::Range(Box::new(lo.map(f.clone())), op, Box::new(hi.map(f)))
+            }
+        }
+    }
 }
 
-impl Parse for LAndExpr {
-    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
-        let lhs = input.parse::<RelExpr>()?;
-        let lookahead = input.lookahead1();
-        if lookahead.peek(Token![&&]) {
-            let _ = input.parse::<Token![&&]>()?;
-            let rhs = input.parse()?;
-            Ok(Self::RelLAnd(lhs, Box::new(rhs)))
-        } else {
-            Ok(Self::Rel(lhs))
+impl Parse for Member {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let lo = Self::parse_bound(input)?;
+        if input.peek(Token![..]) && !input.peek(Token![..=]) {
+            return Err(input.error("use an inclusive range `lo..=hi`"));
+        }
+        if !input.peek(Token![..=]) {
+            return Ok(lo);
+        }
+        let op = input.parse()?;
+        let hi = Self::parse_bound(input)?;
+        for bound in [&lo, &hi] {
+            if let Self::LVal(lv) = bound {
+                let is_constant =
+                    lv.0.len() == 1 && lv.0[0].to_string().starts_with(|c: char| c.is_uppercase());
+                if !is_constant {
+                    return Err(syn::Error::new_spanned(
+                        lv,
+                        "the bounds of a range must be literals or constants",
+                    ));
+                }
+            }
         }
+        if let (Self::LitInt(a), Self::LitInt(b)) = (&lo, &hi) {
+            if a.base10_parse::<u64>()? > b.base10_parse::<u64>()? {
+                return Err(syn::Error::new_spanned(
+                    quote! { #a #op #b },
+                    "empty range in `in { .. }`",
+                ));
+            }
+        }
+        Ok(Self::Range(Box::new(lo), op, Box::new(hi)))
     }
 }
 
-#[derive(Debug, Clone)]
-pub enum LOrExpr {
-    LAnd(LAndExpr),
-
-    /// `expr || expr`
-    LAndOr(LAndExpr, Box<LOrExpr>),
+impl ToTokens for Member {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        match self {
+            Self::LVal(lv) => lv.to_tokens(tokens),
+            Self::LitInt(lit) => lit.to_tokens(tokens),
+            Self::Range(lo, op, hi) => tokens.extend(quote! { #lo #op #hi }),
+        }
+    }
 }
 
-impl Parse for LOrExpr {
-    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
-        let lhs = input.parse::<LAndExpr>()?;
-        let lookahead = input.lookahead1();
-        if lookahead.peek(Token![||]) {
-            let _ = input.parse::<Token![||]>()?;
-            let rhs = input.parse()?;
-            Ok(Self::LAndOr(lhs, Box::new(rhs)))
-        } else {
-            Ok(Self::LAnd(lhs))
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Or,
+    And,
+    Eq,
+    NotEq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+}
+
+/// Precedence of comparisons and `in {..}`.
+const CMP_PREC: u8 = 3;
+
+impl BinOp {
+    fn precedence(self) -> u8 {
+        match self {
+            BinOp::Or => 1,
+            BinOp::And => 2,
+            BinOp::Eq | BinOp::NotEq | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => CMP_PREC,
+            BinOp::BitOr => 4,
+            BinOp::BitXor => 5,
+            BinOp::BitAnd => 6,
+            BinOp::Shl | BinOp::Shr => 7,
+            BinOp::Add | BinOp::Sub => 8,
         }
     }
-}
 
-#[derive(Debug, Clone)]
-pub struct Expr(LOrExpr);
+    /// The operator at the front of `input`. Longer operators are checked
+    /// first, so that `&&` is not taken as `&`, and `->` (the destination of
+    /// a signal) is not taken as `-`.
+    fn peek(input: ParseStream) -> Option<Self> {
+        Some(if input.peek(Token![||]) {
+            BinOp::Or
+        } else if input.peek(Token![&&]) {
+            BinOp::And
+        } else if input.peek(Token![==]) {
+            BinOp::Eq
+        } else if input.peek(Token![!=]) {
+            BinOp::NotEq
+        } else if input.peek(Token![<<]) {
+            BinOp::Shl
+        } else if input.peek(Token![<=]) {
+            BinOp::Le
+        } else if input.peek(Token![<]) {
+            BinOp::Lt
+        } else if input.peek(Token![>>]) {
+            BinOp::Shr
+        } else if input.peek(Token![>=]) {
+            BinOp::Ge
+        } else if input.peek(Token![>]) {
+            BinOp::Gt
+        } else if input.peek(Token![|]) {
+            BinOp::BitOr
+        } else if input.peek(Token![^]) {
+            BinOp::BitXor
+        } else if input.peek(Token![&]) {
+            BinOp::BitAnd
+        } else if input.peek(Token![->]) {
+            return None;
+        } else if input.peek(Token![+]) {
+            BinOp::Add
+        } else if input.peek(Token![-]) {
+            BinOp::Sub
+        } else {
+            return None;
+        })
+    }
 
-impl Parse for Expr {
-    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
-        let expr = input.parse::<LOrExpr>()?;
-        Ok(Self(expr))
+    /// Consume the operator and return its span.
+    fn parse(self, input: ParseStream) -> syn::Result<Span> {
+        Ok(match self {
+            BinOp::Or => input.parse::<Token![||]>()?.spans[0],
+            BinOp::And => input.parse::<Token![&&]>()?.spans[0],
+            BinOp::Eq => input.parse::<Token![==]>()?.spans[0],
+            BinOp::NotEq => input.parse::<Token![!=]>()?.spans[0],
+            BinOp::Lt => input.parse::<Token![<]>()?.spans[0],
+            BinOp::Le => input.parse::<Token![<=]>()?.spans[0],
+            BinOp::Gt => input.parse::<Token![>]>()?.spans[0],
+            BinOp::Ge => input.parse::<Token![>=]>()?.spans[0],
+            BinOp::BitOr => input.parse::<Token![|]>()?.spans[0],
+            BinOp::BitXor => input.parse::<Token![^]>()?.spans[0],
+            BinOp::BitAnd => input.parse::<Token![&]>()?.spans[0],
+            BinOp::Shl => input.parse::<Token![<<]>()?.spans[0],
+            BinOp::Shr => input.parse::<Token![>>]>()?.spans[0],
+            BinOp::Add => input.parse::<Token![+]>()?.spans[0],
+            BinOp::Sub => input.parse::<Token![-]>()?.spans[0],
+        })
     }
-}
 
-impl ToTokens for Expr {
-    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
-        self.0.to_tokens(tokens)
+    fn is_comparison(self) -> bool {
+        self.precedence() == CMP_PREC
     }
-}
 
-impl ToTokens for LOrExpr {
-    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+    /// Name of the wrapping helper in `crate::dsl`, if the operator needs one.
+    fn helper(self) -> Option<&'static str> {
         match self {
-            Self::LAnd(expr) => expr.to_tokens(tokens),
-            Self::LAndOr(expr, next) => {
-                tokens.extend(quote::quote! { #expr || #next });
-            }
+            BinOp::Add => Some("add"),
+            BinOp::Sub => Some("sub"),
+            BinOp::Shl => Some("shl"),
+            BinOp::Shr => Some("shr"),
+            _ => None,
         }
     }
-}
 
-impl ToTokens for LAndExpr {
-    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+    fn to_tokens(self, span: Span) -> TokenStream {
         match self {
-            Self::Rel(expr) => expr.to_tokens(tokens),
-            Self::RelLAnd(expr, next) => {
-                tokens.extend(quote::quote! { #expr && #next });
-            }
+            BinOp::Or => quote_spanned!(span=> ||),
+            BinOp::And => quote_spanned!(span=> &&),
+            BinOp::Eq => quote_spanned!(span=> ==),
+            BinOp::NotEq => quote_spanned!(span=> !=),
+            BinOp::Lt => quote_spanned!(span=> <),
+            BinOp::Le => quote_spanned!(span=> <=),
+            BinOp::Gt => quote_spanned!(span=> >),
+            BinOp::Ge => quote_spanned!(span=> >=),
+            BinOp::BitOr => quote_spanned!(span=> |),
+            BinOp::BitXor => quote_spanned!(span=> ^),
+            BinOp::BitAnd => quote_spanned!(span=> &),
+            BinOp::Shl => quote_spanned!(span=> <<),
+            BinOp::Shr => quote_spanned!(span=> >>),
+            BinOp::Add => quote_spanned!(span=> +),
+            BinOp::Sub => quote_spanned!(span=> -),
         }
     }
 }
 
-impl ToTokens for RelExpr {
-    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
-        match self {
-            Self::Unary(expr) => expr.to_tokens(tokens),
-            Self::Eq(lhs, rhs) => {
-                tokens.extend(quote::quote! { #lhs == #rhs });
-            }
-            Self::NotEq(lhs, rhs) => {
-                tokens.extend(quote::quote! { #lhs != #rhs });
-            }
-            Self::In(lhs, values) => {
-                tokens.extend(quote::quote! { crate::dsl::mtc(#lhs, [#values]) });
-            }
-        }
-    }
+#[derive(Debug, Clone)]
+pub enum Expr {
+    LVal(LValue),
+    LitInt(syn::LitInt),
+    LitBool(syn::LitBool),
+    Paren(Box<Expr>),
+    /// `!expr`
+    Not(Box<Expr>),
+    /// `expr op expr`, with the span of the operator
+    Binary(Box<Expr>, BinOp, Span, Box<Expr>),
+    /// `expr in { a, b, .. }`
+    In(Box<Expr>, Punctuated<Member, Token![,]>),
+    /// `cond ? expr : expr`
+    Cond(Box<Expr>, Box<Expr>, Box<Expr>),
 }
 
-impl ToTokens for UnaryExpr {
-    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
-        match self {
-            Self::Primary(expr) => expr.to_tokens(tokens),
-            Self::Not(expr) => {
-                tokens.extend(quote::quote! { !#expr });
-            }
+impl Parse for Expr {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let cond = Self::parse_binary(input, 1)?;
+        if input.peek(Token![?]) {
+            let _ = input.parse::<Token![?]>()?;
+            let then = input.parse()?;
+            let _ = input.parse::<Token![:]>()?;
+            let otherwise = input.parse()?;
+            Ok(Self::Cond(
+                Box::new(cond),
+                Box::new(then),
+                Box::new(otherwise),
+            ))
+        } else {
+            Ok(cond)
         }
     }
 }
 
-impl ToTokens for PrimaryExpr {
-    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
-        match self {
-            Self::LVal(lval) => lval.to_tokens(tokens),
-            Self::LitInt(lit) => lit.to_tokens(tokens),
-            Self::LitBool(lit) => lit.to_tokens(tokens),
-            Self::Paren(expr) => {
-                tokens.extend(quote::quote! { ( #expr ) });
+impl Expr {
+    /// Precedence climbing over the binary operators whose precedence is at
+    /// least `min_prec`.
+    fn parse_binary(input: ParseStream, min_prec: u8) -> syn::Result<Self> {
+        let mut lhs = Self::parse_unary(input)?;
+        // a comparison at this level, which can't be chained
+        let mut compared = false;
+        loop {
+            if input.peek(Token![in]) {
+                if CMP_PREC < min_prec {
+                    break;
+                }
+                let kw = input.parse::<Token![in]>()?;
+                if compared {
+                    return Err(syn::Error::new(
+                        kw.span,
+                        "comparison operators can't be chained, use parentheses",
+                    ));
+                }
+                let items;
+                let _ = syn::braced!(items in input);
+                let members = items.parse_terminated(Member::parse, Token![,])?;
+                // the same member twice is a typo, overlapping values are
+                // checked at compile time by `ConstSet::is_unreachable`
+                for (i, m) in members.iter().enumerate() {
+                    let text = m.to_token_stream().to_string();
+                    if members
+                        .iter()
+                        .take(i)
+                        .any(|p| p.to_token_stream().to_string() == text)
+                    {
+                        return Err(syn::Error::new_spanned(
+                            m,
+                            format!("`{}` appears twice in `in {{ .. }}`", text.replace(' ', "")),
+                        ));
+                    }
+                }
+                lhs = Self::In(Box::new(lhs), members);
+                compared = true;
+                continue;
+            }
+
+            let Some(op) = BinOp::peek(input) else {
+                break;
+            };
+            if op.precedence() < min_prec {
+                break;
             }
+            let span = op.parse(input)?;
+            if op.is_comparison() {
+                if compared {
+                    return Err(syn::Error::new(
+                        span,
+                        "comparison operators can't be chained, use parentheses",
+                    ));
+                }
+                compared = true;
+            } else {
+                compared = false;
+            }
+            let rhs = Self::parse_binary(input, op.precedence() + 1)?;
+            lhs = Self::Binary(Box::new(lhs), op, span, Box::new(rhs));
         }
+        Ok(lhs)
     }
-}
 
-impl PrimaryExpr {
-    fn lvalues(&self) -> Punctuated<LValue, Token![,]> {
-        match self {
-            PrimaryExpr::LVal(v) => std::iter::once(v.clone()).collect(),
-            PrimaryExpr::LitInt(_) => Default::default(),
-            PrimaryExpr::LitBool(_) => Default::default(),
-            PrimaryExpr::Paren(e) => e.lvalues(),
+    fn parse_unary(input: ParseStream) -> syn::Result<Self> {
+        if input.peek(Token![!]) {
+            let _ = input.parse::<Token![!]>()?;
+            let expr = Self::parse_unary(input)?;
+            Ok(Self::Not(Box::new(expr)))
+        } else {
+            Self::parse_primary(input)
         }
     }
-}
 
-impl UnaryExpr {
-    fn lvalues(&self) -> Punctuated<LValue, Token![,]> {
-        match self {
-            UnaryExpr::Primary(p) => p.lvalues(),
-            UnaryExpr::Not(e) => e.lvalues(),
+    fn parse_primary(input: ParseStream) -> syn::Result<Self> {
+        let lookahead = input.lookahead1();
+        if lookahead.peek(syn::LitInt) {
+            Ok(Self::LitInt(input.parse()?))
+        } else if lookahead.peek(syn::LitBool) {
+            Ok(Self::LitBool(input.parse()?))
+        } else if lookahead.peek(syn::token::Paren) {
+            let content;
+            let _ = syn::parenthesized!(content in input);
+            let expr = content.parse()?;
+            Ok(Self::Paren(Box::new(expr)))
+        } else {
+            Ok(Self::LVal(input.parse()?))
         }
     }
 }
 
-impl RelExpr {
-    fn lvalues(&self) -> Punctuated<LValue, Token![,]> {
+impl ToTokens for Expr {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
         match self {
-            RelExpr::Unary(u) => u.lvalues(),
-            RelExpr::Eq(lhs, rhs) | RelExpr::NotEq(lhs, rhs) => {
-                let mut lvalues = lhs.lvalues();
-                lvalues.extend(rhs.lvalues());
-                lvalues
+            Self::LVal(lval) => lval.to_tokens(tokens),
+            Self::LitInt(lit) => lit.to_tokens(tokens),
+            Self::LitBool(lit) => lit.to_tokens(tokens),
+            Self::Paren(expr) => {
+                tokens.extend(quote! { ( #expr ) });
             }
-            RelExpr::In(lhs, values) => {
-                let mut lvalues = lhs.lvalues();
-                lvalues.extend(values.iter().cloned());
-                lvalues
+            Self::Not(expr) => {
+                tokens.extend(quote! { !#expr });
+            }
+            Self::Binary(lhs, op, span, rhs) => {
+                if let Some(helper) = op.helper() {
+                    let helper = syn::Ident::new(helper, *span);
+                    tokens.extend(quote_spanned! {*span=> crate::dsl::#helper(#lhs, #rhs) });
+                } else {
+                    let op = op.to_tokens(*span);
+                    tokens.extend(quote! { (#lhs #op #rhs) });
+                }
+            }
+            Self::In(lhs, members) => {
+                if !members.iter().any(Member::is_constant) {
+                    tokens.extend(quote! { crate::dsl::mtc(#lhs, [#members]) });
+                    return;
+                }
+                let signals = members.iter().filter(|m| !m.is_constant());
+                let constants = members
+                    .iter()
+                    .filter(|m| m.is_constant())
+                    .collect::<Vec<_>>();
+                let n = constants.len();
+                let mut checks = Vec::new();
+                let mut ranges = Vec::new();
+                for (i, m) in constants.into_iter().enumerate() {
+                    let span = syn::spanned::Spanned::span(m);
+                    let (lo, hi) = match m {
+                        Member::Range(lo, _, hi) => (&**lo, &**hi),
+                        m => (m, m),
+                    };
+                    // a member has the type of `lhs`, and is reachable
+                    for bound in [lo, hi] {
+                        checks.push(quote_spanned! {span=> crate::dsl::same_type(&v_, &#bound); });
+                    }
+                    checks.push(quote_spanned! {span=>
+                        const _: () = assert!(
+                            !SET.is_unreachable(#i),
+                            "this member of `in` is empty or covered by the members before it"
+                        );
+                    });
+                    ranges.push(quote! { (#lo as u64, #hi as u64) });
+                }
+                let signals = signals
+                    .map(|m| quote! { || crate::dsl::mtc(v_, [#m]) })
+                    .collect::<TokenStream>();
+                tokens.extend(quote! {{
+                    let v_ = #lhs;
+                    const SET: crate::dsl::ConstSet<#n> = crate::dsl::ConstSet::new([#(#ranges),*]);
+                    #(#checks)*
+                    SET.contains(v_) #signals
+                }});
+            }
+            Self::Cond(cond, then, otherwise) => {
+                tokens.extend(quote! {
+                    (if u8::from(#cond) != 0 { #then } else { #otherwise })
+                });
             }
         }
     }
 }
 
-impl LAndExpr {
-    fn lvalues(&self) -> Punctuated<LValue, Token![,]> {
+impl Expr {
+    /// Get all lvalues in the expression
+    pub fn lvalues(&self) -> Punctuated<LValue, Token![,]> {
         match self {
-            LAndExpr::Rel(r) => r.lvalues(),
-            LAndExpr::RelLAnd(lhs, rhs) => {
+            Expr::LVal(v) => std::iter::once(v.clone()).collect(),
+            Expr::LitInt(_) | Expr::LitBool(_) => Default::default(),
+            Expr::Paren(e) | Expr::Not(e) => e.lvalues(),
+            Expr::Binary(lhs, _, _, rhs) => {
                 let mut lvalues = lhs.lvalues();
                 lvalues.extend(rhs.lvalues());
                 lvalues
             }
-        }
-    }
-}
-
-impl LOrExpr {
-    fn lvalues(&self) -> Punctuated<LValue, Token![,]> {
-        match self {
-            LOrExpr::LAnd(l) => l.lvalues(),
-            LOrExpr::LAndOr(lhs, rhs) => {
+            Expr::In(lhs, members) => {
                 let mut lvalues = lhs.lvalues();
-                lvalues.extend(rhs.lvalues());
+                lvalues.extend(members.iter().flat_map(Member::lvalues));
+                lvalues
+            }
+            Expr::Cond(cond, then, otherwise) => {
+                let mut lvalues = cond.lvalues();
+                lvalues.extend(then.lvalues());
+                lvalues.extend(otherwise.lvalues());
                 lvalues
             }
         }
     }
 }
 
-impl Expr {
-    /// Get all lvalues in the expression
-    pub fn lvalues(&self) -> Punctuated<LValue, Token![,]> {
-        self.0.lvalues()
-    }
-}
-
 impl LValue {
     pub fn map(self, f: impl Fn(LValue) -> LValue + Clone) -> Self {
         f(self)
     }
 }
 
-impl PrimaryExpr {
-    fn map(self, f: impl Fn(LValue) -> LValue + Clone) -> Self {
+impl Expr {
+    /// Map all lvalues in the expression
+    pub fn map(self, f: impl Fn(LValue) -> LValue + Clone) -> Self {
         match self {
-            PrimaryExpr::LVal(lval) => PrimaryExpr::LVal(lval.map(f)),
-            PrimaryExpr::Paren(expr) => PrimaryExpr::Paren(Box::new(expr.map(f))),
+            Expr::LVal(lval) => Expr::LVal(lval.map(f)),
+            Expr::Paren(e) => Expr::Paren(Box::new(e.map(f))),
+            Expr::Not(e) => Expr::Not(Box::new(e.map(f))),
+            Expr::Binary(lhs, op, span, rhs) => {
+                Expr::Binary(Box::new(lhs.map(f.clone())), op, span, Box::new(rhs.map(f)))
+            }
+            Expr::In(lhs, members) => Expr::In(
+                Box::new(lhs.map(f.clone())),
+                members.into_iter().map(|m| m.map(f.clone())).collect(),
+            ),
+            Expr::Cond(cond, then, otherwise) => Expr::Cond(
+                Box::new(cond.map(f.clone())),
+                Box::new(then.map(f.clone())),
+                Box::new(otherwise.map(f)),
+            ),
             other => other,
         }
     }
 }
 
-impl UnaryExpr {
-    fn map(self, f: impl Fn(LValue) -> LValue + Clone) -> Self {
-        match self {
-            UnaryExpr::Primary(p) => UnaryExpr::Primary(p.map(f)),
-            UnaryExpr::Not(e) => UnaryExpr::Not(Box::new(e.map(f))),
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The expression with every operation in parentheses.
+    fn tree(expr: &Expr) -> String {
+        match expr {
+            Expr::LVal(lv) => lv.to_token_stream().to_string().replace(' ', ""),
+            Expr::LitInt(lit) => lit.to_string(),
+            Expr::LitBool(lit) => lit.value.to_string(),
+            Expr::Paren(e) => tree(e),
+            Expr::Not(e) => format!("!{}", tree(e)),
+            Expr::Binary(lhs, op, span, rhs) => {
+                format!("({} {} {})", tree(lhs), op.to_tokens(*span), tree(rhs))
+            }
+            Expr::In(lhs, members) => {
+                let members = members
+                    .iter()
+                    .map(|m| m.to_token_stream().to_string().replace(' ', ""));
+                let members = members.collect::<Vec<_>>().join(", ");
+                format!("({} in {{ {members} }})", tree(lhs))
+            }
+            Expr::Cond(cond, then, otherwise) => {
+                format!("({} ? {} : {})", tree(cond), tree(then), tree(otherwise))
+            }
         }
     }
-}
 
-impl RelExpr {
-    fn map(self, f: impl Fn(LValue) -> LValue + Clone) -> Self {
-        match self {
-            RelExpr::Unary(u) => RelExpr::Unary(u.map(f)),
-            RelExpr::Eq(lhs, rhs) => RelExpr::Eq(lhs.map(f.clone()), rhs.map(f)),
-            RelExpr::NotEq(lhs, rhs) => RelExpr::NotEq(lhs.map(f.clone()), rhs.map(f)),
-            RelExpr::In(lhs, values) => {
-                RelExpr::In(lhs.map(f.clone()), values.into_iter().map(f).collect())
-            }
-        }
+    fn parse(src: &str) -> String {
+        tree(&syn::parse_str::<Expr>(src).unwrap())
     }
-}
 
-impl LAndExpr {
-    fn map(self, f: impl Fn(LValue) -> LValue + Clone) -> Self {
-        match self {
-            LAndExpr::Rel(r) => LAndExpr::Rel(r.map(f)),
-            LAndExpr::RelLAnd(lhs, rhs) => {
-                LAndExpr::RelLAnd(lhs.map(f.clone()), Box::new(rhs.map(f)))
-            }
-        }
+    fn error(src: &str) -> String {
+        syn::parse_str::<Expr>(src).unwrap_err().to_string()
     }
-}
 
-impl LOrExpr {
-    fn map(self, f: impl Fn(LValue) -> LValue + Clone) -> Self {
-        match self {
-            LOrExpr::LAnd(l) => LOrExpr::LAnd(l.map(f)),
-            LOrExpr::LAndOr(lhs, rhs) => LOrExpr::LAndOr(lhs.map(f.clone()), Box::new(rhs.map(f))),
-        }
+    #[test]
+    fn test_precedence() {
+        assert_eq!(
+            parse("a + b << c | d == e && f"),
+            "(((((a + b) << c) | d) == e) && f)"
+        );
+        assert_eq!(
+            parse("a || b && !c ^ d & e"),
+            "(a || (b && (!c ^ (d & e))))"
+        );
+        assert_eq!(parse("a - b - c"), "((a - b) - c)");
+        assert_eq!(parse("(a | b) & c"), "((a | b) & c)");
+        assert_eq!(
+            parse("D.icode in { NOP..=HALT, 0x7 } && ok"),
+            "((D.icode in { NOP..=HALT, 0x7 }) && ok)"
+        );
     }
-}
 
-impl Expr {
-    /// Map all lvalues in the expression
-    pub fn map(self, f: impl Fn(LValue) -> LValue + Clone) -> Self {
-        Self(self.0.map(f))
+    #[test]
+    fn test_conditional() {
+        assert_eq!(parse("a ? b : c ? d : e"), "(a ? b : (c ? d : e))");
+        assert_eq!(parse("a ? b ? c : d : e"), "(a ? (b ? c : d) : e)");
+        assert_eq!(parse("a || b ? c + 1 : d"), "((a || b) ? (c + 1) : d)");
+    }
+
+    #[test]
+    fn test_chained_comparison() {
+        let chained = "comparison operators can't be chained, use parentheses";
+        assert_eq!(error("a < b < c"), chained);
+        assert_eq!(error("a == b in { 1 }"), chained);
+        assert_eq!(parse("(a < b) == c"), "((a < b) == c)");
+    }
+
+    #[test]
+    fn test_destination() {
+        let parser = |input: ParseStream| {
+            let expr: Expr = input.parse()?;
+            input.parse::<Token![->]>()?;
+            let dest: LValue = input.parse()?;
+            Ok((expr, dest))
+        };
+        let (expr, dest) = syn::parse::Parser::parse_str(parser, "x - 1 -> d.x").unwrap();
+        assert_eq!(tree(&expr), "(x - 1)");
+        assert_eq!(dest.to_token_stream().to_string().replace(' ', ""), "d.x");
+        let (expr, _) = syn::parse::Parser::parse_str(parser, "x -> dest").unwrap();
+        assert_eq!(tree(&expr), "x");
+    }
+
+    #[test]
+    fn test_members() {
+        assert_eq!(
+            error("a in { 1, 2, 1 }"),
+            "`1` appears twice in `in { .. }`"
+        );
+        assert_eq!(
+            error("a in { NOP..=HALT, NOP..=HALT }"),
+            "`NOP..=HALT` appears twice in `in { .. }`"
+        );
+        assert_eq!(error("a in { 1..3 }"), "use an inclusive range `lo..=hi`");
+        assert_eq!(error("a in { 3..=1 }"), "empty range in `in { .. }`");
+        assert_eq!(
+            error("a in { b..=3 }"),
+            "the bounds of a range must be literals or constants"
+        );
     }
 }
@@ -28,11 +28,12 @@ impl Parse for StageDecl {
         let _ = input.parse::<StageTitleBoundary>()?;
         let mut name = Vec::new();
         while !input.peek(Token![:]) || !input.peek2(Token![=]) {
-            let ident = input
-                .parse::<syn::Ident>()
-                .map(|id| id.to_string())
-                .unwrap_or_else(|_| input.parse::<syn::LitStr>().unwrap().value());
-            name.push(ident);
+            let word = if input.peek(syn::LitStr) {
+                input.parse::<syn::LitStr>()?.value()
+            } else {
+                input.parse::<syn::Ident>()?.to_string()
+            };
+            name.push(word);
         }
         let _ = input.parse::<StageTitleBoundary>()?;
         Ok(Self {
@@ -69,8 +70,17 @@ impl Parse for StageAlias {
     }
 }
 
+/// The tunnel given by `#[tunnel(name)]`, if any.
+fn parse_tunnel(attrs: &[syn::Attribute]) -> syn::Result<Option<syn::Ident>> {
+    attrs
+        .iter()
+        .find(|attr| attr.path().is_ident("tunnel"))
+        .map(|attr| attr.parse_args())
+        .transpose()
+}
+
 /// e.g. `imem.error => NOP`
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Case {
     pub tunnel: Option<syn::Ident>,
     pub condition: expr::Expr,
@@ -92,14 +102,7 @@ impl Parse for Case {
         let _ = input.parse::<syn::Token![:]>()?;
         let value: expr::Expr = input.parse()?;
 
-        let tunnel = attr.iter().find_map(|attr| {
-            if attr.path().is_ident("tunnel") {
-                let tunnel_name: syn::Ident = attr.parse_args().unwrap();
-                Some(tunnel_name)
-            } else {
-                None
-            }
-        });
+        let tunnel = parse_tunnel(&attr)?;
 
         Ok(Self {
             tunnel,
@@ -109,7 +112,7 @@ impl Parse for Case {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SignalSwitch(pub Punctuated<Case, syn::Token![;]>);
 
 impl Parse for SignalSwitch {
@@ -121,7 +124,7 @@ impl Parse for SignalSwitch {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SignalSourceExpr {
     pub tunnel: Option<syn::Ident>,
     pub expr: expr::Expr,
@@ -131,19 +134,12 @@ impl Parse for SignalSourceExpr {
     fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
         let attr = input.call(syn::Attribute::parse_outer)?;
         let expr: expr::Expr = input.parse()?;
-        let tunnel = attr.iter().find_map(|attr| {
-            if attr.path().is_ident("tunnel") {
-                let tunnel_name: syn::Ident = attr.parse_args().unwrap();
-                Some(tunnel_name)
-            } else {
-                None
-            }
-        });
+        let tunnel = parse_tunnel(&attr)?;
         Ok(Self { tunnel, expr })
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum SignalSource {
     Switch(SignalSwitch),
     Expr(SignalSourceExpr),
@@ -169,9 +165,37 @@ impl SignalSource {
             Self::Expr(expr) => expr.expr.lvalues(),
         }
     }
+
+    /// Tunnels of the cases or of the expression.
+    pub fn tunnels(&self) -> Vec<&syn::Ident> {
+        match self {
+            Self::Switch(switch) => switch.0.iter().filter_map(|c| c.tunnel.as_ref()).collect(),
+            Self::Expr(expr) => expr.tunnel.iter().collect(),
+        }
+    }
+
+    /// Map all lvalues in the conditions and values.
+    pub fn map(self, f: impl Fn(LValue) -> LValue + Clone) -> Self {
+        match self {
+            Self::Switch(SignalSwitch(cases)) => Self::Switch(SignalSwitch(
+                cases
+                    .into_iter()
+                    .map(|case| Case {
+                        tunnel: case.tunnel,
+                        condition: case.condition.map(f.clone()),
+                        value: case.value.map(f.clone()),
+                    })
+                    .collect(),
+            )),
+            Self::Expr(SignalSourceExpr { tunnel, expr }) => Self::Expr(SignalSourceExpr {
+                tunnel,
+                expr: expr.map(f),
+            }),
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SignalDest {
     pub tunnel: Option<syn::Ident>,
     pub dest: LValue,
@@ -184,14 +208,7 @@ impl Parse for SignalDest {
         let attr = input.call(syn::Attribute::parse_outer)?;
         let dest: LValue = input.parse()?;
 
-        let tunnel = attr.iter().find_map(|attr| {
-            if attr.path().is_ident("tunnel") {
-                let tunnel_name: syn::Ident = attr.parse_args().unwrap();
-                Some(tunnel_name)
-            } else {
-                None
-            }
-        });
+        let tunnel = parse_tunnel(&attr)?;
 
         Ok(Self {
             tunnel,
@@ -218,8 +235,11 @@ impl Parse for SignalDest {
 /// ];
 ///
 /// u8 f_icode = A in [B, C, D];
+///
+/// #[delay(2)]
+/// u64 aluA = [ ... ];
 /// ```
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SignalDef {
     /// name of the variable
     pub name: syn::Ident,
@@ -228,10 +248,24 @@ pub struct SignalDef {
     pub source: SignalSource,
     pub destinations: Vec<SignalDest>,
     pub stage_index: Option<usize>,
+    /// delay of the combinational logic, specified by `#[delay(n)]`
+    pub delay: Option<syn::LitInt>,
 }
 
 impl Parse for SignalDef {
     fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let attr = input.call(syn::Attribute::parse_outer)?;
+        let delay = attr
+            .iter()
+            .find_map(|attr| {
+                if attr.path().is_ident("delay") {
+                    Some(attr.parse_args::<syn::LitInt>())
+                } else {
+                    None
+                }
+            })
+            .transpose()?;
+
         let typ: syn::Type = input.parse()?;
         let name: syn::Ident = input.parse()?;
         let _ = input.parse::<syn::Token![=]>()?;
@@ -258,11 +292,13 @@ impl Parse for SignalDef {
             source,
             destinations,
             stage_index: None,
+            delay,
         })
     }
 }
 
 /// a: b
+#[derive(Clone)]
 pub struct FieldAssign(pub syn::Ident, pub syn::Ident);
 
 impl Parse for FieldAssign {
@@ -274,6 +310,7 @@ impl Parse for FieldAssign {
     }
 }
 
+#[derive(Clone)]
 pub struct ComponentInputs {
     pub name: syn::Ident,
     pub fields: Punctuated<FieldAssign, Token![,]>,
@@ -293,3 +330,41 @@ impl Parse for ComponentInputs {
         Ok(Self { name, fields })
     }
 }
+
+/// `@count(name, expr);`, a performance counter incremented in every cycle
+/// where `expr` holds.
+pub struct Counter {
+    pub name: syn::Ident,
+    pub expr: expr::Expr,
+}
+
+impl Parse for Counter {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let args;
+        let _ = syn::parenthesized!(args in input);
+
+        let name = args.parse::<syn::Ident>()?;
+        let _ = args.parse::<Token![,]>()?;
+        let expr = args.parse()?;
+        Ok(Self { name, expr })
+    }
+}
+
+/// `@assert(expr, "message");`, an invariant checked at the end of every
+/// cycle.
+pub struct Assertion {
+    pub expr: expr::Expr,
+    pub message: syn::LitStr,
+}
+
+impl Parse for Assertion {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let args;
+        let _ = syn::parenthesized!(args in input);
+
+        let expr = args.parse()?;
+        let _ = args.parse::<Token![,]>()?;
+        let message = args.parse()?;
+        Ok(Self { expr, message })
+    }
+}
@@ -1,11 +1,15 @@
-use std::{env, path::PathBuf};
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
 
 use expr::LValue;
 use items::{SignalDef, SignalSourceExpr, SignalSwitch};
-use quote::{format_ident, quote, ToTokens};
+use quote::{format_ident, quote, quote_spanned, ToTokens};
 use syn::{parse::Parse, parse_quote, punctuated::Punctuated, Token};
 mod expr;
 mod items;
+mod module;
 
 struct HclData {
     hardware: syn::ExprPath,
@@ -16,83 +20,52 @@ struct HclData {
     stage_decls: Vec<items::StageDecl>,
     use_items: Vec<syn::ItemUse>,
     intermediate_signals: Vec<items::SignalDef>,
+    counters: Vec<items::Counter>,
+    assertions: Vec<items::Assertion>,
+    /// files of `@import`, which the generated code depends on
+    imports: Vec<String>,
+    diagram: Option<DiagramFile>,
+}
+
+/// The template given by `@diagram("path")`, see `framework::Diagram`.
+struct DiagramFile {
+    lit: syn::LitStr,
+    /// absolute path of the file
+    path: String,
+    tunnels: Vec<String>,
+    signals: Vec<String>,
 }
 
 impl Parse for HclData {
     fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
         // Parse inner attributes
         let attrs = syn::Attribute::parse_inner(input)?;
-        // find the hardware attribute
-        let hardware = attrs
-            .iter()
-            .find_map(|attr| {
-                if attr.path().is_ident("hardware") {
-                    let value = &attr.meta.require_name_value().unwrap().value;
-                    // parse value as path
-                    let syn::Expr::Path(path) = value else {
-                        panic!("hardware attribute must be a path");
-                    };
-
-                    Some(path)
-                } else {
-                    None
-                }
-            })
-            .cloned()
-            .unwrap();
+        let hardware = path_attr(&attrs, "hardware")?;
 
         let stage_alias = attrs
             .iter()
-            .find_map(|attr| {
-                if attr.path().is_ident("stage_alias") {
-                    let stage_alias = attr.parse_args::<items::StageAlias>().unwrap();
-
-                    Some(stage_alias)
-                } else {
-                    None
-                }
-            })
+            .find(|attr| attr.path().is_ident("stage_alias"))
+            .map(|attr| attr.parse_args::<items::StageAlias>())
+            .transpose()?
             .unwrap_or_default();
 
-        let program_counter = attrs
-            .iter()
-            .find_map(|attr| {
-                if attr.path().is_ident("program_counter") {
-                    let value = &attr.meta.require_name_value().unwrap().value;
-                    // parse value as path
-                    let syn::Expr::Path(path) = value else {
-                        panic!("program_counter attribute must be a path");
-                    };
-
-                    Some(parse_quote!(#path))
-                } else {
-                    None
-                }
-            })
-            .unwrap();
-
-        let termination = attrs
-            .iter()
-            .find_map(|attr| {
-                if attr.path().is_ident("termination") {
-                    let value = &attr.meta.require_name_value().unwrap().value;
-                    // parse value as path
-                    let syn::Expr::Path(path) = value else {
-                        panic!("program_counter attribute must be a path");
-                    };
-
-                    Some(parse_quote!(#path))
-                } else {
-                    None
-                }
-            })
-            .unwrap();
+        let program_counter = path_attr(&attrs, "program_counter")?;
+        let program_counter: LValue = parse_quote!(#program_counter);
+        let termination = path_attr(&attrs, "termination")?;
+        let termination: LValue = parse_quote!(#termination);
 
         let mut use_items = Vec::new();
         let mut intermediate_signals = Vec::new();
         let mut stage_decls = Vec::new();
         let mut set_inputs = Vec::new();
         let mut set_stages = Vec::new();
+        let mut counters = Vec::new();
+        let mut assertions = Vec::new();
+        let mut modules = Vec::new();
+        // (index of the next signal, number of stage dividers so far, instance)
+        let mut instances = Vec::new();
+        let mut imports = Vec::new();
+        let mut diagram = None;
 
         // repeatly parse the rest of the input
         loop {
@@ -116,11 +89,52 @@ impl Parse for HclData {
                     let unit_input = input.parse::<items::ComponentInputs>()?;
                     set_stages.push(unit_input);
                     let _ = input.parse::<Token![;]>()?;
+                } else if fn_name == "count" {
+                    counters.push(input.parse::<items::Counter>()?);
+                    let _ = input.parse::<Token![;]>()?;
+                } else if fn_name == "assert" {
+                    assertions.push(input.parse::<items::Assertion>()?);
+                    let _ = input.parse::<Token![;]>()?;
+                } else if fn_name == "instance" {
+                    let instance = input.parse::<module::Instance>()?;
+                    instances.push((intermediate_signals.len(), stage_decls.len(), instance));
+                    let _ = input.parse::<Token![;]>()?;
+                } else if fn_name == "import" {
+                    let args;
+                    let _ = syn::parenthesized!(args in input);
+                    let (path, file) = import_modules(&args.parse()?)?;
+                    modules.extend(file.0);
+                    imports.push(path);
+                    let _ = input.parse::<Token![;]>()?;
+                } else if fn_name == "diagram" {
+                    let args;
+                    let _ = syn::parenthesized!(args in input);
+                    if diagram.is_some() {
+                        return Err(syn::Error::new_spanned(
+                            &fn_name,
+                            "an architecture has a single `@diagram`",
+                        ));
+                    }
+                    diagram = Some(read_diagram(args.parse()?)?);
+                    let _ = input.parse::<Token![;]>()?;
                 } else {
-                    panic!("unknown directive: {}", fn_name);
+                    return Err(syn::Error::new_spanned(
+                        &fn_name,
+                        format!(
+                            "unknown directive `@{fn_name}`, expected `@set_input`, `@set_stage`, `@count`, `@assert`, `@instance`, `@import` or `@diagram`"
+                        ),
+                    ));
                 }
+            } else if module::Module::peek(input) {
+                modules.push(input.parse::<module::Module>()?);
             } else {
                 let mut item = input.parse::<items::SignalDef>()?;
+                if module::is_reserved(&item.name) {
+                    return Err(syn::Error::new_spanned(
+                        &item.name,
+                        "a name starting with `_` and a digit is reserved for the signals of instances",
+                    ));
+                }
                 if !stage_decls.is_empty() {
                     item.stage_index = Some(stage_decls.len() - 1);
                 }
@@ -133,36 +147,132 @@ impl Parse for HclData {
             }
         }
 
-        for unit_input in set_inputs {
-            let uname = unit_input.name;
-            for fieldvalue in unit_input.fields {
-                let sig = intermediate_signals
-                    .iter_mut()
-                    .find(|s| s.name == fieldvalue.1)
-                    .unwrap();
-                sig.destinations.push(items::SignalDest {
-                    dest: LValue([uname.clone(), fieldvalue.0].into_iter().collect()),
-                    tunnel: None,
-                    is_stage_field: false,
+        // expand the instances where they are, from the last one so that the
+        // positions of the others stay valid
+        let mut instance_names: Vec<(syn::Ident, &module::Module)> = Vec::new();
+        for (pos, n_decls, instance) in instances.iter().rev() {
+            let Some(module) = modules.iter().find(|m| m.name == instance.module) else {
+                return Err(syn::Error::new_spanned(
+                    &instance.module,
+                    format!("undefined module `{}`", instance.module),
+                ));
+            };
+            if instance_names
+                .iter()
+                .any(|(name, _)| name == &instance.name)
+            {
+                return Err(syn::Error::new_spanned(
+                    &instance.name,
+                    format!("instance `{}` is defined twice", instance.name),
+                ));
+            }
+            if stage_alias
+                .0
+                .iter()
+                .any(|(cur, pre)| cur == &instance.name || pre == &instance.name)
+            {
+                return Err(syn::Error::new_spanned(
+                    &instance.name,
+                    format!("instance `{}` has the name of a stage", instance.name),
+                ));
+            }
+            instance_names.push((instance.name.clone(), module));
+
+            let (mut signals, inputs) = module.instantiate(instance)?;
+            for item in &mut signals {
+                item.stage_index = n_decls.checked_sub(1);
+                item.destinations.iter_mut().for_each(|dest| {
+                    if stage_alias.0.iter().any(|(cur, _)| cur == &dest.dest.0[0]) {
+                        dest.is_stage_field = true;
+                    }
                 });
             }
+            intermediate_signals.splice(*pos..*pos, signals);
+            for (inputs, is_stage_field) in inputs {
+                if is_stage_field {
+                    set_stages.push(inputs);
+                } else {
+                    set_inputs.push(inputs);
+                }
+            }
+        }
+
+        // `fwd_a.val` reads the signal `val` of instance `fwd_a`
+        let mut lvalues: Vec<LValue> = vec![program_counter.clone(), termination.clone()];
+        for signal in &intermediate_signals {
+            lvalues.extend(signal.source.lvalues());
+        }
+        for expr in counters
+            .iter()
+            .map(|c| &c.expr)
+            .chain(assertions.iter().map(|a| &a.expr))
+        {
+            lvalues.extend(expr.lvalues());
         }
-        for stage_input in set_stages {
-            let uname = stage_input.name;
-            for fieldvalue in stage_input.fields {
-                let sig = intermediate_signals
+        for lv in &lvalues {
+            let Some((inst, module)) = instance_names.iter().find(|(name, _)| name == &lv.0[0])
+            else {
+                continue;
+            };
+            if lv.0.len() != 2 || module.outputs.iter().all(|out| out != &lv.0[1]) {
+                let name = lv.to_token_stream().to_string().replace(' ', "");
+                return Err(syn::Error::new_spanned(
+                    lv,
+                    format!(
+                        "`{name}` is not an output of instance `{inst}` of module `{}`",
+                        module.name
+                    ),
+                ));
+            }
+        }
+        let output = |lv: LValue| {
+            if lv.0.len() == 2 && instance_names.iter().any(|(name, _)| name == &lv.0[0]) {
+                LValue(std::iter::once(module::instance_signal(&lv.0[0], &lv.0[1])).collect())
+            } else {
+                lv
+            }
+        };
+        for signal in &mut intermediate_signals {
+            signal.source = signal.source.clone().map(output);
+        }
+        for counter in &mut counters {
+            counter.expr = counter.expr.clone().map(output);
+        }
+        for assertion in &mut assertions {
+            assertion.expr = assertion.expr.clone().map(output);
+        }
+        let program_counter = output(program_counter);
+        let termination = output(termination);
+
+        let set_inputs = set_inputs.into_iter().map(|inputs| (inputs, false));
+        let set_stages = set_stages.into_iter().map(|inputs| (inputs, true));
+        for (inputs, is_stage_field) in set_inputs.chain(set_stages) {
+            let uname = inputs.name;
+            if is_stage_field && stage_alias.0.iter().all(|(cur, _)| cur != &uname) {
+                return Err(syn::Error::new_spanned(
+                    &uname,
+                    format!("stage `{uname}` is not listed in `#![stage_alias(...)]`"),
+                ));
+            }
+            for fieldvalue in inputs.fields {
+                let Some(sig) = intermediate_signals
                     .iter_mut()
                     .find(|s| s.name == fieldvalue.1)
-                    .unwrap();
+                else {
+                    return Err(syn::Error::new_spanned(
+                        &fieldvalue.1,
+                        format!("undefined signal `{}`", fieldvalue.1),
+                    ));
+                };
                 sig.destinations.push(items::SignalDest {
                     dest: LValue([uname.clone(), fieldvalue.0].into_iter().collect()),
                     tunnel: None,
-                    is_stage_field: true,
+                    is_stage_field,
                 });
             }
         }
 
-        Ok(Self {
+        let data = Self {
             stage_alias,
             hardware,
             program_counter,
@@ -170,11 +280,280 @@ impl Parse for HclData {
             use_items,
             intermediate_signals,
             stage_decls,
-        })
+            counters,
+            assertions,
+            imports,
+            diagram,
+        };
+        data.validate()?;
+        Ok(data)
+    }
+}
+
+/// Path of a file given to `@import` or `@diagram`, relative to the file
+/// invoking `hcl!`, as the interpreter resolves it relative to the HCL file.
+/// Outside of a macro expansion (in the tests of this crate) it is relative
+/// to the root of the crate. The path is absolute, for `include_str!`.
+fn included_path(path: &str) -> PathBuf {
+    let source = proc_macro::is_available()
+        .then(|| proc_macro::Span::call_site().local_file())
+        .flatten();
+    let dir = match source.as_deref().and_then(Path::parent) {
+        Some(dir) => dir.to_path_buf(),
+        None => env::var_os("CARGO_MANIFEST_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_default(),
+    };
+    let file = dir.join(path);
+    std::path::absolute(&file).unwrap_or(file)
+}
+
+/// Name of `lv` as the simulator reports it, e.g. `fwd_a.val` or `imem.pc`.
+fn lvalue_name(lv: &LValue) -> String {
+    lv.0.iter()
+        .map(module::signal_name)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Read the modules of `@import("path")`, see [`included_path`]. The content
+/// is the absolute path of the file.
+fn import_modules(path: &syn::LitStr) -> syn::Result<(String, module::ModuleFile)> {
+    let file = included_path(&path.value());
+    let src = std::fs::read_to_string(&file).map_err(|e| {
+        syn::Error::new_spanned(path, format!("can't read {}: {e}", file.display()))
+    })?;
+    let modules = syn::parse_str(&src)
+        .map_err(|e| syn::Error::new_spanned(path, format!("in {}: {e}", file.display())))?;
+    Ok((file.to_string_lossy().to_string(), modules))
+}
+
+/// Read the names of tunnels and signals in the template of
+/// `@diagram("path")`, see [`included_path`]. The template itself is parsed
+/// at run time.
+fn read_diagram(lit: syn::LitStr) -> syn::Result<DiagramFile> {
+    let file = included_path(&lit.value());
+    let src = std::fs::read_to_string(&file).map_err(|e| {
+        syn::Error::new_spanned(&lit, format!("can't read {}: {e}", file.display()))
+    })?;
+    // the drawing, the mask and the tunnels, separated by `---`
+    let mut parts = vec![Vec::new()];
+    for line in src.lines() {
+        if line.trim_end() == "---" {
+            parts.push(Vec::new());
+        } else {
+            parts.last_mut().unwrap().push(line);
+        }
+    }
+    let tunnels = parts
+        .get(2)
+        .into_iter()
+        .flatten()
+        .filter_map(|l| l.split_once('='))
+        .map(|(_, name)| name.trim().to_string())
+        .collect();
+    let signals = parts[0]
+        .iter()
+        .flat_map(|l| l.split('{').skip(1))
+        .filter_map(|s| s.split_once('}'))
+        .map(|(name, _)| name.trim().to_string())
+        .collect();
+    Ok(DiagramFile {
+        lit,
+        path: file.to_string_lossy().to_string(),
+        tunnels,
+        signals,
+    })
+}
+
+/// The path given by `#![name = path]`.
+fn path_attr(attrs: &[syn::Attribute], name: &str) -> syn::Result<syn::ExprPath> {
+    let Some(attr) = attrs.iter().find(|attr| attr.path().is_ident(name)) else {
+        return Err(syn::Error::new(
+            proc_macro2::Span::call_site(),
+            format!("missing `#![{name} = ...]` attribute"),
+        ));
+    };
+    match &attr.meta.require_name_value()?.value {
+        syn::Expr::Path(path) => Ok(path.clone()),
+        value => Err(syn::Error::new_spanned(
+            value,
+            format!("`{name}` attribute must be a path"),
+        )),
     }
 }
 
 impl HclData {
+    /// Check the names used in the HCL. Errors point to the offending tokens.
+    ///
+    /// Unit ports are not known here, they are checked by
+    /// [`HclData::render_port_checks`] when the generated code compiles.
+    fn validate(&self) -> syn::Result<()> {
+        let mut errors = Vec::new();
+        let is_signal =
+            |ident: &syn::Ident| self.intermediate_signals.iter().any(|s| &s.name == ident);
+        let stage_alias = &self.stage_alias.0;
+
+        for lv in [&self.program_counter, &self.termination] {
+            if !is_signal(&lv.0[0]) {
+                errors.push(syn::Error::new_spanned(
+                    lv,
+                    format!("undefined signal `{}`", lv.0[0]),
+                ));
+            }
+        }
+
+        // `is_counter`: counters and assertions may also read the stage inputs
+        let mut check_expr = |lv: &LValue, is_counter: bool| {
+            let head = &lv.0[0];
+            if lv.0.len() == 1 {
+                // constants such as `NOP` or `Aok` are left to rustc
+                if !is_signal(head) && head.to_string().starts_with(|c: char| c.is_lowercase()) {
+                    errors.push(syn::Error::new_spanned(
+                        lv,
+                        format!("undefined signal `{head}`"),
+                    ));
+                }
+            } else if let Some((_, pre)) = stage_alias.iter().find(|(cur, _)| cur == head) {
+                if !is_counter {
+                    let field = &lv.0[1];
+                    errors.push(syn::Error::new_spanned(
+                        lv,
+                        format!("the input of stage `{head}` can't be read by a signal, use `{pre}.{field}`"),
+                    ));
+                }
+            } else if stage_alias.iter().all(|(_, pre)| pre != head)
+                && head.to_string().starts_with(|c: char| c.is_uppercase())
+            {
+                errors.push(syn::Error::new_spanned(
+                    head,
+                    format!("stage `{head}` is not listed in `#![stage_alias(...)]`"),
+                ));
+            }
+        };
+        for signal in &self.intermediate_signals {
+            signal
+                .source
+                .lvalues()
+                .iter()
+                .for_each(|lv| check_expr(lv, false));
+        }
+        for expr in self.counters.iter().map(|c| &c.expr) {
+            expr.lvalues().iter().for_each(|lv| check_expr(lv, true));
+        }
+        for expr in self.assertions.iter().map(|a| &a.expr) {
+            expr.lvalues().iter().for_each(|lv| check_expr(lv, true));
+        }
+
+        for (i, signal) in self.intermediate_signals.iter().enumerate() {
+            if self.intermediate_signals[..i]
+                .iter()
+                .any(|s| s.name == signal.name)
+            {
+                errors.push(syn::Error::new_spanned(
+                    &signal.name,
+                    format!("signal `{}` is defined twice", signal.name),
+                ));
+            }
+        }
+
+        if let Some(diagram) = &self.diagram {
+            let tunnels = self
+                .intermediate_signals
+                .iter()
+                .flat_map(|s| {
+                    let dests = s.destinations.iter().filter_map(|d| d.tunnel.as_ref());
+                    s.source.tunnels().into_iter().chain(dests)
+                })
+                .collect::<Vec<_>>();
+            for name in &diagram.tunnels {
+                if tunnels.iter().all(|t| *t != name) {
+                    errors.push(syn::Error::new_spanned(
+                        &diagram.lit,
+                        format!("`{name}` of the diagram is not a tunnel"),
+                    ));
+                }
+            }
+            for name in &diagram.signals {
+                if self
+                    .intermediate_signals
+                    .iter()
+                    .all(|s| module::signal_name(&s.name) != *name)
+                {
+                    errors.push(syn::Error::new_spanned(
+                        &diagram.lit,
+                        format!("`{{{name}}}` of the diagram is not a signal"),
+                    ));
+                }
+            }
+        }
+
+        // each stage field has a single source
+        let mut assigned: Vec<(String, &syn::Ident)> = Vec::new();
+        for signal in &self.intermediate_signals {
+            for dest in signal.destinations.iter().filter(|d| d.is_stage_field) {
+                let field = dest.dest.to_token_stream().to_string().replace(' ', "");
+                match assigned.iter().find(|(f, _)| f == &field) {
+                    Some((_, other)) => errors.push(syn::Error::new_spanned(
+                        &dest.dest,
+                        format!("stage field `{field}` is already assigned by signal `{other}`"),
+                    )),
+                    None => assigned.push((field, &signal.name)),
+                }
+            }
+        }
+
+        match errors.into_iter().reduce(|mut a, b| {
+            a.combine(b);
+            a
+        }) {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// A constant assertion for each unit port read or written by the HCL,
+    /// reporting an unknown port at its token.
+    fn render_port_checks(&self) -> proc_macro2::TokenStream {
+        let stage_alias = &self.stage_alias.0;
+        let is_port = |lv: &LValue| {
+            let head = &lv.0[0];
+            lv.0.len() == 2
+                && stage_alias
+                    .iter()
+                    .all(|(cur, pre)| cur != head && pre != head)
+                && head.to_string().starts_with(|c: char| c.is_lowercase())
+        };
+        let sources = self
+            .intermediate_signals
+            .iter()
+            .flat_map(|s| s.source.lvalues())
+            .chain(self.counters.iter().flat_map(|c| c.expr.lvalues()))
+            .chain(self.assertions.iter().flat_map(|a| a.expr.lvalues()));
+        let dests = self
+            .intermediate_signals
+            .iter()
+            .flat_map(|s| s.destinations.iter())
+            .filter(|d| !d.is_stage_field)
+            .map(|d| d.dest.clone());
+
+        let mut checked = Vec::new();
+        let mut checks = proc_macro2::TokenStream::new();
+        for lv in sources.chain(dests).filter(is_port) {
+            let name = lv.to_token_stream().to_string().replace(' ', "");
+            if checked.contains(&name) {
+                continue;
+            }
+            let message = format!("unknown unit port `{name}`");
+            let span = lv.0[1].span();
+            checks.extend(quote_spanned! {span=>
+                const _: () = assert!(crate::dsl::has_name(PORT_NAMES, #name), #message);
+            });
+            checked.push(name);
+        }
+        checks
+    }
+
     fn render_intermediate_signal_struct(&self) -> proc_macro2::TokenStream {
         let signal_fields: Punctuated<syn::Field, Token![,]> = self
             .intermediate_signals
@@ -202,6 +581,13 @@ impl HclData {
         lval_mapper: impl Fn(LValue) -> LValue + Clone,
     ) -> proc_macro2::TokenStream {
         let name = &signal.name;
+        let name_str = module::signal_name(name);
+        let typ = &signal.typ;
+        // the value converted to the declared type, checked at the HCL source
+        let typed = |orig: &expr::Expr, value: expr::Expr| {
+            let span = syn::spanned::Spanned::span(orig);
+            quote_spanned! {span=> <_ as crate::dsl::Assign<#typ>>::value(#value) }
+        };
 
         let source_stmts = match &signal.source {
             items::SignalSource::Switch(SignalSwitch(cases)) => {
@@ -209,7 +595,7 @@ impl HclData {
                     .iter()
                     .map(|case| {
                         let cond = case.condition.clone().map(expr_mapper.clone());
-                        let val = case.value.clone().map(expr_mapper.clone());
+                        let val = typed(&case.value, case.value.clone().map(expr_mapper.clone()));
                         let tunnel_stmts = case.tunnel.as_ref().cloned().map(|tunnel| {
                             quote! {
                                 has_tunnel_input = true;
@@ -233,7 +619,7 @@ impl HclData {
                 }
             }
             items::SignalSource::Expr(SignalSourceExpr { tunnel, expr }) => {
-                let expr = expr.clone().map(expr_mapper.clone());
+                let expr = typed(expr, expr.clone().map(expr_mapper.clone()));
                 let tunnel_stmts = tunnel.as_ref().cloned().map(|tunnel| {
                     quote! {
                         has_tunnel_input = true;
@@ -269,8 +655,10 @@ impl HclData {
                 } else {
                     quote! { i_.#dst }
                 };
-                quote! {
-                    #dst_name = c_.#name.to_owned();
+                // for `@set_input` and `@set_stage`, the field is spanned in HCL
+                let span = dest.dest.0.last().unwrap().span();
+                quote_spanned! {span=>
+                    crate::dsl::wire(&mut #dst_name, &c_.#name);
                     #tunner_stmt
                 }
             })
@@ -291,11 +679,83 @@ impl HclData {
                     #source_stmts
                     #dest_tunnel_stmts
                 };
-                circuit.add_update(stringify!(#name), updater);
+                circuit.add_update(#name_str, updater);
             }
         }
     }
 
+    /// Statements that add the intermediate signals and their edges to a
+    /// graph builder `g`, shared by the propagation order and the timing
+    /// model.
+    fn render_graph_stmts(&self) -> proc_macro2::TokenStream {
+        let inter_names = self
+            .intermediate_signals
+            .iter()
+            .map(|s| &s.name)
+            .collect::<Vec<_>>();
+        let stage_alias = &self.stage_alias.0;
+
+        self.intermediate_signals
+            .iter()
+            .map(|signal| {
+                let name_str = module::signal_name(&signal.name);
+                let update_stmts = signal
+                    .source
+                    .lvalues()
+                    .into_iter()
+                    .filter(|lv| {
+                        // is intermediate signal or unit port
+                        // previous stage fields are not included
+                        lv.0.len() == 1 && inter_names.iter().any(|n| &lv.0[0] == *n)
+                            || lv.0.len() == 2 && stage_alias.iter().all(|(_, pre)| &lv.0[0] != pre)
+                    })
+                    .map(|lv| {
+                        let lv_name = lvalue_name(&lv);
+                        quote! {
+                            g.add_edge(#lv_name.to_string(), #name_str.to_string());
+                        }
+                    })
+                    .reduce(|a, b| quote! { #a #b })
+                    .unwrap_or_default();
+                // .collect::<Punctuated<LValue, Token![,]>>();
+
+                let update_stmts = quote! {
+                    #update_stmts
+                    g.add_intermediate(#name_str);
+                };
+                let rev_deps_stmts = signal
+                    .destinations
+                    .iter()
+                    .map(move |dest| {
+                        // the dest is either a stage name or input of a device
+                        let dest_name = lvalue_name(&dest.dest);
+
+                        if dest.is_stage_field {
+                            // dest is a stage output
+                            // no edge is needed
+                            quote! {}
+                        } else {
+                            // dest is a device input
+                            quote! {
+                                g.add_edge(
+                                    #name_str.to_string(),
+                                    #dest_name.to_string()
+                                );
+                            }
+                        }
+                    })
+                    .reduce(|a, b| quote! { #a #b })
+                    .unwrap_or_default();
+
+                quote! {
+                    #update_stmts
+                    #rev_deps_stmts
+                }
+            })
+            .reduce(|a, b| quote! { #a #b })
+            .unwrap_or_default()
+    }
+
     fn render_build_circuit(&self) -> proc_macro2::TokenStream {
         let inter = &quote::format_ident!("c_");
         let inter_names = self
@@ -334,62 +794,7 @@ impl HclData {
             .reduce(|a, b| quote! { #a #b })
             .unwrap_or_default();
 
-        let stmts =
-            self.intermediate_signals
-                .iter()
-                .map(|signal| {
-                    let name = &signal.name;
-                    let update_stmts = signal
-                    .source
-                    .lvalues()
-                    .into_iter()
-                    .filter(|lv| {
-                        // is intermediate signal or unit port
-                        // previous stage fields are not included
-                        lv.0.len() == 1 && inter_names.iter().any(|n| &lv.0[0] == *n)
-                            || lv.0.len() == 2 && stage_alias.iter().all(|(_, pre)| &lv.0[0] != pre)
-                    })
-                    .map(|lv| quote! {
-                        g.add_edge(stringify!(#lv).to_string(), stringify!(#name).to_string());
-                    })
-                    .reduce(|a, b| quote! { #a #b }).unwrap_or_default();
-                    // .collect::<Punctuated<LValue, Token![,]>>();
-
-                    let update_stmts = quote! {
-                        #update_stmts
-                        g.add_intermediate(stringify!(#name));
-                    };
-                    let rev_deps_stmts = signal
-                        .destinations
-                        .iter()
-                        .map(move |dest| {
-                            // the dest is either a stage name or input of a device
-                            let dest_name = &dest.dest;
-
-                            if dest.is_stage_field {
-                                // dest is a stage output
-                                // no edge is needed
-                                quote! {}
-                            } else {
-                                // dest is a device input
-                                quote! {
-                                    g.add_edge(
-                                        stringify!(#name).to_string(),
-                                        stringify!(#dest_name).to_string()
-                                    );
-                                }
-                            }
-                        })
-                        .reduce(|a, b| quote! { #a #b })
-                        .unwrap_or_default();
-
-                    quote! {
-                        #update_stmts
-                        #rev_deps_stmts
-                    }
-                })
-                .reduce(|a, b| quote! { #a #b })
-                .unwrap_or_default();
+        let stmts = self.render_graph_stmts();
 
         quote! {
             fn build_circuit() -> crate::framework::PropCircuit<Arch> {
@@ -416,6 +821,37 @@ impl HclData {
         }
     }
 
+    fn render_timing(&self) -> proc_macro2::TokenStream {
+        let delays = self
+            .intermediate_signals
+            .iter()
+            .filter_map(|signal| {
+                let name = module::signal_name(&signal.name);
+                let delay = signal.delay.as_ref()?;
+                Some(quote! { (#name, #delay) })
+            })
+            .collect::<Vec<_>>();
+
+        quote! {
+            impl Arch {
+                /// Weighted timing model of this architecture, built once on
+                /// first use from its propagation order.
+                #[allow(unused)]
+                pub fn timing() -> &'static crate::framework::TimingModel {
+                    use crate::framework::*;
+
+                    static TIMING: std::sync::OnceLock<TimingModel> = std::sync::OnceLock::new();
+                    TIMING.get_or_init(|| {
+                        let order = <Arch as CpuArch>::build_circuit().order;
+                        let mut delays = unit_delays();
+                        delays.extend_from_slice(&[#(#delays),*]);
+                        TimingModel::new(&order, &delays)
+                    })
+                }
+            }
+        }
+    }
+
     fn render_update(&self) -> proc_macro2::TokenStream {
         quote! {
             /// Simulate one cycle of the CPU, update the input and output signals
@@ -448,6 +884,131 @@ impl HclData {
         }
     }
 
+    /// Map the lvalues of an expression evaluated at the end of a cycle, by
+    /// `count_events` and `check_assertions`. Unlike the updaters, it may
+    /// also read the inputs of the stages (e.g. `d.stall`), which are final
+    /// at the end of the cycle.
+    fn end_of_cycle_mapper(&self) -> impl Fn(LValue) -> LValue + Clone + '_ {
+        let stage_alias = &self.stage_alias.0;
+        move |mut lv: LValue| -> LValue {
+            if self.intermediate_signals.iter().any(|s| s.name == lv.0[0]) {
+                lv.0.insert(0, format_ident!("c_"));
+            } else if let Some((cur, _)) = stage_alias.iter().find(|(_, pre)| &lv.0[0] == pre) {
+                lv.0[0] = cur.clone();
+                lv.0.insert(0, format_ident!("p_"));
+            } else if stage_alias.iter().any(|(cur, _)| &lv.0[0] == cur) {
+                lv.0.insert(0, format_ident!("n_"));
+            } else if lv.0.len() > 1 {
+                lv.0.insert(0, format_ident!("o_"));
+            }
+            lv
+        }
+    }
+
+    /// The condition of a directive evaluated at the end of a cycle. It must
+    /// be a `bool`: unlike the cases of a switch, a word is not compared with
+    /// 0, so rustc reports anything else at the condition.
+    fn end_of_cycle_condition(&self, expr: &expr::Expr) -> proc_macro2::TokenStream {
+        let span = syn::spanned::Spanned::span(expr);
+        let cond = expr.clone().map(self.end_of_cycle_mapper());
+        quote_spanned! {span=> { let cond_: bool = #cond; cond_ } }
+    }
+
+    fn render_count_events(&self) -> proc_macro2::TokenStream {
+        let count_stmts = self
+            .counters
+            .iter()
+            .map(|counter| {
+                let name = &counter.name;
+                let cond = self.end_of_cycle_condition(&counter.expr);
+                quote! {
+                    self.counters.count(stringify!(#name), u64::from(#cond));
+                }
+            })
+            .reduce(|a, b| quote! { #a #b })
+            .unwrap_or_default();
+
+        quote! {
+            /// Update the performance counters at the end of a cycle.
+            #[allow(unused)]
+            #[allow(non_snake_case)]
+            fn count_events(&mut self) {
+                use crate::isa::inst_code::*;
+                use crate::isa::reg_code::*;
+                use crate::isa::op_code::*;
+
+                let c_ = &self.cur_inter;
+                let p_ = &self.cur_state;
+                let n_ = &self.nex_state;
+                let o_ = &self.cur_unit_out;
+                self.counters.cycles += 1;
+                n_.count_controls(&mut self.counters);
+                #count_stmts
+            }
+        }
+    }
+
+    fn render_check_assertions(&self) -> proc_macro2::TokenStream {
+        let expr_mapper = self.end_of_cycle_mapper();
+        let assert_stmts = self
+            .assertions
+            .iter()
+            .map(|assertion| {
+                let message = &assertion.message;
+                let cond = self.end_of_cycle_condition(&assertion.expr);
+                // values of the signals in the condition (not the constants),
+                // each reported once
+                let mut names = Vec::new();
+                let mut values = Vec::new();
+                for lv in assertion.expr.lvalues() {
+                    let is_constant = lv.0.len() == 1
+                        && self.intermediate_signals.iter().all(|s| s.name != lv.0[0]);
+                    let name = lvalue_name(&lv);
+                    if !is_constant && !names.contains(&name) {
+                        let value = lv.map(expr_mapper.clone());
+                        values.push(quote! { (#name.to_string(), format!("{:#x?}", #value)) });
+                        names.push(name);
+                    }
+                }
+                quote! {
+                    if !#cond {
+                        return Err(crate::framework::AssertionFailure {
+                            cycle: self.cycle_count,
+                            message: #message.to_string(),
+                            values: vec![#(#values),*],
+                        });
+                    }
+                }
+            })
+            .reduce(|a, b| quote! { #a #b })
+            .unwrap_or_default();
+
+        quote! {
+            /// Check the `@assert` directives, and that no stage is bubbled
+            /// and stalled at the same time, at the end of a cycle.
+            #[allow(unused)]
+            #[allow(non_snake_case)]
+            fn check_assertions(&self) -> Result<(), crate::framework::AssertionFailure> {
+                use crate::isa::inst_code::*;
+                use crate::isa::reg_code::*;
+                use crate::isa::op_code::*;
+
+                let c_ = &self.cur_inter;
+                let p_ = &self.cur_state;
+                let n_ = &self.nex_state;
+                let o_ = &self.cur_unit_out;
+                if let Some(stage) = n_.control_conflict() {
+                    return Err(crate::framework::AssertionFailure::control_conflict(
+                        self.cycle_count,
+                        stage,
+                    ));
+                }
+                #assert_stmts
+                Ok(())
+            }
+        }
+    }
+
     fn render_get_stage_info(&self) -> proc_macro2::TokenStream {
         let mut stage_items = self
             .stage_decls
@@ -477,48 +1038,143 @@ impl HclData {
             .map(|sig| {
                 let stage_index = sig.stage_index.unwrap_or(0);
                 let name = &sig.name;
-
-                let formatted_val = if let syn::Type::Array(_) = &sig.typ {
-                    quote! {
-                        format!("{:x?}", self.cur_inter.#name)
-                    }
-                } else if let syn::Type::Path(p) = &sig.typ {
-                    if ["u8", "u64"].into_iter().any(|t| p.path.is_ident(t)) {
-                        quote! {
-                            format!("{:#x?}", self.cur_inter.#name)
-                        }
-                    } else {
-                        quote! {
-                            format!("{:x?}", self.cur_inter.#name)
-                        }
-                    }
-                } else {
-                    quote! {
-                        format!("{:#x?}", self.cur_inter.#name)
-                    }
-                };
-
+                let name_str = module::signal_name(name);
+                let typ = &sig.typ;
                 quote! {
-                    info[#stage_index].signals.push((
-                        stringify!(#name).to_string(),
-                        #formatted_val
+                    info[#stage_index].signals.push(crate::framework::Signal::new(
+                        #name_str,
+                        crate::framework::SignalKind::Intermediate,
+                        stringify!(#typ),
+                        &self.cur_inter.#name,
                     ));
                 }
             })
             .reduce(|a, b| quote! { #a #b })
             .unwrap_or_default();
+        let alias = self.stage_alias.0.iter().map(|(cur, pre)| {
+            quote! { (stringify!(#cur), stringify!(#pre)) }
+        });
 
         quote! {
             #[allow(unused)]
             #[allow(non_snake_case)]
             fn get_stage_info(&self) -> Vec<crate::framework::StageInfo> {
+                use crate::framework::SignalKind;
                 let mut info = vec![#stage_items];
                 #sig_stmts
+
+                // fields at the start of the cycle, and the stage controls
+                // computed in it
+                let mut regs = self.cur_state.signals();
+                for (sig, next) in regs.iter_mut().zip(self.nex_state.signals()) {
+                    if sig.kind == SignalKind::StageControl {
+                        *sig = next;
+                    } else {
+                        sig.alias_stage(&[#(#alias),*]);
+                    }
+                }
+                info.push(crate::framework::StageInfo {
+                    name: "Pipeline registers",
+                    signals: regs,
+                });
+                let mut ports = self.cur_unit_in.signals();
+                ports.extend(self.cur_unit_out.signals());
+                ports.extend(self.units.signals());
+                info.push(crate::framework::StageInfo {
+                    name: "Units",
+                    signals: ports,
+                });
                 info
             }
         }
     }
 
+    fn render_signal_access(&self) -> proc_macro2::TokenStream {
+        let inter_arms = self.intermediate_signals.iter().map(|sig| {
+            let name = &sig.name;
+            let name_str = module::signal_name(name);
+            let typ = &sig.typ;
+            quote! {
+                #name_str => {
+                    return crate::framework::set_value(
+                        &mut self.cur_inter.#name,
+                        name,
+                        stringify!(#typ),
+                        &value,
+                    );
+                }
+            }
+        });
+        let alias = self.stage_alias.0.iter().map(|(cur, pre)| {
+            quote! { (stringify!(#cur), stringify!(#pre)) }
+        });
+
+        quote! {
+            fn signal(&self, name: &str) -> Option<crate::framework::SignalValue> {
+                self.get_stage_info()
+                    .into_iter()
+                    .flat_map(|stage| stage.signals)
+                    .find(|sig| sig.name == name)
+                    .map(|sig| sig.value)
+            }
+            fn signal_names(&self) -> Vec<String> {
+                self.get_stage_info()
+                    .into_iter()
+                    .flat_map(|stage| stage.signals)
+                    .map(|sig| sig.name)
+                    .collect()
+            }
+            #[allow(unused)]
+            #[allow(non_snake_case)]
+            fn set_signal(
+                &mut self,
+                name: &str,
+                value: crate::framework::SignalValue,
+            ) -> Result<(), crate::framework::SignalError> {
+                use crate::framework::SignalError;
+                match name {
+                    #(#inter_arms)*
+                    _ => {}
+                }
+                // `E.valA` is a field at the start of the cycle, `e.stall` an
+                // input computed in it
+                if let Some((stage, field)) = name.split_once('.') {
+                    let alias: &[(&str, &str)] = &[#(#alias),*];
+                    let is_control = matches!(field, "bubble" | "stall");
+                    let result = match alias.iter().find(|(_, pre)| *pre == stage) {
+                        Some((cur, _)) if !is_control => self
+                            .cur_state
+                            .set_signal(&format!("{cur}.{field}"), &value)
+                            .map_err(|err| match err {
+                                SignalError::Type { typ, value, .. } => SignalError::Type {
+                                    name: name.to_string(),
+                                    typ,
+                                    value,
+                                },
+                                err => err,
+                            }),
+                        Some(_) => Err(SignalError::NotFound(name.to_string())),
+                        None if is_control => self.nex_state.set_signal(name, &value),
+                        None => self.cur_state.set_signal(name, &value),
+                    };
+                    match result {
+                        Err(SignalError::NotFound(_)) => {}
+                        result => return result,
+                    }
+                }
+                match self.cur_unit_in.set_signal(name, &value) {
+                    Err(SignalError::NotFound(_)) => {}
+                    result => return result,
+                }
+                match self.cur_unit_out.set_signal(name, &value) {
+                    Err(SignalError::NotFound(_)) => {}
+                    result => return result,
+                }
+                self.units.set_signal(name, &value)
+            }
+        }
+    }
+
     fn render(&self) -> proc_macro2::TokenStream {
         let hardware = &self.hardware;
         let use_stmts = self
@@ -528,16 +1184,33 @@ impl HclData {
             .reduce(|a, b| quote! { #a #b })
             .unwrap_or_default();
 
+        let port_checks = self.render_port_checks();
         let intermediate_signal_struct = self.render_intermediate_signal_struct();
         let build_circuit_fn = self.render_build_circuit();
+        let timing_fn = self.render_timing();
         let update_fn = self.render_update();
+        let count_events_fn = self.render_count_events();
+        let check_assertions_fn = self.render_check_assertions();
         let get_stage_info_fn = self.render_get_stage_info();
+        let signal_access_fn = self.render_signal_access();
         let pc_name = &self.program_counter;
         let termination = &self.termination;
 
+        // rebuild when an imported file changes
+        let imports = &self.imports;
+        let diagram_fn = match &self.diagram {
+            Some(diagram) => {
+                let path = &diagram.path;
+                quote! { Some(include_str!(#path)) }
+            }
+            None => quote! { None },
+        };
+
         quote! {
             use #hardware::*;
             #use_stmts
+            #(const _: &str = include_str!(#imports);)*
+            #port_checks
 
             #intermediate_signal_struct
 
@@ -556,12 +1229,25 @@ impl HclData {
                 #build_circuit_fn
             }
 
+            #timing_fn
+
             impl crate::framework::PipeSim<Arch> {
                 #update_fn
+                #count_events_fn
+                #check_assertions_fn
             }
+
+            // simulators are moved to worker threads by the grader and the debugger
+            const _: () = {
+                fn assert_send<T: Send>() {}
+                #[allow(unused)]
+                fn assert_sim_send() {
+                    assert_send::<crate::framework::PipeSim<Arch>>();
+                }
+            };
             impl std::fmt::Display for crate::framework::PipeSim<Arch> {
                 fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-                    write!(f, "{}", self.circuit.order)
+                    write!(f, "{}\n{}", self.circuit.order, Arch::timing())
                 }
             }
             impl crate::framework::CpuSim for crate::framework::PipeSim<Arch> {
@@ -569,8 +1255,9 @@ impl HclData {
                     self.cur_state.mux(&self.nex_state);
                 }
                 fn propagate_signals(&mut self) {
-                    self.update();
+                    self.tunnels = self.update().tunnel;
                     self.cycle_count += 1;
+                    self.count_events();
 
                     if self.cur_inter.#termination {
                         self.terminate = true;
@@ -586,7 +1273,19 @@ impl HclData {
                     self.cycle_count
                 }
                 fn cycle_cost(&self) -> u64 {
-                    self.circuit.order.max_dist as u64
+                    Arch::timing().cycle_cost()
+                }
+                fn timing(&self) -> &crate::framework::TimingModel {
+                    Arch::timing()
+                }
+                fn counters(&self) -> &crate::framework::PerfCounters {
+                    &self.counters
+                }
+                fn tunnels(&self) -> &[&'static str] {
+                    &self.tunnels
+                }
+                fn diagram(&self) -> Option<&str> {
+                    #diagram_fn
                 }
                 fn registers(&self) -> crate::isa::RegFile {
                     use crate::framework::HardwareUnits;
@@ -594,8 +1293,9 @@ impl HclData {
                 }
 
                 #get_stage_info_fn
+                #signal_access_fn
 
-                fn step(&mut self) {
+                fn step(&mut self) -> Result<(), crate::framework::AssertionFailure> {
                     use binutils::clap::builder::styling::*;
                     let title_style = Style::new().bold();
 
@@ -612,6 +1312,7 @@ impl HclData {
 
                     use crate::framework::CpuSim;
                     self.propagate_signals();
+                    let checked = self.check_assertions();
 
                     tracing::trace!("{:?}", self.get_stage_info());
 
@@ -627,12 +1328,14 @@ impl HclData {
                         let stages = self.get_stage_info();
                         for stage in stages {
                             tracing::info!("{:-^70}", format!(" {} ", stage.name));
-                            for (name, val) in stage.signals {
-                                tracing::info!("{:<10} = {}", name, val);
+                            for sig in stage.signals {
+                                tracing::info!("{:<10} = {}", sig.name, sig.value);
                             }
                         }
                     }
 
+                    // the state of the failing cycle is printed above
+                    checked?;
                     if self.is_terminate() {
                         if self.tty_out {
                             println!("terminate!");
@@ -640,6 +1343,7 @@ impl HclData {
                     } else {
                         self.initiate_next_cycle();
                     }
+                    Ok(())
                 }
 
                 fn proporder(&self) -> &crate::framework::PropOrder {
@@ -655,7 +1359,7 @@ impl HclData {
 /// inputs of units through Boolean expressions.
 #[proc_macro]
 pub fn hcl(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    let data: HclData = syn::parse(item).unwrap();
+    let data = syn::parse_macro_input!(item as HclData);
     data.render().into()
 }
 
@@ -719,7 +1423,7 @@ pub fn extra_pipelines(_item: proc_macro::TokenStream) -> proc_macro::TokenStrea
 
         pub fn create_sim(
             kind: String, memory: super::MemData, tty_out: bool
-        ) -> Box<dyn super::CpuSim> {
+        ) -> Box<dyn super::CpuSim + Send> {
             match kind.as_str() {
                 #case_stmts
                 _ => panic!("Unknown architecture: {}", kind),
@@ -0,0 +1,338 @@
+//! Modules of HCL, named blocks of signals that can be instantiated many
+//! times, e.g. the forwarding logic of `valA` and `valB`:
+//!
+//! ```plain
+//! module forward(src, rval, e_dst, e_val, m_val) -> (val) {
+//!     u64 val = [
+//!         src == e_dst : e_val;
+//!         src == M.dstM : m_val;
+//!         src == M.dstE : M.valE;
+//!         true : rval;
+//!     ];
+//! }
+//!
+//! @instance(fwd_a = forward, { src: d_srcA, rval: reg_file.valA, ... });
+//! u64 d_valA = fwd_a.val;
+//! ```
+//!
+//! An instance is expanded into ordinary signals before anything else is
+//! done: signal `val` of instance `fwd_a` is named `fwd_a.val`, as it is read
+//! outside the module, and its field of `IntermediateSignal` is `_5_fwd_a_val`
+//! (see [`instance_signal`]). In the body, a parameter stands for the
+//! lvalue bound to it: a signal, a unit port, a stage field or a whole stage.
+//! A signal may drive a parameter too, e.g. `-> pred` with `pred: f.pred_pc`.
+
+use quote::format_ident;
+use syn::{parse::Parse, punctuated::Punctuated, Token};
+
+use crate::expr::LValue;
+use crate::items::{ComponentInputs, FieldAssign, SignalDef};
+
+syn::custom_keyword!(module);
+
+/// A `@set_input` or `@set_stage` directive, `true` for `@set_stage`.
+pub type UnitInputs = (ComponentInputs, bool);
+
+/// `module name(params) -> (outputs) { ... }`, containing signals and
+/// `@set_input`/`@set_stage` directives.
+pub struct Module {
+    pub name: syn::Ident,
+    pub params: Punctuated<syn::Ident, Token![,]>,
+    pub outputs: Punctuated<syn::Ident, Token![,]>,
+    pub signals: Vec<SignalDef>,
+    pub inputs: Vec<UnitInputs>,
+}
+
+/// The modules of a file given to `@import`.
+pub struct ModuleFile(pub Vec<Module>);
+
+/// `param: lvalue`
+struct Arg(syn::Ident, LValue);
+
+impl Parse for Arg {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let param = input.parse()?;
+        let _ = input.parse::<Token![:]>()?;
+        let value = input.parse()?;
+        Ok(Self(param, value))
+    }
+}
+
+/// `@instance(name = module, { param: lvalue, ... });`
+pub struct Instance {
+    pub name: syn::Ident,
+    pub module: syn::Ident,
+    pub args: Vec<(syn::Ident, LValue)>,
+}
+
+impl Parse for Instance {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let args;
+        let _ = syn::parenthesized!(args in input);
+
+        let name = args.parse()?;
+        let _ = args.parse::<Token![=]>()?;
+        let module = args.parse()?;
+        let _ = args.parse::<Token![,]>()?;
+
+        let fields;
+        let _ = syn::braced!(fields in args);
+        let fields = fields.parse_terminated(Arg::parse, Token![,])?;
+        Ok(Self {
+            name,
+            module,
+            args: fields.into_iter().map(|Arg(p, v)| (p, v)).collect(),
+        })
+    }
+}
+
+impl Module {
+    /// Whether a module definition starts here.
+    pub fn peek(input: syn::parse::ParseStream) -> bool {
+        input.peek(module) && input.peek2(syn::Ident) && input.peek3(syn::token::Paren)
+    }
+
+    fn is_param(&self, ident: &syn::Ident) -> bool {
+        self.params.iter().any(|p| p == ident)
+    }
+
+    fn is_signal(&self, ident: &syn::Ident) -> bool {
+        self.signals.iter().any(|s| &s.name == ident)
+    }
+
+    /// The body may only read its parameters and its own signals, besides
+    /// constants, unit ports and stage fields.
+    fn validate(&self) -> syn::Result<()> {
+        let mut errors = Vec::new();
+        let mut check = |ident: &syn::Ident| {
+            let is_constant = ident.to_string().starts_with(|c: char| c.is_uppercase());
+            if !is_constant && !self.is_param(ident) && !self.is_signal(ident) {
+                errors.push(syn::Error::new_spanned(
+                    ident,
+                    format!(
+                        "`{ident}` is neither a parameter nor a signal of module `{}`",
+                        self.name
+                    ),
+                ));
+            }
+        };
+        for signal in &self.signals {
+            for lv in signal.source.lvalues() {
+                if lv.0.len() == 1 {
+                    check(&lv.0[0]);
+                }
+            }
+        }
+        for (inputs, _) in &self.inputs {
+            for FieldAssign(_, signal) in &inputs.fields {
+                check(signal);
+            }
+        }
+        for output in &self.outputs {
+            if !self.is_signal(output) {
+                errors.push(syn::Error::new_spanned(
+                    output,
+                    format!(
+                        "output `{output}` is not a signal of module `{}`",
+                        self.name
+                    ),
+                ));
+            }
+        }
+        match errors.into_iter().reduce(|mut a, b| {
+            a.combine(b);
+            a
+        }) {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Signals and `@set_input`/`@set_stage` directives of `inst`, with the
+    /// signals renamed and the parameters replaced by their arguments.
+    pub fn instantiate(&self, inst: &Instance) -> syn::Result<(Vec<SignalDef>, Vec<UnitInputs>)> {
+        for (i, (param, _)) in inst.args.iter().enumerate() {
+            if !self.is_param(param) {
+                return Err(syn::Error::new_spanned(
+                    param,
+                    format!("module `{}` has no parameter `{param}`", self.name),
+                ));
+            }
+            if inst.args[..i].iter().any(|(p, _)| p == param) {
+                return Err(syn::Error::new_spanned(
+                    param,
+                    format!("parameter `{param}` is bound twice"),
+                ));
+            }
+        }
+        let missing = self
+            .params
+            .iter()
+            .filter(|p| inst.args.iter().all(|(a, _)| a != *p))
+            .map(|p| format!("`{p}`"))
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            return Err(syn::Error::new_spanned(
+                &inst.name,
+                format!(
+                    "missing arguments of module `{}`: {}",
+                    self.name,
+                    missing.join(", ")
+                ),
+            ));
+        }
+
+        let rename = |lv: LValue| -> LValue {
+            let head = &lv.0[0];
+            let mut renamed = if let Some((_, arg)) = inst.args.iter().find(|(p, _)| p == head) {
+                arg.clone()
+            } else if self.is_signal(head) {
+                LValue(std::iter::once(instance_signal(&inst.name, head)).collect())
+            } else {
+                return lv;
+            };
+            renamed.0.extend(lv.0.into_iter().skip(1));
+            renamed
+        };
+
+        let signals = self
+            .signals
+            .iter()
+            .map(|signal| {
+                let mut signal = signal.clone();
+                signal.name = instance_signal(&inst.name, &signal.name);
+                signal.source = signal.source.map(rename);
+                for dest in &mut signal.destinations {
+                    dest.dest = dest.dest.clone().map(rename);
+                }
+                signal
+            })
+            .collect();
+
+        let mut inputs = Vec::new();
+        for (directive, is_stage) in &self.inputs {
+            let mut directive = directive.clone();
+            for FieldAssign(_, signal) in directive.fields.iter_mut() {
+                let lv = rename(LValue(std::iter::once(signal.clone()).collect()));
+                if lv.0.len() != 1 {
+                    return Err(syn::Error::new_spanned(
+                        &*signal,
+                        format!(
+                            "`{signal}` must be bound to a signal, since it's used in a directive"
+                        ),
+                    ));
+                }
+                *signal = lv.0[0].clone();
+            }
+            inputs.push((directive, *is_stage));
+        }
+        Ok((signals, inputs))
+    }
+}
+
+/// Field of signal `name` of instance `inst`. The length of `inst` keeps
+/// `fwd_a.val` and `fwd.a_val` apart, and a signal outside the modules can't
+/// start with `_` and a digit.
+pub fn instance_signal(inst: &syn::Ident, name: &syn::Ident) -> syn::Ident {
+    let inst = inst.to_string();
+    format_ident!("_{}_{}_{}", inst.len(), inst, name, span = name.span())
+}
+
+/// Name of the signal of field `ident`, `fwd_a.val` for an instance signal.
+pub fn signal_name(ident: &syn::Ident) -> String {
+    let field = ident.to_string();
+    let decoded = field
+        .strip_prefix('_')
+        .and_then(|rest| rest.split_once('_'))
+        .and_then(|(len, rest)| {
+            let len = len.parse::<usize>().ok()?;
+            let (inst, name) = (rest.get(..len)?, rest.get(len..)?.strip_prefix('_')?);
+            Some(format!("{inst}.{name}"))
+        });
+    decoded.unwrap_or(field)
+}
+
+/// Whether `ident` can't name a signal outside the modules, as it could be
+/// the field of an instance signal.
+pub fn is_reserved(ident: &syn::Ident) -> bool {
+    let name = ident.to_string();
+    let mut chars = name.chars();
+    chars.next() == Some('_') && chars.next().is_some_and(|c| c.is_ascii_digit())
+}
+
+impl Parse for Module {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let _ = input.parse::<module>()?;
+        let name = input.parse()?;
+
+        let params;
+        let _ = syn::parenthesized!(params in input);
+        let params = params.parse_terminated(syn::Ident::parse, Token![,])?;
+        let _ = input.parse::<Token![->]>()?;
+        let outputs;
+        let _ = syn::parenthesized!(outputs in input);
+        let outputs = outputs.parse_terminated(syn::Ident::parse, Token![,])?;
+
+        let body;
+        let _ = syn::braced!(body in input);
+        let mut signals = Vec::new();
+        let mut inputs = Vec::new();
+        while !body.is_empty() {
+            if body.peek(Token![@]) {
+                let _ = body.parse::<Token![@]>()?;
+                let directive = body.parse::<syn::Ident>()?;
+                let is_stage = directive == "set_stage";
+                if !is_stage && directive != "set_input" {
+                    return Err(syn::Error::new_spanned(
+                        &directive,
+                        format!("`@{directive}` can't be used in a module, expected `@set_input` or `@set_stage`"),
+                    ));
+                }
+                inputs.push((body.parse()?, is_stage));
+                let _ = body.parse::<Token![;]>()?;
+            } else {
+                signals.push(body.parse()?);
+            }
+        }
+
+        let module = Self {
+            name,
+            params,
+            outputs,
+            signals,
+            inputs,
+        };
+        module.validate()?;
+        Ok(module)
+    }
+}
+
+impl Parse for ModuleFile {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let mut modules = Vec::new();
+        while !input.is_empty() {
+            modules.push(input.parse()?);
+        }
+        Ok(Self(modules))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instance_signal() {
+        let field = |inst: &str, name: &str| {
+            instance_signal(&format_ident!("{inst}"), &format_ident!("{name}"))
+        };
+        let (a, b) = (field("fwd_a", "val"), field("fwd", "a_val"));
+        assert_eq!(a, "_5_fwd_a_val");
+        assert_ne!(a, b);
+        assert_eq!(signal_name(&a), "fwd_a.val");
+        assert_eq!(signal_name(&b), "fwd.a_val");
+        assert_eq!(signal_name(&format_ident!("_1_x")), "_1_x");
+        assert!(is_reserved(&a));
+        assert!(!is_reserved(&format_ident!("_valA")));
+    }
+}
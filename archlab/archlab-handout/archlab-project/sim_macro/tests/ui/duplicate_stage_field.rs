@@ -0,0 +1,12 @@
+sim_macro::hcl! {
+#![hardware = crate::hardware]
+#![program_counter = pc]
+#![termination = done]
+#![stage_alias(F => f)]
+
+u64 pc = F.pc + 1 -> f.pc;
+u64 next = F.pc + 2 -> f.pc;
+bool done = false;
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+sim_macro::hcl! {
+#![hardware = crate::hardware]
+#![termination = done]
+#![stage_alias(F => f)]
+
+u64 pc = F.pc + 1 -> f.pc;
+bool done = false;
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+sim_macro::hcl! {
+#![hardware = crate::hardware]
+#![program_counter = pc]
+#![stage_alias(F => f)]
+
+u64 pc = F.pc + 1 -> f.pc;
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+sim_macro::hcl! {
+#![hardware = crate::hardware]
+#![program_counter = pc]
+#![termination = done]
+#![stage_alias(F => f)]
+
+u64 pc = F.pc + step -> f.pc;
+bool done = false;
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+sim_macro::hcl! {
+#![hardware = crate::hardware]
+#![program_counter = pc]
+#![termination = done]
+#![stage_alias(F => f)]
+
+u64 pc = F.pc + 1 -> f.pc;
+bool done = D.stat == Hlt;
+}
+
+fn main() {}